@@ -1,22 +1,258 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::env;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::Path;
 
 fn main() {
-    // create perfect hash table for timezone lookup
-    let raw_data = include_str!("timezones.json");
-    let tz_map: HashMap<String, String> = serde_json::from_str(raw_data).unwrap();
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("timezone-codegen.rs");
+    let mut file = BufWriter::new(File::create(path).unwrap());
+    let minimal = env::var_os("CARGO_FEATURE_MINIMAL").is_some();
+    // create perfect hash tables for timezone, region and language lookups,
+    // empty ones in minimal builds
+    write_map(
+        &mut file,
+        "TIMEZONES",
+        minimal,
+        include_str!("timezones.json"),
+    );
+    write_map(
+        &mut file,
+        "SUBDIVISIONS",
+        minimal,
+        include_str!("subdivisions.json"),
+    );
+    write_map(
+        &mut file,
+        "CONTINENTS",
+        minimal,
+        include_str!("continents.json"),
+    );
+    write_map(
+        &mut file,
+        "LANGUAGE_NAMES",
+        minimal,
+        include_str!("languages.json"),
+    );
+    write_map(
+        &mut file,
+        "REGION_NAMES",
+        minimal,
+        include_str!("regions.json"),
+    );
+    // the known region codes in every build, so timezone overrides can be
+    // validated and interned without the tables above
+    let continents: HashMap<String, String> =
+        serde_json::from_str(include_str!("continents.json")).unwrap();
+    let mut regions = phf_codegen::Set::new();
+    for region in continents.keys() {
+        regions.entry(region.as_str());
+    }
+    writeln!(
+        file,
+        "pub static REGIONS: phf::Set<&'static str> = {};",
+        regions.build()
+    )
+    .unwrap();
+
+    // static names of the browser and os families the uap parser returns,
+    // so parsed user agents can borrow instead of allocating them
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("ua-codegen.rs");
+    let mut file = BufWriter::new(File::create(path).unwrap());
+    let mut families = phf_codegen::Set::new();
+    if !minimal {
+        for family in ua_families(include_str!("uap-core/regexes.yaml")) {
+            families.entry(family);
+        }
+    }
+    writeln!(
+        file,
+        "pub static UA_FAMILIES: phf::Set<&'static str> = {};",
+        families.build()
+    )
+    .unwrap();
+
+    if !minimal && env::var_os("CARGO_FEATURE_TRIM_UA_REGEXES").is_some() {
+        let path = Path::new(&env::var("OUT_DIR").unwrap()).join("regexes.yaml");
+        let regexes = trim_regexes(include_str!("uap-core/regexes.yaml"));
+        std::fs::write(path, regexes).unwrap();
+    }
+}
+
+/// Browsers, operating systems and makers of devices that have not been in
+/// use for years.
+const OBSOLETE: &[&str] = &[
+    "BlackBerry",
+    "BB10",
+    "Bada",
+    "BREW",
+    "Brew",
+    "J2ME",
+    "MIDP",
+    "Maemo",
+    "MeeGo",
+    "NetFront",
+    "Nokia",
+    "Obigo",
+    "Openwave",
+    "Palm",
+    "PlayBook",
+    "RIM Tablet",
+    "S40",
+    "S60",
+    "Series40",
+    "Series60",
+    "SymbOS",
+    "Symbian",
+    "Teleca",
+    "UP.Browser",
+    "Windows CE",
+    "Windows Mobile",
+    "Windows Phone",
+    "hpwOS",
+    "webOS",
+];
+
+/// Removes the device parsers, which the collector doesn't use, and the
+/// parsers mentioning an [`OBSOLETE`] name from uap-core's regexes.yaml.
+///
+/// Works on lines since the file has a fixed layout: sections at the top
+/// level, parsers starting with `  - regex:` and their fields indented by
+/// four spaces.
+fn trim_regexes(regexes: &str) -> String {
+    let mut trimmed = String::with_capacity(regexes.len());
+    let mut parser = String::new();
+    let flush = |parser: &mut String, trimmed: &mut String| {
+        if !OBSOLETE.iter().any(|name| parser.contains(name)) {
+            trimmed.push_str(parser);
+        }
+        parser.clear();
+    };
+    let mut devices = false;
+    for line in regexes.lines() {
+        if !line.starts_with([' ', '#']) && line.ends_with(':') {
+            devices = line == "device_parsers:";
+            if devices {
+                flush(&mut parser, &mut trimmed);
+                trimmed.push_str("device_parsers: []\n");
+                continue;
+            }
+        }
+        if devices {
+            continue;
+        }
+        if line.starts_with("    ") {
+            parser.push_str(line);
+            parser.push('\n');
+            continue;
+        }
+        flush(&mut parser, &mut trimmed);
+        if line.trim_start().starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if line.starts_with("  - ") {
+            parser.push_str(line);
+            parser.push('\n');
+        } else {
+            trimmed.push_str(line);
+            trimmed.push('\n');
+        }
+    }
+    flush(&mut parser, &mut trimmed);
+    trimmed
+}
+
+/// The families the browser and os parsers of uap-core's regexes.yaml
+/// return verbatim: replacements without placeholders and the literal
+/// alternatives of first capture groups, like `Firefox` of `(Firefox)`.
+fn ua_families(regexes: &str) -> BTreeSet<String> {
+    let unquote = |value: &str| {
+        let value = value.trim();
+        value
+            .strip_prefix('\'')
+            .and_then(|value| value.strip_suffix('\''))
+            .unwrap_or(value)
+            .replace("''", "'")
+    };
+    let literal = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || " _-.".contains(c))
+    };
+    // what the parser returns if no regex matches
+    let mut families = BTreeSet::from(["Other".to_string()]);
+    let mut parsers = false;
+    for line in regexes.lines() {
+        if !line.starts_with([' ', '#']) && line.ends_with(':') {
+            parsers = line != "device_parsers:";
+        }
+        if !parsers {
+            continue;
+        }
+        let line = line.trim_start();
+        if let Some(regex) = line.strip_prefix("- regex:") {
+            let alternatives = first_group(&unquote(regex));
+            families.extend(alternatives.into_iter().filter(|name| literal(name)));
+        } else if let Some((_, replacement)) = line
+            .split_once("family_replacement:")
+            .or_else(|| line.split_once("os_replacement:"))
+        {
+            let replacement = unquote(replacement);
+            if !replacement.contains('$') && !replacement.is_empty() {
+                families.insert(replacement);
+            }
+        }
+    }
+    families
+}
+
+/// The top-level alternatives of the first capturing group of a regex.
+fn first_group(regex: &str) -> Vec<String> {
+    let mut chars = regex.chars().peekable();
+    let (mut depth, mut escaped) = (0, false);
+    let mut alternatives = vec![String::new()];
+    while let Some(c) = chars.next() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '(' {
+            if depth == 0 && chars.peek() == Some(&'?') {
+                continue;
+            }
+            depth += 1;
+            if depth == 1 {
+                continue;
+            }
+        } else if c == ')' && depth > 0 {
+            depth -= 1;
+            if depth == 0 {
+                return alternatives;
+            }
+        } else if c == '|' && depth == 1 {
+            alternatives.push(String::new());
+            continue;
+        }
+        if depth > 0 {
+            alternatives.last_mut().unwrap().push(c);
+        }
+    }
+    Vec::new()
+}
+
+fn write_map(file: &mut impl Write, name: &str, empty: bool, raw_data: &str) {
+    let tz_map: HashMap<String, String> = match empty {
+        true => HashMap::new(),
+        false => serde_json::from_str(raw_data).unwrap(),
+    };
     let mut map = phf_codegen::Map::new();
     for (key, value) in tz_map.into_iter() {
-        map.entry(key, format!("\"{}\"", value).as_str());
+        map.entry(key, format!("{:?}", value).as_str());
     }
-    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("timezone-codegen.rs");
-    let mut file = BufWriter::new(File::create(path).unwrap());
     writeln!(
-        &mut file,
-        "pub static TIMEZONES: phf::Map<&'static str, &'static str> = {};",
+        file,
+        "pub static {name}: phf::Map<&'static str, &'static str> = {};",
         map.build()
     )
     .unwrap();
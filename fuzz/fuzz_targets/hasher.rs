@@ -0,0 +1,17 @@
+//! Both hashers over inputs of any length and alignment.
+
+#![no_main]
+
+use abineo_analytics_collector::hash::Hasher;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (u64, Vec<u8>, Option<Vec<u8>>)| {
+    let (chunk, bytes, optional) = input;
+    for mut hasher in [Hasher::new(), Hasher::hardened()] {
+        hasher.write(chunk);
+        hasher.write_bytes(&bytes);
+        hasher.write_optional_bytes(optional.as_deref());
+        let _ = hasher.finalize();
+    }
+    let _ = Hasher::hash_bytes(&bytes);
+});
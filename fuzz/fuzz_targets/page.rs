@@ -0,0 +1,17 @@
+//! Page, campaign and referrer ids of arbitrary urls.
+
+#![no_main]
+
+use abineo_analytics_collector::{Page, Referrer, UtmParam};
+use libfuzzer_sys::fuzz_target;
+use url::Url;
+
+fuzz_target!(|url: &str| {
+    let Ok(url) = Url::parse(url) else {
+        return;
+    };
+    let _ = UtmParam::new(1, &url);
+    if let Ok(page) = Page::new(1, &url) {
+        let _ = Referrer::new(1, Some(&url), &page.domain);
+    }
+});
@@ -0,0 +1,30 @@
+//! Beacon bodies as sent by anyone on the internet, decoded and enriched
+//! like the api functions do.
+
+#![no_main]
+
+use abineo_analytics_collector::api::{decode, PubEvent, PubVisit};
+use abineo_analytics_collector::{Page, Referrer, UtmParam, Visitor};
+use libfuzzer_sys::fuzz_target;
+
+const USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36";
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode(data);
+    if let Ok(visit) = serde_json::from_slice::<PubVisit>(data) {
+        let _ = Visitor::new(1, &visit.visitor, USER_AGENT);
+        let _ = UtmParam::new(1, &visit.page.url);
+        let canonical = visit.page.canonical.as_ref();
+        if let Ok(page) = Page::with_canonical(1, &visit.page.url, canonical) {
+            let _ = Referrer::new(1, visit.page.referrer.as_ref(), &page.domain);
+        }
+    }
+    if let Ok(event) = serde_json::from_slice::<PubEvent>(data) {
+        let _ = Visitor::new(1, &event.visitor, USER_AGENT);
+        let _ = Page::new(1, &event.page.url);
+    }
+    // the user agent header is just as public
+    if let Ok(user_agent) = std::str::from_utf8(data) {
+        let _ = Visitor::new(1, &Default::default(), user_agent);
+    }
+});
@@ -0,0 +1,9 @@
+//! Streaming aggregations over processed records.
+//!
+//! Aggregates are kept in memory and flushed periodically, so reports don't
+//! have to scan the raw records.
+
+pub mod live;
+pub mod paths;
+pub mod sketch;
+pub mod top;
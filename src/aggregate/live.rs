@@ -0,0 +1,630 @@
+//! Live counters of the recent past, e.g. for a realtime dashboard served
+//! directly by the collector.
+//!
+//! An [`Aggregator`] keeps the page views, events, unique visitors and top
+//! pages, referrers and countries of each project in short buckets.
+//! [`Aggregator::query`] combines the buckets of a trailing window, so the
+//! answers are approximate: unique visitors are estimated with a
+//! HyperLogLog, top keys of buckets are merged from their heavy hitters.
+//! Buckets are downsampled as they age, see [`Aggregator::with_rollup`].
+//!
+//! All state is mergeable, a coordinator can combine the
+//! [snapshots](Aggregator::snapshot) of a fleet of collectors with
+//! [`Aggregator::merge_from`].
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::top::{HeavyHitter, SpaceSaving};
+use crate::{clock, Error, Record};
+
+/// Registers of a [`UniqueCounter`] are addressed by this many bits, the
+/// standard error is about `1.04 / sqrt(2^bits)`, 3.3%.
+const REGISTER_BITS: u32 = 10;
+
+/// The splitmix64 finalizer, ids of persistent visitors aren't hashes and
+/// need to be spread over the registers.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Estimates the number of distinct ids with a HyperLogLog.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<u8>", into = "Vec<u8>")]
+pub struct UniqueCounter {
+    registers: Box<[u8]>,
+}
+
+impl TryFrom<Vec<u8>> for UniqueCounter {
+    type Error = Error;
+
+    fn try_from(registers: Vec<u8>) -> Result<Self, Self::Error> {
+        if registers.len() != 1 << REGISTER_BITS {
+            return Err(Error::IncompatibleSketch);
+        }
+        Ok(UniqueCounter {
+            registers: registers.into_boxed_slice(),
+        })
+    }
+}
+
+impl From<UniqueCounter> for Vec<u8> {
+    fn from(counter: UniqueCounter) -> Self {
+        counter.registers.into_vec()
+    }
+}
+
+impl Default for UniqueCounter {
+    fn default() -> Self {
+        UniqueCounter {
+            registers: vec![0; 1 << REGISTER_BITS].into_boxed_slice(),
+        }
+    }
+}
+
+impl UniqueCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, id: i64) {
+        let hash = mix(id as u64);
+        let register = (hash >> (64 - REGISTER_BITS)) as usize;
+        let rank = (hash << REGISTER_BITS)
+            .leading_zeros()
+            .min(64 - REGISTER_BITS)
+            + 1;
+        self.registers[register] = self.registers[register].max(rank as u8);
+    }
+
+    /// Adds the ids counted by `other`.
+    pub fn merge(&mut self, other: &UniqueCounter) {
+        for (register, other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|rank| 2f64.powi(-i32::from(*rank)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|rank| **rank == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // linear counting is more accurate for small sets
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
+
+/// What [`Aggregator::query`] reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Page views, exit beacons and embeds are not counted.
+    Views,
+    Events,
+    /// Estimated distinct visitors of views and events.
+    Visitors,
+    /// The `n` most viewed page ids.
+    TopPages(usize),
+    /// The `n` most frequent referrer domains of views.
+    TopReferrers(usize),
+    /// The `n` most frequent regions of views.
+    TopCountries(usize),
+}
+
+/// Answer of [`Aggregator::query`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum LiveValue {
+    Count(u64),
+    Pages(Vec<HeavyHitter<i64>>),
+    Keys(Vec<HeavyHitter<String>>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Bucket {
+    start: DateTime<Utc>,
+    views: u64,
+    events: u64,
+    visitors: UniqueCounter,
+    pages: SpaceSaving<i64>,
+    referrers: SpaceSaving<String>,
+    countries: SpaceSaving<String>,
+}
+
+impl Bucket {
+    fn new(start: DateTime<Utc>, capacity: usize) -> Self {
+        Bucket {
+            start,
+            views: 0,
+            events: 0,
+            visitors: UniqueCounter::new(),
+            pages: SpaceSaving::new(capacity),
+            referrers: SpaceSaving::new(capacity),
+            countries: SpaceSaving::new(capacity),
+        }
+    }
+
+    /// Adds the counts of a finer bucket or of another instance.
+    fn merge(&mut self, other: &Bucket) {
+        self.views += other.views;
+        self.events += other.events;
+        self.visitors.merge(&other.visitors);
+        self.pages.merge(&other.pages);
+        self.referrers.merge(&other.referrers);
+        self.countries.merge(&other.countries);
+    }
+}
+
+/// Sums the heavy hitters of several buckets, the `n` highest first.
+fn merge_top<K: Clone + Ord + std::hash::Hash>(
+    tops: impl Iterator<Item = Vec<HeavyHitter<K>>>,
+    n: usize,
+) -> Vec<HeavyHitter<K>> {
+    let mut merged: HashMap<K, (u64, u64)> = HashMap::new();
+    for top in tops {
+        for hitter in top {
+            let (count, error) = merged.entry(hitter.key).or_default();
+            *count += hitter.count;
+            *error += hitter.error;
+        }
+    }
+    let mut merged: Vec<_> = merged
+        .into_iter()
+        .map(|(key, (count, error))| HeavyHitter { key, count, error })
+        .collect();
+    merged.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+    merged.truncate(n);
+    merged
+}
+
+/// Bucket size and retention of a resolution of an [`Aggregator`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub bucket: Duration,
+    /// Buckets ending before this are rolled up into the next coarser
+    /// resolution, or dropped by the coarsest.
+    pub retention: Duration,
+}
+
+impl Resolution {
+    fn bucket_start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let bucket = self.bucket.num_milliseconds();
+        let start = time.timestamp_millis().div_euclid(bucket) * bucket;
+        Utc.timestamp_millis_opt(start).unwrap()
+    }
+
+    /// Start of the oldest bucket kept at `now`.
+    fn oldest(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        self.bucket_start(now - self.retention)
+    }
+}
+
+/// Buckets of a project per resolution, finest first, each sorted by start.
+type Tiers = Vec<VecDeque<Bucket>>;
+
+/// Serialized state of an [`Aggregator`].
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// Bucket sizes of the resolutions in milliseconds.
+    buckets: Vec<i64>,
+    projects: BTreeMap<i64, Tiers>,
+}
+
+/// Live counters of each project, downsampled as they age.
+///
+/// Records are counted in buckets of the finest [`Resolution`]. Buckets
+/// older than its retention are rolled up into the buckets of the next
+/// coarser resolution, e.g. minutes into hours into days, so memory stays
+/// bounded while recent data remains detailed. Buckets are aligned to the
+/// unix epoch like the windows of
+/// [`TopContent`](crate::aggregate::top::TopContent), records older than
+/// the coarsest retention are ignored.
+#[derive(Debug)]
+pub struct Aggregator {
+    capacity: usize,
+    resolutions: Vec<Resolution>,
+    projects: Mutex<HashMap<i64, Tiers>>,
+}
+
+impl Default for Aggregator {
+    /// Minute buckets of the last hour, hour buckets of the last two days
+    /// and day buckets of the last 30 days, tracking 100 top keys each.
+    fn default() -> Self {
+        Aggregator::new(100, Duration::minutes(1), Duration::hours(1))
+            .with_rollup(Duration::hours(1), Duration::days(2))
+            .with_rollup(Duration::days(1), Duration::days(30))
+    }
+}
+
+impl Aggregator {
+    /// Tracks up to `capacity` top keys per dimension, project and bucket,
+    /// in buckets of a single resolution.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero, `bucket` is not positive or
+    /// `retention` is shorter than `bucket`.
+    pub fn new(capacity: usize, bucket: Duration, retention: Duration) -> Self {
+        assert!(capacity > 0, "capacity must be positive");
+        assert!(bucket > Duration::zero(), "bucket must be positive");
+        assert!(retention >= bucket, "retention must span a bucket");
+        Aggregator {
+            capacity,
+            resolutions: vec![Resolution { bucket, retention }],
+            projects: Mutex::default(),
+        }
+    }
+
+    /// Rolls buckets beyond the retention of the coarsest resolution up
+    /// into buckets of `bucket`, kept for `retention`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bucket` is not a multiple of the coarsest bucket or
+    /// `retention` doesn't exceed the coarsest retention.
+    pub fn with_rollup(mut self, bucket: Duration, retention: Duration) -> Self {
+        let coarsest = self.resolutions.last().expect("a resolution");
+        assert!(
+            bucket > coarsest.bucket
+                && bucket.num_milliseconds() % coarsest.bucket.num_milliseconds() == 0,
+            "bucket must be a multiple of the coarsest bucket"
+        );
+        assert!(
+            retention > coarsest.retention,
+            "retention must exceed the coarsest retention"
+        );
+        self.resolutions.push(Resolution { bucket, retention });
+        self
+    }
+
+    pub fn resolutions(&self) -> &[Resolution] {
+        &self.resolutions
+    }
+
+    /// Number of buckets held in memory, over all projects and resolutions.
+    pub fn len(&self) -> usize {
+        let projects = self.projects.lock().unwrap();
+        projects.values().flatten().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The state of all projects, e.g. to be sent to a coordinator.
+    pub fn snapshot(&self) -> Result<Vec<u8>, Error> {
+        let projects = self.projects.lock().unwrap();
+        let snapshot = Snapshot {
+            buckets: self.bucket_sizes(),
+            projects: projects
+                .iter()
+                .map(|(project, tiers)| (*project, tiers.clone()))
+                .collect(),
+        };
+        Ok(serde_json::to_vec(&snapshot)?)
+    }
+
+    /// Adds the counts of a [snapshot](Aggregator::snapshot) of another
+    /// instance, which needs the same bucket sizes. Buckets beyond the
+    /// retention are rolled up or dropped as usual.
+    pub fn merge_from(&self, bytes: &[u8]) -> Result<(), Error> {
+        let snapshot: Snapshot = serde_json::from_slice(bytes)?;
+        if snapshot.buckets != self.bucket_sizes()
+            || (snapshot.projects.values()).any(|tiers| tiers.len() != self.resolutions.len())
+        {
+            return Err(Error::IncompatibleAggregate);
+        }
+        let now = clock::now();
+        let mut projects = self.projects.lock().unwrap();
+        for (project, other) in snapshot.projects {
+            let tiers = projects
+                .entry(project)
+                .or_insert_with(|| vec![VecDeque::new(); self.resolutions.len()]);
+            for (buckets, other) in tiers.iter_mut().zip(other) {
+                for bucket in other {
+                    self.bucket(buckets, bucket.start).merge(&bucket);
+                }
+            }
+            self.compact(tiers, now);
+        }
+        Ok(())
+    }
+
+    fn bucket_sizes(&self) -> Vec<i64> {
+        self.resolutions
+            .iter()
+            .map(|resolution| resolution.bucket.num_milliseconds())
+            .collect()
+    }
+
+    /// Rolls up or drops the buckets beyond their retention.
+    fn compact(&self, tiers: &mut Tiers, now: DateTime<Utc>) {
+        for (i, resolution) in self.resolutions.iter().enumerate() {
+            let oldest = resolution.oldest(now);
+            while tiers[i].front().is_some_and(|bucket| bucket.start < oldest) {
+                let expired = tiers[i].pop_front().expect("bucket exists");
+                if let Some(coarser) = self.resolutions.get(i + 1) {
+                    let start = coarser.bucket_start(expired.start);
+                    self.bucket(&mut tiers[i + 1], start).merge(&expired);
+                }
+            }
+        }
+    }
+
+    /// The bucket starting at `start`, inserted if needed.
+    fn bucket<'a>(
+        &self,
+        buckets: &'a mut VecDeque<Bucket>,
+        start: DateTime<Utc>,
+    ) -> &'a mut Bucket {
+        // late records and rollups go to an earlier bucket
+        let i = buckets.partition_point(|bucket| bucket.start < start);
+        if buckets.get(i).is_none_or(|bucket| bucket.start != start) {
+            buckets.insert(i, Bucket::new(start, self.capacity));
+        }
+        &mut buckets[i]
+    }
+
+    /// Counts a record in the finest resolution that still keeps its time.
+    /// Dry runs and session summaries are ignored.
+    pub fn record(&self, record: &Record) {
+        if record.is_dry_run() || matches!(record, Record::Session(_)) {
+            return;
+        }
+        let now = clock::now();
+        let Some((tier, resolution)) = self
+            .resolutions
+            .iter()
+            .enumerate()
+            .find(|(_, resolution)| record.time() >= resolution.oldest(now))
+        else {
+            return;
+        };
+        let mut projects = self.projects.lock().unwrap();
+        let tiers = projects
+            .entry(record.project())
+            .or_insert_with(|| vec![VecDeque::new(); self.resolutions.len()]);
+        self.compact(tiers, now);
+        let start = resolution.bucket_start(record.time());
+        let bucket = self.bucket(&mut tiers[tier], start);
+
+        match record {
+            Record::Visit(visit) if visit.duration.is_none() && visit.embed.is_none() => {
+                bucket.views += 1;
+                bucket.visitors.insert(visit.visitor.id);
+                bucket.pages.insert(visit.page.id);
+                if let Some(referrer) = &visit.referrer {
+                    bucket.referrers.insert(referrer.domain.clone());
+                }
+                if let Some(region) = &visit.visitor.region {
+                    bucket.countries.insert(region.to_string());
+                }
+            }
+            Record::Event(event) => {
+                bucket.events += 1;
+                bucket.visitors.insert(event.visitor.id);
+            }
+            _ => {}
+        }
+    }
+
+    /// The current value of a metric over the trailing `window`, capped at
+    /// the coarsest retention.
+    ///
+    /// The bucket the window starts in is included. Beyond the retention of
+    /// the finest resolution, the window is rounded to the rolled up
+    /// buckets: they count if they start within the window.
+    pub fn query(&self, project_id: i64, metric: Metric, window: Duration) -> LiveValue {
+        let now = clock::now();
+        let coarsest = self.resolutions.last().expect("a resolution");
+        let from = self.resolutions[0].bucket_start(now - window.min(coarsest.retention));
+        let mut projects = self.projects.lock().unwrap();
+        let tiers = projects.get_mut(&project_id);
+        if let Some(tiers) = tiers {
+            self.compact(tiers, now);
+        }
+        let buckets = projects
+            .get(&project_id)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|bucket| bucket.start >= from);
+        match metric {
+            Metric::Views => LiveValue::Count(buckets.map(|bucket| bucket.views).sum()),
+            Metric::Events => LiveValue::Count(buckets.map(|bucket| bucket.events).sum()),
+            Metric::Visitors => {
+                let mut visitors = UniqueCounter::new();
+                buckets.for_each(|bucket| visitors.merge(&bucket.visitors));
+                LiveValue::Count(visitors.estimate())
+            }
+            Metric::TopPages(n) => LiveValue::Pages(merge_top(
+                buckets.map(|bucket| bucket.pages.top(self.capacity)),
+                n,
+            )),
+            Metric::TopReferrers(n) => LiveValue::Keys(merge_top(
+                buckets.map(|bucket| bucket.referrers.top(self.capacity)),
+                n,
+            )),
+            Metric::TopCountries(n) => LiveValue::Keys(merge_top(
+                buckets.map(|bucket| bucket.countries.top(self.capacity)),
+                n,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::{Event, Page, Referrer, Visit, Visitor};
+
+    use super::*;
+
+    fn view(minutes_ago: i64, visitor: i64, page: i64, referrer: Option<&str>) -> Record {
+        Record::Visit(Visit {
+            time: clock::now() - Duration::minutes(minutes_ago),
+            project: 1,
+            visitor: Visitor {
+                id: visitor,
+                region: Some(Cow::Borrowed("CH")),
+                ..Default::default()
+            },
+            page: Page {
+                id: page,
+                ..Default::default()
+            },
+            referrer: referrer.map(|domain| Referrer {
+                domain: domain.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn estimates_unique_ids() {
+        let mut a = UniqueCounter::new();
+        let mut b = UniqueCounter::new();
+        for id in 0..10_000 {
+            a.insert(id);
+            a.insert(id);
+            b.insert(id + 5_000);
+        }
+        assert_eq!(UniqueCounter::new().estimate(), 0);
+        let error = |estimate: u64, n: f64| (estimate as f64 - n).abs() / n;
+        assert!(error(a.estimate(), 10_000.0) < 0.1, "{}", a.estimate());
+        a.merge(&b);
+        assert!(error(a.estimate(), 15_000.0) < 0.1, "{}", a.estimate());
+
+        let mut small = UniqueCounter::new();
+        (0..20).for_each(|id| small.insert(id));
+        assert_eq!(small.estimate(), 20);
+    }
+
+    #[test]
+    fn queries_trailing_windows() {
+        let aggregator = Aggregator::new(100, Duration::minutes(1), Duration::hours(1));
+        let records = [
+            view(0, 1, 10, Some("duckduckgo.com")),
+            view(1, 1, 11, None),
+            view(5, 2, 10, Some("duckduckgo.com")),
+            view(30, 3, 12, Some("example.org")),
+            // beyond the retention
+            view(90, 4, 12, None),
+            Record::Event(Event {
+                time: clock::now(),
+                project: 1,
+                visitor: Visitor {
+                    id: 5,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+        ];
+        records.iter().for_each(|record| aggregator.record(record));
+
+        let query = |metric, minutes| aggregator.query(1, metric, Duration::minutes(minutes));
+        assert_eq!(query(Metric::Views, 10), LiveValue::Count(3));
+        assert_eq!(query(Metric::Views, 120), LiveValue::Count(4));
+        assert_eq!(query(Metric::Events, 10), LiveValue::Count(1));
+        assert_eq!(query(Metric::Visitors, 10), LiveValue::Count(3));
+        let LiveValue::Pages(pages) = query(Metric::TopPages(1), 60) else {
+            panic!("pages expected");
+        };
+        assert_eq!((pages[0].key, pages[0].count), (10, 2));
+        let LiveValue::Keys(referrers) = query(Metric::TopReferrers(5), 60) else {
+            panic!("keys expected");
+        };
+        let referrers: Vec<_> = referrers
+            .iter()
+            .map(|r| (r.key.as_str(), r.count))
+            .collect();
+        assert_eq!(referrers, [("duckduckgo.com", 2), ("example.org", 1)]);
+        assert_eq!(
+            serde_json::to_value(query(Metric::TopCountries(1), 60)).unwrap(),
+            serde_json::json!([{"key": "CH", "count": 4, "error": 0}])
+        );
+        assert_eq!(
+            aggregator.query(2, Metric::Views, Duration::hours(1)),
+            LiveValue::Count(0)
+        );
+    }
+
+    #[test]
+    fn rolls_up_aging_buckets() {
+        let aggregator = Aggregator::new(10, Duration::minutes(1), Duration::minutes(10))
+            .with_rollup(Duration::hours(1), Duration::days(1));
+        // the last one is beyond the minute retention and rolled up right away
+        for record in [
+            view(2, 1, 10, None),
+            view(3, 2, 11, None),
+            view(30, 3, 10, None),
+        ] {
+            aggregator.record(&record);
+        }
+        assert_eq!(aggregator.len(), 3);
+
+        let compact = |later| {
+            let mut projects = aggregator.projects.lock().unwrap();
+            let tiers = projects.get_mut(&1).unwrap();
+            aggregator.compact(tiers, clock::now() + later);
+        };
+        compact(Duration::hours(1));
+        assert!(aggregator.len() <= 2);
+        let query = |metric| aggregator.query(1, metric, Duration::days(1));
+        assert_eq!(query(Metric::Views), LiveValue::Count(3));
+        assert_eq!(query(Metric::Visitors), LiveValue::Count(3));
+        let LiveValue::Pages(pages) = query(Metric::TopPages(1)) else {
+            panic!("pages expected");
+        };
+        assert_eq!((pages[0].key, pages[0].count), (10, 2));
+
+        compact(Duration::days(2));
+        assert!(aggregator.is_empty());
+    }
+
+    #[test]
+    fn merges_snapshots_of_other_instances() {
+        let (a, b) = (Aggregator::default(), Aggregator::default());
+        a.record(&view(1, 1, 10, Some("duckduckgo.com")));
+        a.record(&view(2, 2, 11, None));
+        b.record(&view(1, 2, 10, Some("duckduckgo.com")));
+        b.record(&view(1, 3, 10, None));
+        b.record(&view(120, 4, 12, None));
+
+        let coordinator = Aggregator::default();
+        coordinator.merge_from(&a.snapshot().unwrap()).unwrap();
+        coordinator.merge_from(&b.snapshot().unwrap()).unwrap();
+        let query = |metric| coordinator.query(1, metric, Duration::minutes(10));
+        assert_eq!(query(Metric::Views), LiveValue::Count(4));
+        assert_eq!(query(Metric::Visitors), LiveValue::Count(3));
+        let LiveValue::Pages(pages) = query(Metric::TopPages(1)) else {
+            panic!("pages expected");
+        };
+        assert_eq!((pages[0].key, pages[0].count), (10, 3));
+        assert_eq!(
+            coordinator.query(1, Metric::Views, Duration::days(1)),
+            LiveValue::Count(5)
+        );
+
+        let other = Aggregator::new(100, Duration::minutes(5), Duration::hours(1));
+        assert!(matches!(
+            other.merge_from(&a.snapshot().unwrap()),
+            Err(Error::IncompatibleAggregate)
+        ));
+        assert!(coordinator.merge_from(b"{}").is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Navigation paths between pages, e.g. for Sankey flow reports.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use chrono::NaiveDate;
+use serde::Serialize;
+
+use crate::flush::Drain;
+use crate::Visit;
+
+/// Number of page views that followed `from_page` with `to_page`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PathEdge {
+    pub project: i64,
+    pub date: NaiveDate,
+    /// `None` for views that entered the site.
+    pub from_page: Option<i64>,
+    pub to_page: i64,
+    pub count: u64,
+}
+
+type EdgeKey = (i64, NaiveDate, Option<i64>, i64);
+
+/// Counts page-to-page transitions per project and UTC day.
+///
+/// Transitions are taken from [`Visit::previous_page_id`], so visits need to
+/// be linked by the tracker or the
+/// [`SessionTracker`](crate::session::SessionTracker) first.
+#[derive(Debug, Default)]
+pub struct PathGraph {
+    edges: Mutex<BTreeMap<EdgeKey, u64>>,
+}
+
+impl PathGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the transition to the page of a visit.
+    ///
+    /// Exit beacons (visits with a duration) are ignored, they report on a
+    /// view that was already counted.
+    pub fn record(&self, visit: &Visit) {
+        if visit.duration.is_some() {
+            return;
+        }
+        let key = (
+            visit.project,
+            visit.time.date_naive(),
+            visit.previous_page_id,
+            visit.page.id,
+        );
+        *self.edges.lock().unwrap().entry(key).or_default() += 1;
+    }
+
+    /// Removes and returns all edges.
+    pub fn flush(&self) -> Vec<PathEdge> {
+        let edges = std::mem::take(&mut *self.edges.lock().unwrap());
+        edges.into_iter().map(edge).collect()
+    }
+
+    /// Removes and returns the edges of days before `date`, e.g. to only
+    /// flush completed days.
+    pub fn flush_before(&self, date: NaiveDate) -> Vec<PathEdge> {
+        let mut edges = self.edges.lock().unwrap();
+        let flushed: Vec<EdgeKey> = edges
+            .keys()
+            .filter(|(_, day, _, _)| *day < date)
+            .copied()
+            .collect();
+        flushed
+            .into_iter()
+            .map(|key| edge((key, edges.remove(&key).expect("key exists"))))
+            .collect()
+    }
+
+    /// Number of distinct edges held in memory.
+    pub fn len(&self) -> usize {
+        self.edges.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drain for PathGraph {
+    type Item = PathEdge;
+
+    fn drain(&self) -> Vec<PathEdge> {
+        self.flush()
+    }
+}
+
+fn edge(((project, date, from_page, to_page), count): (EdgeKey, u64)) -> PathEdge {
+    PathEdge {
+        project,
+        date,
+        from_page,
+        to_page,
+        count,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::Page;
+
+    use super::*;
+
+    fn visit(day: i64, previous: Option<i64>, page: i64) -> Visit {
+        Visit {
+            time: Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap() + Duration::days(day),
+            project: 1,
+            page: Page {
+                id: page,
+                ..Default::default()
+            },
+            previous_page_id: previous,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_transitions() {
+        let graph = PathGraph::new();
+        graph.record(&visit(0, None, 1));
+        graph.record(&visit(0, Some(1), 2));
+        graph.record(&visit(0, None, 1));
+        graph.record(&visit(0, Some(1), 2));
+        graph.record(&Visit {
+            duration: Some(10),
+            ..visit(0, Some(1), 2)
+        });
+
+        let date = NaiveDate::from_ymd_opt(2023, 10, 1).unwrap();
+        assert_eq!(
+            graph.flush(),
+            vec![
+                PathEdge {
+                    project: 1,
+                    date,
+                    from_page: None,
+                    to_page: 1,
+                    count: 2
+                },
+                PathEdge {
+                    project: 1,
+                    date,
+                    from_page: Some(1),
+                    to_page: 2,
+                    count: 2
+                },
+            ]
+        );
+        assert!(graph.is_empty());
+    }
+
+    #[test]
+    fn flushes_completed_days() {
+        let graph = PathGraph::new();
+        graph.record(&visit(0, Some(1), 2));
+        graph.record(&visit(1, Some(1), 2));
+
+        let edges = graph.flush_before(NaiveDate::from_ymd_opt(2023, 10, 2).unwrap());
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0].date, NaiveDate::from_ymd_opt(2023, 10, 1).unwrap());
+        assert_eq!(graph.len(), 1);
+    }
+}
@@ -0,0 +1,222 @@
+//! Approximate per-id counters for constrained deployments.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hash::Hasher;
+use crate::Error;
+
+/// Counts keyed by a page, referrer or utm id.
+pub trait Counter {
+    fn add(&mut self, id: i64, n: u64);
+
+    /// The count of `id`, possibly overestimated.
+    fn estimate(&self, id: i64) -> u64;
+
+    /// Sum of all counts.
+    fn total(&self) -> u64;
+}
+
+/// Exact counts, memory grows with the number of distinct ids.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExactCounter {
+    counts: HashMap<i64, u64>,
+}
+
+impl ExactCounter {
+    /// Adds the counts of another counter, e.g. of another instance.
+    pub fn merge(&mut self, other: &ExactCounter) {
+        for (id, n) in &other.counts {
+            self.add(*id, *n);
+        }
+    }
+}
+
+impl Counter for ExactCounter {
+    fn add(&mut self, id: i64, n: u64) {
+        *self.counts.entry(id).or_default() += n;
+    }
+
+    fn estimate(&self, id: i64) -> u64 {
+        self.counts.get(&id).copied().unwrap_or_default()
+    }
+
+    fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+/// A count-min sketch with fixed memory of `width * depth` counters.
+///
+/// Estimates are never too low and exceed the true count by at most
+/// `e / width * total` with probability `1 - exp(-depth)`. Rows are hashed
+/// deterministically, so sketches of the same dimensions built on
+/// different instances can be [merged](CountMinSketch::merge).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawSketch")]
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    total: u64,
+    counters: Vec<u64>,
+}
+
+#[derive(Deserialize)]
+struct RawSketch {
+    width: usize,
+    depth: usize,
+    total: u64,
+    counters: Vec<u64>,
+}
+
+impl TryFrom<RawSketch> for CountMinSketch {
+    type Error = Error;
+
+    fn try_from(raw: RawSketch) -> Result<Self, Self::Error> {
+        if raw.width == 0 || raw.depth == 0 || raw.counters.len() != raw.width * raw.depth {
+            return Err(Error::IncompatibleSketch);
+        }
+        Ok(CountMinSketch {
+            width: raw.width,
+            depth: raw.depth,
+            total: raw.total,
+            counters: raw.counters,
+        })
+    }
+}
+
+impl CountMinSketch {
+    /// # Panics
+    ///
+    /// Panics if `width` or `depth` is zero.
+    pub fn new(width: usize, depth: usize) -> Self {
+        assert!(width > 0 && depth > 0, "dimensions must be positive");
+        CountMinSketch {
+            width,
+            depth,
+            total: 0,
+            counters: vec![0; width * depth],
+        }
+    }
+
+    /// Dimensions for estimates within `epsilon * total` of the true count
+    /// with probability `1 - delta`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `epsilon` or `delta` is not within `(0, 1)`.
+    pub fn with_error(epsilon: f64, delta: f64) -> Self {
+        assert!(epsilon > 0.0 && epsilon < 1.0, "epsilon must be in (0, 1)");
+        assert!(delta > 0.0 && delta < 1.0, "delta must be in (0, 1)");
+        let width = (std::f64::consts::E / epsilon).ceil() as usize;
+        let depth = (1.0 / delta).ln().ceil().max(1.0) as usize;
+        CountMinSketch::new(width, depth)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn index(&self, row: usize, id: i64) -> usize {
+        let mut hasher = Hasher::hardened();
+        hasher.write(row as u64);
+        hasher.write(id as u64);
+        row * self.width + (hasher.finalize() % self.width as u64) as usize
+    }
+
+    /// Adds the counts of another sketch of the same dimensions.
+    pub fn merge(&mut self, other: &CountMinSketch) -> Result<(), Error> {
+        if (self.width, self.depth) != (other.width, other.depth) {
+            return Err(Error::IncompatibleSketch);
+        }
+        for (counter, other) in self.counters.iter_mut().zip(&other.counters) {
+            *counter += other;
+        }
+        self.total += other.total;
+        Ok(())
+    }
+}
+
+impl Counter for CountMinSketch {
+    fn add(&mut self, id: i64, n: u64) {
+        for row in 0..self.depth {
+            let index = self.index(row, id);
+            self.counters[index] += n;
+        }
+        self.total += n;
+    }
+
+    fn estimate(&self, id: i64) -> u64 {
+        (0..self.depth)
+            .map(|row| self.counters[self.index(row, id)])
+            .min()
+            .expect("depth is positive")
+    }
+
+    fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_are_bounded() {
+        let mut sketch = CountMinSketch::with_error(0.01, 0.01);
+        let mut exact = ExactCounter::default();
+        for id in 0..2000i64 {
+            let n = (id % 7 + 1) as u64;
+            sketch.add(id * 7919, n);
+            exact.add(id * 7919, n);
+        }
+        assert_eq!(sketch.total(), exact.total());
+
+        let bound = (0.01 * sketch.total() as f64) as u64;
+        let mut within = 0;
+        for id in 0..2000i64 {
+            let (estimate, count) = (sketch.estimate(id * 7919), exact.estimate(id * 7919));
+            assert!(estimate >= count);
+            if estimate - count <= bound {
+                within += 1;
+            }
+        }
+        assert!(within >= 1980, "{within} of 2000 within bound");
+    }
+
+    #[test]
+    fn merges_and_roundtrips() {
+        let mut a = CountMinSketch::new(64, 4);
+        let mut b = CountMinSketch::new(64, 4);
+        a.add(42, 3);
+        b.add(42, 2);
+        b.add(7, 1);
+        a.merge(&b).unwrap();
+        assert_eq!(a.estimate(42), 5);
+        assert_eq!(a.total(), 6);
+        assert!(a.merge(&CountMinSketch::new(32, 4)).is_err());
+
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(serde_json::from_str::<CountMinSketch>(&json).unwrap(), a);
+        assert!(serde_json::from_str::<CountMinSketch>(
+            r#"{"width": 2, "depth": 2, "total": 0, "counters": [0]}"#
+        )
+        .is_err());
+
+        let mut exact = ExactCounter::default();
+        let mut other = ExactCounter::default();
+        exact.add(42, 3);
+        other.add(42, 2);
+        other.add(7, 1);
+        exact.merge(&other);
+        assert_eq!((exact.estimate(42), exact.total()), (5, 6));
+        let json = serde_json::to_string(&exact).unwrap();
+        assert_eq!(serde_json::from_str::<ExactCounter>(&json).unwrap(), exact);
+    }
+}
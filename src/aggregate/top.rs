@@ -0,0 +1,371 @@
+//! Memory-bounded top-N counting, e.g. for real-time "top content" widgets.
+
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Visit};
+
+/// A key reported by [`SpaceSaving::top`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeavyHitter<K> {
+    pub key: K,
+    /// Upper bound of the true count.
+    pub count: u64,
+    /// Maximum overestimation, the true count is at least `count - error`.
+    pub error: u64,
+}
+
+/// The SpaceSaving heavy hitters algorithm.
+///
+/// Keeps at most `capacity` counters. When a new key arrives and all
+/// counters are taken, the smallest counter is reassigned to it. Every key
+/// with a true count above `total / capacity` is guaranteed to be kept.
+///
+/// Counters of other instances can be [merged](SpaceSaving::merge) from
+/// their serialized form.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    try_from = "RawSpaceSaving<K>",
+    bound(
+        serialize = "K: Serialize + Hash + Eq",
+        deserialize = "K: Deserialize<'de> + Hash + Ord + Clone"
+    )
+)]
+pub struct SpaceSaving<K> {
+    capacity: usize,
+    /// key → (count, error)
+    counters: HashMap<K, (u64, u64)>,
+    /// (count, key), smallest first
+    #[serde(skip)]
+    order: BTreeSet<(u64, K)>,
+}
+
+#[derive(Deserialize)]
+struct RawSpaceSaving<K: Hash + Eq> {
+    capacity: usize,
+    counters: HashMap<K, (u64, u64)>,
+}
+
+impl<K: Hash + Ord + Clone> TryFrom<RawSpaceSaving<K>> for SpaceSaving<K> {
+    type Error = Error;
+
+    fn try_from(raw: RawSpaceSaving<K>) -> Result<Self, Self::Error> {
+        if raw.capacity == 0
+            || raw.counters.len() > raw.capacity
+            || raw.counters.values().any(|(count, error)| error > count)
+        {
+            return Err(Error::IncompatibleSketch);
+        }
+        let order = raw
+            .counters
+            .iter()
+            .map(|(key, (count, _))| (*count, key.clone()))
+            .collect();
+        Ok(SpaceSaving {
+            capacity: raw.capacity,
+            counters: raw.counters,
+            order,
+        })
+    }
+}
+
+impl<K: Hash + Ord + Clone> SpaceSaving<K> {
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be positive");
+        SpaceSaving {
+            capacity,
+            counters: HashMap::with_capacity(capacity),
+            order: BTreeSet::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: K) {
+        self.insert_n(key, 1);
+    }
+
+    pub fn insert_n(&mut self, key: K, n: u64) {
+        if let Some((count, _)) = self.counters.get_mut(&key) {
+            self.order.remove(&(*count, key.clone()));
+            *count += n;
+            self.order.insert((*count, key));
+            return;
+        }
+
+        let (count, error) = if self.counters.len() < self.capacity {
+            (n, 0)
+        } else {
+            let (min, evicted) = self.order.pop_first().expect("counters are full");
+            self.counters.remove(&evicted);
+            (min + n, min)
+        };
+        self.counters.insert(key.clone(), (count, error));
+        self.order.insert((count, key));
+    }
+
+    /// Adds the counts of `other`, e.g. of an earlier time window. Keys
+    /// evicted by either side are counted as errors.
+    pub fn merge(&mut self, other: &SpaceSaving<K>) {
+        // highest first, so small counters are the ones evicted
+        for (count, key) in other.order.iter().rev() {
+            self.insert_n(key.clone(), *count);
+            if let Some((_, error)) = self.counters.get_mut(key) {
+                *error += other.counters[key].1;
+            }
+        }
+    }
+
+    /// The `n` keys with the highest counts, highest first.
+    pub fn top(&self, n: usize) -> Vec<HeavyHitter<K>> {
+        self.order
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(count, key)| HeavyHitter {
+                key: key.clone(),
+                count: *count,
+                error: self.counters[key].1,
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.counters.clear();
+        self.order.clear();
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    start: DateTime<Utc>,
+    pages: SpaceSaving<i64>,
+    referrers: SpaceSaving<String>,
+    countries: SpaceSaving<String>,
+}
+
+/// Top pages, referrers and countries of each project in the current
+/// time window.
+///
+/// Windows are aligned to the unix epoch, so hourly windows start at the
+/// full hour. Visits of past windows are ignored.
+#[derive(Debug)]
+pub struct TopContent {
+    capacity: usize,
+    window: Duration,
+    projects: Mutex<HashMap<i64, Window>>,
+}
+
+impl TopContent {
+    /// Tracks up to `capacity` keys per dimension and project.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero or `window` is not positive.
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        assert!(capacity > 0, "capacity must be positive");
+        assert!(window > Duration::zero(), "window must be positive");
+        TopContent {
+            capacity,
+            window,
+            projects: Mutex::default(),
+        }
+    }
+
+    fn window_start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let window = self.window.num_milliseconds();
+        let start = time.timestamp_millis().div_euclid(window) * window;
+        Utc.timestamp_millis_opt(start).unwrap()
+    }
+
+    /// Counts a page view. Exit beacons (visits with a duration) are ignored.
+    pub fn record(&self, visit: &Visit) {
+        if visit.duration.is_some() {
+            return;
+        }
+        let start = self.window_start(visit.time);
+        let mut projects = self.projects.lock().unwrap();
+        let window = projects.entry(visit.project).or_insert_with(|| Window {
+            start,
+            pages: SpaceSaving::new(self.capacity),
+            referrers: SpaceSaving::new(self.capacity),
+            countries: SpaceSaving::new(self.capacity),
+        });
+        if start < window.start {
+            return;
+        }
+        if start > window.start {
+            window.start = start;
+            window.pages.clear();
+            window.referrers.clear();
+            window.countries.clear();
+        }
+
+        window.pages.insert(visit.page.id);
+        if let Some(referrer) = &visit.referrer {
+            window.referrers.insert(referrer.domain.clone());
+        }
+        if let Some(region) = &visit.visitor.region {
+            window.countries.insert(region.to_string());
+        }
+    }
+
+    /// Start of the current window of a project.
+    pub fn window(&self, project_id: i64) -> Option<DateTime<Utc>> {
+        Some(self.projects.lock().unwrap().get(&project_id)?.start)
+    }
+
+    /// Top page ids of a project.
+    pub fn top_pages(&self, project_id: i64, n: usize) -> Vec<HeavyHitter<i64>> {
+        self.top(project_id, |window| window.pages.top(n))
+    }
+
+    /// Top referrer domains of a project.
+    pub fn top_referrers(&self, project_id: i64, n: usize) -> Vec<HeavyHitter<String>> {
+        self.top(project_id, |window| window.referrers.top(n))
+    }
+
+    /// Top country codes of a project.
+    pub fn top_countries(&self, project_id: i64, n: usize) -> Vec<HeavyHitter<String>> {
+        self.top(project_id, |window| window.countries.top(n))
+    }
+
+    fn top<T>(&self, project_id: i64, f: impl FnOnce(&Window) -> Vec<T>) -> Vec<T> {
+        self.projects
+            .lock()
+            .unwrap()
+            .get(&project_id)
+            .map(f)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::{Page, Referrer, Visitor};
+
+    use super::*;
+
+    #[test]
+    fn keeps_heavy_hitters() {
+        let stream = [1, 2, 1, 3, 1, 4, 1, 5, 1, 6, 2, 1, 2];
+        let mut top = SpaceSaving::new(3);
+        for key in stream {
+            top.insert(key);
+        }
+        assert_eq!(top.len(), 3);
+
+        let hitters = top.top(3);
+        assert_eq!(hitters[0].key, 1);
+        for hitter in hitters {
+            let count = stream.iter().filter(|key| **key == hitter.key).count() as u64;
+            assert!(hitter.count - hitter.error <= count && count <= hitter.count);
+        }
+    }
+
+    #[test]
+    fn merges_within_error_bounds() {
+        let (a, b) = ([1, 2, 1, 3, 1, 4], [5, 1, 6, 2, 1, 2]);
+        let mut merged = SpaceSaving::new(3);
+        let mut other = SpaceSaving::new(3);
+        a.into_iter().for_each(|key| merged.insert(key));
+        b.into_iter().for_each(|key| other.insert(key));
+        // as if sent by another instance
+        let json = serde_json::to_string(&other).unwrap();
+        let received: SpaceSaving<i32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(received.top(3), other.top(3));
+        merged.merge(&received);
+        assert_eq!(merged.len(), 3);
+
+        let hitters = merged.top(3);
+        assert_eq!((hitters[0].key, hitters[0].count), (1, 5));
+        for hitter in hitters {
+            let count = a.iter().chain(&b).filter(|key| **key == hitter.key).count() as u64;
+            assert!(hitter.count - hitter.error <= count && count <= hitter.count);
+        }
+        let invalid = r#"{"capacity": 1, "counters": {"1": [1, 0], "2": [1, 0]}}"#;
+        assert!(serde_json::from_str::<SpaceSaving<i32>>(invalid).is_err());
+    }
+
+    #[test]
+    fn exact_below_capacity() {
+        let mut top = SpaceSaving::new(10);
+        top.insert_n("a", 5);
+        top.insert("b");
+        top.insert("a");
+        assert_eq!(
+            top.top(5),
+            vec![
+                HeavyHitter {
+                    key: "a",
+                    count: 6,
+                    error: 0
+                },
+                HeavyHitter {
+                    key: "b",
+                    count: 1,
+                    error: 0
+                },
+            ]
+        );
+    }
+
+    fn visit(minutes: i64, page: i64, referrer: &str) -> Visit {
+        Visit {
+            time: Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap() + Duration::minutes(minutes),
+            project: 1,
+            visitor: Visitor {
+                region: Some(Cow::Borrowed("CH")),
+                ..Default::default()
+            },
+            page: Page {
+                id: page,
+                ..Default::default()
+            },
+            referrer: Some(Referrer {
+                domain: referrer.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn top_content_per_window() {
+        let top = TopContent::new(8, Duration::hours(1));
+        top.record(&visit(0, 1, "duckduckgo.com"));
+        top.record(&visit(5, 1, "duckduckgo.com"));
+        top.record(&visit(10, 2, "news.ycombinator.com"));
+
+        assert_eq!(top.top_pages(1, 1)[0].key, 1);
+        assert_eq!(top.top_referrers(1, 1)[0].key, "duckduckgo.com");
+        assert_eq!(top.top_countries(1, 1)[0].count, 3);
+        assert!(top.top_pages(2, 1).is_empty());
+
+        top.record(&visit(60, 2, "duckduckgo.com"));
+        assert_eq!(
+            top.window(1),
+            Some(Utc.with_ymd_and_hms(2023, 10, 1, 13, 0, 0).unwrap())
+        );
+        assert_eq!(top.top_pages(1, 5).len(), 1);
+
+        // late visits of the previous window are ignored
+        top.record(&visit(30, 1, "duckduckgo.com"));
+        assert_eq!(top.top_pages(1, 5)[0].key, 2);
+    }
+}
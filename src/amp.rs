@@ -0,0 +1,75 @@
+//! AMP cache and viewer urls.
+//!
+//! AMP pages are often served from a cache, e.g.
+//! `https://example-com.cdn.ampproject.org/c/s/example.com/post`. The
+//! origin url is encoded in the path, so [`Page`](crate::Page) can attribute
+//! the view to the publisher instead of the cache domain.
+
+use url::Url;
+
+/// The origin url of an AMP cache or viewer url, `None` for other urls.
+///
+/// The query of the cache url is kept, fragments are dropped.
+pub fn origin(url: &Url) -> Option<Url> {
+    let host = url.domain()?;
+    let path = url.path();
+
+    let encoded = if host == "cdn.ampproject.org"
+        || host.ends_with(".cdn.ampproject.org")
+        || host.ends_with(".bing-amp.com")
+    {
+        path.strip_prefix("/c/")
+            .or_else(|| path.strip_prefix("/v/"))?
+    } else if host.starts_with("www.google.") || host.starts_with("google.") {
+        path.strip_prefix("/amp/")?
+    } else {
+        return None;
+    };
+
+    let (scheme, encoded) = match encoded.strip_prefix("s/") {
+        Some(encoded) => ("https", encoded),
+        None => ("http", encoded),
+    };
+    let mut val = Url::parse(&format!("{scheme}://{encoded}")).ok()?;
+    val.domain()?;
+    val.set_query(url.query());
+    Some(val)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin(url: &str) -> Option<String> {
+        super::origin(&Url::parse(url).unwrap()).map(String::from)
+    }
+
+    #[test]
+    fn cache_urls() {
+        assert_eq!(
+            origin("https://example-com.cdn.ampproject.org/c/s/example.com/blog/post?source=x#y")
+                .as_deref(),
+            Some("https://example.com/blog/post?source=x")
+        );
+        assert_eq!(
+            origin("https://cdn.ampproject.org/v/example.com/post").as_deref(),
+            Some("http://example.com/post")
+        );
+        assert_eq!(
+            origin("https://example-com.bing-amp.com/c/s/example.com/post").as_deref(),
+            Some("https://example.com/post")
+        );
+        assert_eq!(
+            origin("https://www.google.com/amp/s/example.com/post").as_deref(),
+            Some("https://example.com/post")
+        );
+    }
+
+    #[test]
+    fn other_urls() {
+        assert_eq!(origin("https://example.com/c/s/other.com/post"), None);
+        assert_eq!(origin("https://www.google.com/search?q=amp"), None);
+        assert_eq!(origin("https://cdn.ampproject.org/v0.js"), None);
+        assert_eq!(origin("https://cdn.ampproject.org/c/s/"), None);
+    }
+}
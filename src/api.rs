@@ -1,15 +1,71 @@
-use serde::Deserialize;
+pub mod http;
+pub mod lenient;
+pub mod response;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
 use serde_json::Value;
 use url::Url;
 
-use crate::{Error, Event, Page, Referrer, UtmParam, Visit, Visitor};
+use crate::channel::{self, ChannelRule, Touch};
+use crate::clock;
+use crate::config::ProjectSettings;
+use crate::explain::EnrichmentReport;
+use crate::session::SessionStore;
+use crate::shedding::LoadShedder;
+use crate::ua::{UserAgent, UA_CACHE};
+use crate::utm::{self, UtmOptions};
+use crate::{
+    ColorScheme, Error, Event, Identity, Page, Record, Referrer, UtmParam, Visit, Visitor,
+    VisitorBuilder,
+};
 
 #[derive(Debug, Default, Deserialize)]
 #[serde(default)]
 pub struct PubVisitor {
+    /// An IANA timezone or a UTC offset like `+02:00`. Numbers are read as
+    /// minutes behind UTC, as returned by `Date.getTimezoneOffset()`.
+    #[serde(deserialize_with = "timezone")]
     pub tz: String,
     pub lang: String,
     pub screen: (i32, i32),
+    /// Size of the browser window, `window.innerWidth`/`innerHeight`.
+    pub viewport: Option<(i32, i32)>,
+    /// `window.devicePixelRatio`
+    pub dpr: Option<f64>,
+    pub prefers_color_scheme: Option<ColorScheme>,
+    pub prefers_reduced_motion: Option<bool>,
+    /// `navigator.connection`, where supported.
+    pub connection: Option<PubConnection>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PubConnection {
+    pub effective_type: Option<String>,
+    /// Estimated bandwidth in megabits per second.
+    pub downlink: Option<f64>,
+    pub save_data: Option<bool>,
+}
+
+fn timezone<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Tz {
+        Name(String),
+        Minutes(i32),
+    }
+    Ok(match Tz::deserialize(deserializer)? {
+        Tz::Name(name) => name,
+        Tz::Minutes(minutes) => {
+            let offset = -minutes;
+            let sign = if offset < 0 { '-' } else { '+' };
+            let offset = offset.unsigned_abs();
+            format!("{sign}{:02}:{:02}", offset / 60, offset % 60)
+        }
+    })
 }
 
 #[derive(Debug, Deserialize)]
@@ -17,17 +73,31 @@ pub struct PubPage {
     pub url: Url,
     #[serde(rename = "ref")]
     pub referrer: Option<Url>,
+    /// The url of the page's `<link rel="canonical">`.
+    #[serde(default)]
+    pub canonical: Option<Url>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct PubVisit {
+    /// Payload version, absent in payloads of scripts from before
+    /// versioning, see [`decode`].
+    #[serde(default)]
+    pub v: Option<u32>,
     pub session: String,
     pub visitor: PubVisitor,
     pub page: PubPage,
+    /// Url of the page viewed before, if the tracker knows it.
+    #[serde(default)]
+    pub prev: Option<Url>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct PubExit {
+    /// Payload version, absent in payloads of scripts from before
+    /// versioning, see [`decode`].
+    #[serde(default)]
+    pub v: Option<u32>,
     pub session: String,
     pub visitor: PubVisitor,
     pub page: PubPage,
@@ -37,6 +107,10 @@ pub struct PubExit {
 
 #[derive(Debug, Deserialize)]
 pub struct PubEvent {
+    /// Payload version, absent in payloads of scripts from before
+    /// versioning, see [`decode`].
+    #[serde(default)]
+    pub v: Option<u32>,
     pub session: String,
     pub visitor: PubVisitor,
     pub page: PubPage,
@@ -44,56 +118,1084 @@ pub struct PubEvent {
     pub data: Value,
 }
 
+/// Beacon of a widget embedded in an iframe.
+#[derive(Debug, Deserialize)]
+pub struct PubEmbed {
+    /// Payload version, absent in payloads of scripts from before
+    /// versioning, see [`decode`].
+    #[serde(default)]
+    pub v: Option<u32>,
+    pub session: String,
+    pub visitor: PubVisitor,
+    /// The embedding page, not the iframe.
+    pub parent: PubPage,
+    pub embed: String,
+}
+
+/// Beacon sent after a user logged in.
+#[derive(Debug, Deserialize)]
+pub struct PubIdentify {
+    /// Payload version, absent in payloads of scripts from before
+    /// versioning, see [`decode`].
+    #[serde(default)]
+    pub v: Option<u32>,
+    pub session: String,
+    pub visitor: PubVisitor,
+    /// Hash of the user id, hashed by the site so the raw id never reaches
+    /// the collector.
+    pub user: String,
+}
+
+/// The newest payload version this collector understands.
+pub const PAYLOAD_VERSION: u32 = 1;
+
+/// A single beacon within a batch, tagged by `type`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PubRecord {
+    Visit(PubVisit),
+    Exit(PubExit),
+    Event(PubEvent),
+    Embed(PubEmbed),
+}
+
+/// Decodes a beacon body holding a single tagged record or an array of them.
+///
+/// Every record is routed to the parser of its payload version `v`, so
+/// scripts cached by browsers keep working while the payloads evolve.
+/// Records without a version are version 1.
+pub fn decode(body: &[u8]) -> Result<Vec<PubRecord>, Error> {
+    split(body)?.into_iter().map(decode_record).collect()
+}
+
+/// The records of a beacon body, still untyped.
+pub(crate) fn split(body: &[u8]) -> Result<Vec<Value>, Error> {
+    let trimmed = body.trim_ascii_start();
+    if trimmed.starts_with(b"[") {
+        Ok(serde_json::from_slice(trimmed)?)
+    } else {
+        Ok(vec![serde_json::from_slice(trimmed)?])
+    }
+}
+
+pub(crate) fn decode_record(value: Value) -> Result<PubRecord, Error> {
+    let version = match value.get("v") {
+        None | Some(Value::Null) => 1,
+        Some(v) => v
+            .as_u64()
+            .ok_or(Error::Missing("valid version".to_string()))?,
+    };
+    match version {
+        1 => Ok(serde_json::from_value(value)?),
+        // newer payloads get a parser here that converts them into the
+        // current records, e.g. `2 => v2::parse(value)`
+        _ => Err(Error::UnsupportedVersion(version)),
+    }
+}
+
+/// Processes a page view with the `settings` of its project, e.g. from the
+/// provider of [`PipelineConfig::projects`](crate::pipeline::PipelineConfig::projects),
+/// so it is enriched like in the [`Pipeline`](crate::pipeline::Pipeline).
+/// Unconfigured projects use the defaults.
+///
+/// Like every handler, this rejects beacons of paused projects with
+/// [`Error::ProjectPaused`] before they are processed.
 pub async fn handle_visit(
     project_id: i64,
     body: PubVisit,
     user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Result<Visit, Error> {
+    let enrich = Enrich::collecting(settings)?;
+    process_visit(project_id, body, user_agent, enrich)
+}
+
+/// Like [`handle_visit`], but continues the session of another domain if
+/// the url carries a valid [linker token](crate::linker).
+#[cfg(feature = "linker")]
+pub async fn handle_linked_visit(
+    project_id: i64,
+    body: PubVisit,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+    linker: &crate::linker::Linker,
+) -> Result<Visit, Error> {
+    let enrich = Enrich::collecting(settings)?;
+    let link = linker.link(&body.page.url, clock::now());
+    let mut visit = process_visit(project_id, body, user_agent, enrich)?;
+    if let Some(link) = link {
+        visit.session = link.session;
+        visit.visitor.id = link.visitor;
+    }
+    Ok(visit)
+}
+
+/// Like [`handle_visit`], but enriches the visit with the state of its
+/// session: whether the page was viewed before, the previous page and the
+/// campaign that acquired the session. The visit is
+/// [observed](SessionStore::observe) for the summary of the session.
+pub async fn handle_tracked_visit(
+    project_id: i64,
+    body: PubVisit,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+    sessions: &dyn SessionStore,
+) -> Result<Visit, Error> {
+    let enrich = Enrich::collecting(settings)?;
+    let mut visit = process_visit(project_id, body, user_agent, enrich)?;
+    sessions.mark_unique(&mut visit)?;
+    sessions.link_previous(&mut visit)?;
+    sessions.attribute_visit(&mut visit)?;
+    sessions.observe(&visit.clone().into())?;
+    Ok(visit)
+}
+
+pub async fn handle_exit(
+    project_id: i64,
+    body: PubExit,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Result<Visit, Error> {
+    let enrich = Enrich::collecting(settings)?;
+    process_exit(project_id, body, user_agent, enrich)
+}
+
+/// Like [`handle_exit`], but `dur` is a cumulative value that the tracker
+/// reports repeatedly while the page is open.
+///
+/// Only the highest duration per session and page is kept. Returns `None`
+/// if the beacon does not exceed a previously reported duration.
+/// Kept durations count towards the summary of the session.
+pub async fn handle_ping(
+    project_id: i64,
+    body: PubExit,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+    sessions: &dyn SessionStore,
+) -> Result<Option<Visit>, Error> {
+    let enrich = Enrich::collecting(settings)?;
+    let session: i64 = body.session.parse()?;
+    let page = Page::with_canonical(project_id, &body.page.url, body.page.canonical.as_ref())?;
+    let Some(duration) = sessions.record_duration(project_id, session, page.id, body.dur)? else {
+        return Ok(None);
+    };
+
+    let visitor = enrich.visitor(project_id, &body.visitor, user_agent);
+    let utm_param = UtmParam::parse(project_id, &body.page.url, enrich.utm);
+    let referrer = Referrer::new(project_id, body.page.referrer.as_ref(), &page.domain);
+
+    let mut visit = Visit::new(project_id, session, visitor, page, utm_param, referrer);
+    visit.duration = Some(duration);
+    visit.distance = Some(body.dist);
+    visit.channel_group = enrich.channel_group(&body.page.url, &visit);
+    sessions.attribute_visit(&mut visit)?;
+    sessions.observe(&visit.clone().into())?;
+
+    Ok(Some(visit))
+}
+
+/// Records a view of an embedded widget, attributed to the embedding page.
+pub async fn handle_embed(
+    project_id: i64,
+    body: PubEmbed,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Result<Visit, Error> {
+    let enrich = Enrich::collecting(settings)?;
+    process_embed(project_id, body, user_agent, enrich)
+}
+
+pub async fn handle_event(
+    project_id: i64,
+    body: PubEvent,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Result<Event, Error> {
+    let enrich = Enrich::collecting(settings)?;
+    process_event(project_id, body, user_agent, enrich)
+}
+
+/// Like [`handle_batch`], but gives the user agent parsing of each record
+/// at most `budget`.
+///
+/// Records that run out of it are enriched without browser and platform
+/// and flagged as [`partial`](Visitor::partial), so a pathological user
+/// agent cannot stall the request. Their region is resolved all the same.
+/// User agents are then parsed on the parser threads, see
+/// [`UaCache::parse_within`](crate::ua::UaCache::parse_within).
+pub async fn handle_batch_within(
+    project_id: i64,
+    body: Vec<PubRecord>,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+    budget: Duration,
+) -> Vec<Result<Record, Error>> {
+    let enrich = Enrich::collecting(settings).map(|enrich| Enrich {
+        budget: Some(budget),
+        ..enrich
+    });
+    process_batch(project_id, body, user_agent, enrich)
+}
+
+/// Validates and enriches records like [`handle_batch`], but marks them
+/// as [`dry_run`](Visit::dry_run), so new tracker versions and settings can
+/// be verified against production traffic. Hosts must not store the
+/// records, a [`Pipeline`](crate::pipeline::Pipeline) never writes them to
+/// its sink.
+pub async fn handle_dry_run(
+    project_id: i64,
+    body: Vec<PubRecord>,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Vec<Result<Record, Error>> {
+    let enrich = Enrich::collecting(settings).map(|enrich| Enrich {
+        dry_run: true,
+        ..enrich
+    });
+    process_batch(project_id, body, user_agent, enrich)
+}
+
+/// Explains how the records of a beacon body are enriched: the records
+/// with their region and user agent sources, id inputs and applied
+/// normalizations.
+///
+/// Project settings like campaign aliases are not applied.
+pub fn explain(
+    project_id: i64,
+    body: &[u8],
+    user_agent: &str,
+) -> Result<Vec<EnrichmentReport>, Error> {
+    crate::explain::explain(project_id, body, user_agent)
+}
+
+/// Links the hashed user id to the visitor id of the session.
+///
+/// Rejects user ids that aren't hashed, see [`Identity::normalize_user`].
+pub async fn handle_identify(
+    project_id: i64,
+    body: PubIdentify,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Result<Identity, Error> {
+    let enrich = Enrich::collecting(settings)?;
+    let session: i64 = body.session.parse()?;
+    let user = Identity::normalize_user(&body.user)?;
+    let visitor = enrich.visitor(project_id, &body.visitor, user_agent);
+    Ok(Identity {
+        time: clock::now(),
+        project: project_id,
+        session,
+        visitor: visitor.id,
+        user,
+    })
+}
+
+/// Processes every beacon of a batch. The output preserves the input order.
+pub async fn handle_batch(
+    project_id: i64,
+    body: Vec<PubRecord>,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Vec<Result<Record, Error>> {
+    process_batch(project_id, body, user_agent, Enrich::collecting(settings))
+}
+
+/// Processes the records of a batch with `enrich`, or fails every record
+/// with the error of a project that isn't collecting.
+fn process_batch(
+    project_id: i64,
+    body: Vec<PubRecord>,
+    user_agent: &str,
+    enrich: Result<Enrich<'_>, Error>,
+) -> Vec<Result<Record, Error>> {
+    match enrich {
+        Ok(enrich) => body
+            .into_iter()
+            .map(|record| process_record(project_id, record, user_agent, enrich))
+            .collect(),
+        Err(_) => body.iter().map(|_| Err(Error::ProjectPaused)).collect(),
+    }
+}
+
+/// Like [`handle_batch`], but fans the beacons out across the rayon thread
+/// pool. The output preserves the input order.
+///
+/// This blocks until the whole batch is processed, call it from a blocking
+/// context (e.g. `spawn_blocking`) in async servers.
+#[cfg(feature = "rayon")]
+pub fn process_batch_parallel(
+    project_id: i64,
+    body: Vec<PubRecord>,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Vec<Result<Record, Error>> {
+    use rayon::prelude::*;
+
+    let Ok(enrich) = Enrich::collecting(settings) else {
+        return body.iter().map(|_| Err(Error::ProjectPaused)).collect();
+    };
+    body.into_par_iter()
+        .map(|record| process_record(project_id, record, user_agent, enrich))
+        .collect()
+}
+
+/// How [`process_record`] enriches records.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Enrich<'a> {
+    pub utm: &'a UtmOptions,
+    /// Set while shedding load, user agents are then only looked up in the
+    /// cache.
+    pub shedding: Option<&'a LoadShedder>,
+    /// Marks the records as [dry runs](handle_dry_run).
+    pub dry_run: bool,
+    /// Deadline of the user agent parsing of a record, see
+    /// [`handle_batch_within`].
+    pub budget: Option<Duration>,
+    /// Channel grouping rules of the project, see [`channel::group`].
+    pub channels: &'a [ChannelRule],
+}
+
+static DEFAULT_UTM: UtmOptions = UtmOptions {
+    max_len: utm::DEFAULT_MAX_LEN,
+    aliases: BTreeMap::new(),
+    repeated: utm::Repeated::Last,
+};
+
+impl Default for Enrich<'_> {
+    fn default() -> Self {
+        Enrich::new(&DEFAULT_UTM)
+    }
+}
+
+impl<'a> Enrich<'a> {
+    pub fn new(utm: &'a UtmOptions) -> Self {
+        Enrich {
+            utm,
+            shedding: None,
+            dry_run: false,
+            budget: None,
+            channels: &[],
+        }
+    }
+
+    /// Enriches records with the campaign and channel settings of a
+    /// project, like the [`Pipeline`](crate::pipeline::Pipeline) does.
+    pub fn for_project(settings: &'a ProjectSettings) -> Self {
+        Enrich {
+            channels: &settings.channels,
+            ..Enrich::new(&settings.utm)
+        }
+    }
+
+    /// [`Enrich::for_project`] if the project is configured, the defaults
+    /// otherwise. Fails with [`Error::ProjectPaused`] for paused projects.
+    pub(crate) fn collecting(settings: Option<&'a ProjectSettings>) -> Result<Self, Error> {
+        match settings {
+            Some(settings) => {
+                settings.ensure_collecting()?;
+                Ok(Enrich::for_project(settings))
+            }
+            None => Ok(Enrich::default()),
+        }
+    }
+
+    fn channel_group(&self, url: &Url, visit: &Visit) -> Option<String> {
+        let referrer = visit
+            .referrer
+            .as_ref()
+            .map(|referrer| referrer.domain.as_str());
+        Some(
+            self.group(url, visit.utm_param.as_ref(), referrer)
+                .to_string(),
+        )
+    }
+
+    /// Channel group of a record at `url`, see [`channel::group`].
+    pub(crate) fn group(
+        &self,
+        url: &Url,
+        utm_param: Option<&UtmParam>,
+        referrer: Option<&str>,
+    ) -> &'a str {
+        channel::group(self.channels, &Touch::new(url, utm_param, referrer))
+    }
+
+    fn visitor(&self, project_id: i64, visitor: &PubVisitor, user_agent: &str) -> Visitor {
+        let (builder, partial) = self.visitor_builder(project_id, visitor, user_agent);
+        Visitor {
+            partial,
+            ..builder.build()
+        }
+    }
+
+    /// Prepares the visitor of a record, flagged if it is
+    /// [partial](Visitor::partial).
+    pub(crate) fn visitor_builder<'v>(
+        &self,
+        project_id: i64,
+        visitor: &'v PubVisitor,
+        user_agent: &'v str,
+    ) -> (VisitorBuilder<'v>, bool) {
+        let builder = Visitor::builder(project_id, visitor);
+        let (parsed, partial) = match (self.shedding, self.budget) {
+            (None, None) => return (builder.user_agent(user_agent), false),
+            (Some(shedder), _) => {
+                let parsed = UA_CACHE.cached(user_agent).unwrap_or_else(|| {
+                    shedder.count_user_agent();
+                    UserAgent::default()
+                });
+                (parsed, false)
+            }
+            (None, Some(budget)) => match UA_CACHE.parse_within(user_agent, budget) {
+                Some(parsed) => (parsed, false),
+                None => (UserAgent::default(), true),
+            },
+        };
+        (builder.parsed_user_agent(parsed), partial)
+    }
+}
+
+pub(crate) fn process_record(
+    project_id: i64,
+    record: PubRecord,
+    user_agent: &str,
+    enrich: Enrich<'_>,
+) -> Result<Record, Error> {
+    let dry_run = enrich.dry_run;
+    let mut record = match record {
+        PubRecord::Visit(body) => {
+            process_visit(project_id, body, user_agent, enrich).map(Record::Visit)
+        }
+        PubRecord::Exit(body) => {
+            process_exit(project_id, body, user_agent, enrich).map(Record::Visit)
+        }
+        PubRecord::Event(body) => {
+            process_event(project_id, body, user_agent, enrich).map(Record::Event)
+        }
+        PubRecord::Embed(body) => {
+            process_embed(project_id, body, user_agent, enrich).map(Record::Visit)
+        }
+    }?;
+    if dry_run {
+        record.mark_dry_run();
+    }
+    Ok(record)
+}
+
+pub(crate) fn process_visit(
+    project_id: i64,
+    body: PubVisit,
+    user_agent: &str,
+    enrich: Enrich<'_>,
 ) -> Result<Visit, Error> {
     let session: i64 = body.session.parse()?;
-    let visitor = Visitor::new(project_id, &body.visitor, user_agent);
-    let page = Page::new(project_id, &body.page.url)?;
-    let utm_param = UtmParam::new(project_id, &body.page.url);
+    let visitor = enrich.visitor(project_id, &body.visitor, user_agent);
+    let page = Page::with_canonical(project_id, &body.page.url, body.page.canonical.as_ref())?;
+    let utm_param = UtmParam::parse(project_id, &body.page.url, enrich.utm);
     let referrer = Referrer::new(project_id, body.page.referrer.as_ref(), &page.domain);
 
-    let visit = Visit::new(project_id, session, visitor, page, utm_param, referrer);
+    let mut visit = Visit::new(project_id, session, visitor, page, utm_param, referrer);
+    visit.previous_page_id = previous_page_id(project_id, body.prev.as_ref());
+    visit.channel_group = enrich.channel_group(&body.page.url, &visit);
 
     Ok(visit)
 }
 
-pub async fn handle_exit(project_id: i64, body: PubExit, user_agent: &str) -> Result<Visit, Error> {
+pub(crate) fn previous_page_id(project_id: i64, prev: Option<&Url>) -> Option<i64> {
+    Some(Page::new(project_id, prev?).ok()?.id)
+}
+
+fn process_exit(
+    project_id: i64,
+    body: PubExit,
+    user_agent: &str,
+    enrich: Enrich<'_>,
+) -> Result<Visit, Error> {
     let session: i64 = body.session.parse()?;
-    let visitor = Visitor::new(project_id, &body.visitor, user_agent);
-    let page = Page::new(project_id, &body.page.url)?;
-    let utm_param = UtmParam::new(project_id, &body.page.url);
+    let visitor = enrich.visitor(project_id, &body.visitor, user_agent);
+    let page = Page::with_canonical(project_id, &body.page.url, body.page.canonical.as_ref())?;
+    let utm_param = UtmParam::parse(project_id, &body.page.url, enrich.utm);
     let referrer = Referrer::new(project_id, body.page.referrer.as_ref(), &page.domain);
 
     let mut visit = Visit::new(project_id, session, visitor, page, utm_param, referrer);
     visit.duration = Some(body.dur);
     visit.distance = Some(body.dist);
+    visit.channel_group = enrich.channel_group(&body.page.url, &visit);
 
     Ok(visit)
 }
 
-pub async fn handle_event(
+fn process_embed(
+    project_id: i64,
+    body: PubEmbed,
+    user_agent: &str,
+    enrich: Enrich<'_>,
+) -> Result<Visit, Error> {
+    let embed = body.embed.trim();
+    if embed.is_empty() {
+        return Err(Error::Missing("embed".to_string()));
+    }
+
+    let session: i64 = body.session.parse()?;
+    let visitor = enrich.visitor(project_id, &body.visitor, user_agent);
+    let page = Page::with_canonical(project_id, &body.parent.url, body.parent.canonical.as_ref())?;
+    let utm_param = UtmParam::parse(project_id, &body.parent.url, enrich.utm);
+    let referrer = Referrer::new(project_id, body.parent.referrer.as_ref(), &page.domain);
+
+    let mut visit = Visit::new(project_id, session, visitor, page, utm_param, referrer);
+    visit.embed = Some(embed.to_string());
+    visit.channel_group = enrich.channel_group(&body.parent.url, &visit);
+
+    Ok(visit)
+}
+
+pub(crate) fn process_event(
     project_id: i64,
     body: PubEvent,
     user_agent: &str,
+    enrich: Enrich<'_>,
 ) -> Result<Event, Error> {
     let session: i64 = body.session.parse()?;
-    let visitor = Visitor::new(project_id, &body.visitor, user_agent);
-    let page = Page::new(project_id, &body.page.url)?;
+    let visitor = enrich.visitor(project_id, &body.visitor, user_agent);
+    let page = Page::with_canonical(project_id, &body.page.url, body.page.canonical.as_ref())?;
 
-    let event = Event::new(project_id, session, visitor, page, body.name, body.data);
+    let name = Event::normalize_name(&body.name)?.into_owned();
+
+    let event = Event::new(project_id, session, visitor, page, name, body.data);
 
     Ok(event)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    use crate::session::SessionTracker;
+
     use super::*;
 
+    const USER_AGENT: &str = "Mozilla/5.0 (Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/112.0.0.0 Safari/537.36";
+
+    /// The handlers never await anything, so a single poll completes them.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        match future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+        {
+            Poll::Ready(val) => val,
+            Poll::Pending => unreachable!("handler awaited"),
+        }
+    }
+
+    fn exit(url: &str, dur: i32) -> PubExit {
+        PubExit {
+            v: None,
+            session: "7".to_string(),
+            visitor: PubVisitor {
+                tz: "Europe/Zurich".to_string(),
+                lang: "de-CH".to_string(),
+                screen: (1920, 1080),
+                ..Default::default()
+            },
+            page: PubPage {
+                url: Url::parse(url).unwrap(),
+                referrer: None,
+                canonical: None,
+            },
+            dur,
+            dist: 0.5,
+        }
+    }
+
+    #[test]
+    fn decode_routes_versions() {
+        let visit = r#"{"type": "visit", "session": "7", "visitor": {}, "page": {"url": "https://example.com/"}}"#;
+        let versioned = visit.replacen('{', r#"{"v": 1, "#, 1);
+        let records = decode(format!("[{visit}, {versioned}]").as_bytes()).unwrap();
+        assert!(matches!(&records[0], PubRecord::Visit(visit) if visit.v.is_none()));
+        assert!(matches!(&records[1], PubRecord::Visit(visit) if visit.v == Some(1)));
+        assert_eq!(decode(versioned.as_bytes()).unwrap().len(), 1);
+
+        let future = visit.replacen('{', r#"{"v": 2, "#, 1);
+        assert!(matches!(
+            decode(future.as_bytes()),
+            Err(Error::UnsupportedVersion(2))
+        ));
+        let invalid = visit.replacen('{', r#"{"v": "x", "#, 1);
+        assert!(decode(invalid.as_bytes()).is_err());
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn handle_visit_enriches_visit() {
+        let body = PubVisit {
+            v: None,
+            session: "7".to_string(),
+            visitor: PubVisitor {
+                tz: "Europe/Zurich".to_string(),
+                lang: "de-CH".to_string(),
+                screen: (1920, 1080),
+                ..Default::default()
+            },
+            page: PubPage {
+                url: Url::parse("https://example.com/blog?source=newsletter").unwrap(),
+                referrer: Some(Url::parse("https://duckduckgo.com/").unwrap()),
+                canonical: None,
+            },
+            prev: None,
+        };
+        let visit = block_on(handle_visit(1, body, USER_AGENT, None)).unwrap();
+        assert_eq!(visit.session, 7);
+        assert_eq!(visit.visitor.region.as_deref(), Some("CH"));
+        assert_eq!(visit.visitor.browser.as_deref(), Some("Chrome"));
+        assert_eq!(visit.page.domain, "example.com");
+        assert_eq!(visit.page.path, "/blog");
+        assert_eq!(
+            visit.utm_param.and_then(|utm| utm.source).as_deref(),
+            Some("newsletter")
+        );
+        assert_eq!(visit.referrer.unwrap().domain, "duckduckgo.com");
+    }
+
+    #[test]
+    fn handle_identify_links_visitor() {
+        let identify = |user: &str| PubIdentify {
+            v: None,
+            session: "7".to_string(),
+            visitor: PubVisitor {
+                tz: "Europe/Zurich".to_string(),
+                ..Default::default()
+            },
+            user: user.to_string(),
+        };
+        let hash = "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08";
+        let identity = block_on(handle_identify(1, identify(hash), USER_AGENT, None)).unwrap();
+        let visitor = Visitor::new(1, &identify(hash).visitor, USER_AGENT);
+        assert_eq!(identity.visitor, visitor.id);
+        assert_eq!(identity.session, 7);
+        assert_eq!(identity.user, hash.to_ascii_lowercase());
+
+        for user in ["jane@example.com", "42", &hash[..16]] {
+            assert!(matches!(
+                block_on(handle_identify(1, identify(user), USER_AGENT, None)),
+                Err(Error::InvalidUserHash(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn handle_visit_rejects_invalid_session() {
+        let body = PubVisit {
+            v: None,
+            session: "not a number".to_string(),
+            visitor: PubVisitor::default(),
+            page: PubPage {
+                url: Url::parse("https://example.com/").unwrap(),
+                referrer: None,
+                canonical: None,
+            },
+            prev: None,
+        };
+        let err = block_on(handle_visit(1, body, USER_AGENT, None)).unwrap_err();
+        assert!(matches!(err, Error::ParseIntError(_)));
+    }
+
+    #[test]
+    fn handlers_reject_paused_projects() {
+        let paused = ProjectSettings {
+            paused: true,
+            ..Default::default()
+        };
+        let body = || PubVisit {
+            v: None,
+            session: "7".to_string(),
+            visitor: PubVisitor::default(),
+            page: PubPage {
+                url: Url::parse("https://example.com/").unwrap(),
+                referrer: None,
+                canonical: None,
+            },
+            prev: None,
+        };
+        assert!(matches!(
+            block_on(handle_visit(1, body(), USER_AGENT, Some(&paused))),
+            Err(Error::ProjectPaused)
+        ));
+        let records = block_on(handle_batch(
+            1,
+            vec![PubRecord::Visit(body()), PubRecord::Visit(body())],
+            USER_AGENT,
+            Some(&paused),
+        ));
+        assert!(matches!(
+            &records[..],
+            [Err(Error::ProjectPaused), Err(Error::ProjectPaused)]
+        ));
+        let collecting = ProjectSettings::default();
+        assert!(block_on(handle_visit(1, body(), USER_AGENT, Some(&collecting))).is_ok());
+    }
+
+    #[test]
+    fn handlers_enrich_with_project_settings() {
+        let settings: ProjectSettings = serde_yaml::from_str(
+            "
+            utm: {aliases: {ref: source}}
+            channels: [{channel: Partners, source: ^partner$}]
+            ",
+        )
+        .unwrap();
+        let body = || PubVisit {
+            v: None,
+            session: "7".to_string(),
+            visitor: PubVisitor::default(),
+            page: PubPage {
+                url: Url::parse("https://example.com/?ref=partner").unwrap(),
+                referrer: None,
+                canonical: None,
+            },
+            prev: None,
+        };
+
+        let visit = block_on(handle_visit(1, body(), USER_AGENT, Some(&settings))).unwrap();
+        let expected =
+            process_visit(1, body(), USER_AGENT, Enrich::for_project(&settings)).unwrap();
+        assert_eq!(visit.channel_group.as_deref(), Some("Partners"));
+        assert_eq!(visit.channel_group, expected.channel_group);
+        assert_eq!(
+            visit.utm_param.and_then(|utm| utm.source).as_deref(),
+            Some("partner")
+        );
+
+        let visit = block_on(handle_visit(1, body(), USER_AGENT, None)).unwrap();
+        assert!(visit.utm_param.is_none());
+        assert_ne!(visit.channel_group.as_deref(), Some("Partners"));
+    }
+
+    #[test]
+    fn handle_ping_keeps_max_duration() {
+        let sessions = SessionTracker::new();
+        let ping = |dur| {
+            block_on(handle_ping(
+                1,
+                exit("https://example.com/", dur),
+                USER_AGENT,
+                None,
+                &sessions,
+            ))
+        };
+
+        let visit = ping(5).unwrap().expect("first ping is recorded");
+        assert_eq!(visit.duration, Some(5));
+        let visit = ping(15).unwrap().expect("higher duration is recorded");
+        assert_eq!(visit.duration, Some(15));
+        assert!(ping(15).unwrap().is_none());
+        assert!(ping(10).unwrap().is_none());
+    }
+
+    #[test]
+    fn handle_ping_tracks_pages_separately() {
+        let sessions = SessionTracker::new();
+        let a = block_on(handle_ping(
+            1,
+            exit("https://example.com/a", 30),
+            USER_AGENT,
+            None,
+            &sessions,
+        ));
+        let b = block_on(handle_ping(
+            1,
+            exit("https://example.com/b", 10),
+            USER_AGENT,
+            None,
+            &sessions,
+        ));
+        assert!(a.unwrap().is_some());
+        assert!(b.unwrap().is_some());
+    }
+
+    #[test]
+    fn handle_dry_run_marks_records() {
+        let body = r#"[
+            {"type": "visit", "session": "1", "visitor": {}, "page": {"url": "https://example.com/a"}},
+            {"type": "event", "session": "1", "visitor": {}, "page": {"url": "https://example.com/a"}, "name": "signup", "data": null}
+        ]"#;
+        let parse = || serde_json::from_str::<Vec<PubRecord>>(body).unwrap();
+        let records = block_on(handle_dry_run(1, parse(), USER_AGENT, None));
+        assert!(records
+            .iter()
+            .all(|record| record.as_ref().unwrap().is_dry_run()));
+        let json = serde_json::to_value(records[0].as_ref().unwrap()).unwrap();
+        assert_eq!(json["dry_run"], true);
+
+        let records = block_on(handle_batch(1, parse(), USER_AGENT, None));
+        assert!(records
+            .iter()
+            .all(|record| !record.as_ref().unwrap().is_dry_run()));
+        let json = serde_json::to_value(records[0].as_ref().unwrap()).unwrap();
+        assert!(json.get("dry_run").is_none());
+    }
+
+    #[test]
+    fn handle_batch_within_flags_partial_records() {
+        // neither classified by the prefilter nor cached by other tests
+        const USER_AGENT: &str = "Mozilla/5.0 (compatible; PartialRecords/1.0)";
+        let body = r#"[{"type": "visit", "session": "1", "visitor": {"tz": "Europe/Zurich"}, "page": {"url": "https://example.com/a"}}]"#;
+        let parse = || serde_json::from_str::<Vec<PubRecord>>(body).unwrap();
+        let within = |budget| {
+            let records = block_on(handle_batch_within(1, parse(), USER_AGENT, None, budget));
+            records[0].as_ref().unwrap().visitor().clone()
+        };
+
+        let visitor = within(Duration::ZERO);
+        assert!(visitor.partial);
+        assert_eq!(visitor.browser, None);
+        assert_eq!(visitor.region.as_deref(), Some("CH"));
+
+        let visitor = within(Duration::from_secs(60));
+        assert!(!visitor.partial);
+        let full = block_on(handle_batch(1, parse(), USER_AGENT, None));
+        let full = full[0].as_ref().unwrap().visitor();
+        assert_eq!((visitor.id, &visitor.browser), (full.id, &full.browser));
+    }
+
     #[test]
-    fn test() {
-        todo!()
+    fn handle_batch_preserves_order() {
+        let body: Vec<PubRecord> = serde_json::from_str(
+            r#"[
+                {"type": "visit", "session": "1", "visitor": {}, "page": {"url": "https://example.com/a"}},
+                {"type": "event", "session": "1", "visitor": {}, "page": {"url": "https://example.com/a"}, "name": "signup", "data": null},
+                {"type": "visit", "session": "x", "visitor": {}, "page": {"url": "https://example.com/b"}},
+                {"type": "exit", "session": "1", "visitor": {}, "page": {"url": "https://example.com/a"}, "dur": 12, "dist": 0.8}
+            ]"#,
+        )
+        .unwrap();
+        let records = block_on(handle_batch(1, body, USER_AGENT, None));
+        assert_eq!(records.len(), 4);
+        assert!(matches!(&records[0], Ok(Record::Visit(visit)) if visit.page.path == "/a"));
+        assert!(matches!(&records[1], Ok(Record::Event(event)) if event.name == "signup"));
+        assert!(matches!(&records[2], Err(Error::ParseIntError(_))));
+        assert!(matches!(&records[3], Ok(Record::Visit(visit)) if visit.duration == Some(12)));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn process_batch_parallel_matches_handle_batch() {
+        let body = (0..2_000)
+            .map(|i| match i % 4 {
+                0 => format!(
+                    r#"{{"type": "visit", "session": "{i}", "visitor": {{}}, "page": {{"url": "https://example.com/{i}"}}}}"#
+                ),
+                1 => format!(
+                    r#"{{"type": "event", "session": "{i}", "visitor": {{}}, "page": {{"url": "https://example.com/"}}, "name": "signup", "data": {i}}}"#
+                ),
+                // not a number
+                2 => format!(
+                    r#"{{"type": "visit", "session": "s{i}", "visitor": {{}}, "page": {{"url": "https://example.com/"}}}}"#
+                ),
+                // no domain
+                _ => format!(
+                    r#"{{"type": "exit", "session": "{i}", "visitor": {{}}, "page": {{"url": "data:text/plain,{i}"}}, "dur": {i}, "dist": 0.5}}"#
+                ),
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let parse = || serde_json::from_str::<Vec<PubRecord>>(&format!("[{body}]")).unwrap();
+        // the timestamps are taken separately
+        let json = |record: &Record| {
+            let mut json = serde_json::to_value(record).unwrap();
+            json.as_object_mut().unwrap().remove("time");
+            json
+        };
+
+        let parallel = process_batch_parallel(1, parse(), USER_AGENT, None);
+        let sequential = block_on(handle_batch(1, parse(), USER_AGENT, None));
+        assert_eq!(parallel.len(), sequential.len());
+        for (parallel, sequential) in parallel.iter().zip(&sequential) {
+            match (parallel, sequential) {
+                (Ok(parallel), Ok(sequential)) => assert_eq!(json(parallel), json(sequential)),
+                (Err(parallel), Err(sequential)) => {
+                    assert_eq!(parallel.to_string(), sequential.to_string())
+                }
+                (parallel, sequential) => panic!("{parallel:?} != {sequential:?}"),
+            }
+        }
+        assert!(matches!(&parallel[2], Err(Error::ParseIntError(_))));
+        assert!(matches!(&parallel[3], Err(Error::Missing(_))));
+        assert!(matches!(&parallel[1_996], Ok(Record::Visit(visit)) if visit.page.path == "/1996"));
+    }
+
+    #[test]
+    fn handle_event_normalizes_name() {
+        let event = |name: &str| PubEvent {
+            v: None,
+            session: "7".to_string(),
+            visitor: PubVisitor::default(),
+            page: PubPage {
+                url: Url::parse("https://example.com/").unwrap(),
+                referrer: None,
+                canonical: None,
+            },
+            name: name.to_string(),
+            data: Value::Null,
+        };
+        let val = block_on(handle_event(1, event("Sign Up"), USER_AGENT, None)).unwrap();
+        assert_eq!(val.name, "sign_up");
+
+        let err = block_on(handle_event(1, event("_internal"), USER_AGENT, None)).unwrap_err();
+        assert!(matches!(err, Error::InvalidEventName(_)));
+    }
+
+    #[cfg(feature = "linker")]
+    #[test]
+    fn handle_linked_visit_continues_session() {
+        use crate::linker::{Link, Linker, LINKER_PARAM};
+
+        let linker = Linker::new(1, *b"0123456789abcdef", ["checkout.example"]);
+        let link = Link {
+            visitor: 42,
+            session: 1234,
+        };
+        let token = linker.token(link, chrono::Utc::now());
+        let visit = |url: &str| PubVisit {
+            v: None,
+            session: "7".to_string(),
+            visitor: PubVisitor::default(),
+            page: PubPage {
+                url: Url::parse(url).unwrap(),
+                referrer: None,
+                canonical: None,
+            },
+            prev: None,
+        };
+
+        let url = format!("https://checkout.example/cart?{LINKER_PARAM}={token}");
+        let val = block_on(handle_linked_visit(
+            1,
+            visit(&url),
+            USER_AGENT,
+            None,
+            &linker,
+        ))
+        .unwrap();
+        assert_eq!((val.visitor.id, val.session), (42, 1234));
+        assert_eq!(val.page.path, "/cart");
+
+        let url = format!("https://checkout.example/cart?{LINKER_PARAM}=forged");
+        let val = block_on(handle_linked_visit(
+            1,
+            visit(&url),
+            USER_AGENT,
+            None,
+            &linker,
+        ))
+        .unwrap();
+        assert_eq!(val.session, 7);
+    }
+
+    #[test]
+    fn handle_embed_attributes_to_parent_page() {
+        let body: PubEmbed = serde_json::from_str(
+            r#"{
+                "session": "7",
+                "visitor": {"tz": "Europe/Zurich"},
+                "parent": {"url": "https://blog.example/post?source=newsletter", "ref": "https://duckduckgo.com/"},
+                "embed": " booking-widget "
+            }"#,
+        )
+        .unwrap();
+        let visit = block_on(handle_embed(1, body, USER_AGENT, None)).unwrap();
+        assert_eq!(visit.page.domain, "blog.example");
+        assert_eq!(visit.page.path, "/post");
+        assert_eq!(visit.embed.as_deref(), Some("booking-widget"));
+        assert_eq!(
+            visit.utm_param.and_then(|utm| utm.source).as_deref(),
+            Some("newsletter")
+        );
+        assert_eq!(visit.referrer.unwrap().domain, "duckduckgo.com");
+
+        let body: PubRecord = serde_json::from_str(
+            r#"{"type": "embed", "session": "7", "visitor": {}, "parent": {"url": "https://blog.example/"}, "embed": ""}"#,
+        )
+        .unwrap();
+        let records = block_on(handle_batch(1, vec![body], USER_AGENT, None));
+        assert!(matches!(&records[0], Err(Error::Missing(field)) if field == "embed"));
+    }
+
+    #[test]
+    fn handle_tracked_visit_marks_unique_views() {
+        let sessions = SessionTracker::new();
+        let visit = |url: &str| PubVisit {
+            v: None,
+            session: "7".to_string(),
+            visitor: PubVisitor::default(),
+            page: PubPage {
+                url: Url::parse(url).unwrap(),
+                referrer: None,
+                canonical: None,
+            },
+            prev: None,
+        };
+        let view = |url| {
+            block_on(handle_tracked_visit(
+                1,
+                visit(url),
+                USER_AGENT,
+                None,
+                &sessions,
+            ))
+            .unwrap()
+        };
+
+        let landing = view("https://example.com/?campaign=autumn");
+        assert_eq!(landing.is_unique_in_session, Some(true));
+        let next = view("https://example.com/pricing");
+        assert_eq!(next.is_unique_in_session, Some(true));
+        assert_eq!(
+            next.utm_param.and_then(|utm| utm.campaign).as_deref(),
+            Some("autumn")
+        );
+        let back = view("https://example.com/");
+        assert_eq!(back.is_unique_in_session, Some(false));
+
+        let summaries = sessions.expire_all();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].pages_viewed, 3);
+    }
+
+    #[test]
+    fn handle_tracked_visit_links_previous_page() {
+        let sessions = SessionTracker::new();
+        let visit = |url: &str, prev: Option<&str>| PubVisit {
+            v: None,
+            session: "7".to_string(),
+            visitor: PubVisitor::default(),
+            page: PubPage {
+                url: Url::parse(url).unwrap(),
+                referrer: None,
+                canonical: None,
+            },
+            prev: prev.map(|prev| Url::parse(prev).unwrap()),
+        };
+        let home = Page::new(1, &Url::parse("https://example.com/").unwrap()).unwrap();
+        let blog = Page::new(1, &Url::parse("https://example.com/blog").unwrap()).unwrap();
+
+        let view =
+            |body| block_on(handle_tracked_visit(1, body, USER_AGENT, None, &sessions)).unwrap();
+        assert_eq!(
+            view(visit("https://example.com/", None)).previous_page_id,
+            None
+        );
+        let pricing = view(visit("https://example.com/pricing", None));
+        assert_eq!(pricing.previous_page_id, Some(home.id));
+
+        // the tracker knows better, e.g. after a reload in a new tab
+        let supplied = view(visit(
+            "https://example.com/contact",
+            Some("https://example.com/blog"),
+        ));
+        assert_eq!(supplied.previous_page_id, Some(blog.id));
     }
 }
@@ -0,0 +1,158 @@
+//! Http headers of collector responses.
+//!
+//! Trackers post beacons cross-origin, so every response needs the right
+//! CORS headers for the project's domains: the origin is echoed instead of
+//! `*` so beacons may carry credentials, caches must key on it and never
+//! store the response.
+
+use url::Url;
+
+use crate::config::ProjectSettings;
+
+/// Methods the collector accepts beacons with.
+pub const ALLOWED_METHODS: &str = "POST, OPTIONS";
+
+/// Request headers trackers may set.
+pub const ALLOWED_HEADERS: &str = "Content-Type, X-Abineo-Dry-Run";
+
+/// Request header of beacons that are processed but not stored, see
+/// [`handle_dry_run`](crate::api::handle_dry_run).
+pub const DRY_RUN_HEADER: &str = "x-abineo-dry-run";
+
+/// Default for [`CorsPolicy::with_max_age`], one day.
+pub const DEFAULT_MAX_AGE: u32 = 24 * 60 * 60;
+
+/// A response header name and value.
+pub type Header = (&'static str, String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsPolicy {
+    domains: Vec<String>,
+    max_age: u32,
+}
+
+impl CorsPolicy {
+    /// Allows origins on `domains`, or any origin if there are none.
+    pub fn new<'a>(domains: impl IntoIterator<Item = &'a str>) -> Self {
+        CorsPolicy {
+            domains: domains.into_iter().map(str::to_lowercase).collect(),
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+
+    pub fn for_project(settings: &ProjectSettings) -> Self {
+        CorsPolicy::new(settings.domains.iter().map(String::as_str))
+    }
+
+    /// How long browsers may cache a preflight response, in seconds.
+    pub fn with_max_age(mut self, seconds: u32) -> Self {
+        self.max_age = seconds;
+        self
+    }
+
+    /// Whether beacons from the `Origin` header value are allowed.
+    ///
+    /// Only http and https origins are allowed, `null` origins never are.
+    pub fn allows(&self, origin: &str) -> bool {
+        let Ok(url) = Url::parse(origin) else {
+            return false;
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return false;
+        }
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        self.domains.is_empty() || self.domains.iter().any(|domain| domain == host)
+    }
+
+    /// Headers of a response to a beacon.
+    ///
+    /// Disallowed or missing origins get no `Access-Control-*` headers, so
+    /// the browser blocks the response, while the beacon may still have been
+    /// processed.
+    pub fn response_headers(&self, origin: Option<&str>) -> Vec<Header> {
+        let mut val = vec![
+            ("Cache-Control", "no-store".to_string()),
+            ("Vary", "Origin".to_string()),
+            ("X-Content-Type-Options", "nosniff".to_string()),
+        ];
+        if let Some(origin) = origin.filter(|origin| self.allows(origin)) {
+            val.push(("Access-Control-Allow-Origin", origin.to_string()));
+            val.push(("Access-Control-Allow-Credentials", "true".to_string()));
+            val.push(("Timing-Allow-Origin", origin.to_string()));
+        }
+        val
+    }
+
+    /// Headers of a response to an `OPTIONS` preflight request.
+    pub fn preflight_headers(&self, origin: Option<&str>) -> Vec<Header> {
+        let mut val = self.response_headers(origin);
+        if origin.is_some_and(|origin| self.allows(origin)) {
+            val.push(("Access-Control-Allow-Methods", ALLOWED_METHODS.to_string()));
+            val.push(("Access-Control-Allow-Headers", ALLOWED_HEADERS.to_string()));
+            val.push(("Access-Control-Max-Age", self.max_age.to_string()));
+        }
+        val
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn echoes_allowed_origins() {
+        let policy = CorsPolicy::new(["Example.com"]);
+        let headers = policy.response_headers(Some("https://example.com"));
+        assert_eq!(
+            get(&headers, "Access-Control-Allow-Origin"),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            get(&headers, "Timing-Allow-Origin"),
+            Some("https://example.com")
+        );
+        assert_eq!(get(&headers, "Cache-Control"), Some("no-store"));
+        assert_eq!(get(&headers, "Vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn rejects_other_origins() {
+        let policy = CorsPolicy::new(["example.com"]);
+        for origin in [
+            Some("https://evil.com"),
+            Some("https://example.com.evil.com"),
+            Some("null"),
+            Some("file:///etc"),
+            None,
+        ] {
+            let headers = policy.preflight_headers(origin);
+            assert_eq!(get(&headers, "Access-Control-Allow-Origin"), None);
+            assert_eq!(get(&headers, "Access-Control-Allow-Methods"), None);
+            assert_eq!(get(&headers, "Vary"), Some("Origin"));
+        }
+    }
+
+    #[test]
+    fn preflight_allows_beacons() {
+        let policy = CorsPolicy::new([]).with_max_age(60);
+        let headers = policy.preflight_headers(Some("http://localhost:3000"));
+        assert_eq!(
+            get(&headers, "Access-Control-Allow-Origin"),
+            Some("http://localhost:3000")
+        );
+        assert_eq!(
+            get(&headers, "Access-Control-Allow-Methods"),
+            Some(ALLOWED_METHODS)
+        );
+        assert_eq!(get(&headers, "Access-Control-Max-Age"), Some("60"));
+    }
+}
@@ -0,0 +1,197 @@
+//! Tolerant decoding of beacons from outdated or sloppy trackers.
+//!
+//! Pages may cache old tracker scripts for a long time. Instead of
+//! rejecting their beacons, [`decode`] repairs known deviations before the
+//! strict parse and reports each repair as a [`Warning`]:
+//!
+//! - numbers sent as strings, e.g. `"dur": "12"`, and sessions sent as
+//!   numbers
+//! - the screen sent as an object `{"w": 1920, "h": 1080}`
+//! - a missing visitor, or event data
+//! - the page sent as a bare url string
+//!
+//! Unknown fields are ignored like in the strict parse.
+
+use serde_json::{Map, Value};
+
+use crate::api::{decode_record, split, PubRecord};
+use crate::Error;
+
+/// A deviation from the strict payload that was repaired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Warning {
+    /// Index of the record within the beacon.
+    pub record: usize,
+    pub field: &'static str,
+    pub message: &'static str,
+}
+
+/// Like [`api::decode`](crate::api::decode), but repairs the deviations
+/// listed in the [module docs](self) first.
+pub fn decode(body: &[u8]) -> Result<(Vec<PubRecord>, Vec<Warning>), Error> {
+    let mut warnings = Vec::new();
+    let records = split(body)?
+        .into_iter()
+        .enumerate()
+        .map(|(idx, mut value)| {
+            let mut warn = |field, message| {
+                warnings.push(Warning {
+                    record: idx,
+                    field,
+                    message,
+                })
+            };
+            if let Some(record) = value.as_object_mut() {
+                repair(record, &mut warn);
+            }
+            decode_record(value)
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((records, warnings))
+}
+
+fn repair(record: &mut Map<String, Value>, warn: &mut impl FnMut(&'static str, &'static str)) {
+    if let Some(session @ Value::Number(_)) = record.get_mut("session") {
+        *session = Value::String(session.to_string());
+        warn("session", "number converted to string");
+    }
+
+    if !record.get("visitor").is_some_and(Value::is_object) {
+        record.insert("visitor".to_string(), Value::Object(Map::new()));
+        warn("visitor", "missing visitor replaced with defaults");
+    }
+    if let Some(visitor) = record.get_mut("visitor").and_then(Value::as_object_mut) {
+        repair_visitor(visitor, warn);
+    }
+
+    for (field, key) in [("page", "page"), ("parent", "parent")] {
+        if let Some(page) = record.get_mut(key) {
+            if let Value::String(url) = page {
+                *page = serde_json::json!({ "url": url });
+                warn(field, "url string converted to page");
+            }
+        }
+    }
+
+    if let Some(dur) = record.get_mut("dur") {
+        if string_to_number(dur) {
+            warn("dur", "string converted to number");
+        }
+    }
+    if let Some(dist) = record.get_mut("dist") {
+        if string_to_number(dist) {
+            warn("dist", "string converted to number");
+        }
+    }
+    if record.get("type").and_then(Value::as_str) == Some("event") && !record.contains_key("data") {
+        record.insert("data".to_string(), Value::Object(Map::new()));
+        warn("data", "missing event data replaced with an empty object");
+    }
+}
+
+fn repair_visitor(
+    visitor: &mut Map<String, Value>,
+    warn: &mut impl FnMut(&'static str, &'static str),
+) {
+    for key in ["screen", "viewport"] {
+        let Some(size) = visitor.get_mut(key) else {
+            continue;
+        };
+        let field = if key == "screen" {
+            "visitor.screen"
+        } else {
+            "visitor.viewport"
+        };
+        if let Value::Object(object) = size {
+            let dimension =
+                |names: [&str; 2]| names.iter().find_map(|name| object.get(*name)).cloned();
+            if let (Some(w), Some(h)) = (dimension(["w", "width"]), dimension(["h", "height"])) {
+                *size = Value::Array(vec![w, h]);
+                warn(field, "object converted to array");
+            }
+        }
+        if let Value::Array(dimensions) = size {
+            let mut converted = false;
+            for dimension in dimensions.iter_mut() {
+                converted |= string_to_number(dimension);
+            }
+            if converted {
+                warn(field, "string converted to number");
+            }
+        }
+    }
+    if let Some(dpr) = visitor.get_mut("dpr") {
+        if string_to_number(dpr) {
+            warn("visitor.dpr", "string converted to number");
+        }
+    }
+}
+
+/// Replaces a numeric string with the number, returns whether it did.
+fn string_to_number(value: &mut Value) -> bool {
+    let Value::String(s) = value else {
+        return false;
+    };
+    let Ok(number) = s.trim().parse::<serde_json::Number>() else {
+        return false;
+    };
+    *value = Value::Number(number);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strict_payloads_pass_unchanged() {
+        let body = r#"{"type": "visit", "session": "7", "visitor": {"screen": [1920, 1080]}, "page": {"url": "https://example.com/"}}"#;
+        let (records, warnings) = decode(body.as_bytes()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn repairs_known_deviations() {
+        let body = r#"[
+            {"type": "exit", "session": 7, "visitor": {"screen": {"w": "1920", "h": 1080}, "dpr": "2"},
+             "page": "https://example.com/", "dur": "12", "dist": 0.5, "unknown": true},
+            {"type": "event", "session": "7", "page": {"url": "https://example.com/"}, "name": "signup"}
+        ]"#;
+        let (records, warnings) = decode(body.as_bytes()).unwrap();
+
+        let PubRecord::Exit(exit) = &records[0] else {
+            panic!("expected exit");
+        };
+        assert_eq!(exit.session, "7");
+        assert_eq!(exit.visitor.screen, (1920, 1080));
+        assert_eq!(exit.visitor.dpr, Some(2.0));
+        assert_eq!(exit.page.url.as_str(), "https://example.com/");
+        assert_eq!(exit.dur, 12);
+        let PubRecord::Event(event) = &records[1] else {
+            panic!("expected event");
+        };
+        assert!(event.data.as_object().unwrap().is_empty());
+
+        let fields: Vec<(usize, &str)> = warnings.iter().map(|w| (w.record, w.field)).collect();
+        assert_eq!(
+            fields,
+            [
+                (0, "session"),
+                (0, "visitor.screen"),
+                (0, "visitor.screen"),
+                (0, "visitor.dpr"),
+                (0, "page"),
+                (0, "dur"),
+                (1, "visitor"),
+                (1, "data"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unrepairable_payloads() {
+        let body = r#"{"type": "visit", "session": "7", "page": {"url": "not a url"}}"#;
+        assert!(decode(body.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Responses to trackers, optionally carrying directives.
+//!
+//! Beacons are answered with `204 No Content`. When the collector wants the
+//! tracker to change its behavior, it answers `202 Accepted` with a compact
+//! json body of directives instead, e.g. `{"s":"42","b":30}`:
+//!
+//! - `s`: continue with this session id, as a string
+//! - `b`: wait this many seconds before sending again
+//! - `d`: `1` to stop tracking on this page
+
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+use super::http::{CorsPolicy, Header};
+use crate::Error;
+
+/// Backoff of beacons to a [paused](crate::config::ProjectSettings::paused)
+/// project or one over its [quota](crate::quota).
+pub const PAUSED_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Backoff of beacons rejected by an overloaded pipeline.
+pub const OVERLOADED_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CollectorResponse {
+    /// Session id the tracker continues with.
+    pub session: Option<i64>,
+    /// How long the tracker waits before sending again, in whole seconds.
+    pub backoff: Option<Duration>,
+    /// Whether the tracker stops sending.
+    pub disabled: bool,
+}
+
+impl CollectorResponse {
+    /// The beacon was accepted, nothing for the tracker to do.
+    pub fn accepted() -> Self {
+        CollectorResponse::default()
+    }
+
+    /// The response to a rejected beacon, if the tracker should back off
+    /// instead of retrying.
+    pub fn for_error(err: &Error) -> Option<Self> {
+        match err {
+            Error::ProjectPaused | Error::QuotaExceeded => {
+                Some(CollectorResponse::accepted().with_backoff(PAUSED_BACKOFF))
+            }
+            Error::Overloaded => {
+                Some(CollectorResponse::accepted().with_backoff(OVERLOADED_BACKOFF))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn with_session(mut self, session: i64) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = Some(backoff);
+        self
+    }
+
+    pub fn disabled(mut self) -> Self {
+        self.disabled = true;
+        self
+    }
+
+    pub fn has_directives(&self) -> bool {
+        self.session.is_some() || self.backoff.is_some() || self.disabled
+    }
+
+    /// 202 if there are directives, 204 otherwise.
+    pub fn status(&self) -> u16 {
+        if self.has_directives() {
+            202
+        } else {
+            204
+        }
+    }
+
+    /// The directives as compact json, empty without directives.
+    pub fn body(&self) -> Vec<u8> {
+        if !self.has_directives() {
+            return Vec::new();
+        }
+        let mut directives = Map::new();
+        if let Some(session) = self.session {
+            directives.insert("s".to_string(), session.to_string().into());
+        }
+        if let Some(backoff) = self.backoff {
+            directives.insert("b".to_string(), whole_seconds(backoff).into());
+        }
+        if self.disabled {
+            directives.insert("d".to_string(), 1.into());
+        }
+        Value::Object(directives).to_string().into_bytes()
+    }
+
+    /// The [CORS headers](CorsPolicy::response_headers) of the response, plus
+    /// the content type of the body and `Retry-After` for a backoff.
+    pub fn headers(&self, cors: &CorsPolicy, origin: Option<&str>) -> Vec<Header> {
+        let mut val = cors.response_headers(origin);
+        if self.has_directives() {
+            val.push(("Content-Type", "application/json".to_string()));
+        }
+        if let Some(backoff) = self.backoff {
+            val.push(("Retry-After", whole_seconds(backoff).to_string()));
+        }
+        val
+    }
+}
+
+/// Rounds up, a backoff must never be shortened to zero.
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepted_has_no_content() {
+        let response = CollectorResponse::accepted();
+        assert_eq!(response.status(), 204);
+        assert!(response.body().is_empty());
+        let headers = response.headers(&CorsPolicy::new([]), None);
+        assert!(headers.iter().all(|(name, _)| *name != "Content-Type"));
+    }
+
+    #[test]
+    fn paused_projects_back_off() {
+        let response = CollectorResponse::for_error(&Error::ProjectPaused).unwrap();
+        assert_eq!(response.backoff, Some(PAUSED_BACKOFF));
+        assert_eq!(response.status(), 202);
+        assert!(CollectorResponse::for_error(&Error::Closed).is_none());
+    }
+
+    #[test]
+    fn projects_over_quota_back_off() {
+        let response = CollectorResponse::for_error(&Error::QuotaExceeded).unwrap();
+        assert_eq!(response.backoff, Some(PAUSED_BACKOFF));
+        assert_eq!(response.status(), 202);
+    }
+
+    #[test]
+    fn encodes_directives_compactly() {
+        let response = CollectorResponse::accepted()
+            .with_session(9_007_199_254_740_993)
+            .with_backoff(Duration::from_millis(1500))
+            .disabled();
+        assert_eq!(response.status(), 202);
+        assert_eq!(
+            String::from_utf8(response.body()).unwrap(),
+            r#"{"b":2,"d":1,"s":"9007199254740993"}"#
+        );
+        let headers = response.headers(&CorsPolicy::new([]), None);
+        assert!(headers.contains(&("Retry-After", "2".to_string())));
+    }
+}
@@ -0,0 +1,715 @@
+//! Arena allocation mode for the ingest hot path.
+//!
+//! The records produced here borrow their strings from the beacon body or,
+//! for derived values, from a [`Bump`] arena that the caller resets after
+//! each request or batch. They are enriched by the same logic as the owned
+//! records of [`api`](crate::api), so both are identical but for where their
+//! strings are stored.
+
+use std::borrow::Cow;
+
+use bumpalo::Bump;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use url::Url;
+
+use crate::amp;
+use crate::api::{
+    previous_page_id, Enrich, PubEmbed, PubEvent, PubExit, PubPage, PubRecord, PubVisit, PubVisitor,
+};
+use crate::clock;
+use crate::config::ProjectSettings;
+use crate::id::IdVersion;
+use crate::{
+    ColorScheme, Connection, Error, Event, Page, Record, Referrer, UtmParam, Visit, Visitor,
+    VisitorBuilder, VisitorFields,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaVisitor<'b> {
+    pub id: i64,
+    pub project: i64,
+    pub region: Option<&'b str>,
+    pub subdivision: Option<&'b str>,
+    pub continent: Option<&'b str>,
+    pub is_eu: bool,
+    pub low_confidence: bool,
+    pub timezone: &'b str,
+    pub language: &'b str,
+    pub browser: Option<&'b str>,
+    pub platform: Option<&'b str>,
+    pub bogus_user_agent: bool,
+    pub width: i32,
+    pub height: i32,
+    pub invalid_screen: bool,
+    pub viewport_width: Option<i32>,
+    pub viewport_height: Option<i32>,
+    pub pixel_ratio: Option<f64>,
+    pub prefers_color_scheme: Option<ColorScheme>,
+    pub prefers_reduced_motion: Option<bool>,
+    pub connection: Option<Connection>,
+    /// See [`Visitor::partial`].
+    pub partial: bool,
+}
+
+impl<'b> ArenaVisitor<'b> {
+    /// Arena counterpart of [`Visitor::new`].
+    pub fn new_in(
+        bump: &'b Bump,
+        project_id: i64,
+        visitor: &'b PubVisitor,
+        user_agent: &str,
+    ) -> Self {
+        let builder = Visitor::builder(project_id, visitor).user_agent(user_agent);
+        ArenaVisitor::build_in(bump, visitor, builder, false)
+    }
+
+    /// Builds the visitor like the owned one, with the timezone and
+    /// language borrowed from `visitor`.
+    fn build_in(
+        bump: &'b Bump,
+        visitor: &'b PubVisitor,
+        builder: VisitorBuilder<'_>,
+        partial: bool,
+    ) -> Self {
+        let val = builder.build_detached();
+        ArenaVisitor {
+            id: val.id,
+            project: val.project,
+            region: val.region.map(|s| str_in(bump, s)),
+            subdivision: val.subdivision.map(|s| str_in(bump, s)),
+            continent: val.continent.map(|s| str_in(bump, s)),
+            is_eu: val.is_eu,
+            low_confidence: val.low_confidence,
+            timezone: &visitor.tz,
+            language: &visitor.lang,
+            browser: val.browser.map(|s| str_in(bump, s)),
+            platform: val.platform.map(|s| str_in(bump, s)),
+            bogus_user_agent: val.bogus_user_agent,
+            width: val.width,
+            height: val.height,
+            invalid_screen: val.invalid_screen,
+            viewport_width: val.viewport_width,
+            viewport_height: val.viewport_height,
+            pixel_ratio: val.pixel_ratio,
+            prefers_color_scheme: val.prefers_color_scheme,
+            prefers_reduced_motion: val.prefers_reduced_motion,
+            connection: val.connection,
+            partial,
+        }
+    }
+
+    pub fn fields(&self) -> VisitorFields<'b> {
+        VisitorFields {
+            project: self.project,
+            region: self.region,
+            timezone: self.timezone,
+            language: self.language,
+            browser: self.browser,
+            platform: self.platform,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+impl From<&ArenaVisitor<'_>> for Visitor {
+    fn from(val: &ArenaVisitor<'_>) -> Self {
+        Visitor {
+            id: val.id,
+            project: val.project,
+            region: val.region.map(|s| Cow::Owned(s.to_string())),
+            subdivision: val.subdivision.map(|s| Cow::Owned(s.to_string())),
+            continent: val.continent.map(|s| Cow::Owned(s.to_string())),
+            is_eu: val.is_eu,
+            low_confidence: val.low_confidence,
+            timezone: val.timezone.to_string(),
+            language: val.language.to_string(),
+            browser: val.browser.map(|s| Cow::Owned(s.to_string())),
+            platform: val.platform.map(|s| Cow::Owned(s.to_string())),
+            width: val.width,
+            height: val.height,
+            invalid_screen: val.invalid_screen,
+            viewport_width: val.viewport_width,
+            viewport_height: val.viewport_height,
+            pixel_ratio: val.pixel_ratio,
+            prefers_color_scheme: val.prefers_color_scheme,
+            prefers_reduced_motion: val.prefers_reduced_motion,
+            connection: val.connection,
+            bogus_user_agent: val.bogus_user_agent,
+            partial: val.partial,
+            profile: None,
+            cohort: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaPage<'b> {
+    pub id: i64,
+    pub project: i64,
+    pub domain: &'b str,
+    pub path: &'b str,
+    pub actual_url: Option<&'b str>,
+}
+
+impl<'b> ArenaPage<'b> {
+    /// Returns an error if the url has no valid domain.
+    ///
+    /// Values are only copied into the arena for AMP cache urls and
+    /// truncated ones, see [`Page::with_canonical`].
+    pub fn new_in(bump: &'b Bump, project_id: i64, page: &'b PubPage) -> Result<Self, Error> {
+        let canonical = page.canonical.as_ref();
+        let (domain, path, actual_url) = match amp::origin(&page.url) {
+            // the origin is dropped at the end of this call
+            Some(origin) => {
+                let (domain, path, actual_url) = Page::parts(&page.url, canonical, Some(&origin))?;
+                (
+                    &*bump.alloc_str(domain),
+                    &*bump.alloc_str(&path),
+                    actual_url.map(|url| &*bump.alloc_str(&url)),
+                )
+            }
+            None => {
+                let (domain, path, actual_url) = Page::parts(&page.url, canonical, None)?;
+                (
+                    domain,
+                    str_in(bump, path),
+                    actual_url.map(|url| str_in(bump, url)),
+                )
+            }
+        };
+        Ok(ArenaPage {
+            id: Page::compute_id(IdVersion::CURRENT, project_id, domain, path),
+            project: project_id,
+            domain,
+            path,
+            actual_url,
+        })
+    }
+}
+
+/// Borrows `s` if it is, copies it into the arena otherwise.
+fn str_in<'b>(bump: &'b Bump, s: Cow<'b, str>) -> &'b str {
+    match s {
+        Cow::Borrowed(s) => s,
+        Cow::Owned(s) => bump.alloc_str(&s),
+    }
+}
+
+impl From<&ArenaPage<'_>> for Page {
+    fn from(val: &ArenaPage<'_>) -> Self {
+        Page {
+            id: val.id,
+            project: val.project,
+            domain: val.domain.to_string(),
+            path: val.path.to_string(),
+            actual_url: val.actual_url.map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaReferrer<'b> {
+    pub id: i64,
+    pub project: i64,
+    pub domain: &'b str,
+}
+
+impl<'b> ArenaReferrer<'b> {
+    pub fn new_in(
+        bump: &'b Bump,
+        project_id: i64,
+        referrer: Option<&'b Url>,
+        host: &str,
+    ) -> Option<Self> {
+        let domain = str_in(bump, Referrer::domain(referrer, host)?);
+        Some(ArenaReferrer {
+            id: Referrer::compute_id(IdVersion::CURRENT, project_id, domain),
+            project: project_id,
+            domain,
+        })
+    }
+}
+
+impl From<&ArenaReferrer<'_>> for Referrer {
+    fn from(val: &ArenaReferrer<'_>) -> Self {
+        Referrer {
+            id: val.id,
+            project: val.project,
+            domain: val.domain.to_string(),
+        }
+    }
+}
+
+/// Arena counterpart of [`Visit`].
+///
+/// UTM parameters are rare enough that they stay owned.
+#[derive(Debug, Clone)]
+pub struct ArenaVisit<'b> {
+    pub time: DateTime<Utc>,
+    pub project: i64,
+    pub session: i64,
+    pub visitor: ArenaVisitor<'b>,
+    pub page: ArenaPage<'b>,
+    pub utm_param: Option<UtmParam>,
+    pub referrer: Option<ArenaReferrer<'b>>,
+    pub duration: Option<i32>,
+    pub distance: Option<f64>,
+    pub embed: Option<&'b str>,
+    pub is_unique_in_session: Option<bool>,
+    pub previous_page_id: Option<i64>,
+    pub channel_group: Option<&'b str>,
+    pub dry_run: bool,
+}
+
+impl<'b> ArenaVisit<'b> {
+    /// Arena counterpart of [`Visit::new`], grouped into a channel by the
+    /// rules of `enrich`.
+    fn new_in(
+        bump: &'b Bump,
+        project_id: i64,
+        session: &str,
+        visitor: &'b PubVisitor,
+        page: &'b PubPage,
+        user_agent: &str,
+        enrich: Enrich<'_>,
+    ) -> Result<Self, Error> {
+        let session: i64 = session.parse()?;
+        let (builder, partial) = enrich.visitor_builder(project_id, visitor, user_agent);
+        let visitor = ArenaVisitor::build_in(bump, visitor, builder, partial);
+        let arena_page = ArenaPage::new_in(bump, project_id, page)?;
+        let utm_param = UtmParam::parse(project_id, &page.url, enrich.utm);
+        let referrer =
+            ArenaReferrer::new_in(bump, project_id, page.referrer.as_ref(), arena_page.domain);
+        let channel_group = enrich.group(
+            &page.url,
+            utm_param.as_ref(),
+            referrer.map(|referrer| referrer.domain),
+        );
+
+        Ok(ArenaVisit {
+            time: clock::now(),
+            project: project_id,
+            session,
+            visitor,
+            page: arena_page,
+            utm_param,
+            referrer,
+            duration: None,
+            distance: None,
+            embed: None,
+            is_unique_in_session: None,
+            previous_page_id: None,
+            channel_group: Some(&*bump.alloc_str(channel_group)),
+            dry_run: false,
+        })
+    }
+}
+
+impl From<&ArenaVisit<'_>> for Visit {
+    fn from(val: &ArenaVisit<'_>) -> Self {
+        Visit {
+            time: val.time,
+            project: val.project,
+            session: val.session,
+            visitor: (&val.visitor).into(),
+            page: (&val.page).into(),
+            utm_param: val.utm_param.clone(),
+            referrer: val.referrer.as_ref().map(Into::into),
+            duration: val.duration,
+            distance: val.distance,
+            embed: val.embed.map(str::to_string),
+            is_unique_in_session: val.is_unique_in_session,
+            previous_page_id: val.previous_page_id,
+            channel_group: val.channel_group.map(str::to_string),
+            dry_run: val.dry_run,
+        }
+    }
+}
+
+/// Arena counterpart of [`Event`].
+#[derive(Debug, Clone)]
+pub struct ArenaEvent<'b> {
+    pub time: DateTime<Utc>,
+    pub project: i64,
+    pub session: i64,
+    pub visitor: ArenaVisitor<'b>,
+    pub page: ArenaPage<'b>,
+    pub name: &'b str,
+    pub data: &'b Value,
+    pub dry_run: bool,
+}
+
+impl From<&ArenaEvent<'_>> for Event {
+    fn from(val: &ArenaEvent<'_>) -> Self {
+        Event {
+            time: val.time,
+            project: val.project,
+            session: val.session,
+            visitor: (&val.visitor).into(),
+            page: (&val.page).into(),
+            utm_param: None,
+            name: val.name.to_string(),
+            data: val.data.clone(),
+            dry_run: val.dry_run,
+        }
+    }
+}
+
+/// Arena counterpart of [`Record`].
+#[derive(Debug, Clone)]
+pub enum ArenaRecord<'b> {
+    Visit(ArenaVisit<'b>),
+    Event(ArenaEvent<'b>),
+}
+
+impl From<&ArenaRecord<'_>> for Record {
+    fn from(val: &ArenaRecord<'_>) -> Self {
+        match val {
+            ArenaRecord::Visit(visit) => Record::Visit(visit.into()),
+            ArenaRecord::Event(event) => Record::Event(event.into()),
+        }
+    }
+}
+
+/// Arena counterpart of [`handle_visit`](crate::api::handle_visit).
+pub fn process_visit_in<'b>(
+    bump: &'b Bump,
+    project_id: i64,
+    body: &'b PubVisit,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Result<ArenaVisit<'b>, Error> {
+    visit_in(
+        bump,
+        project_id,
+        body,
+        user_agent,
+        Enrich::collecting(settings)?,
+    )
+}
+
+/// Arena counterpart of [`handle_exit`](crate::api::handle_exit).
+pub fn process_exit_in<'b>(
+    bump: &'b Bump,
+    project_id: i64,
+    body: &'b PubExit,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Result<ArenaVisit<'b>, Error> {
+    exit_in(
+        bump,
+        project_id,
+        body,
+        user_agent,
+        Enrich::collecting(settings)?,
+    )
+}
+
+/// Arena counterpart of [`handle_embed`](crate::api::handle_embed).
+pub fn process_embed_in<'b>(
+    bump: &'b Bump,
+    project_id: i64,
+    body: &'b PubEmbed,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Result<ArenaVisit<'b>, Error> {
+    embed_in(
+        bump,
+        project_id,
+        body,
+        user_agent,
+        Enrich::collecting(settings)?,
+    )
+}
+
+/// Arena counterpart of [`handle_event`](crate::api::handle_event).
+pub fn process_event_in<'b>(
+    bump: &'b Bump,
+    project_id: i64,
+    body: &'b PubEvent,
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Result<ArenaEvent<'b>, Error> {
+    event_in(
+        bump,
+        project_id,
+        body,
+        user_agent,
+        Enrich::collecting(settings)?,
+    )
+}
+
+/// Arena counterpart of [`handle_batch`](crate::api::handle_batch).
+pub fn process_batch_in<'b>(
+    bump: &'b Bump,
+    project_id: i64,
+    body: &'b [PubRecord],
+    user_agent: &str,
+    settings: Option<&ProjectSettings>,
+) -> Vec<Result<ArenaRecord<'b>, Error>> {
+    let Ok(enrich) = Enrich::collecting(settings) else {
+        return body.iter().map(|_| Err(Error::ProjectPaused)).collect();
+    };
+    body.iter()
+        .map(|record| record_in(bump, project_id, record, user_agent, enrich))
+        .collect()
+}
+
+/// Arena counterpart of `api::process_record`.
+fn record_in<'b>(
+    bump: &'b Bump,
+    project_id: i64,
+    record: &'b PubRecord,
+    user_agent: &str,
+    enrich: Enrich<'_>,
+) -> Result<ArenaRecord<'b>, Error> {
+    let mut record = match record {
+        PubRecord::Visit(body) => {
+            visit_in(bump, project_id, body, user_agent, enrich).map(ArenaRecord::Visit)
+        }
+        PubRecord::Exit(body) => {
+            exit_in(bump, project_id, body, user_agent, enrich).map(ArenaRecord::Visit)
+        }
+        PubRecord::Event(body) => {
+            event_in(bump, project_id, body, user_agent, enrich).map(ArenaRecord::Event)
+        }
+        PubRecord::Embed(body) => {
+            embed_in(bump, project_id, body, user_agent, enrich).map(ArenaRecord::Visit)
+        }
+    }?;
+    match &mut record {
+        ArenaRecord::Visit(visit) => visit.dry_run = enrich.dry_run,
+        ArenaRecord::Event(event) => event.dry_run = enrich.dry_run,
+    }
+    Ok(record)
+}
+
+fn visit_in<'b>(
+    bump: &'b Bump,
+    project_id: i64,
+    body: &'b PubVisit,
+    user_agent: &str,
+    enrich: Enrich<'_>,
+) -> Result<ArenaVisit<'b>, Error> {
+    let mut visit = ArenaVisit::new_in(
+        bump,
+        project_id,
+        &body.session,
+        &body.visitor,
+        &body.page,
+        user_agent,
+        enrich,
+    )?;
+    visit.previous_page_id = previous_page_id(project_id, body.prev.as_ref());
+    Ok(visit)
+}
+
+fn exit_in<'b>(
+    bump: &'b Bump,
+    project_id: i64,
+    body: &'b PubExit,
+    user_agent: &str,
+    enrich: Enrich<'_>,
+) -> Result<ArenaVisit<'b>, Error> {
+    let mut visit = ArenaVisit::new_in(
+        bump,
+        project_id,
+        &body.session,
+        &body.visitor,
+        &body.page,
+        user_agent,
+        enrich,
+    )?;
+    visit.duration = Some(body.dur);
+    visit.distance = Some(body.dist);
+    Ok(visit)
+}
+
+fn embed_in<'b>(
+    bump: &'b Bump,
+    project_id: i64,
+    body: &'b PubEmbed,
+    user_agent: &str,
+    enrich: Enrich<'_>,
+) -> Result<ArenaVisit<'b>, Error> {
+    let embed = body.embed.trim();
+    if embed.is_empty() {
+        return Err(Error::Missing("embed".to_string()));
+    }
+
+    let mut visit = ArenaVisit::new_in(
+        bump,
+        project_id,
+        &body.session,
+        &body.visitor,
+        &body.parent,
+        user_agent,
+        enrich,
+    )?;
+    visit.embed = Some(embed);
+    Ok(visit)
+}
+
+fn event_in<'b>(
+    bump: &'b Bump,
+    project_id: i64,
+    body: &'b PubEvent,
+    user_agent: &str,
+    enrich: Enrich<'_>,
+) -> Result<ArenaEvent<'b>, Error> {
+    let session: i64 = body.session.parse()?;
+    let (builder, partial) = enrich.visitor_builder(project_id, &body.visitor, user_agent);
+    let visitor = ArenaVisitor::build_in(bump, &body.visitor, builder, partial);
+    let page = ArenaPage::new_in(bump, project_id, &body.page)?;
+    let name = str_in(bump, Event::normalize_name(&body.name)?);
+
+    Ok(ArenaEvent {
+        time: clock::now(),
+        project: project_id,
+        session,
+        visitor,
+        page,
+        name,
+        data: &body.data,
+        dry_run: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api;
+
+    use super::*;
+
+    const USER_AGENT: &str = "Mozilla/5.0 (Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/112.0.0.0 Safari/537.36";
+
+    #[test]
+    fn ids_match_owned_records() {
+        let body: PubVisit = serde_json::from_str(
+            r#"{
+                "session": "7",
+                "visitor": {"tz": "Europe/Zurich", "lang": "de-CH", "screen": [1920, 1080]},
+                "page": {"url": "https://example.com/blog?source=newsletter", "ref": "https://DuckDuckGo.com/"}
+            }"#,
+        )
+        .unwrap();
+        let bump = Bump::new();
+        let arena = process_visit_in(&bump, 1, &body, USER_AGENT, None).unwrap();
+
+        let visitor = Visitor::new(1, &body.visitor, USER_AGENT);
+        let page = Page::new(1, &body.page.url).unwrap();
+        let referrer = Referrer::new(1, body.page.referrer.as_ref(), &page.domain).unwrap();
+        assert_eq!(arena.visitor.id, visitor.id);
+        assert_eq!(arena.page.id, page.id);
+        assert_eq!(arena.referrer.unwrap().id, referrer.id);
+        assert_eq!(arena.referrer.unwrap().domain, "duckduckgo.com");
+
+        let owned = Visit::from(&arena);
+        assert_eq!(owned.visitor.browser.as_deref(), Some("Chrome"));
+        assert_eq!(owned.page.path, "/blog");
+    }
+
+    #[test]
+    fn events_borrow_from_body() {
+        let body: PubEvent = serde_json::from_str(
+            r#"{
+                "session": "7",
+                "visitor": {},
+                "page": {"url": "https://example.com/"},
+                "name": "signup",
+                "data": {"plan": "pro"}
+            }"#,
+        )
+        .unwrap();
+        let bump = Bump::new();
+        let event = process_event_in(&bump, 1, &body, USER_AGENT, None).unwrap();
+        assert_eq!(event.name, "signup");
+        assert_eq!(event.data["plan"], "pro");
+    }
+
+    #[test]
+    fn records_match_owned_records() {
+        let settings: ProjectSettings = serde_yaml::from_str(
+            "
+            utm: {aliases: {ref: source}}
+            channels: [{channel: Partners, source: ^partner$}]
+            ",
+        )
+        .unwrap();
+        let body = r#"[
+            {"type": "visit", "session": "7", "visitor": {"tz": "Europe/Zurich", "lang": "de-CH", "screen": [1080, 1920]}, "page": {"url": "https://example.com/blog?ref=partner", "ref": "https://News.Example.org/"}, "prev": "https://example.com/"},
+            {"type": "visit", "session": "7", "visitor": {"tz": "+02:00", "lang": "fr"}, "page": {"url": "https://example-com.cdn.ampproject.org/c/s/example.com/blog?page=2", "canonical": "https://example.com/blog/"}},
+            {"type": "exit", "session": "7", "visitor": {}, "page": {"url": "https://example.com/blog"}, "dur": 12, "dist": 0.8},
+            {"type": "embed", "session": "7", "visitor": {}, "parent": {"url": "https://blog.example/"}, "embed": " video "},
+            {"type": "event", "session": "7", "visitor": {}, "page": {"url": "https://example.com/"}, "name": "Sign Up", "data": {"plan": "pro"}},
+            {"type": "visit", "session": "x", "visitor": {}, "page": {"url": "https://example.com/"}},
+            {"type": "embed", "session": "7", "visitor": {}, "parent": {"url": "https://blog.example/"}, "embed": ""}
+        ]"#;
+        // the timestamps are taken separately
+        let json = |record: &Record| {
+            let mut json = serde_json::to_value(record).unwrap();
+            json.as_object_mut().unwrap().remove("time");
+            json
+        };
+
+        let records: Vec<PubRecord> = serde_json::from_str(body).unwrap();
+        let bump = Bump::new();
+        let arena = process_batch_in(&bump, 1, &records, USER_AGENT, Some(&settings));
+        let owned: Vec<PubRecord> = serde_json::from_str(body).unwrap();
+        assert_eq!(arena.len(), owned.len());
+        for (arena, owned) in arena.iter().zip(owned) {
+            let owned = api::process_record(1, owned, USER_AGENT, Enrich::for_project(&settings));
+            match (arena, owned) {
+                (Ok(arena), Ok(owned)) => assert_eq!(json(&arena.into()), json(&owned)),
+                (Err(arena), Err(owned)) => assert_eq!(arena.to_string(), owned.to_string()),
+                (arena, owned) => panic!("{arena:?} != {owned:?}"),
+            }
+        }
+
+        let Ok(ArenaRecord::Visit(visit)) = &arena[0] else {
+            panic!("not a visit");
+        };
+        assert_eq!(visit.channel_group, Some("Partners"));
+    }
+
+    #[test]
+    fn canonical_pages_match_owned_records() {
+        let page: PubPage = serde_json::from_str(
+            r#"{
+                "url": "https://example-com.cdn.ampproject.org/c/s/example.com/blog?page=2",
+                "canonical": "https://example.com/blog/"
+            }"#,
+        )
+        .unwrap();
+        let bump = Bump::new();
+        let arena = ArenaPage::new_in(&bump, 1, &page).unwrap();
+        let owned = Page::with_canonical(1, &page.url, page.canonical.as_ref()).unwrap();
+        assert_eq!(arena.id, owned.id);
+        assert_eq!(arena.path, "/blog/");
+        assert_eq!(arena.actual_url, owned.actual_url.as_deref());
+        assert_eq!(arena.actual_url, Some("https://example.com/blog?page=2"));
+    }
+
+    #[test]
+    fn long_paths_match_owned_records() {
+        let long = "a".repeat(10_000);
+        let page: PubPage = serde_json::from_str(&format!(
+            r#"{{"url": "https://example.com/{long}", "canonical": "https://example.com/"}}"#
+        ))
+        .unwrap();
+        let bump = Bump::new();
+        let arena = ArenaPage::new_in(&bump, 1, &page).unwrap();
+        let owned = Page::with_canonical(1, &page.url, page.canonical.as_ref()).unwrap();
+        assert_eq!(arena.actual_url, owned.actual_url.as_deref());
+
+        let page = PubPage {
+            canonical: None,
+            ..page
+        };
+        let arena = ArenaPage::new_in(&bump, 1, &page).unwrap();
+        let owned = Page::new(1, &page.url).unwrap();
+        assert_eq!((arena.id, arena.path), (owned.id, owned.path.as_str()));
+    }
+}
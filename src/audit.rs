@@ -0,0 +1,221 @@
+//! Audit log of traffic dropped or modified by configuration.
+//!
+//! When a customer reports missing data, operators need to know whether the
+//! collector discarded it on purpose: a paused project, an exceeded quota,
+//! an event filter. The [`AuditLog`] counts such decisions per project,
+//! reason and hour, so it stays small however much traffic is affected.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::filter::{EventFilter, Verdict};
+use crate::middleware::Middleware;
+use crate::Record;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// Beacons of a [paused](crate::config::ProjectSettings::paused) project.
+    ProjectPaused,
+    /// Records over the hard [quota](crate::quota).
+    QuotaExceeded,
+    /// Events the [`EventFilter`] dropped.
+    EventDropped,
+    /// Events the [`EventFilter`] renamed.
+    EventRenamed,
+}
+
+impl Reason {
+    /// Whether the traffic was discarded, otherwise it was modified.
+    pub fn is_drop(self) -> bool {
+        !matches!(self, Reason::EventRenamed)
+    }
+}
+
+/// Decisions of one reason within an hour.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub project: i64,
+    pub hour: DateTime<Utc>,
+    pub reason: Reason,
+    /// E.g. the name of a dropped event.
+    pub detail: Option<String>,
+    pub count: u64,
+}
+
+/// Filter of [`AuditLog::query`], empty fields match everything.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AuditQuery {
+    pub project: Option<i64>,
+    pub reason: Option<Reason>,
+    /// Inclusive.
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive.
+    pub to: Option<DateTime<Utc>>,
+}
+
+type Key = (i64, DateTime<Utc>, Reason, Option<String>);
+
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    counts: Mutex<BTreeMap<Key, u64>>,
+}
+
+impl AuditLog {
+    pub fn record(&self, project: i64, reason: Reason, detail: Option<&str>, time: DateTime<Utc>) {
+        let hour = time.duration_trunc(Duration::hours(1)).unwrap_or(time);
+        let key = (project, hour, reason, detail.map(ToString::to_string));
+        *self.counts.lock().unwrap().entry(key).or_default() += 1;
+    }
+
+    /// Entries matching the query, by project and hour.
+    pub fn query(&self, query: &AuditQuery) -> Vec<AuditEntry> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((project, hour, reason, _), _)| {
+                query.project.is_none_or(|want| want == *project)
+                    && query.reason.is_none_or(|want| want == *reason)
+                    && query.from.is_none_or(|from| *hour >= from)
+                    && query.to.is_none_or(|to| *hour < to)
+            })
+            .map(|((project, hour, reason, detail), count)| AuditEntry {
+                project: *project,
+                hour: *hour,
+                reason: *reason,
+                detail: detail.clone(),
+                count: *count,
+            })
+            .collect()
+    }
+
+    /// Forgets the hours before `before`, e.g. after persisting them.
+    pub fn prune(&self, before: DateTime<Utc>) {
+        self.counts
+            .lock()
+            .unwrap()
+            .retain(|(_, hour, _, _), _| *hour >= before);
+    }
+}
+
+/// An [`EventFilter`] that records its decisions.
+#[derive(Debug)]
+pub struct AuditedFilter {
+    filter: EventFilter,
+    log: Arc<AuditLog>,
+}
+
+impl AuditedFilter {
+    pub fn new(filter: EventFilter, log: Arc<AuditLog>) -> Self {
+        AuditedFilter { filter, log }
+    }
+}
+
+impl Middleware for AuditedFilter {
+    fn process(&self, record: Record) -> Option<Record> {
+        let Record::Event(mut event) = record else {
+            return Some(record);
+        };
+        let name = event.name.clone();
+        let reason = match self.filter.apply(&mut event) {
+            Verdict::Dropped => Reason::EventDropped,
+            Verdict::Renamed => Reason::EventRenamed,
+            _ => return Some(Record::Event(event)),
+        };
+        // dry runs are not collected, nothing to audit
+        if !event.dry_run {
+            self.log
+                .record(event.project, reason, Some(&name), event.time);
+        }
+        match reason {
+            Reason::EventDropped => None,
+            _ => Some(Record::Event(event)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::filter::UnknownEvent;
+    use crate::Event;
+
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 10, 1, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn counts_per_hour_and_reason() {
+        let log = AuditLog::default();
+        log.record(1, Reason::QuotaExceeded, None, at(12, 5));
+        log.record(1, Reason::QuotaExceeded, None, at(12, 55));
+        log.record(1, Reason::QuotaExceeded, None, at(13, 0));
+        log.record(2, Reason::ProjectPaused, None, at(12, 0));
+
+        let entries = log.query(&AuditQuery {
+            project: Some(1),
+            ..Default::default()
+        });
+        let counts: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.hour, entry.count))
+            .collect();
+        assert_eq!(counts, [(at(12, 0), 2), (at(13, 0), 1)]);
+
+        let paused = log.query(&AuditQuery {
+            reason: Some(Reason::ProjectPaused),
+            to: Some(at(13, 0)),
+            ..Default::default()
+        });
+        assert_eq!(paused.len(), 1);
+        assert!(paused[0].reason.is_drop());
+
+        log.prune(at(13, 0));
+        assert_eq!(log.query(&AuditQuery::default()).len(), 1);
+    }
+
+    #[test]
+    fn records_filter_decisions() {
+        let log = Arc::new(AuditLog::default());
+        let filter = EventFilter::deny(["debug"]).unwrap();
+        let audited = AuditedFilter::new(filter, log.clone());
+        let event = |name: &str| -> Record {
+            Event {
+                project: 1,
+                name: name.to_string(),
+                ..Default::default()
+            }
+            .into()
+        };
+        assert!(audited.process(event("debug")).is_none());
+        assert!(audited.process(event("signup")).is_some());
+
+        let renaming = AuditedFilter::new(
+            EventFilter::allow(["signup"])
+                .unwrap()
+                .on_unknown(UnknownEvent::Rename),
+            log.clone(),
+        );
+        assert!(renaming.process(event("promo")).is_some());
+
+        let entries = log.query(&AuditQuery::default());
+        let reasons: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.reason, entry.detail.as_deref()))
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                (Reason::EventDropped, Some("debug")),
+                (Reason::EventRenamed, Some("promo")),
+            ]
+        );
+    }
+}
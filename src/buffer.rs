@@ -0,0 +1,310 @@
+//! Write-ahead journal for processed records.
+//!
+//! Records are appended to segment files in a local directory before they
+//! are handed to a sink. If the sink is unavailable or the process crashes,
+//! the records are replayed from the journal instead of being lost.
+//!
+//! Segments are named by a sequence number and hold one json record per
+//! line. The newest segment is written to, older ones are sealed and
+//! removed once [drained](Journal::drain).
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::flush::Flushable;
+use crate::{Error, Record};
+
+const SEGMENT_EXTENSION: &str = "wal";
+
+/// Default for [`Journal::with_segment_size`].
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Journal of entries of type `T`, processed [records](Record) by default.
+///
+/// Other serializable entries can be journaled as well, e.g. the
+/// [raw beacons](crate::raw::RawBeacon) kept for reprocessing.
+#[derive(Debug)]
+pub struct Journal<T = Record> {
+    dir: PathBuf,
+    segment_size: u64,
+    sync: bool,
+    /// Sequence number of the active segment.
+    seq: u64,
+    writer: BufWriter<File>,
+    written: u64,
+    entries: PhantomData<fn(T) -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Journal<T> {
+    /// Opens the journal in `dir`, creating the directory if needed.
+    ///
+    /// Segments left over from a previous run are kept for replay and a
+    /// new active segment is started.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let seq = segments(&dir)?.last().map_or(0, |seq| seq + 1);
+        let writer = create_segment(&dir, seq)?;
+        Ok(Journal {
+            dir,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            sync: false,
+            seq,
+            writer,
+            written: 0,
+            entries: PhantomData,
+        })
+    }
+
+    /// Size in bytes after which a new segment is started.
+    pub fn with_segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Whether every append is flushed to disk with `fsync`.
+    ///
+    /// Without it, records still in the OS page cache are lost on power
+    /// failure but not on a process crash.
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn append(&mut self, record: &T) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.writer.flush()?;
+        if self.sync {
+            self.writer.get_ref().sync_data()?;
+        }
+
+        self.written += line.len() as u64;
+        if self.written >= self.segment_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Seals the active segment and starts a new one.
+    pub fn rotate(&mut self) -> Result<(), Error> {
+        if self.written == 0 {
+            return Ok(());
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.seq += 1;
+        self.writer = create_segment(&self.dir, self.seq)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Hands the records of every segment to `deliver`, oldest first.
+    ///
+    /// The active segment is sealed first. A segment is removed once
+    /// `deliver` accepted its records, the first error stops draining and
+    /// leaves the remaining segments for the next attempt.
+    pub fn drain<E>(
+        &mut self,
+        mut deliver: impl FnMut(Vec<T>) -> Result<(), E>,
+    ) -> Result<(), DrainError<E>> {
+        self.rotate()?;
+        for seq in segments(&self.dir)? {
+            if seq == self.seq {
+                continue;
+            }
+            let path = segment_path(&self.dir, seq);
+            let records = read_segment(&path)?;
+            if !records.is_empty() {
+                deliver(records).map_err(DrainError::Sink)?;
+            }
+            fs::remove_file(path).map_err(Error::from)?;
+        }
+        Ok(())
+    }
+}
+
+/// Flushing syncs the active segment to disk, closing seals it.
+impl<T: Serialize + DeserializeOwned> Flushable for Journal<T> {
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.rotate()
+    }
+}
+
+/// Error of [`Journal::drain`].
+#[derive(Debug, thiserror::Error)]
+pub enum DrainError<E> {
+    #[error(transparent)]
+    Journal(#[from] Error),
+
+    #[error("sink rejected records")]
+    Sink(E),
+}
+
+impl<E> From<std::io::Error> for DrainError<E> {
+    fn from(err: std::io::Error) -> Self {
+        DrainError::Journal(err.into())
+    }
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{seq:020}.{SEGMENT_EXTENSION}"))
+}
+
+fn create_segment(dir: &Path, seq: u64) -> Result<BufWriter<File>, Error> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, seq))?;
+    Ok(BufWriter::new(file))
+}
+
+/// Sequence numbers of the segments in `dir`, sorted.
+fn segments(dir: &Path) -> Result<Vec<u64>, Error> {
+    let mut val = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(seq) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            val.push(seq);
+        }
+    }
+    val.sort_unstable();
+    Ok(val)
+}
+
+/// Reads the records of a segment.
+///
+/// A last line without a newline is a write torn by a crash and skipped.
+fn read_segment<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut val = Vec::new();
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        if !line.ends_with('\n') {
+            break;
+        }
+        val.push(serde_json::from_str(&line)?);
+        line.clear();
+    }
+    Ok(val)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use crate::{Event, Visit};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("abineo-journal-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn event(name: &str) -> Record {
+        Event {
+            name: name.to_string(),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    fn names(records: &[Record]) -> Vec<String> {
+        records
+            .iter()
+            .map(|record| match record {
+                Record::Event(event) => event.name.clone(),
+                Record::Visit(_) => "visit".to_string(),
+                Record::Session(_) => "session".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replays_after_restart() {
+        let dir = temp_dir("replay");
+        {
+            let mut journal = Journal::open(&dir).unwrap().with_segment_size(1);
+            journal.append(&event("a")).unwrap();
+            journal.append(&Visit::default().into()).unwrap();
+            journal.append(&event("b")).unwrap();
+        }
+        assert_eq!(segments(&dir).unwrap().len(), 4);
+
+        let mut journal = Journal::open(&dir).unwrap();
+        let mut delivered = Vec::new();
+        journal
+            .drain(|records| {
+                delivered.extend(records);
+                Ok::<_, Infallible>(())
+            })
+            .unwrap();
+        assert_eq!(names(&delivered), ["a", "visit", "b"]);
+        assert_eq!(segments(&dir).unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_segments_on_sink_error() {
+        let dir = temp_dir("sink-error");
+        let mut journal = Journal::open(&dir).unwrap().with_segment_size(1);
+        journal.append(&event("a")).unwrap();
+        journal.append(&event("b")).unwrap();
+
+        let mut attempts = 0;
+        let err = journal.drain(|_| {
+            attempts += 1;
+            if attempts == 2 {
+                Err("unavailable")
+            } else {
+                Ok(())
+            }
+        });
+        assert!(matches!(err, Err(DrainError::Sink("unavailable"))));
+
+        let mut delivered = Vec::new();
+        journal
+            .drain(|records| {
+                delivered.extend(records);
+                Ok::<_, Infallible>(())
+            })
+            .unwrap();
+        assert_eq!(names(&delivered), ["b"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_torn_writes() {
+        let dir = temp_dir("torn");
+        fs::create_dir_all(&dir).unwrap();
+        let line = serde_json::to_string(&event("a")).unwrap();
+        fs::write(segment_path(&dir, 0), format!("{line}\n{{\"type\":\"ev")).unwrap();
+
+        let records = read_segment(&segment_path(&dir, 0)).unwrap();
+        assert_eq!(names(&records), ["a"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
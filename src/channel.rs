@@ -0,0 +1,302 @@
+//! Channel grouping of visits.
+//!
+//! Every visit is assigned a channel group like `Organic Search` or `Email`
+//! from its [`Touch`]: the campaign source and medium, the referrer domain
+//! and the click id of the landing page. The first matching [`ChannelRule`]
+//! names the group. Projects list their own rules in
+//! [`ProjectSettings::channels`](crate::config::ProjectSettings::channels),
+//! which are tried before the [defaults](default_rules). The defaults mirror
+//! the GA4 default channel grouping, visits no rule matches are
+//! [`UNASSIGNED`].
+
+use std::fmt;
+
+use lazy_static::lazy_static;
+use regex::{Regex, RegexBuilder};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use url::Url;
+
+use crate::{utm, UtmParam};
+
+/// Group of visits no rule matches.
+pub const UNASSIGNED: &str = "Unassigned";
+
+/// Query keys of ad click ids, in the order they are looked up.
+pub const CLICK_IDS: [&str; 9] = [
+    "gclid",
+    "gbraid",
+    "wbraid",
+    "dclid",
+    "msclkid",
+    "fbclid",
+    "ttclid",
+    "twclid",
+    "li_fat_id",
+];
+
+/// What a visit's channel is derived from, absent values are empty.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Touch<'a> {
+    pub source: &'a str,
+    pub medium: &'a str,
+    /// Domain of an external referrer.
+    pub referrer: &'a str,
+    /// Query key of the landing page's click id, one of [`CLICK_IDS`].
+    pub click_id: &'a str,
+}
+
+impl<'a> Touch<'a> {
+    /// The touch of a visit landing on `url`, `referrer` is the domain of
+    /// an external referrer.
+    pub fn new(url: &Url, utm_param: Option<&'a UtmParam>, referrer: Option<&'a str>) -> Self {
+        let field = |value: Option<&'a String>| value.map_or("", String::as_str);
+        Touch {
+            source: field(utm_param.and_then(|utm| utm.source.as_ref())),
+            medium: field(utm_param.and_then(|utm| utm.medium.as_ref())),
+            referrer: referrer.unwrap_or_default(),
+            click_id: click_id(url).unwrap_or_default(),
+        }
+    }
+}
+
+/// The first [click id](CLICK_IDS) key in the query of `url`.
+pub fn click_id(url: &Url) -> Option<&'static str> {
+    utm::query_pairs(url).find_map(|(key, value)| {
+        CLICK_IDS
+            .into_iter()
+            .find(|id| !value.is_empty() && key.eq_ignore_ascii_case(id))
+    })
+}
+
+/// A regex matched case-insensitively against a [`Touch`] value, `^$`
+/// matches absent values.
+#[derive(Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        RegexBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .map(Pattern)
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.0.is_match(value)
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.0.as_str(), f)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Pattern::new(&pattern).map_err(D::Error::custom)
+    }
+}
+
+/// Names the channel of the visits matching all of its patterns, a rule
+/// without patterns matches every visit.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelRule {
+    pub channel: String,
+    #[serde(default)]
+    pub source: Option<Pattern>,
+    #[serde(default)]
+    pub medium: Option<Pattern>,
+    #[serde(default)]
+    pub referrer: Option<Pattern>,
+    #[serde(default)]
+    pub click_id: Option<Pattern>,
+}
+
+impl ChannelRule {
+    fn new(channel: &str) -> Self {
+        ChannelRule {
+            channel: channel.to_string(),
+            source: None,
+            medium: None,
+            referrer: None,
+            click_id: None,
+        }
+    }
+
+    fn with(mut self, field: fn(&mut Self) -> &mut Option<Pattern>, pattern: &str) -> Self {
+        *field(&mut self) = Some(Pattern::new(pattern).expect("valid default pattern"));
+        self
+    }
+
+    pub fn matches(&self, touch: &Touch<'_>) -> bool {
+        [
+            (&self.source, touch.source),
+            (&self.medium, touch.medium),
+            (&self.referrer, touch.referrer),
+            (&self.click_id, touch.click_id),
+        ]
+        .into_iter()
+        .all(|(pattern, value)| pattern.as_ref().is_none_or(|p| p.is_match(value)))
+    }
+}
+
+const SEARCH: &str = r"^(.+\.)?(google|bing|duckduckgo|yahoo|yandex|baidu|ecosia|startpage|qwant|naver|seznam|brave)(\.[a-z]+)+$";
+const SEARCH_SOURCE: &str =
+    r"^(google|bing|duckduckgo|yahoo|yandex|baidu|ecosia|startpage|qwant|naver|seznam|brave)$";
+const SOCIAL: &str = r"^(.+\.)?(facebook|instagram|linkedin|lnkd|twitter|t|x|pinterest|reddit|tiktok|snapchat|threads|mastodon\.social|bsky|tumblr|vk|weibo|xing)(\.[a-z]+)+$";
+const SOCIAL_SOURCE: &str = r"^(facebook|fb|instagram|ig|linkedin|twitter|x|pinterest|reddit|tiktok|snapchat|threads|mastodon|bluesky|tumblr|vk|weibo|xing)$";
+const VIDEO: &str = r"^(.+\.)?(youtube|youtu|vimeo|twitch|dailymotion)(\.[a-z]+)+$";
+const PAID_MEDIUM: &str = r"^(.*cp.*|ppc|retargeting|paid.*)$";
+
+lazy_static! {
+    static ref DEFAULT_RULES: Vec<ChannelRule> = vec![
+        ChannelRule::new("Direct")
+            .with(|r| &mut r.source, r"^(\(direct\))?$")
+            .with(|r| &mut r.medium, r"^(\(none\)|\(not set\))?$")
+            .with(|r| &mut r.referrer, "^$")
+            .with(|r| &mut r.click_id, "^$"),
+        ChannelRule::new("Paid Search")
+            .with(|r| &mut r.click_id, "^(gclid|gbraid|wbraid|msclkid)$"),
+        ChannelRule::new("Paid Search")
+            .with(|r| &mut r.source, SEARCH_SOURCE)
+            .with(|r| &mut r.medium, PAID_MEDIUM),
+        ChannelRule::new("Paid Social")
+            .with(|r| &mut r.click_id, "^(fbclid|ttclid|twclid|li_fat_id)$"),
+        ChannelRule::new("Paid Social")
+            .with(|r| &mut r.source, SOCIAL_SOURCE)
+            .with(|r| &mut r.medium, PAID_MEDIUM),
+        ChannelRule::new("Display").with(|r| &mut r.click_id, "^dclid$"),
+        ChannelRule::new("Display").with(
+            |r| &mut r.medium,
+            "^(display|banner|expandable|interstitial|cpm)$"
+        ),
+        ChannelRule::new("Paid Other").with(|r| &mut r.medium, PAID_MEDIUM),
+        ChannelRule::new("Organic Search").with(|r| &mut r.source, SEARCH_SOURCE),
+        ChannelRule::new("Organic Search")
+            .with(|r| &mut r.medium, r"^(organic)?$")
+            .with(|r| &mut r.referrer, SEARCH),
+        ChannelRule::new("Organic Social").with(|r| &mut r.source, SOCIAL_SOURCE),
+        ChannelRule::new("Organic Social").with(
+            |r| &mut r.medium,
+            "^(social|social-network|social-media|sm|social network|social media)$"
+        ),
+        ChannelRule::new("Organic Social")
+            .with(|r| &mut r.medium, "^$")
+            .with(|r| &mut r.referrer, SOCIAL),
+        ChannelRule::new("Organic Video").with(|r| &mut r.medium, "video"),
+        ChannelRule::new("Organic Video")
+            .with(|r| &mut r.medium, "^$")
+            .with(|r| &mut r.referrer, VIDEO),
+        ChannelRule::new("Email").with(
+            |r| &mut r.source,
+            "^(email|e-mail|e_mail|e mail|newsletter)$"
+        ),
+        ChannelRule::new("Email").with(|r| &mut r.medium, "^(email|e-mail|e_mail|e mail)$"),
+        ChannelRule::new("Affiliates").with(|r| &mut r.medium, "^affiliate$"),
+        ChannelRule::new("Referral").with(|r| &mut r.medium, "^(referral|app|link)$"),
+        ChannelRule::new("Referral")
+            .with(|r| &mut r.medium, "^$")
+            .with(|r| &mut r.referrer, "."),
+    ];
+}
+
+/// The rules after those of the project, mirroring the GA4 default channel
+/// grouping.
+pub fn default_rules() -> &'static [ChannelRule] {
+    &DEFAULT_RULES
+}
+
+/// The channel group of the first of `rules` and then the
+/// [defaults](default_rules) that matches, [`UNASSIGNED`] if none does.
+pub fn group<'a>(rules: &'a [ChannelRule], touch: &Touch<'_>) -> &'a str {
+    rules
+        .iter()
+        .chain(default_rules())
+        .find(|rule| rule.matches(touch))
+        .map_or(UNASSIGNED, |rule| rule.channel.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch<'a>(source: &'a str, medium: &'a str, referrer: &'a str) -> Touch<'a> {
+        Touch {
+            source,
+            medium,
+            referrer,
+            click_id: "",
+        }
+    }
+
+    #[test]
+    fn groups_like_ga4_defaults() {
+        let cases = [
+            (touch("", "", ""), "Direct"),
+            (touch("google", "cpc", ""), "Paid Search"),
+            (touch("facebook", "paid_social", ""), "Paid Social"),
+            (touch("partner", "cpc", ""), "Paid Other"),
+            (touch("", "", "www.google.ch"), "Organic Search"),
+            (touch("", "", "duckduckgo.com"), "Organic Search"),
+            (touch("", "", "l.facebook.com"), "Organic Social"),
+            (touch("", "", "m.youtube.com"), "Organic Video"),
+            (touch("newsletter", "email", ""), "Email"),
+            (touch("Mail", "E-Mail", ""), "Email"),
+            (touch("partner", "affiliate", ""), "Affiliates"),
+            (touch("", "", "news.ycombinator.com"), "Referral"),
+            (touch("print", "flyer", ""), UNASSIGNED),
+        ];
+        for (touch, channel) in cases {
+            assert_eq!(group(&[], &touch), channel, "{touch:?}");
+        }
+    }
+
+    #[test]
+    fn detects_click_ids() {
+        let url = Url::parse("https://example.com/?ref=nav&GCLID=abc").unwrap();
+        assert_eq!(click_id(&url), Some("gclid"));
+        let touch = Touch::new(&url, None, None);
+        assert_eq!(group(&[], &touch), "Paid Search");
+
+        let empty = Url::parse("https://example.com/?fbclid=").unwrap();
+        assert_eq!(click_id(&empty), None);
+    }
+
+    #[test]
+    fn project_rules_take_precedence() {
+        let rules: Vec<ChannelRule> = serde_yaml::from_str(
+            r#"
+- channel: Partners
+  referrer: (^|\.)partner\.com$
+- channel: Print
+  source: ^print$
+  medium: ^flyer$
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            group(&rules, &touch("", "", "shop.partner.com")),
+            "Partners"
+        );
+        assert_eq!(group(&rules, &touch("print", "flyer", "")), "Print");
+        assert_eq!(
+            group(&rules, &touch("", "", "google.com")),
+            "Organic Search"
+        );
+
+        let invalid = serde_yaml::from_str::<Vec<ChannelRule>>("- {channel: X, source: '('}");
+        assert!(invalid.is_err());
+    }
+}
@@ -0,0 +1,50 @@
+//! The current time of the parsing and enrichment path.
+//!
+//! Records, sessions and raw beacons are timestamped with [`now`], which
+//! reads the system clock unless the host installed another [`Clock`]. This
+//! keeps the path usable where `Utc::now` is unavailable, e.g. on
+//! `wasm32-unknown-unknown` at the CDN edge, where the host passes in the
+//! time of its runtime (`Date.now()` on Cloudflare Workers).
+
+use std::sync::RwLock;
+
+use chrono::{DateTime, Utc};
+
+/// Returns the current time.
+pub type Clock = fn() -> DateTime<Utc>;
+
+static CLOCK: RwLock<Clock> = RwLock::new(Utc::now);
+
+/// The current time of the installed [`Clock`].
+pub fn now() -> DateTime<Utc> {
+    (CLOCK.read().unwrap())()
+}
+
+/// Installs the clock used by [`now`] from then on.
+pub fn set_clock(clock: Clock) {
+    *CLOCK.write().unwrap() = clock;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn counting() -> DateTime<Utc> {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        Utc::now()
+    }
+
+    #[test]
+    fn uses_installed_clock() {
+        // still the system time, tests running in parallel don't notice
+        set_clock(counting);
+        let before = CALLS.load(Ordering::Relaxed);
+        now();
+        set_clock(Utc::now);
+        assert!(CALLS.load(Ordering::Relaxed) > before);
+    }
+}
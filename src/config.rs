@@ -0,0 +1,437 @@
+//! Configuration of a collector, loaded from YAML and the environment.
+//!
+//! A [`CollectorConfig`] is read from a YAML (or JSON) file, then
+//! environment variables prefixed with [`ENV_PREFIX`] override single
+//! values. Nested keys are separated by a double underscore and matched
+//! case-insensitively, e.g. `ABINEO_PIPELINE__BATCH_SIZE=100` or
+//! `ABINEO_PROJECTS__1__SALT=42`. Values are parsed as YAML scalars.
+//!
+//! ```yaml
+//! salt:
+//!   secret: 123456789
+//!   period_hours: 24
+//! pipeline:
+//!   batch_size: 1000
+//!   overload: park
+//! projects:
+//!   1:
+//!     name: Example
+//!     domains: [example.com, www.example.com]
+//! ```
+//!
+//! Project settings can be reloaded at runtime, see [`provider`].
+
+pub mod provider;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use url::Url;
+
+use crate::channel::ChannelRule;
+use crate::filter::EventFilter;
+use crate::pipeline::{Overload, PipelineConfig};
+use crate::priority::EventPriorities;
+use crate::privacy::RotatingSalt;
+use crate::quota::Quota;
+use crate::raw::CaptureLimits;
+use crate::utm::UtmOptions;
+use crate::Error;
+
+/// Prefix of environment variables that override the configuration.
+pub const ENV_PREFIX: &str = "ABINEO_";
+
+const ENV_SEPARATOR: &str = "__";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollectorConfig {
+    pub salt: SaltSettings,
+    pub pipeline: PipelineSettings,
+    pub projects: BTreeMap<i64, ProjectSettings>,
+    pub journal: Option<JournalSettings>,
+    pub capture: Option<CaptureSettings>,
+    pub archive: Option<ArchiveSettings>,
+    pub webhook: Option<WebhookSettings>,
+}
+
+/// Salt mixed into visitor ids, see [`crate::privacy`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SaltSettings {
+    /// Without a secret, visitor ids are unsalted.
+    pub secret: Option<u64>,
+    /// Rotation period of the salt, daily by default.
+    pub period_hours: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineSettings {
+    pub capacity: usize,
+    pub parse_workers: usize,
+    /// Defaults to the available parallelism.
+    pub enrich_workers: Option<usize>,
+    pub batch_size: usize,
+    pub batch_timeout_ms: u64,
+    pub overload: Overload,
+    /// Repairs beacons of outdated trackers, see [`crate::api::lenient`].
+    pub lenient: bool,
+    /// Latency budget of enriching a record in milliseconds, unlimited by
+    /// default, see [`PipelineConfig::enrich_budget`].
+    pub enrich_budget_ms: Option<u64>,
+}
+
+impl Default for PipelineSettings {
+    fn default() -> Self {
+        let defaults = PipelineConfig::default();
+        PipelineSettings {
+            capacity: defaults.capacity,
+            parse_workers: defaults.parse_workers,
+            enrich_workers: None,
+            batch_size: defaults.batch_size,
+            batch_timeout_ms: defaults.batch_timeout.as_millis() as u64,
+            overload: defaults.overload,
+            lenient: defaults.lenient,
+            enrich_budget_ms: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectSettings {
+    pub name: Option<String>,
+    /// Hostnames the project accepts beacons from.
+    pub domains: Vec<String>,
+    /// Static salt of the project, instead of the rotating one.
+    pub salt: Option<u64>,
+    pub events: EventFilter,
+    /// Whether the project is temporarily not collecting, beacons are
+    /// rejected with [`Error::ProjectPaused`] before they are processed.
+    pub paused: bool,
+    /// Monthly record limits, see [`crate::quota`].
+    pub quota: Option<Quota>,
+    /// How campaign parameters are read, see [`crate::utm`].
+    pub utm: UtmOptions,
+    /// Sampling and overload handling of events, see [`crate::priority`].
+    pub priorities: EventPriorities,
+    /// Whether beacons are processed but never written to the sink, e.g.
+    /// to verify new settings against production traffic.
+    pub dry_run: bool,
+    /// Channel grouping rules tried before the defaults, see
+    /// [`crate::channel`].
+    pub channels: Vec<ChannelRule>,
+}
+
+impl ProjectSettings {
+    pub fn ensure_collecting(&self) -> Result<(), Error> {
+        if self.paused {
+            return Err(Error::ProjectPaused);
+        }
+        Ok(())
+    }
+}
+
+/// Settings of a [`Journal`](crate::buffer::Journal).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JournalSettings {
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub segment_size: Option<u64>,
+    #[serde(default)]
+    pub sync: bool,
+}
+
+/// Settings of a [`Capture`](crate::raw::Capture).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureSettings {
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub max_body: Option<usize>,
+    #[serde(default)]
+    pub max_headers: Option<usize>,
+}
+
+impl CaptureSettings {
+    pub fn limits(&self) -> CaptureLimits {
+        let defaults = CaptureLimits::default();
+        CaptureLimits {
+            max_body: self.max_body.unwrap_or(defaults.max_body),
+            max_headers: self.max_headers.unwrap_or(defaults.max_headers),
+        }
+    }
+}
+
+/// Settings of an [`ArchiveSink`](crate::sink::archive::ArchiveSink).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveSettings {
+    pub prefix: String,
+    pub instance: String,
+    #[serde(default)]
+    pub max_object_size: Option<usize>,
+}
+
+/// Settings of a [`WebhookSink`](crate::sink::webhook::WebhookSink).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSettings {
+    pub url: Url,
+    #[serde(default)]
+    pub retries: u32,
+    #[serde(default)]
+    pub backoff_ms: u64,
+}
+
+impl CollectorConfig {
+    /// Reads the file at `path` and applies the overrides of the process
+    /// environment.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let yaml = fs::read_to_string(path)?;
+        Self::parse(&yaml, std::env::vars())
+    }
+
+    /// Parses `yaml` and applies the overrides among `env`, then validates
+    /// the result.
+    pub fn parse<I>(yaml: &str, env: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut value: Value = serde_yaml::from_str(yaml)?;
+        if value.is_null() {
+            value = Value::Mapping(Mapping::new());
+        }
+        for (key, raw) in env {
+            let Some(path) = key.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let path: Vec<String> = path
+                .split(ENV_SEPARATOR)
+                .map(str::to_ascii_lowercase)
+                .collect();
+            let scalar = serde_yaml::from_str(&raw).unwrap_or(Value::String(raw));
+            set(&mut value, &path, scalar)
+                .map_err(|_| Error::Config(format!("{key}: not a map")))?;
+        }
+
+        // serde_yaml only reads enums from tagged values, the json detour
+        // accepts them as maps, e.g. `names: {allow: [signup]}`
+        let config: CollectorConfig = serde_json::from_value(serde_json::to_value(value)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |reason: &str| Err(Error::Config(reason.to_string()));
+        let pipeline = &self.pipeline;
+        if pipeline.capacity == 0 || pipeline.batch_size == 0 {
+            return invalid("pipeline capacity and batch size must be positive");
+        }
+        if pipeline.parse_workers == 0 || pipeline.enrich_workers == Some(0) {
+            return invalid("pipeline workers must be positive");
+        }
+        if matches!(self.salt.period_hours, Some(hours) if hours <= 0) {
+            return invalid("salt period must be positive");
+        }
+        if matches!(&self.journal, Some(journal) if journal.segment_size == Some(0)) {
+            return invalid("journal segment size must be positive");
+        }
+        if matches!(&self.archive, Some(archive) if archive.instance.is_empty()) {
+            return invalid("archive instance must not be empty");
+        }
+        for (id, project) in &self.projects {
+            if let Some(domain) = project.domains.iter().find(|domain| !valid_domain(domain)) {
+                return Err(Error::Config(format!(
+                    "project {id}: invalid domain {domain}"
+                )));
+            }
+            if project.priorities.sample.values().any(|rate| *rate > 100) {
+                return Err(Error::Config(format!(
+                    "project {id}: sample rates are percentages"
+                )));
+            }
+            if project.utm.max_len == 0 {
+                return Err(Error::Config(format!(
+                    "project {id}: utm max_len must be positive"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pipeline_config(&self) -> PipelineConfig {
+        let settings = &self.pipeline;
+        let defaults = PipelineConfig::default();
+        PipelineConfig {
+            capacity: settings.capacity,
+            parse_workers: settings.parse_workers,
+            enrich_workers: settings.enrich_workers.unwrap_or(defaults.enrich_workers),
+            batch_size: settings.batch_size,
+            batch_timeout: Duration::from_millis(settings.batch_timeout_ms),
+            overload: settings.overload,
+            lenient: settings.lenient,
+            enrich_budget: settings.enrich_budget_ms.map(Duration::from_millis),
+            middleware: Arc::default(),
+            optional_middleware: Arc::default(),
+            shedding: None,
+            capture: None,
+            projects: None,
+            quota: None,
+            usage: None,
+            audit: None,
+            sessions: None,
+            session_timeout: defaults.session_timeout,
+        }
+    }
+
+    /// The rotating salt, if a secret is configured.
+    pub fn salt_schedule(&self) -> Option<RotatingSalt> {
+        let hours = self.salt.period_hours.unwrap_or(24);
+        self.salt
+            .secret
+            .map(|secret| RotatingSalt::new(secret, chrono::Duration::hours(hours)))
+    }
+
+    pub fn project(&self, id: i64) -> Option<&ProjectSettings> {
+        self.projects.get(&id)
+    }
+}
+
+/// Sets the value at `path`, creating maps along the way.
+///
+/// Keys match existing keys case-insensitively, numeric keys also match
+/// numbers, e.g. project ids.
+fn set(value: &mut Value, path: &[String], scalar: Value) -> Result<(), ()> {
+    let Some((key, rest)) = path.split_first() else {
+        *value = scalar;
+        return Ok(());
+    };
+    if value.is_null() {
+        *value = Value::Mapping(Mapping::new());
+    }
+    let Value::Mapping(map) = value else {
+        return Err(());
+    };
+    let existing = map
+        .keys()
+        .find(|existing| match existing {
+            Value::String(existing) => existing.eq_ignore_ascii_case(key),
+            Value::Number(existing) => existing.to_string() == *key,
+            _ => false,
+        })
+        .cloned();
+    let key = existing.unwrap_or_else(|| match key.parse::<i64>() {
+        Ok(number) => Value::Number(number.into()),
+        Err(_) => Value::String(key.clone()),
+    });
+    set(map.entry(key).or_insert(Value::Null), rest, scalar)
+}
+
+fn valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utm::{Repeated, UtmField};
+
+    const YAML: &str = r#"
+salt:
+  secret: 7
+pipeline:
+  batch_size: 100
+  overload: park
+projects:
+  1:
+    name: Example
+    domains: [example.com]
+    events:
+      names:
+        allow: [signup]
+    utm:
+      aliases: {pk_campaign: campaign, ref: source}
+      repeated: join
+    channels:
+      - {channel: Partners, referrer: partner\.com$}
+archive:
+  prefix: records
+  instance: collector-1
+"#;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_yaml() {
+        let config = CollectorConfig::parse(YAML, []).unwrap();
+        assert_eq!(config.pipeline.batch_size, 100);
+        assert_eq!(config.pipeline.capacity, PipelineConfig::default().capacity);
+        assert_eq!(config.pipeline_config().overload, Overload::Park);
+        assert_eq!(config.project(1).unwrap().domains, ["example.com"]);
+        assert_eq!(
+            config.project(1).unwrap().utm.alias("PK_CAMPAIGN"),
+            Some(UtmField::Campaign)
+        );
+        assert_eq!(config.project(1).unwrap().utm.repeated, Repeated::Join);
+        assert_eq!(config.project(1).unwrap().channels[0].channel, "Partners");
+        assert!(config.salt_schedule().is_some());
+        assert_eq!(config.archive.unwrap().instance, "collector-1");
+    }
+
+    #[test]
+    fn env_overrides_values() {
+        let vars = env(&[
+            ("ABINEO_PIPELINE__BATCH_SIZE", "5"),
+            ("ABINEO_PROJECTS__1__SALT", "42"),
+            ("ABINEO_PROJECTS__2__NAME", "Other"),
+            ("ABINEO_WEBHOOK__URL", "https://example.com/hook"),
+            ("HOME", "/root"),
+        ]);
+        let config = CollectorConfig::parse(YAML, vars).unwrap();
+        assert_eq!(config.pipeline.batch_size, 5);
+        assert_eq!(config.project(1).unwrap().salt, Some(42));
+        assert_eq!(config.project(1).unwrap().name.as_deref(), Some("Example"));
+        assert_eq!(config.project(2).unwrap().name.as_deref(), Some("Other"));
+        assert_eq!(config.webhook.unwrap().url.host_str(), Some("example.com"));
+    }
+
+    #[test]
+    fn empty_file_uses_defaults() {
+        let config = CollectorConfig::parse("", []).unwrap();
+        assert_eq!(config, CollectorConfig::default());
+        assert!(config.salt_schedule().is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let invalid = |yaml: &str| CollectorConfig::parse(yaml, []).unwrap_err();
+        assert!(matches!(
+            invalid("pipeline: {batch_size: 0}"),
+            Error::Config(_)
+        ));
+        assert!(matches!(
+            invalid("projects: {1: {domains: ['https://example.com']}}"),
+            Error::Config(_)
+        ));
+        assert!(matches!(invalid("unknown: 1"), Error::Json(_)));
+        assert!(matches!(
+            CollectorConfig::parse("salt: 1", env(&[("ABINEO_SALT__SECRET", "1")])).unwrap_err(),
+            Error::Config(_)
+        ));
+    }
+}
@@ -0,0 +1,274 @@
+//! Project settings that change while the collector is running.
+//!
+//! A [`ProjectConfigProvider`] hands out the current settings of a project
+//! and picks up changes on [`refresh`](ProjectConfigProvider::refresh). A
+//! [`Watcher`] refreshes a provider periodically on a background thread, so
+//! adding a domain or changing a salt takes effect without a restart.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{CollectorConfig, ProjectSettings};
+use crate::Error;
+
+pub trait ProjectConfigProvider: Debug + Send + Sync {
+    fn project(&self, id: i64) -> Option<Arc<ProjectSettings>>;
+
+    /// Picks up changes of the source, returns whether there were any.
+    ///
+    /// On error the previous settings stay in use.
+    fn refresh(&self) -> Result<bool, Error>;
+}
+
+type Projects = BTreeMap<i64, Arc<ProjectSettings>>;
+
+fn shared(projects: BTreeMap<i64, ProjectSettings>) -> Projects {
+    projects
+        .into_iter()
+        .map(|(id, settings)| (id, Arc::new(settings)))
+        .collect()
+}
+
+/// Settings that never change, e.g. from a [`CollectorConfig`] loaded once.
+#[derive(Debug, Default)]
+pub struct StaticProjects(Projects);
+
+impl StaticProjects {
+    pub fn new(projects: BTreeMap<i64, ProjectSettings>) -> Self {
+        StaticProjects(shared(projects))
+    }
+}
+
+impl ProjectConfigProvider for StaticProjects {
+    fn project(&self, id: i64) -> Option<Arc<ProjectSettings>> {
+        self.0.get(&id).cloned()
+    }
+
+    fn refresh(&self) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+/// Reloads the projects of a [`CollectorConfig`] file when its
+/// modification time changes.
+///
+/// Environment overrides are applied on every reload.
+#[derive(Debug)]
+pub struct FileProjects {
+    path: PathBuf,
+    modified: Mutex<Option<SystemTime>>,
+    projects: RwLock<Projects>,
+}
+
+impl FileProjects {
+    /// Loads the file, failing if it is missing or invalid.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let val = FileProjects {
+            path: path.into(),
+            modified: Mutex::default(),
+            projects: RwLock::default(),
+        };
+        val.refresh()?;
+        Ok(val)
+    }
+}
+
+impl ProjectConfigProvider for FileProjects {
+    fn project(&self, id: i64) -> Option<Arc<ProjectSettings>> {
+        self.projects.read().unwrap().get(&id).cloned()
+    }
+
+    fn refresh(&self) -> Result<bool, Error> {
+        let mut modified = self.modified.lock().unwrap();
+        let current = fs::metadata(&self.path)?.modified()?;
+        if *modified == Some(current) {
+            return Ok(false);
+        }
+        let config = CollectorConfig::load(&self.path)?;
+        *self.projects.write().unwrap() = shared(config.projects);
+        *modified = Some(current);
+        Ok(true)
+    }
+}
+
+/// A change sent to a [`ChannelProjects`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProjectUpdate {
+    Upsert(i64, Arc<ProjectSettings>),
+    Remove(i64),
+    /// Replaces all projects.
+    Replace(BTreeMap<i64, ProjectSettings>),
+}
+
+/// Applies the updates sent by the host, e.g. after polling an admin API
+/// or receiving a change notification.
+#[derive(Debug)]
+pub struct ChannelProjects {
+    updates: Mutex<Receiver<ProjectUpdate>>,
+    projects: RwLock<Projects>,
+}
+
+impl ChannelProjects {
+    /// Starts with `projects`, updates are sent through the returned sender.
+    pub fn new(projects: BTreeMap<i64, ProjectSettings>) -> (Self, Sender<ProjectUpdate>) {
+        let (tx, rx) = mpsc::channel();
+        let val = ChannelProjects {
+            updates: Mutex::new(rx),
+            projects: RwLock::new(shared(projects)),
+        };
+        (val, tx)
+    }
+}
+
+impl ProjectConfigProvider for ChannelProjects {
+    fn project(&self, id: i64) -> Option<Arc<ProjectSettings>> {
+        self.projects.read().unwrap().get(&id).cloned()
+    }
+
+    fn refresh(&self) -> Result<bool, Error> {
+        let updates: Vec<_> = self.updates.lock().unwrap().try_iter().collect();
+        if updates.is_empty() {
+            return Ok(false);
+        }
+        let mut projects = self.projects.write().unwrap();
+        for update in updates {
+            match update {
+                ProjectUpdate::Upsert(id, settings) => {
+                    projects.insert(id, settings);
+                }
+                ProjectUpdate::Remove(id) => {
+                    projects.remove(&id);
+                }
+                ProjectUpdate::Replace(all) => *projects = shared(all),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Refreshes a provider every `interval` until dropped.
+#[derive(Debug)]
+pub struct Watcher {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    refreshed: Arc<Mutex<Instant>>,
+}
+
+impl Watcher {
+    /// `on_error` is called with refresh errors, the watcher keeps going.
+    pub fn spawn(
+        provider: Arc<dyn ProjectConfigProvider>,
+        interval: Duration,
+        mut on_error: impl FnMut(Error) + Send + 'static,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let refreshed = Arc::new(Mutex::new(Instant::now()));
+        let thread = thread::spawn({
+            let refreshed = refreshed.clone();
+            move || loop {
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => match provider.refresh() {
+                        Ok(_) => *refreshed.lock().unwrap() = Instant::now(),
+                        Err(err) => on_error(err),
+                    },
+                    _ => return,
+                }
+            }
+        });
+        Watcher {
+            stop: Some(stop),
+            thread: Some(thread),
+            refreshed,
+        }
+    }
+
+    /// When the settings were last refreshed successfully, or the watcher
+    /// started, see [`Check::freshness`](crate::health::Check::freshness).
+    pub fn last_refresh(&self) -> Instant {
+        *self.refreshed.lock().unwrap()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(name: &str) -> ProjectSettings {
+        ProjectSettings {
+            name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn applies_channel_updates_on_refresh() {
+        let (provider, updates) = ChannelProjects::new(BTreeMap::from([(1, settings("a"))]));
+        updates
+            .send(ProjectUpdate::Upsert(2, Arc::new(settings("b"))))
+            .unwrap();
+        updates.send(ProjectUpdate::Remove(1)).unwrap();
+        assert!(provider.project(1).is_some());
+        assert!(provider.project(2).is_none());
+
+        assert!(provider.refresh().unwrap());
+        assert!(provider.project(1).is_none());
+        assert_eq!(provider.project(2).unwrap().name.as_deref(), Some("b"));
+        assert!(!provider.refresh().unwrap());
+    }
+
+    #[test]
+    fn reloads_changed_file() {
+        let path =
+            std::env::temp_dir().join(format!("abineo-projects-{}.yaml", std::process::id()));
+        fs::write(&path, "projects: {1: {domains: [example.com]}}").unwrap();
+        let provider = FileProjects::open(&path).unwrap();
+        assert_eq!(provider.project(1).unwrap().domains, ["example.com"]);
+        assert!(!provider.refresh().unwrap());
+
+        // an invalid file keeps the previous settings
+        fs::write(&path, "projects: {1: {domains: ['not a domain']}}").unwrap();
+        *provider.modified.lock().unwrap() = None;
+        assert!(provider.refresh().is_err());
+        assert_eq!(provider.project(1).unwrap().domains, ["example.com"]);
+
+        fs::write(&path, "projects: {1: {domains: [example.org]}}").unwrap();
+        *provider.modified.lock().unwrap() = None;
+        assert!(provider.refresh().unwrap());
+        assert_eq!(provider.project(1).unwrap().domains, ["example.org"]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn watcher_refreshes_periodically() {
+        let (provider, updates) = ChannelProjects::new(BTreeMap::new());
+        let provider = Arc::new(provider);
+        let watcher = Watcher::spawn(provider.clone(), Duration::from_millis(5), |_| {});
+        let started = watcher.last_refresh();
+        updates
+            .send(ProjectUpdate::Upsert(1, Arc::new(settings("a"))))
+            .unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let pending = || provider.project(1).is_none() || watcher.last_refresh() == started;
+        while pending() && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(watcher.last_refresh() > started);
+        drop(watcher);
+        assert!(provider.project(1).is_some());
+    }
+}
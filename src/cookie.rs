@@ -0,0 +1,186 @@
+//! Signed first-party visitor cookies.
+//!
+//! Visitor ids are fingerprints that change with the salt, the browser
+//! version or the screen. Projects that obtained consent can instead keep
+//! the id of the first visit in a first-party [`COOKIE_NAME`] cookie and
+//! pass it to [`VisitorBuilder::persistent_id`](crate::VisitorBuilder::persistent_id).
+//!
+//! The cookie is signed, so visitors can't claim the id of another visitor,
+//! and carries its own expiry, so stale cookies are rejected even if the
+//! browser keeps them.
+
+use std::hash::Hasher as _;
+
+use chrono::{DateTime, Duration, Utc};
+use siphasher::sip::SipHasher24;
+
+use crate::Error;
+
+/// Name of the visitor cookie.
+pub const COOKIE_NAME: &str = "_abineo_id";
+
+/// Mints and verifies visitor cookies of a project.
+///
+/// Values are `visitor.expires.signature` in hex, signed with SipHash-2-4
+/// under a secret key.
+#[derive(Debug, Clone)]
+pub struct VisitorCookie {
+    project: i64,
+    key: [u8; 16],
+    ttl: Duration,
+    domain: Option<String>,
+}
+
+impl VisitorCookie {
+    /// Cookies expire after 390 days, the longest lifetime browsers accept.
+    pub fn new(project_id: i64, key: [u8; 16]) -> Self {
+        VisitorCookie {
+            project: project_id,
+            key,
+            ttl: Duration::days(390),
+            domain: None,
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Shares the cookie with the subdomains of `domain`.
+    pub fn with_domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_lowercase());
+        self
+    }
+
+    pub fn mint(&self, visitor: i64, now: DateTime<Utc>) -> String {
+        let expires = (now + self.ttl).timestamp();
+        let signature = self.sign(visitor, expires);
+        format!("{:x}.{:x}.{signature:x}", visitor as u64, expires)
+    }
+
+    /// Verifies a cookie value, returning the visitor id if it is authentic
+    /// and not expired.
+    pub fn verify(&self, value: &str, now: DateTime<Utc>) -> Result<i64, Error> {
+        let parts: Vec<&str> = value.split('.').collect();
+        let [visitor, expires, signature] = parts[..] else {
+            return Err(Error::InvalidCookie("malformed"));
+        };
+        let hex =
+            |part| u64::from_str_radix(part, 16).map_err(|_| Error::InvalidCookie("malformed"));
+        let visitor = hex(visitor)? as i64;
+        let expires = hex(expires)? as i64;
+
+        if self.sign(visitor, expires) != hex(signature)? {
+            return Err(Error::InvalidCookie("bad signature"));
+        }
+        if now.timestamp() > expires {
+            return Err(Error::InvalidCookie("expired"));
+        }
+        Ok(visitor)
+    }
+
+    /// The visitor id of a `Cookie` request header, if it carries a valid
+    /// visitor cookie. Invalid cookies are ignored like a missing one.
+    pub fn from_header(&self, header: &str, now: DateTime<Utc>) -> Option<i64> {
+        header
+            .split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .filter(|(name, _)| *name == COOKIE_NAME)
+            .find_map(|(_, value)| self.verify(value, now).ok())
+    }
+
+    /// A `Set-Cookie` header value storing the visitor, minted anew so its
+    /// expiry is extended on every visit.
+    pub fn set_cookie(&self, visitor: i64, now: DateTime<Utc>) -> String {
+        let mut val = format!(
+            "{COOKIE_NAME}={}; Max-Age={}; Path=/; SameSite=Lax; Secure; HttpOnly",
+            self.mint(visitor, now),
+            self.ttl.num_seconds()
+        );
+        if let Some(domain) = &self.domain {
+            val.push_str("; Domain=");
+            val.push_str(domain);
+        }
+        val
+    }
+
+    /// A `Set-Cookie` header value removing the cookie, e.g. after consent
+    /// was withdrawn.
+    pub fn clear_cookie(&self) -> String {
+        let mut val = format!("{COOKIE_NAME}=; Max-Age=0; Path=/; SameSite=Lax; Secure; HttpOnly");
+        if let Some(domain) = &self.domain {
+            val.push_str("; Domain=");
+            val.push_str(domain);
+        }
+        val
+    }
+
+    fn sign(&self, visitor: i64, expires: i64) -> u64 {
+        let mut hasher = SipHasher24::new_with_key(&self.key);
+        hasher.write_i64(self.project);
+        hasher.write_i64(visitor);
+        hasher.write_i64(expires);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap()
+    }
+
+    fn cookie() -> VisitorCookie {
+        VisitorCookie::new(1, [7; 16])
+    }
+
+    #[test]
+    fn roundtrips() {
+        let value = cookie().mint(-42, now());
+        assert_eq!(cookie().verify(&value, now()).unwrap(), -42);
+    }
+
+    #[test]
+    fn rejects_forged_and_expired() {
+        let value = cookie().mint(42, now());
+        let forged = value.replacen("2a", "2b", 1);
+        assert!(matches!(
+            cookie().verify(&forged, now()),
+            Err(Error::InvalidCookie("bad signature"))
+        ));
+        assert!(matches!(
+            VisitorCookie::new(2, [7; 16]).verify(&value, now()),
+            Err(Error::InvalidCookie("bad signature"))
+        ));
+        assert!(matches!(
+            cookie().verify(&value, now() + Duration::days(391)),
+            Err(Error::InvalidCookie("expired"))
+        ));
+        assert!(matches!(
+            cookie().verify("x", now()),
+            Err(Error::InvalidCookie("malformed"))
+        ));
+    }
+
+    #[test]
+    fn reads_request_header() {
+        let value = cookie().mint(42, now());
+        let header = format!("theme=dark; {COOKIE_NAME}=bogus; {COOKIE_NAME}={value}");
+        assert_eq!(cookie().from_header(&header, now()), Some(42));
+        assert_eq!(cookie().from_header("theme=dark", now()), None);
+    }
+
+    #[test]
+    fn formats_set_cookie() {
+        let header = cookie().with_domain("Example.com").set_cookie(42, now());
+        assert!(header.starts_with(&format!("{COOKIE_NAME}=")));
+        assert!(header.contains("Max-Age=33696000"));
+        assert!(header.ends_with("; Domain=example.com"));
+        assert!(cookie().clear_cookie().contains("Max-Age=0"));
+    }
+}
@@ -0,0 +1,163 @@
+//! Columnar view of event data.
+
+use std::collections::btree_map::{self, BTreeMap};
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Separator between the keys of nested objects and array indices.
+pub const KEY_SEPARATOR: char = '.';
+
+/// A scalar json value.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Scalar {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl Scalar {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Scalar::Number(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Scalar::String(val) => Some(val),
+            _ => None,
+        }
+    }
+}
+
+/// Event data flattened to a map of paths to scalars.
+///
+/// `{"cart": {"items": [{"sku": "A1"}]}}` becomes `{"cart.items.0.sku": "A1"}`.
+/// Empty objects and arrays are omitted. Keys are sorted, so sinks get a
+/// stable column order.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct FlatEventData(BTreeMap<String, Scalar>);
+
+impl FlatEventData {
+    pub fn new(data: &Value) -> Self {
+        let mut val = FlatEventData::default();
+        val.flatten(String::new(), data);
+        val
+    }
+
+    fn flatten(&mut self, path: String, value: &Value) {
+        let join = |key: &str| {
+            if path.is_empty() {
+                key.to_string()
+            } else {
+                format!("{path}{KEY_SEPARATOR}{key}")
+            }
+        };
+        let scalar = match value {
+            Value::Null => Scalar::Null,
+            Value::Bool(val) => Scalar::Bool(*val),
+            Value::Number(val) => match val.as_f64() {
+                Some(val) => Scalar::Number(val),
+                None => return,
+            },
+            Value::String(val) => Scalar::String(val.clone()),
+            Value::Array(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    self.flatten(join(&i.to_string()), value);
+                }
+                return;
+            }
+            Value::Object(map) => {
+                for (key, value) in map {
+                    self.flatten(join(key), value);
+                }
+                return;
+            }
+        };
+        self.0.insert(path, scalar);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Scalar> {
+        self.0.get(key)
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, String, Scalar> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<&Value> for FlatEventData {
+    fn from(data: &Value) -> Self {
+        FlatEventData::new(data)
+    }
+}
+
+impl IntoIterator for FlatEventData {
+    type Item = (String, Scalar);
+    type IntoIter = btree_map::IntoIter<String, Scalar>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a FlatEventData {
+    type Item = (&'a String, &'a Scalar);
+    type IntoIter = btree_map::Iter<'a, String, Scalar>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn flattens_nested_data() {
+        let data = FlatEventData::new(&json!({
+            "plan": "pro",
+            "seats": 5,
+            "trial": false,
+            "coupon": null,
+            "cart": {"items": [{"sku": "A1"}, {"sku": "B2"}], "tags": []},
+        }));
+        let keys: Vec<&str> = data.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            keys,
+            [
+                "cart.items.0.sku",
+                "cart.items.1.sku",
+                "coupon",
+                "plan",
+                "seats",
+                "trial"
+            ]
+        );
+        assert_eq!(data.get("cart.items.1.sku").unwrap().as_str(), Some("B2"));
+        assert_eq!(data.get("seats").unwrap().as_f64(), Some(5.0));
+        assert_eq!(data.get("coupon"), Some(&Scalar::Null));
+    }
+
+    #[test]
+    fn scalar_root() {
+        let data = FlatEventData::new(&json!("clicked"));
+        assert_eq!(data.get(""), Some(&Scalar::String("clicked".to_string())));
+        assert!(FlatEventData::new(&json!({})).is_empty());
+    }
+}
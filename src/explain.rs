@@ -0,0 +1,290 @@
+//! Explanations of how beacons are enriched.
+//!
+//! An [`EnrichmentReport`] holds the record a beacon becomes together with
+//! every value derived on the way: where the region came from, how the user
+//! agent was parsed, the inputs of each id and the normalizations that were
+//! applied. Integrators get it from [`api::explain`] to
+//! answer "why did this visit get this id/country?".
+
+use serde::Serialize;
+use serde_json::Value;
+use url::Url;
+
+use crate::api::{self, Enrich};
+use crate::id::IdInputs;
+use crate::ua;
+use crate::utm::{self, UtmOptions, TRUNCATION_MARKER};
+use crate::{amp, region_for_offset, region_for_tz, Error, Page, Record, Visitor, MAX_PATH_LEN};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EnrichmentReport {
+    /// The record as the api functions return it.
+    pub record: Record,
+    pub region: RegionReport,
+    pub user_agent: UserAgentReport,
+    /// Ids of the visitor, page, campaign and referrer, in that order.
+    pub ids: Vec<IdReport>,
+    /// Changes to the payload values before they were stored or hashed.
+    pub normalizations: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegionSource {
+    /// Looked up from the IANA timezone.
+    Timezone,
+    /// Guessed from a UTC offset, see [`region_for_offset`].
+    UtcOffset,
+    /// Neither the timezone nor an offset is known.
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RegionReport {
+    pub timezone: String,
+    pub source: RegionSource,
+    pub region: Option<String>,
+    pub subdivision: Option<String>,
+    pub continent: Option<String>,
+    pub is_eu: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserAgentParser {
+    /// Classified by the [`prefilter`](ua::prefilter).
+    Prefilter,
+    /// Parsed with the uap regexes.
+    Uap,
+    /// Not parsed, e.g. in minimal builds.
+    None,
+    /// Rejected unparsed by [`is_bogus`](ua::is_bogus).
+    Bogus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserAgentReport {
+    pub user_agent: String,
+    pub parser: UserAgentParser,
+    pub browser: Option<String>,
+    pub platform: Option<String>,
+}
+
+/// An id with the inputs it was hashed from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IdReport {
+    pub kind: &'static str,
+    pub id: i64,
+    /// Inputs in hashing order, `None` for absent values.
+    pub inputs: Vec<(&'static str, Option<String>)>,
+}
+
+impl IdReport {
+    fn new<T: IdInputs>(record: &T) -> Self {
+        IdReport {
+            kind: T::KIND,
+            id: record.id(),
+            inputs: record.inputs(),
+        }
+    }
+}
+
+/// Reports on the records of a beacon body, enriched like by
+/// [`handle_batch`](crate::api::handle_batch) with the default settings.
+pub(crate) fn explain(
+    project_id: i64,
+    body: &[u8],
+    user_agent: &str,
+) -> Result<Vec<EnrichmentReport>, Error> {
+    api::split(body)?
+        .into_iter()
+        .map(|raw| {
+            let record = api::decode_record(raw.clone())?;
+            let record = api::process_record(project_id, record, user_agent, Enrich::default())?;
+            Ok(report(&raw, record, user_agent))
+        })
+        .collect()
+}
+
+fn report(raw: &Value, record: Record, user_agent: &str) -> EnrichmentReport {
+    let (visitor, page, utm_param, referrer) = match &record {
+        Record::Visit(visit) => (
+            &visit.visitor,
+            &visit.page,
+            visit.utm_param.as_ref(),
+            visit.referrer.as_ref(),
+        ),
+        Record::Event(event) => (&event.visitor, &event.page, event.utm_param.as_ref(), None),
+        Record::Session(summary) => (
+            &summary.visitor,
+            &summary.entry_page,
+            summary.utm_param.as_ref(),
+            summary.referrer.as_ref(),
+        ),
+    };
+
+    let mut ids = vec![IdReport::new(visitor), IdReport::new(page)];
+    ids.extend(utm_param.map(IdReport::new));
+    ids.extend(referrer.map(IdReport::new));
+
+    let mut normalizations = Vec::new();
+    if let Some(offset) = raw["visitor"]["tz"].as_i64() {
+        normalizations.push(format!(
+            "timezone offset {offset} read as {}",
+            visitor.timezone
+        ));
+    }
+    if visitor.invalid_screen {
+        normalizations.push(format!(
+            "invalid screen {} hashed as {}x{}",
+            raw["visitor"]["screen"], visitor.width, visitor.height
+        ));
+    }
+    // embeds are attributed to the embedding page
+    let page_key = if raw["type"] == "embed" {
+        "parent"
+    } else {
+        "page"
+    };
+    if let Some(url) = raw[page_key]["url"].as_str() {
+        normalize_page(url, page, &mut normalizations);
+    }
+    for (field, value) in utm_param.iter().flat_map(|utm| {
+        [
+            ("campaign", &utm.campaign),
+            ("content", &utm.content),
+            ("medium", &utm.medium),
+            ("source", &utm.source),
+            ("term", &utm.term),
+        ]
+    }) {
+        if value
+            .as_deref()
+            .is_some_and(|value| value.ends_with(TRUNCATION_MARKER))
+        {
+            normalizations.push(format!("campaign {field} truncated"));
+        }
+    }
+    if let (Record::Event(event), Some(name)) = (&record, raw["name"].as_str()) {
+        if event.name != name {
+            normalizations.push(format!(
+                "event name {name:?} normalized to {:?}",
+                event.name
+            ));
+        }
+    }
+
+    EnrichmentReport {
+        region: region(visitor),
+        user_agent: parsed_user_agent(visitor, user_agent),
+        ids,
+        normalizations,
+        record,
+    }
+}
+
+fn normalize_page(url: &str, page: &Page, normalizations: &mut Vec<String>) {
+    let Ok(url) = Url::parse(url) else {
+        return;
+    };
+    for field in utm::ambiguous(&url, &UtmOptions::default()) {
+        normalizations.push(format!(
+            "campaign {} repeated with different values, the last one is kept",
+            field.key()
+        ));
+    }
+    if let Some(origin) = amp::origin(&url) {
+        normalizations.push(format!("amp url resolved to {origin}"));
+    }
+    if page.path.ends_with(TRUNCATION_MARKER) && !url.path().ends_with(TRUNCATION_MARKER) {
+        normalizations.push(format!("page path truncated to {MAX_PATH_LEN} characters"));
+    }
+    if let Some(actual) = &page.actual_url {
+        normalizations.push(format!(
+            "page identified by canonical url instead of {actual}"
+        ));
+    }
+}
+
+fn region(visitor: &Visitor) -> RegionReport {
+    let source = if region_for_tz(&visitor.timezone).is_some() {
+        RegionSource::Timezone
+    } else if region_for_offset(&visitor.timezone).is_some() {
+        RegionSource::UtcOffset
+    } else {
+        RegionSource::Unknown
+    };
+    RegionReport {
+        timezone: visitor.timezone.clone(),
+        source,
+        region: visitor.region.as_deref().map(ToString::to_string),
+        subdivision: visitor.subdivision.as_deref().map(ToString::to_string),
+        continent: visitor.continent.as_deref().map(ToString::to_string),
+        is_eu: visitor.is_eu,
+    }
+}
+
+fn parsed_user_agent(visitor: &Visitor, user_agent: &str) -> UserAgentReport {
+    let parser = if cfg!(feature = "minimal") {
+        UserAgentParser::None
+    } else if visitor.bogus_user_agent {
+        UserAgentParser::Bogus
+    } else if ua::prefilter(user_agent).is_some() {
+        UserAgentParser::Prefilter
+    } else if ua::parser().is_some() {
+        UserAgentParser::Uap
+    } else {
+        UserAgentParser::None
+    };
+    UserAgentReport {
+        user_agent: user_agent.to_string(),
+        parser,
+        browser: visitor.browser.as_deref().map(ToString::to_string),
+        platform: visitor.platform.as_deref().map(ToString::to_string),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn explains_visit() {
+        let user_agent = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36";
+        let body = br#"{"type": "visit", "session": "7", "visitor": {"tz": "Europe/Zurich", "lang": "de-CH", "screen": [1920, 1080]}, "page": {"url": "https://example.com/blog?source=newsletter", "ref": "https://duckduckgo.com/"}}"#;
+        let [report] = <[_; 1]>::try_from(explain(1, body, user_agent).unwrap()).unwrap();
+        let Record::Visit(visit) = &report.record else {
+            panic!("not a visit");
+        };
+        assert_eq!(report.region.source, RegionSource::Timezone);
+        assert_eq!(report.region.region.as_deref(), Some("CH"));
+        assert_eq!(report.user_agent.parser, UserAgentParser::Prefilter);
+        assert_eq!(report.user_agent.browser.as_deref(), Some("Chrome"));
+
+        let kinds: Vec<_> = report.ids.iter().map(|id| id.kind).collect();
+        assert_eq!(kinds, ["visitor", "page", "utm_param", "referrer"]);
+        assert_eq!(report.ids[0].id, visit.visitor.id);
+        assert!(report.ids[0]
+            .inputs
+            .contains(&("timezone", Some("Europe/Zurich".to_string()))));
+        assert!(report.normalizations.is_empty());
+    }
+
+    #[test]
+    fn reports_normalizations() {
+        let body = br#"[{"type": "visit", "session": "7", "visitor": {"tz": -120, "screen": [-1, 0]}, "page": {"url": "https://example.com/a", "ref": null, "canonical": "https://example.com/"}}, {"type": "event", "session": "7", "visitor": {}, "page": {"url": "https://example.com/", "ref": null}, "name": "  Sign Up ", "data": null}]"#;
+        let reports = explain(1, body, "curl/8.1.2").unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(
+            reports[0].normalizations,
+            [
+                "timezone offset -120 read as +02:00",
+                "invalid screen [-1,0] hashed as 0x0",
+                "page identified by canonical url instead of https://example.com/a",
+            ]
+        );
+        assert_eq!(reports[1].normalizations.len(), 1, "{:?}", reports[1]);
+        assert_eq!(reports[1].region.source, RegionSource::Unknown);
+    }
+}
@@ -0,0 +1,188 @@
+//! C ABI of the api functions.
+//!
+//! Backends not written in Rust embed the collector through these
+//! functions, so their records get the same enrichment and ids. The shared
+//! library is only built on demand, with
+//!
+//! ```sh
+//! cargo rustc --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! as `target/release/libabineo_analytics_collector.so` (`.dylib` on macOS,
+//! `.dll` on Windows). The rlib exports the same symbols, which is what
+//! `tests/ffi.rs` links against.
+//!
+//! The functions take the beacon body and the
+//! [settings](crate::config::ProjectSettings) of the project as JSON, the
+//! settings may be null for projects without any. They return a JSON
+//! object, either `{"ok": record}` or `{"error": message}`, e.g.
+//! `{"error": "project paused"}`. Returned strings are owned by the caller
+//! and released with [`abineo_free_string`].
+
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::api::{process_event, process_visit, Enrich, PubEvent, PubVisit};
+use crate::config::ProjectSettings;
+use crate::Error;
+
+/// Enriches a visit beacon like [`handle_visit`](crate::api::handle_visit).
+///
+/// # Safety
+///
+/// `body`, `user_agent` and `settings` must be null or point to
+/// nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn abineo_handle_visit(
+    project_id: i64,
+    body: *const c_char,
+    user_agent: *const c_char,
+    settings: *const c_char,
+) -> *mut c_char {
+    call(body, user_agent, settings, |body, user_agent, enrich| {
+        let body: PubVisit = serde_json::from_str(body)?;
+        process_visit(project_id, body, user_agent, enrich)
+    })
+}
+
+/// Enriches an event beacon like [`handle_event`](crate::api::handle_event).
+///
+/// # Safety
+///
+/// `body`, `user_agent` and `settings` must be null or point to
+/// nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn abineo_handle_event(
+    project_id: i64,
+    body: *const c_char,
+    user_agent: *const c_char,
+    settings: *const c_char,
+) -> *mut c_char {
+    call(body, user_agent, settings, |body, user_agent, enrich| {
+        let body: PubEvent = serde_json::from_str(body)?;
+        process_event(project_id, body, user_agent, enrich)
+    })
+}
+
+/// Releases a string returned by this module, null is ignored.
+///
+/// # Safety
+///
+/// `s` must be null or returned by a function of this module, and not be
+/// released before.
+#[no_mangle]
+pub unsafe extern "C" fn abineo_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Reads the arguments, rejects beacons of paused projects like
+/// [`handle_visit`](crate::api::handle_visit) and serializes the outcome of
+/// `f`, catching panics since they must not unwind into the caller.
+unsafe fn call<T: Serialize>(
+    body: *const c_char,
+    user_agent: *const c_char,
+    settings: *const c_char,
+    f: impl FnOnce(&str, &str, Enrich<'_>) -> Result<T, Error>,
+) -> *mut c_char {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let settings: Option<ProjectSettings> = if settings.is_null() {
+            None
+        } else {
+            Some(serde_json::from_str(read(settings, "settings")?)?)
+        };
+        let enrich = Enrich::collecting(settings.as_ref())?;
+        let record = f(read(body, "body")?, read(user_agent, "user agent")?, enrich)?;
+        Ok::<_, Error>(serde_json::to_value(record)?)
+    }));
+    let json = match result {
+        Ok(Ok(record)) => json!({ "ok": record }),
+        Ok(Err(err)) => json!({ "error": err.to_string() }),
+        Err(_) => json!({ "error": "processing panicked" }),
+    };
+    // JSON escapes control characters, there is no nul to reject
+    CString::new(json.to_string()).unwrap().into_raw()
+}
+
+unsafe fn read<'a>(s: *const c_char, name: &'static str) -> Result<&'a str, Error> {
+    if s.is_null() {
+        return Err(Error::Missing(name.to_string()));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| Error::InvalidUtf8(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    const USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36";
+
+    fn handle(
+        f: unsafe extern "C" fn(i64, *const c_char, *const c_char, *const c_char) -> *mut c_char,
+        body: &str,
+    ) -> Value {
+        let body = CString::new(body).unwrap();
+        let user_agent = CString::new(USER_AGENT).unwrap();
+        unsafe {
+            let json = f(1, body.as_ptr(), user_agent.as_ptr(), ptr::null());
+            let value = serde_json::from_str(CStr::from_ptr(json).to_str().unwrap()).unwrap();
+            abineo_free_string(json);
+            value
+        }
+    }
+
+    #[test]
+    fn returns_same_records_as_api() {
+        let body = r#"{"session": "7", "visitor": {"tz": "Europe/Zurich", "lang": "de-CH", "screen": [1920, 1080]}, "page": {"url": "https://example.com/blog", "ref": null}}"#;
+        let visit = handle(abineo_handle_visit, body);
+        let expected = process_visit(
+            1,
+            serde_json::from_str(body).unwrap(),
+            USER_AGENT,
+            Enrich::default(),
+        )
+        .unwrap();
+        assert_eq!(visit["ok"]["visitor"]["id"], json!(expected.visitor.id));
+        assert_eq!(visit["ok"]["page"]["id"], json!(expected.page.id));
+
+        let body = r#"{"session": "7", "visitor": {"tz": "Europe/Zurich", "lang": "de-CH", "screen": [1920, 1080]}, "page": {"url": "https://example.com/blog", "ref": null}, "name": "signup", "data": {}}"#;
+        let event = handle(abineo_handle_event, body);
+        assert_eq!(event["ok"]["name"], "signup");
+        assert_eq!(event["ok"]["visitor"]["id"], json!(expected.visitor.id));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = handle(abineo_handle_visit, "{}");
+        assert!(error["error"].is_string(), "{error}");
+
+        let json = unsafe { abineo_handle_event(1, ptr::null(), ptr::null(), ptr::null()) };
+        let error = unsafe { CStr::from_ptr(json) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        unsafe { abineo_free_string(json) };
+        assert_eq!(error, r#"{"error":"missing body"}"#);
+
+        let body = CString::new(*b"{\xFF}").unwrap();
+        let user_agent = CString::new(USER_AGENT).unwrap();
+        let json =
+            unsafe { abineo_handle_visit(1, body.as_ptr(), user_agent.as_ptr(), ptr::null()) };
+        let error = unsafe { CStr::from_ptr(json) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        unsafe { abineo_free_string(json) };
+        assert_eq!(error, r#"{"error":"body is not valid UTF-8"}"#);
+    }
+}
@@ -0,0 +1,194 @@
+//! Per-project filtering of event names.
+//!
+//! Event names are chosen by the tracked site, so a careless integration
+//! (e.g. a name containing a product id) can create an unbounded number of
+//! distinct events. An [`EventFilter`] limits events to a known set of names.
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+use crate::{Error, Event, Record};
+
+/// Name unknown events are renamed to with [`UnknownEvent::Rename`].
+pub const OTHER_EVENT_NAME: &str = "other";
+
+/// Which event names a project accepts.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventNames {
+    /// Accepts every name.
+    #[default]
+    Any,
+    /// Accepts only the listed names.
+    Allow(HashSet<String>),
+    /// Accepts all but the listed names.
+    Deny(HashSet<String>),
+}
+
+impl EventNames {
+    pub fn contains(&self, name: &str) -> bool {
+        match self {
+            EventNames::Any => true,
+            EventNames::Allow(names) => names.contains(name),
+            EventNames::Deny(names) => !names.contains(name),
+        }
+    }
+}
+
+/// What happens to events whose name is not accepted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownEvent {
+    /// The event is discarded.
+    #[default]
+    Drop,
+    /// The event is kept with the name [`OTHER_EVENT_NAME`].
+    Rename,
+    /// The event is kept unchanged but reported as [`Verdict::Flagged`].
+    Flag,
+}
+
+/// Result of [`EventFilter::apply`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accepted,
+    Dropped,
+    Renamed,
+    Flagged,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+pub struct EventFilter {
+    #[serde(default)]
+    pub names: EventNames,
+    #[serde(default)]
+    pub unknown: UnknownEvent,
+}
+
+impl EventFilter {
+    /// Accepts only `names`, which are normalized like incoming names.
+    pub fn allow<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Self, Error> {
+        Ok(EventFilter {
+            names: EventNames::Allow(normalize_all(names)?),
+            unknown: UnknownEvent::default(),
+        })
+    }
+
+    /// Accepts all but `names`, which are normalized like incoming names.
+    pub fn deny<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Self, Error> {
+        Ok(EventFilter {
+            names: EventNames::Deny(normalize_all(names)?),
+            unknown: UnknownEvent::default(),
+        })
+    }
+
+    pub fn on_unknown(mut self, unknown: UnknownEvent) -> Self {
+        self.unknown = unknown;
+        self
+    }
+
+    /// Checks the name of a processed event, renaming it if configured.
+    ///
+    /// The caller is responsible for discarding events that were
+    /// [`Verdict::Dropped`], see [`EventFilter::filter`].
+    pub fn apply(&self, event: &mut Event) -> Verdict {
+        if self.names.contains(&event.name) {
+            return Verdict::Accepted;
+        }
+        match self.unknown {
+            UnknownEvent::Drop => Verdict::Dropped,
+            UnknownEvent::Rename => {
+                event.name = OTHER_EVENT_NAME.to_string();
+                Verdict::Renamed
+            }
+            UnknownEvent::Flag => Verdict::Flagged,
+        }
+    }
+
+    /// Applies the filter to the events of a batch, visits are kept.
+    pub fn filter(&self, records: Vec<Record>) -> Vec<Record> {
+        records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Event(mut event) => match self.apply(&mut event) {
+                    Verdict::Dropped => None,
+                    _ => Some(Record::Event(event)),
+                },
+                record => Some(record),
+            })
+            .collect()
+    }
+}
+
+fn normalize_all<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<HashSet<String>, Error> {
+    names
+        .into_iter()
+        .map(|name| Event::normalize_name(name).map(|name| name.into_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Visit;
+
+    use super::*;
+
+    fn event(name: &str) -> Event {
+        Event {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn allow_list() {
+        let filter = EventFilter::allow(["Sign Up", "purchase"]).unwrap();
+        assert_eq!(filter.apply(&mut event("sign_up")), Verdict::Accepted);
+        assert_eq!(filter.apply(&mut event("product_4711")), Verdict::Dropped);
+
+        let filter = filter.on_unknown(UnknownEvent::Rename);
+        let mut unknown = event("product_4711");
+        assert_eq!(filter.apply(&mut unknown), Verdict::Renamed);
+        assert_eq!(unknown.name, OTHER_EVENT_NAME);
+
+        let filter = filter.on_unknown(UnknownEvent::Flag);
+        let mut unknown = event("product_4711");
+        assert_eq!(filter.apply(&mut unknown), Verdict::Flagged);
+        assert_eq!(unknown.name, "product_4711");
+    }
+
+    #[test]
+    fn deny_list() {
+        let filter = EventFilter::deny(["debug"]).unwrap();
+        assert_eq!(filter.apply(&mut event("purchase")), Verdict::Accepted);
+        assert_eq!(filter.apply(&mut event("debug")), Verdict::Dropped);
+        assert!(EventFilter::deny(["_internal"]).is_err());
+    }
+
+    #[test]
+    fn filters_batches() {
+        let filter = EventFilter::allow(["purchase"]).unwrap();
+        let records = filter.filter(vec![
+            Visit::default().into(),
+            event("purchase").into(),
+            event("debug").into(),
+        ]);
+        assert_eq!(records.len(), 2);
+        assert!(matches!(&records[1], Record::Event(e) if e.name == "purchase"));
+    }
+
+    #[test]
+    fn deserializes_project_config() {
+        let filter: EventFilter = serde_json::from_str(
+            r#"{"names": {"allow": ["signup", "purchase"]}, "unknown": "rename"}"#,
+        )
+        .unwrap();
+        assert_eq!(filter.unknown, UnknownEvent::Rename);
+        assert!(filter.names.contains("signup"));
+        assert_eq!(
+            serde_json::from_str::<EventFilter>("{}").unwrap(),
+            EventFilter::default()
+        );
+    }
+}
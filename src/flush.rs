@@ -0,0 +1,144 @@
+//! Draining of buffered data on shutdown.
+//!
+//! Sinks, buffers and aggregators hold data in memory. The hosting server
+//! collects them as [`Flushable`]s and closes them on `SIGTERM`, so no
+//! analytics data is lost on deploys.
+
+use std::sync::Arc;
+
+use crate::Error;
+
+pub trait Flushable {
+    /// Delivers buffered data, the value stays usable.
+    fn flush(&mut self) -> Result<(), Error>;
+
+    /// Delivers all buffered data and releases resources. Called once on
+    /// shutdown, the value must not be used afterwards.
+    fn close(&mut self) -> Result<(), Error> {
+        self.flush()
+    }
+}
+
+/// Aggregates whose contents can be taken out, see [`FlushInto`].
+pub trait Drain {
+    type Item;
+
+    /// Removes and returns everything aggregated so far.
+    fn drain(&self) -> Vec<Self::Item>;
+}
+
+impl<T: Drain + ?Sized> Drain for Arc<T> {
+    type Item = T::Item;
+
+    fn drain(&self) -> Vec<Self::Item> {
+        (**self).drain()
+    }
+}
+
+/// Makes an aggregate [`Flushable`] by delivering its contents to a
+/// callback, e.g. a database insert.
+///
+/// Drained items are lost if the callback fails.
+#[derive(Debug)]
+pub struct FlushInto<D, F> {
+    source: D,
+    deliver: F,
+}
+
+impl<D, F> FlushInto<D, F>
+where
+    D: Drain,
+    F: FnMut(Vec<D::Item>) -> Result<(), Error>,
+{
+    pub fn new(source: D, deliver: F) -> Self {
+        FlushInto { source, deliver }
+    }
+}
+
+impl<D, F> Flushable for FlushInto<D, F>
+where
+    D: Drain,
+    F: FnMut(Vec<D::Item>) -> Result<(), Error>,
+{
+    fn flush(&mut self) -> Result<(), Error> {
+        let items = self.source.drain();
+        if items.is_empty() {
+            return Ok(());
+        }
+        (self.deliver)(items)
+    }
+}
+
+/// Flushes every value, even if some of them fail, and returns the first
+/// error.
+pub fn close_all<'a>(
+    values: impl IntoIterator<Item = &'a mut (dyn Flushable + 'a)>,
+) -> Result<(), Error> {
+    let mut result = Ok(());
+    for value in values {
+        let closed = value.close();
+        if result.is_ok() {
+            result = closed;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::aggregate::paths::{PathEdge, PathGraph};
+    use crate::session::SessionTracker;
+    use crate::{Page, Record, Visit};
+
+    use super::*;
+
+    #[test]
+    fn flushes_aggregates_into_callback() {
+        let graph = Arc::new(PathGraph::new());
+        graph.record(&Visit {
+            page: Page {
+                id: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let delivered = Mutex::new(Vec::<PathEdge>::new());
+        let mut flushable = FlushInto::new(graph.clone(), |edges| {
+            delivered.lock().unwrap().extend(edges);
+            Ok(())
+        });
+        flushable.flush().unwrap();
+        flushable.flush().unwrap();
+        assert!(graph.is_empty());
+        drop(flushable);
+        assert_eq!(delivered.into_inner().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn close_all_continues_after_errors() {
+        let mut empty = FlushInto::new(Arc::new(PathGraph::new()), |_| Ok(()));
+        let graph = Arc::new(PathGraph::new());
+        graph.record(&Visit::default());
+        let mut broken = FlushInto::new(graph, |_| Err(Error::Closed));
+        let sessions = Arc::new(SessionTracker::new());
+        sessions.observe(&Visit::default().into());
+        let summaries = Mutex::new(Vec::<Record>::new());
+        let mut summarized = FlushInto::new(sessions.clone(), |records| {
+            summaries.lock().unwrap().extend(records);
+            Ok(())
+        });
+
+        let result = close_all([
+            &mut broken as &mut dyn Flushable,
+            &mut empty,
+            &mut summarized,
+        ]);
+        assert!(matches!(result, Err(Error::Closed)));
+        assert!(sessions.is_empty());
+        drop(summarized);
+        assert_eq!(summaries.into_inner().unwrap().len(), 1);
+    }
+}
@@ -1,14 +1,65 @@
+use std::fmt::Debug;
 use std::ops::BitXor;
 
 /// `u64::MAX / PI`
 const C: u64 = 587178100656400245;
 
+/// Number of inputs [`Hasher::hash_many`] hashes side by side.
+const LANES: usize = 4;
+
+/// Backend used to compute ids.
+///
+/// [`Hasher`] is the default. Deployments that want keyed hashing can
+/// enable the `siphash` feature and use [`SipIdHasher`].
+pub trait IdHasher: Debug + Send {
+    fn write(&mut self, chunk: u64);
+
+    fn write_bytes(&mut self, bytes: &[u8]);
+
+    /// Writes an optional byte string, marking absent values.
+    fn write_optional_bytes(&mut self, bytes: Option<&[u8]>) {
+        match bytes {
+            Some(bytes) => {
+                self.write(1);
+                self.write_bytes(bytes);
+            }
+            None => self.write(0),
+        }
+    }
+
+    fn finish(&self) -> u64;
+}
+
+/// Byte order used to read chunks in [`Hasher::write_bytes`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// Produces the same ids on every architecture.
+    #[default]
+    Little,
+    /// Compatibility mode for ids created before the byte order was fixed,
+    /// when the host byte order was used.
+    ///
+    /// Identical to [`ByteOrder::Little`] on little-endian hosts, so only
+    /// deployments on big-endian hosts need it to migrate their ids.
+    Native,
+}
+
 /// Heavily inspired by [`FxHasher`].
 ///
 /// [`FxHasher`]: https://github.com/rust-lang/rustc-hash
+///
+/// The default hasher reproduces the original [V1](crate::id::IdVersion::V1)
+/// ids. It has two known weaknesses, which [`Hasher::hardened`] fixes and
+/// which is why ids from [V2](crate::id::IdVersion::V2) on use it:
+///
+/// - byte strings are zero-padded to full chunks, so `b"ab"` and
+///   `b"ab\0\0\0\0\0\0"` hash equally,
+/// - changing a single input bit flips only about 19 of the 64 output bits.
 #[derive(Debug, Default)]
 pub struct Hasher {
     state: u64,
+    byte_order: ByteOrder,
+    hardened: bool,
 }
 
 impl Hasher {
@@ -16,22 +67,87 @@ impl Hasher {
         Self::default()
     }
 
+    pub fn with_byte_order(byte_order: ByteOrder) -> Self {
+        Hasher {
+            byte_order,
+            ..Default::default()
+        }
+    }
+
+    /// Length-prefixes byte strings and applies a final avalanche step
+    /// (`fmix64` of MurmurHash3), so every input bit affects every output
+    /// bit.
+    pub fn hardened() -> Self {
+        Hasher {
+            // a zero state is a fixed point for zero chunks
+            state: C,
+            hardened: true,
+            ..Default::default()
+        }
+    }
+
     pub fn write(&mut self, chunk: u64) {
         self.state = self.state.rotate_left(5).bitxor(chunk).wrapping_mul(C);
     }
 
     pub fn write_bytes(&mut self, mut bytes: &[u8]) {
+        if self.hardened {
+            self.write(bytes.len() as u64);
+        }
         while bytes.len() > 8 {
-            self.write(u64::from_ne_bytes(bytes[..8].try_into().unwrap()));
-            bytes = &bytes[8..];
+            let (chunk, rest) = bytes.split_at(8);
+            self.write_chunk(chunk.try_into().unwrap());
+            bytes = rest;
         }
-        let mut bytes = bytes.to_vec();
-        bytes.resize(8, 0);
-        self.write(u64::from_ne_bytes(bytes[..8].try_into().unwrap()));
+        // zero-pad the last chunk on the stack
+        let mut chunk = [0; 8];
+        chunk[..bytes.len()].copy_from_slice(bytes);
+        self.write_chunk(chunk);
+    }
+
+    /// Writes an optional byte string.
+    ///
+    /// Absent values are skipped by the default hasher. The hardened hasher
+    /// marks them, so a value can't shift between adjacent optional fields.
+    pub fn write_optional_bytes(&mut self, bytes: Option<&[u8]>) {
+        match bytes {
+            Some(bytes) => {
+                if self.hardened {
+                    self.write(1);
+                }
+                self.write_bytes(bytes);
+            }
+            None => {
+                if self.hardened {
+                    self.write(0);
+                }
+            }
+        }
+    }
+
+    fn write_chunk(&mut self, chunk: [u8; 8]) {
+        let chunk = match self.byte_order {
+            ByteOrder::Little => u64::from_le_bytes(chunk),
+            ByteOrder::Native => u64::from_ne_bytes(chunk),
+        };
+        self.write(chunk);
     }
 
     pub fn finalize(self) -> u64 {
-        self.state
+        self.mix()
+    }
+
+    fn mix(&self) -> u64 {
+        if !self.hardened {
+            return self.state;
+        }
+        let mut state = self.state;
+        state ^= state >> 33;
+        state = state.wrapping_mul(0xff51afd7ed558ccd);
+        state ^= state >> 33;
+        state = state.wrapping_mul(0xc4ceb9fe1a85ec53);
+        state ^= state >> 33;
+        state
     }
 
     pub fn hash_bytes(bytes: &[u8]) -> u64 {
@@ -39,11 +155,108 @@ impl Hasher {
         hasher.write_bytes(bytes);
         hasher.finalize()
     }
+
+    /// Hashes every input independently, same as calling
+    /// [`hash_bytes`](Hasher::hash_bytes) for each of them.
+    ///
+    /// Inputs are hashed four at a time with their chunks interleaved,
+    /// so the independent multiply chains of the lanes can overlap.
+    pub fn hash_many(inputs: &[&[u8]]) -> Vec<u64> {
+        let mut hashes = Vec::with_capacity(inputs.len());
+        for group in inputs.chunks(LANES) {
+            let mut lanes: [Hasher; LANES] = std::array::from_fn(|_| Hasher::new());
+            // `write_bytes` writes at least the zero-padded last chunk
+            let rounds = group
+                .iter()
+                .map(|bytes| bytes.len().div_ceil(8).max(1))
+                .max()
+                .unwrap_or(0);
+            for round in 0..rounds {
+                for (lane, bytes) in lanes.iter_mut().zip(group) {
+                    let start = round * 8;
+                    if start > 0 && start >= bytes.len() {
+                        continue;
+                    }
+                    let rest = &bytes[start..];
+                    let mut chunk = [0; 8];
+                    let len = rest.len().min(8);
+                    chunk[..len].copy_from_slice(&rest[..len]);
+                    lane.write_chunk(chunk);
+                }
+            }
+            hashes.extend(lanes[..group.len()].iter().map(Hasher::mix));
+        }
+        hashes
+    }
+}
+
+impl IdHasher for Hasher {
+    fn write(&mut self, chunk: u64) {
+        Hasher::write(self, chunk);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        Hasher::write_bytes(self, bytes);
+    }
+
+    fn write_optional_bytes(&mut self, bytes: Option<&[u8]>) {
+        Hasher::write_optional_bytes(self, bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.mix()
+    }
+}
+
+/// Keyed SipHash-1-3 backend.
+///
+/// Ids can't be recomputed from their inputs without the keys.
+#[cfg(feature = "siphash")]
+#[derive(Debug, Clone)]
+pub struct SipIdHasher(siphasher::sip::SipHasher13);
+
+#[cfg(feature = "siphash")]
+impl SipIdHasher {
+    pub fn new_with_keys(key0: u64, key1: u64) -> Self {
+        SipIdHasher(siphasher::sip::SipHasher13::new_with_keys(key0, key1))
+    }
+}
+
+#[cfg(feature = "siphash")]
+impl IdHasher for SipIdHasher {
+    fn write(&mut self, chunk: u64) {
+        std::hash::Hasher::write_u64(&mut self.0, chunk);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        std::hash::Hasher::write_u64(&mut self.0, bytes.len() as u64);
+        std::hash::Hasher::write(&mut self.0, bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        std::hash::Hasher::finish(&self.0)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use quickcheck::{QuickCheck, TestResult, Testable};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
     use super::*;
+    use crate::id::IdVersion;
+
+    fn hardened(bytes: &[u8]) -> u64 {
+        let mut hasher = Hasher::hardened();
+        hasher.write_bytes(bytes);
+        hasher.finalize()
+    }
+
+    fn random_bytes(rng: &mut StdRng, max_len: usize) -> Vec<u8> {
+        let len = rng.gen_range(0..=max_len);
+        (0..len).map(|_| rng.gen()).collect()
+    }
 
     #[test]
     fn same_input_produces_same_hash() {
@@ -79,4 +292,272 @@ mod tests {
 
         assert_ne!(a, b);
     }
+
+    #[test]
+    fn hashes_are_stable() {
+        assert_eq!(Hasher::hash_bytes(b"ab"), 12238461990089325909);
+        assert_eq!(Hasher::hash_bytes(b"12345678"), 15217087420662720357);
+        assert_eq!(Hasher::hash_bytes(b"123456789"), 2776412862330031327);
+        assert_eq!(Hasher::hash_bytes(b"Europe/Zurich"), 11291569193396231158);
+    }
+
+    #[test]
+    fn hash_many_matches_hash_bytes() {
+        let inputs: [&[u8]; 6] = [
+            b"",
+            b"ab",
+            b"12345678",
+            b"Europe/Zurich",
+            b"123456789",
+            b"a somewhat longer input spanning chunks",
+        ];
+        let hashes = Hasher::hash_many(&inputs);
+        assert_eq!(hashes.len(), inputs.len());
+        for (bytes, hash) in inputs.iter().zip(hashes) {
+            assert_eq!(Hasher::hash_bytes(bytes), hash);
+        }
+    }
+
+    #[test]
+    fn native_byte_order_matches_host() {
+        let mut native = Hasher::with_byte_order(ByteOrder::Native);
+        native.write_bytes(b"Europe/Zurich");
+        let native = native.finalize();
+        let little = Hasher::hash_bytes(b"Europe/Zurich");
+        if cfg!(target_endian = "little") {
+            assert_eq!(native, little);
+        } else {
+            assert_ne!(native, little);
+        }
+    }
+
+    #[test]
+    fn ids_distinguish_trailing_zeros() {
+        let id = |bytes: &[u8]| {
+            let mut hasher = IdVersion::CURRENT.hasher();
+            hasher.write_bytes(bytes);
+            hasher.finalize()
+        };
+        assert_ne!(id(b"ab"), id(b"ab\0\0\0\0\0\0"));
+        // the weakness of the default hasher is kept for V1 ids
+        assert_eq!(
+            Hasher::hash_bytes(b"ab"),
+            Hasher::hash_bytes(b"ab\0\0\0\0\0\0")
+        );
+    }
+
+    #[test]
+    fn hardened_distinguishes_trailing_zeros() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..1000 {
+            let bytes = random_bytes(&mut rng, 32);
+            let mut padded = bytes.clone();
+            padded.resize(bytes.len() + rng.gen_range(1..=8), 0);
+            assert_ne!(hardened(&bytes), hardened(&padded));
+        }
+    }
+
+    #[test]
+    fn hardened_distinguishes_empty_and_zero() {
+        assert_ne!(hardened(b""), hardened(b"\0"));
+        assert_ne!(hardened(b""), 0);
+
+        let mut empty = Hasher::hardened();
+        empty.write_bytes(b"");
+        let mut zero = Hasher::hardened();
+        zero.write(0);
+        assert_ne!(empty.finalize(), zero.finalize());
+    }
+
+    #[test]
+    fn hardened_separates_fields() {
+        let mut a = Hasher::hardened();
+        a.write_bytes(b"ab");
+        a.write_bytes(b"c");
+        let mut b = Hasher::hardened();
+        b.write_bytes(b"a");
+        b.write_bytes(b"bc");
+        assert_ne!(a.finalize(), b.finalize());
+
+        let mut a = Hasher::hardened();
+        a.write_optional_bytes(Some(b"x"));
+        a.write_optional_bytes(None);
+        let mut b = Hasher::hardened();
+        b.write_optional_bytes(None);
+        b.write_optional_bytes(Some(b"x"));
+        assert_ne!(a.finalize(), b.finalize());
+    }
+
+    #[test]
+    fn hardened_avalanche() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut flipped = 0;
+        let mut samples = 0;
+        for _ in 0..500 {
+            let bytes = random_bytes(&mut rng, 24);
+            if bytes.is_empty() {
+                continue;
+            }
+            let base = hardened(&bytes);
+            for bit in 0..bytes.len() * 8 {
+                let mut changed = bytes.clone();
+                changed[bit / 8] ^= 1 << (bit % 8);
+                flipped += (hardened(&changed) ^ base).count_ones();
+                samples += 1;
+            }
+        }
+        let average = flipped as f64 / samples as f64;
+        assert!((31.0..33.0).contains(&average), "average {average}");
+    }
+
+    #[test]
+    fn hardened_distribution() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let n: u32 = 16_000;
+        let mut high = [0u32; 16];
+        let mut low = [0u32; 16];
+        for _ in 0..n {
+            let bytes: [u8; 12] = rng.gen();
+            let hash = hardened(&bytes);
+            high[(hash >> 60) as usize] += 1;
+            low[(hash & 15) as usize] += 1;
+        }
+        let expected = n / 16;
+        for count in high.into_iter().chain(low) {
+            assert!(count.abs_diff(expected) < expected / 5, "count {count}");
+        }
+    }
+
+    #[test]
+    fn no_collisions_among_random_inputs() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut inputs = std::collections::HashSet::new();
+        let mut hashes = std::collections::HashSet::new();
+        for _ in 0..50_000 {
+            let bytes = random_bytes(&mut rng, 24);
+            if inputs.insert(bytes.clone()) {
+                assert!(hashes.insert(hardened(&bytes)));
+            }
+        }
+    }
+
+    /// The hash of byte strings written one after another with the hasher
+    /// of current ids.
+    fn id_hash(fields: &[&[u8]]) -> u64 {
+        let mut hasher = IdVersion::CURRENT.hasher();
+        fields.iter().for_each(|bytes| hasher.write_bytes(bytes));
+        hasher.finalize()
+    }
+
+    fn property<A: Testable>(f: A) {
+        QuickCheck::new().tests(2000).quickcheck(f);
+    }
+
+    #[test]
+    fn prop_trailing_zeros_change_hash() {
+        fn prop(bytes: Vec<u8>, zeros: u8) -> bool {
+            let mut padded = bytes.clone();
+            padded.resize(bytes.len() + usize::from(zeros % 16) + 1, 0);
+            id_hash(&[&bytes]) != id_hash(&[&padded])
+        }
+        property(prop as fn(Vec<u8>, u8) -> bool);
+    }
+
+    #[test]
+    fn prop_bytes_dont_shift_between_fields() {
+        fn prop(a: Vec<u8>, b: Vec<u8>, c: Vec<u8>) -> TestResult {
+            if b.is_empty() {
+                return TestResult::discard();
+            }
+            let ab = [&a[..], &b[..]].concat();
+            let bc = [&b[..], &c[..]].concat();
+            TestResult::from_bool(id_hash(&[&a, &bc]) != id_hash(&[&ab, &c]))
+        }
+        property(prop as fn(Vec<u8>, Vec<u8>, Vec<u8>) -> TestResult);
+    }
+
+    #[test]
+    fn prop_empty_and_zero_differ() {
+        fn prop(prefix: Vec<u8>) -> bool {
+            let mut empty = IdVersion::CURRENT.hasher();
+            empty.write_bytes(&prefix);
+            empty.write_bytes(b"");
+            let mut zero = IdVersion::CURRENT.hasher();
+            zero.write_bytes(&prefix);
+            zero.write(0);
+            let empty = empty.finalize();
+            empty != zero.finalize() && empty != id_hash(&[&prefix, b"\0"])
+        }
+        property(prop as fn(Vec<u8>) -> bool);
+    }
+
+    #[test]
+    fn prop_bit_flips_avalanche() {
+        fn prop(bytes: Vec<u8>, bit: usize) -> TestResult {
+            if bytes.is_empty() {
+                return TestResult::discard();
+            }
+            let bit = bit % (bytes.len() * 8);
+            let mut flipped = bytes.clone();
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            // fewer than 8 of 64 bits change with a chance of about 1e-10
+            let changed = (id_hash(&[&bytes]) ^ id_hash(&[&flipped])).count_ones();
+            TestResult::from_bool(changed >= 8)
+        }
+        property(prop as fn(Vec<u8>, usize) -> TestResult);
+    }
+
+    #[test]
+    fn prop_distinct_inputs_dont_collide() {
+        fn prop(a: Vec<u8>, b: Vec<u8>) -> TestResult {
+            if a == b {
+                return TestResult::discard();
+            }
+            TestResult::from_bool(id_hash(&[&a]) != id_hash(&[&b]))
+        }
+        property(prop as fn(Vec<u8>, Vec<u8>) -> TestResult);
+    }
+
+    #[test]
+    fn prop_low_bits_are_uniform() {
+        // the buckets of many short inputs, which hash apart the least
+        fn prop(seed: u64) -> bool {
+            let mut buckets = [0u32; 16];
+            for i in 0..1600u64 {
+                let bytes = (seed ^ i).to_le_bytes();
+                buckets[(id_hash(&[&bytes[..(i % 8) as usize + 1]]) & 15) as usize] += 1;
+            }
+            buckets.iter().all(|count| count.abs_diff(100) < 50)
+        }
+        QuickCheck::new()
+            .tests(50)
+            .quickcheck(prop as fn(u64) -> bool);
+    }
+
+    #[test]
+    fn id_hasher_matches_inherent_methods() {
+        let mut inherent = Hasher::new();
+        inherent.write(7);
+        inherent.write_bytes(b"Europe/Zurich");
+        inherent.write_optional_bytes(None);
+
+        let mut dynamic: Box<dyn IdHasher> = Box::new(Hasher::new());
+        dynamic.write(7);
+        dynamic.write_bytes(b"Europe/Zurich");
+        dynamic.write_optional_bytes(None);
+
+        assert_eq!(dynamic.finish(), inherent.finalize());
+    }
+
+    #[test]
+    #[cfg(feature = "siphash")]
+    fn sip_id_hasher_is_keyed() {
+        let hash = |key0, key1| {
+            let mut hasher = SipIdHasher::new_with_keys(key0, key1);
+            hasher.write_bytes(b"Europe/Zurich");
+            hasher.finish()
+        };
+        assert_eq!(hash(1, 2), hash(1, 2));
+        assert_ne!(hash(1, 2), hash(2, 1));
+    }
 }
@@ -0,0 +1,244 @@
+//! Health and readiness of a collector.
+//!
+//! A [`Health`] runs named checks of the components (sink connectivity,
+//! queue depth, session store latency, config freshness) and aggregates
+//! them into a [`HealthReport`], which the hosting server exposes at e.g.
+//! `/healthz`. The report is as bad as its worst check.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::sink::retry::CircuitState;
+use crate::Error;
+
+/// Share of a queue at which it is degraded.
+pub const QUEUE_DEGRADED_PERCENT: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Healthy,
+    /// Working, but e.g. slow or close to its limits.
+    Degraded,
+    /// Not working, the collector should get no traffic.
+    Unhealthy,
+}
+
+/// Result of a single check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Check {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    pub fn healthy() -> Self {
+        Check {
+            status: Status::Healthy,
+            detail: None,
+        }
+    }
+
+    pub fn degraded(detail: impl Into<String>) -> Self {
+        Check {
+            status: Status::Degraded,
+            detail: Some(detail.into()),
+        }
+    }
+
+    pub fn unhealthy(detail: impl Into<String>) -> Self {
+        Check {
+            status: Status::Unhealthy,
+            detail: Some(detail.into()),
+        }
+    }
+
+    /// A queue is degraded from [`QUEUE_DEGRADED_PERCENT`] and unhealthy
+    /// when full.
+    pub fn queue(depth: usize, capacity: usize) -> Self {
+        let detail = format!("{depth} of {capacity} queued");
+        if depth >= capacity {
+            Check::unhealthy(detail)
+        } else if depth * 100 >= capacity * QUEUE_DEGRADED_PERCENT {
+            Check::degraded(detail)
+        } else {
+            Check::healthy()
+        }
+    }
+
+    /// Times `probe`, e.g. a ping of the session store. Failed probes and
+    /// probes slower than `unhealthy_after` are unhealthy, probes slower
+    /// than `degraded_after` degraded.
+    pub fn latency(
+        probe: impl FnOnce() -> Result<(), Error>,
+        degraded_after: Duration,
+        unhealthy_after: Duration,
+    ) -> Self {
+        let start = Instant::now();
+        if let Err(err) = probe() {
+            return Check::unhealthy(err.to_string());
+        }
+        let elapsed = start.elapsed();
+        let detail = format!("{}ms", elapsed.as_millis());
+        if elapsed >= unhealthy_after {
+            Check::unhealthy(detail)
+        } else if elapsed >= degraded_after {
+            Check::degraded(detail)
+        } else {
+            Check::healthy()
+        }
+    }
+
+    /// Data last refreshed `age` ago is degraded once older than `max_age`,
+    /// e.g. project settings whose [watcher](crate::config::provider::Watcher)
+    /// keeps failing.
+    pub fn freshness(age: Duration, max_age: Duration) -> Self {
+        if age > max_age {
+            Check::degraded(format!("{}s old", age.as_secs()))
+        } else {
+            Check::healthy()
+        }
+    }
+
+    /// A sink behind a [`RetrySink`](crate::sink::retry::RetrySink) is
+    /// unhealthy while its circuit is open.
+    pub fn circuit(state: CircuitState) -> Self {
+        match state {
+            CircuitState::Closed => Check::healthy(),
+            CircuitState::HalfOpen => Check::degraded("circuit half open"),
+            CircuitState::Open(_) => Check::unhealthy("circuit open"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ComponentHealth {
+    pub name: String,
+    #[serde(flatten)]
+    pub check: Check,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    pub time: DateTime<Utc>,
+    /// The worst status of the components.
+    pub status: Status,
+    pub components: Vec<ComponentHealth>,
+}
+
+impl HealthReport {
+    /// Whether the collector may get traffic, degraded collectors may.
+    pub fn is_ready(&self) -> bool {
+        self.status != Status::Unhealthy
+    }
+
+    /// 200 if ready, 503 otherwise.
+    pub fn http_status(&self) -> u16 {
+        if self.is_ready() {
+            200
+        } else {
+            503
+        }
+    }
+}
+
+type CheckFn = Box<dyn Fn() -> Check + Send + Sync>;
+
+#[derive(Default)]
+pub struct Health {
+    checks: Vec<(String, CheckFn)>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_check(
+        mut self,
+        name: impl Into<String>,
+        check: impl Fn() -> Check + Send + Sync + 'static,
+    ) -> Self {
+        self.checks.push((name.into(), Box::new(check)));
+        self
+    }
+
+    /// Runs every check, a collector without checks is healthy.
+    pub fn report(&self) -> HealthReport {
+        let components: Vec<ComponentHealth> = self
+            .checks
+            .iter()
+            .map(|(name, check)| ComponentHealth {
+                name: name.clone(),
+                check: check(),
+            })
+            .collect();
+        HealthReport {
+            time: Utc::now(),
+            status: components
+                .iter()
+                .map(|component| component.check.status)
+                .max()
+                .unwrap_or(Status::Healthy),
+            components,
+        }
+    }
+}
+
+impl fmt::Debug for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.checks.iter().map(|(name, _)| name).collect();
+        f.debug_struct("Health").field("checks", &names).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_worst_status() {
+        let health = Health::new()
+            .with_check("queue", || Check::queue(10, 100))
+            .with_check("config", || {
+                Check::freshness(Duration::from_secs(600), Duration::from_secs(300))
+            });
+        let report = health.report();
+        assert_eq!(report.status, Status::Degraded);
+        assert!(report.is_ready());
+
+        let json = serde_json::to_value(&report.components).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"name": "queue", "status": "healthy"},
+                {"name": "config", "status": "degraded", "detail": "600s old"},
+            ])
+        );
+
+        let health = health.with_check("sink", || {
+            Check::circuit(CircuitState::Open(Instant::now()))
+        });
+        assert_eq!(health.report().http_status(), 503);
+        assert_eq!(Health::new().report().status, Status::Healthy);
+    }
+
+    #[test]
+    fn grades_queues_and_latency() {
+        assert_eq!(Check::queue(49, 100).status, Status::Healthy);
+        assert_eq!(Check::queue(50, 100).status, Status::Degraded);
+        assert_eq!(Check::queue(100, 100).status, Status::Unhealthy);
+
+        let second = Duration::from_secs(1);
+        let check = Check::latency(|| Ok(()), second, second * 2);
+        assert_eq!(check.status, Status::Healthy);
+        let check = Check::latency(|| Ok(()), Duration::ZERO, second);
+        assert_eq!(check.status, Status::Degraded);
+        let check = Check::latency(|| Err(Error::Closed), second, second);
+        assert_eq!(check, Check::unhealthy("pipeline closed"));
+    }
+}
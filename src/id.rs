@@ -0,0 +1,447 @@
+//! Versioning of the id scheme.
+//!
+//! Changing the inputs of an id (salts, new fields, normalizations) changes
+//! every id and breaks joins with previously stored records. Such changes are
+//! introduced as a new [`IdVersion`] and rolled out with the [`Rehash`]
+//! helpers, which map old ids to new ones.
+
+use serde::Serialize;
+
+use crate::hash::{ByteOrder, Hasher, IdHasher};
+use crate::{Page, Referrer, UtmParam, Visitor, VisitorFields};
+
+/// Number of high bits that hold the version in ids from [`IdVersion::V2`] on.
+pub const VERSION_BITS: u32 = 4;
+
+/// Tag of ids computed by a custom [`IdHasher`], see
+/// [`VisitorBuilder::hasher`](crate::VisitorBuilder::hasher). They can't be
+/// recomputed from their fields, so no [`IdVersion`] claims the tag.
+pub const CUSTOM_TAG: u64 = (1 << VERSION_BITS) - 1;
+
+/// Turns the hash of a custom [`IdHasher`] into an id with the
+/// [`CUSTOM_TAG`].
+pub fn tag_custom(hash: u64) -> i64 {
+    ((hash >> VERSION_BITS) | (CUSTOM_TAG << (64 - VERSION_BITS))) as i64
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IdVersion {
+    /// [`IdVersion::V1`] with byte strings read in the host byte order, as
+    /// ids were computed before it was fixed to little-endian.
+    ///
+    /// Only ids stored by big-endian hosts differ from V1, they are
+    /// migrated with [`rehash_native_to_v1`].
+    V1Native,
+    /// The plain 64 bit hash, without a version tag.
+    #[default]
+    V1,
+    /// Uses the [hardened](Hasher::hardened) hasher, so field values can't
+    /// collide through padding or by shifting between fields, and stores the
+    /// version in the highest [`VERSION_BITS`] bits of the id.
+    V2,
+    /// Like [`IdVersion::V2`], but hashes the screen size as smaller by
+    /// larger dimension, so rotating a phone or tablet keeps the visitor id.
+    V3,
+    /// Like [`IdVersion::V3`], but hashes page paths percent-decoded and
+    /// composed to Unicode NFC, see [`normalize_path`](crate::normalize_path),
+    /// so differently encoded urls of a page get the same id.
+    V4,
+}
+
+impl IdVersion {
+    /// The version used for newly created ids.
+    ///
+    /// Moving it forward changes the ids of all new records. Before such a
+    /// build is rolled out, stored records are mapped to the new version with
+    /// [`Rehash::rehash`], e.g. [`rehash_v1_to_v2`], so they keep joining with
+    /// new ones. During the rollout collectors of both builds run side by
+    /// side, ids are told apart by their [tag](IdVersion::from_tag).
+    pub const CURRENT: IdVersion = IdVersion::V4;
+
+    fn number(self) -> u64 {
+        match self {
+            IdVersion::V1Native | IdVersion::V1 => 1,
+            IdVersion::V2 => 2,
+            IdVersion::V3 => 3,
+            IdVersion::V4 => 4,
+        }
+    }
+
+    /// Returns the hasher used to compute ids of this version.
+    pub fn hasher(self) -> Hasher {
+        match self {
+            IdVersion::V1Native => Hasher::with_byte_order(ByteOrder::Native),
+            IdVersion::V1 => Hasher::new(),
+            IdVersion::V2 | IdVersion::V3 | IdVersion::V4 => Hasher::hardened(),
+        }
+    }
+
+    /// Turns a raw hash into an id of this version.
+    pub fn tag(self, hash: u64) -> i64 {
+        match self {
+            IdVersion::V1Native | IdVersion::V1 => hash as i64,
+            _ => ((hash >> VERSION_BITS) | (self.number() << (64 - VERSION_BITS))) as i64,
+        }
+    }
+
+    /// Reads the version tag of an id.
+    ///
+    /// V1 ids carry no tag, so a V1 id can be misreported as a later version.
+    /// Only use this on ids known to be at least [`IdVersion::V2`].
+    pub fn from_tag(id: i64) -> Option<IdVersion> {
+        match (id as u64) >> (64 - VERSION_BITS) {
+            2 => Some(IdVersion::V2),
+            3 => Some(IdVersion::V3),
+            4 => Some(IdVersion::V4),
+            _ => None,
+        }
+    }
+}
+
+/// Old and new id of a record after rehashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IdMapping {
+    pub old: i64,
+    pub new: i64,
+}
+
+/// Records whose id can be recomputed from their fields.
+pub trait Rehash {
+    /// The id as stored on the record.
+    fn id(&self) -> i64;
+
+    /// Recomputes the id with the given scheme version.
+    fn rehash(&self, version: IdVersion) -> i64;
+}
+
+/// Maps the V1 id of a record to its V2 id.
+///
+/// The record is expected to carry a V1 id. Visitors built with a
+/// [salt](crate::VisitorBuilder::salt) cannot be rehashed this way, use
+/// [`VisitorFields::id`](crate::VisitorFields::id) with the salt instead.
+pub fn rehash_v1_to_v2<T: Rehash>(record: &T) -> IdMapping {
+    IdMapping {
+        old: record.id(),
+        new: record.rehash(IdVersion::V2),
+    }
+}
+
+/// Maps the id a record got on a big-endian host before the byte order was
+/// fixed, see [`IdVersion::V1Native`], to its V1 id.
+///
+/// Run this on the records stored by big-endian hosts before rolling out a
+/// build that hashes little-endian, on little-endian hosts both ids are the
+/// same.
+pub fn rehash_native_to_v1<T: Rehash>(record: &T) -> IdMapping {
+    IdMapping {
+        old: record.rehash(IdVersion::V1Native),
+        new: record.rehash(IdVersion::V1),
+    }
+}
+
+/// The exact inputs of an id, for auditing identifiability.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IdAudit {
+    pub kind: &'static str,
+    pub id: i64,
+    /// Inputs in hashing order, `None` for absent values.
+    pub inputs: Vec<(&'static str, Option<String>)>,
+    /// Hash of the inputs with the audit hasher.
+    ///
+    /// Salts are not included, so the same inputs produce the same keyed hash
+    /// across salt rotations while their ids must differ.
+    pub keyed_hash: u64,
+}
+
+/// Records that can report the inputs of their id.
+pub trait IdInputs: Rehash {
+    const KIND: &'static str;
+
+    /// Inputs in hashing order, `None` for absent values.
+    fn inputs(&self) -> Vec<(&'static str, Option<String>)>;
+
+    /// Feeds the inputs into a hasher, without salt.
+    fn hash_inputs(&self, hasher: &mut dyn IdHasher);
+
+    /// Returns the id alongside its inputs and their hash with `keyed`,
+    /// which should be a keyed backend only known to the operators.
+    fn audit(&self, mut keyed: impl IdHasher) -> IdAudit {
+        self.hash_inputs(&mut keyed);
+        IdAudit {
+            kind: Self::KIND,
+            id: self.id(),
+            inputs: self.inputs(),
+            keyed_hash: keyed.finish(),
+        }
+    }
+}
+
+impl Rehash for Visitor {
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn rehash(&self, version: IdVersion) -> i64 {
+        self.fields().id(version, None)
+    }
+}
+
+impl Rehash for Page {
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn rehash(&self, version: IdVersion) -> i64 {
+        Page::compute_id(version, self.project, &self.domain, &self.path)
+    }
+}
+
+impl Rehash for UtmParam {
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn rehash(&self, version: IdVersion) -> i64 {
+        self.compute_id(version)
+    }
+}
+
+impl Rehash for Referrer {
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn rehash(&self, version: IdVersion) -> i64 {
+        Referrer::compute_id(version, self.project, &self.domain)
+    }
+}
+
+impl Visitor {
+    /// The fields as hashed by the version of the id, untagged ids as V1.
+    fn hashed_fields(&self) -> VisitorFields<'_> {
+        let version = IdVersion::from_tag(self.id).unwrap_or(IdVersion::V1);
+        self.fields().for_version(version)
+    }
+}
+
+impl IdInputs for Visitor {
+    const KIND: &'static str = "visitor";
+
+    fn inputs(&self) -> Vec<(&'static str, Option<String>)> {
+        let fields = self.hashed_fields();
+        vec![
+            ("project", Some(self.project.to_string())),
+            ("region", self.region.as_deref().map(ToString::to_string)),
+            ("timezone", Some(self.timezone.clone())),
+            ("language", Some(self.language.clone())),
+            ("browser", self.browser.as_deref().map(ToString::to_string)),
+            (
+                "platform",
+                self.platform.as_deref().map(ToString::to_string),
+            ),
+            ("width", Some(fields.width.to_string())),
+            ("height", Some(fields.height.to_string())),
+        ]
+    }
+
+    fn hash_inputs(&self, hasher: &mut dyn IdHasher) {
+        self.hashed_fields().hash_with(hasher, None);
+    }
+}
+
+impl IdInputs for Page {
+    const KIND: &'static str = "page";
+
+    fn inputs(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("project", Some(self.project.to_string())),
+            ("domain", Some(self.domain.clone())),
+            ("path", Some(self.path.clone())),
+        ]
+    }
+
+    fn hash_inputs(&self, hasher: &mut dyn IdHasher) {
+        Page::hash_with(hasher, self.project, &self.domain, &self.path);
+    }
+}
+
+impl IdInputs for UtmParam {
+    const KIND: &'static str = "utm_param";
+
+    fn inputs(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("project", Some(self.project.to_string())),
+            ("campaign", self.campaign.clone()),
+            ("content", self.content.clone()),
+            ("medium", self.medium.clone()),
+            ("source", self.source.clone()),
+            ("term", self.term.clone()),
+        ]
+    }
+
+    fn hash_inputs(&self, hasher: &mut dyn IdHasher) {
+        self.hash_with(hasher);
+    }
+}
+
+impl IdInputs for Referrer {
+    const KIND: &'static str = "referrer";
+
+    fn inputs(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("project", Some(self.project.to_string())),
+            ("domain", Some(self.domain.clone())),
+        ]
+    }
+
+    fn hash_inputs(&self, hasher: &mut dyn IdHasher) {
+        Referrer::hash_with(hasher, self.project, &self.domain);
+    }
+}
+
+// ids of minimal builds lack the enrichment
+#[cfg(all(test, not(feature = "minimal")))]
+mod golden;
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    #[test]
+    fn v1_is_untagged() {
+        assert_eq!(IdVersion::V1.tag(u64::MAX), -1);
+        assert_eq!(IdVersion::V1.tag(42), 42);
+    }
+
+    #[test]
+    fn v2_stores_version_in_high_bits() {
+        for hash in [0, 42, u64::MAX] {
+            let id = IdVersion::V2.tag(hash);
+            assert_eq!(IdVersion::from_tag(id), Some(IdVersion::V2));
+        }
+    }
+
+    #[test]
+    fn rehash_current_version_is_identity() {
+        let url = Url::parse("https://example.com/blog?source=newsletter").unwrap();
+        let page = Page::new(1, &url).unwrap();
+        let utm = UtmParam::new(1, &url).unwrap();
+        assert_eq!(page.rehash(IdVersion::CURRENT), page.id);
+        assert_eq!(utm.rehash(IdVersion::CURRENT), utm.id);
+    }
+
+    #[test]
+    fn rehash_v1_to_v2_maps_ids() {
+        let url = Url::parse("https://example.com/").unwrap();
+        let page = Page::new(1, &url).unwrap();
+        let mapping = rehash_v1_to_v2(&page);
+        assert_eq!(mapping.old, page.id);
+        assert_eq!(IdVersion::from_tag(mapping.new), Some(IdVersion::V2));
+        assert_eq!(
+            mapping.new,
+            Page::compute_id(IdVersion::V2, 1, "example.com", "/")
+        );
+    }
+
+    #[test]
+    fn rehash_native_to_v1_maps_ids() {
+        let url = Url::parse("https://example.com/caf%C3%A9").unwrap();
+        let page = Page::new(1, &url).unwrap();
+        let mapping = rehash_native_to_v1(&page);
+        assert_eq!(mapping.new, page.rehash(IdVersion::V1));
+
+        // the id the page got before the byte order was fixed
+        let mut native = Hasher::with_byte_order(ByteOrder::Native);
+        Page::hash_with(&mut native, 1, "example.com", "/caf%C3%A9");
+        assert_eq!(mapping.old, native.finalize() as i64);
+        if cfg!(target_endian = "little") {
+            assert_eq!(mapping.old, mapping.new);
+        } else {
+            assert_ne!(mapping.old, mapping.new);
+        }
+        assert_eq!(IdVersion::V1Native.tag(u64::MAX), -1);
+    }
+
+    #[test]
+    fn v3_ignores_screen_orientation() {
+        let pub_visitor = |screen| crate::api::PubVisitor {
+            tz: "Europe/Zurich".to_string(),
+            screen,
+            ..Default::default()
+        };
+        let portrait = Visitor::builder(1, &pub_visitor((390, 844))).build();
+        let landscape = Visitor::builder(1, &pub_visitor((844, 390))).build();
+        assert_eq!(portrait.id, landscape.id);
+        assert_eq!(
+            portrait.rehash(IdVersion::V3),
+            landscape.rehash(IdVersion::V3)
+        );
+        assert_eq!(
+            IdVersion::from_tag(portrait.rehash(IdVersion::V3)),
+            Some(IdVersion::V3)
+        );
+        assert_ne!(
+            portrait.rehash(IdVersion::V2),
+            landscape.rehash(IdVersion::V2)
+        );
+    }
+
+    #[test]
+    fn v2_separates_adjacent_fields() {
+        let page = |domain, path| Page::compute_id(IdVersion::V2, 1, domain, path);
+        assert_ne!(page("ab", "c"), page("a", "bc"));
+        let live = |domain, path| Page::compute_id(IdVersion::CURRENT, 1, domain, path);
+        assert_ne!(live("ab", "c"), live("a", "bc"));
+        assert_ne!(live("ab", "c"), live("ab", "c\0"));
+
+        let url = |query| Url::parse(&format!("https://example.com/?{query}")).unwrap();
+        let campaign = UtmParam::new(1, &url("campaign=x")).unwrap();
+        let content = UtmParam::new(1, &url("content=x")).unwrap();
+        // v1 can't tell which field the value belongs to
+        assert_eq!(
+            campaign.rehash(IdVersion::V1),
+            content.rehash(IdVersion::V1)
+        );
+        assert_ne!(campaign.id, content.id);
+        assert_ne!(
+            campaign.rehash(IdVersion::V2),
+            content.rehash(IdVersion::V2)
+        );
+    }
+
+    #[test]
+    fn audit_reports_inputs() {
+        let url = Url::parse("https://example.com/blog").unwrap();
+        let page = Page::new(1, &url).unwrap();
+        let audit = page.audit(Hasher::hardened());
+        assert_eq!(audit.kind, "page");
+        assert_eq!(audit.id, page.id);
+        assert_eq!(
+            audit.inputs,
+            vec![
+                ("project", Some("1".to_string())),
+                ("domain", Some("example.com".to_string())),
+                ("path", Some("/blog".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn audit_keyed_hash_is_stable_across_salts() {
+        let pub_visitor = crate::api::PubVisitor {
+            tz: "Europe/Zurich".to_string(),
+            lang: "de-CH".to_string(),
+            screen: (1920, 1080),
+            ..Default::default()
+        };
+        let a = Visitor::builder(1, &pub_visitor).salt(1).build();
+        let b = Visitor::builder(1, &pub_visitor).salt(2).build();
+        let a = a.audit(Hasher::hardened());
+        let b = b.audit(Hasher::hardened());
+        assert_ne!(a.id, b.id);
+        assert_eq!(a.inputs, b.inputs);
+        assert_eq!(a.keyed_hash, b.keyed_hash);
+    }
+}
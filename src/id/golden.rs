@@ -0,0 +1,113 @@
+//! Golden ids of a corpus of payloads.
+//!
+//! `golden.json` holds payloads with the ids they got when the fixture was
+//! written. An id that differs means stored records no longer join with new
+//! ones, so the test fails unless the change comes with a new [`IdVersion`].
+//! Cases are added by running the tests with `UPDATE_GOLDEN=1`, which
+//! rewrites the fixture with the current ids, and reviewing the diff: only
+//! new cases may show up in it.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+
+use super::{IdInputs, IdVersion, Rehash};
+use crate::api::PubVisitor;
+use crate::{Page, Referrer, UtmParam, Visitor};
+
+const PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/id/golden.json");
+const VERSIONS: [IdVersion; 4] = [IdVersion::V1, IdVersion::V2, IdVersion::V3, IdVersion::V4];
+
+/// Ids of a record by version.
+type Ids = BTreeMap<String, i64>;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Case {
+    name: String,
+    project: i64,
+    /// The `visitor` object of the beacon.
+    visitor: Value,
+    user_agent: String,
+    url: Url,
+    referrer: Option<Url>,
+    /// Left out in new cases until the fixture is updated.
+    #[serde(default)]
+    ids: CaseIds,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct CaseIds {
+    visitor: Ids,
+    page: Ids,
+    utm: Option<Ids>,
+    referrer: Option<Ids>,
+}
+
+fn ids(record: &impl Rehash) -> Ids {
+    VERSIONS
+        .iter()
+        .map(|version| {
+            (
+                format!("{version:?}").to_lowercase(),
+                record.rehash(*version),
+            )
+        })
+        .collect()
+}
+
+impl Case {
+    fn compute(&self) -> CaseIds {
+        let visitor: PubVisitor = serde_json::from_value(self.visitor.clone()).unwrap();
+        let visitor = Visitor::new(self.project, &visitor, &self.user_agent);
+        let page = Page::new(self.project, &self.url).unwrap();
+        let utm = UtmParam::new(self.project, &self.url);
+        let referrer = Referrer::new(self.project, self.referrer.as_ref(), &page.domain);
+        assert_eq!(
+            visitor.id,
+            visitor.rehash(IdVersion::CURRENT),
+            "{}",
+            self.name
+        );
+        CaseIds {
+            visitor: ids(&visitor),
+            page: ids(&page),
+            utm: utm.as_ref().map(ids),
+            referrer: referrer.as_ref().map(ids),
+        }
+    }
+}
+
+#[test]
+fn ids_match_golden_file() {
+    let mut cases: Vec<Case> = serde_json::from_str(include_str!("golden.json")).unwrap();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        for case in &mut cases {
+            case.ids = case.compute();
+        }
+        let json = serde_json::to_string_pretty(&cases).unwrap();
+        std::fs::write(PATH, json + "\n").unwrap();
+        return;
+    }
+    for case in &cases {
+        assert_eq!(case.compute(), case.ids, "ids of {} changed", case.name);
+    }
+}
+
+#[test]
+fn audited_inputs_hash_to_golden_ids() {
+    let cases: Vec<Case> = serde_json::from_str(include_str!("golden.json")).unwrap();
+    for case in &cases {
+        let visitor: PubVisitor = serde_json::from_value(case.visitor.clone()).unwrap();
+        let mut visitor = Visitor::new(case.project, &visitor, &case.user_agent);
+        for version in [IdVersion::V3, IdVersion::V4] {
+            visitor.id = visitor.rehash(version);
+            let mut hasher = version.hasher();
+            visitor.hash_inputs(&mut hasher);
+            let id = version.tag(hasher.finalize());
+            let name = format!("{version:?}").to_lowercase();
+            assert_eq!(id, case.ids.visitor[&name], "{} {name}", case.name);
+        }
+    }
+}
@@ -0,0 +1,3 @@
+//! Import of data from external sources.
+
+pub mod cost;
@@ -0,0 +1,161 @@
+//! Ad spend imported from advertising platforms.
+//!
+//! Cost rows are keyed by the same id as [`UtmParam`], so the return on ad
+//! spend of a campaign can be computed by joining costs with visits and
+//! events on the utm id.
+
+use std::collections::BTreeMap;
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::id::IdVersion;
+use crate::{Error, UtmParam};
+
+/// A row as exported by an advertising platform.
+///
+/// `content` and `term` are optional, visits are only matched if all
+/// parameters are the same.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct CostRow {
+    pub date: NaiveDate,
+    pub campaign: Option<String>,
+    pub source: Option<String>,
+    pub medium: Option<String>,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub term: Option<String>,
+    /// In the project's base currency.
+    pub cost: f64,
+}
+
+/// The cost of a campaign on a single day.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CampaignCost {
+    pub date: NaiveDate,
+    /// Id of the matching [`UtmParam`].
+    pub utm_param: i64,
+    pub cost: f64,
+}
+
+/// Turns cost rows into per day campaign costs.
+///
+/// Empty parameters are treated as missing, like in urls without them.
+/// Rows of the same day and campaign are summed. Returns an error for rows
+/// without any utm parameter and for negative or non-finite costs.
+pub fn import(
+    project_id: i64,
+    rows: impl IntoIterator<Item = CostRow>,
+) -> Result<Vec<CampaignCost>, Error> {
+    let mut costs: BTreeMap<(NaiveDate, i64), f64> = BTreeMap::new();
+    for (row_index, row) in rows.into_iter().enumerate() {
+        let invalid = |reason| Error::InvalidCost {
+            row: row_index,
+            reason,
+        };
+        if !row.cost.is_finite() || row.cost < 0.0 {
+            return Err(invalid("invalid cost"));
+        }
+        let utm = utm_param(project_id, row.clone()).ok_or(invalid("missing utm parameters"))?;
+        *costs.entry((row.date, utm.id)).or_default() += row.cost;
+    }
+
+    Ok(costs
+        .into_iter()
+        .map(|((date, utm_param), cost)| CampaignCost {
+            date,
+            utm_param,
+            cost,
+        })
+        .collect())
+}
+
+/// The utm parameter a cost row is attributed to.
+pub fn utm_param(project_id: i64, row: CostRow) -> Option<UtmParam> {
+    let non_empty = |value: Option<String>| value.filter(|value| !value.is_empty());
+    let mut val = UtmParam {
+        id: 0,
+        project: project_id,
+        campaign: non_empty(row.campaign),
+        content: non_empty(row.content),
+        medium: non_empty(row.medium),
+        source: non_empty(row.source),
+        term: non_empty(row.term),
+    };
+    if [
+        &val.campaign,
+        &val.content,
+        &val.medium,
+        &val.source,
+        &val.term,
+    ]
+    .iter()
+    .all(|value| value.is_none())
+    {
+        return None;
+    }
+    val.id = val.compute_id(IdVersion::CURRENT);
+    Some(val)
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    fn rows() -> Vec<CostRow> {
+        serde_json::from_str(
+            r#"[
+                {"date": "2023-10-01", "campaign": "autumn", "source": "google", "medium": "cpc", "cost": 120.5},
+                {"date": "2023-10-01", "campaign": "autumn", "source": "google", "medium": "cpc", "term": "", "cost": 9.5},
+                {"date": "2023-10-02", "campaign": "autumn", "source": "google", "medium": "cpc", "cost": 80}
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn ids_match_visits() {
+        let url =
+            Url::parse("https://example.com/?campaign=autumn&source=google&medium=cpc").unwrap();
+        let visit_utm = UtmParam::new(1, &url).unwrap();
+
+        let costs = import(1, rows()).unwrap();
+        assert_eq!(
+            costs,
+            vec![
+                CampaignCost {
+                    date: NaiveDate::from_ymd_opt(2023, 10, 1).unwrap(),
+                    utm_param: visit_utm.id,
+                    cost: 130.0,
+                },
+                CampaignCost {
+                    date: NaiveDate::from_ymd_opt(2023, 10, 2).unwrap(),
+                    utm_param: visit_utm.id,
+                    cost: 80.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rows() {
+        let mut rows = rows();
+        rows[1].cost = -1.0;
+        assert!(matches!(
+            import(1, rows),
+            Err(Error::InvalidCost { row: 1, .. })
+        ));
+
+        let row = CostRow {
+            campaign: Some(String::new()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            import(1, [row]),
+            Err(Error::InvalidCost { row: 0, .. })
+        ));
+    }
+}
@@ -2,117 +2,791 @@
 //!
 //! See [api functions] for entry points.
 //!
+//! # Edge deployment
+//!
+//! Decoding, processing and enriching beacons with the [api functions]
+//! spawns no threads, reads no files and takes the time from the installed
+//! [clock], so it runs on `wasm32-wasi` and, with a clock passed in by the
+//! host, on `wasm32-unknown-unknown` like Cloudflare Workers. Initialize the
+//! user agent parser with [`ua::try_init`] there, or leave it out with the
+//! `minimal` feature. The [pipeline], the config [watcher] and the file
+//! based sinks need threads or a file system.
+//!
 //! [api functions]: api#functions
+//! [watcher]: config::provider::Watcher
+
+use crate::api::{PubConnection, PubVisitor};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
 
-use crate::api::PubVisitor;
 use chrono::{DateTime, Utc};
+use icu_normalizer::ComposingNormalizerBorrowed;
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use uaparser::{Parser, UserAgentParser};
 use url::Url;
 
+pub mod aggregate;
+pub mod amp;
 pub mod api;
+#[cfg(feature = "bumpalo")]
+pub mod arena;
+pub mod audit;
+pub mod buffer;
+pub mod channel;
+pub mod clock;
+pub mod config;
+#[cfg(feature = "cookie")]
+pub mod cookie;
+pub mod data;
+pub mod explain;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+pub mod flush;
 pub mod hash;
+pub mod health;
+pub mod id;
+pub mod import;
+#[cfg(feature = "linker")]
+pub mod linker;
+pub mod middleware;
+pub mod pipeline;
+pub mod priority;
+pub mod privacy;
+pub mod profile;
+pub mod projects;
+#[cfg(feature = "pyo3")]
+pub mod python;
+pub mod quota;
+pub mod raw;
+pub mod reprocess;
+pub mod revenue;
+pub mod session;
+pub mod shadow;
+pub mod shedding;
+pub mod sink;
+pub mod ua;
+pub mod usage;
+pub mod utm;
 
-use crate::hash::Hasher;
+use crate::data::FlatEventData;
+use crate::hash::IdHasher;
+use crate::id::IdVersion;
+use crate::profile::VisitorProfile;
+use crate::session::SessionSummary;
+use crate::ua::{UserAgent, UA_CACHE};
+use crate::utm::{UtmField, UtmOptions};
 
 include!(concat!(env!("OUT_DIR"), "/timezone-codegen.rs"));
 
-static UAP_REGEXES: &[u8] = include_bytes!("../uap-core/regexes.yaml");
-
 lazy_static! {
-    pub static ref UA_PARSER: UserAgentParser =
-        UserAgentParser::from_bytes(UAP_REGEXES).expect("can parse regexes.yaml");
+    /// Runtime corrections of [`TIMEZONES`], `None` removes a mapping.
+    static ref TIMEZONE_OVERRIDES: RwLock<HashMap<String, Option<&'static str>>> =
+        RwLock::default();
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Visitor {
     pub id: i64,
     pub project: i64,
-    pub region: Option<String>,
+    pub region: Option<Cow<'static, str>>,
+    /// ISO 3166-2 code of the state or province, e.g. `US-IN`, where the
+    /// timezone implies one.
+    pub subdivision: Option<Cow<'static, str>>,
+    /// Two-letter continent code of the region, e.g. `EU` or `NA`.
+    pub continent: Option<Cow<'static, str>>,
+    /// Whether the region is a member state of the European Union.
+    #[serde(default)]
+    pub is_eu: bool,
+    /// Whether the region is a guess from a UTC offset, see [`region_for_offset`].
+    #[serde(default)]
+    pub low_confidence: bool,
     pub timezone: String,
     pub language: String,
-    pub browser: Option<String>,
-    pub platform: Option<String>,
+    pub browser: Option<Cow<'static, str>>,
+    pub platform: Option<Cow<'static, str>>,
+    /// Screen size, 0 if the reported one was implausible.
     pub width: i32,
     pub height: i32,
+    /// Whether the reported screen size was out of range, see
+    /// [`MAX_SCREEN_SIZE`].
+    #[serde(default)]
+    pub invalid_screen: bool,
+    /// Rendering size in CSS pixels, unlike the screen size it reflects
+    /// the browser window.
+    pub viewport_width: Option<i32>,
+    pub viewport_height: Option<i32>,
+    pub pixel_ratio: Option<f64>,
+    /// UX preferences reported by the browser's media queries, not part
+    /// of the id.
+    pub prefers_color_scheme: Option<ColorScheme>,
+    pub prefers_reduced_motion: Option<bool>,
+    /// Network conditions for performance segmentation, not part of the id.
+    pub connection: Option<Connection>,
+    /// Whether the user agent was rejected unparsed, see [`ua::is_bogus`].
+    #[serde(default)]
+    pub bogus_user_agent: bool,
+    /// Whether enrichment ran out of its latency budget, see
+    /// [`api::handle_batch_within`]. Browser and platform may be missing,
+    /// so the id differs from the fully enriched visitor's.
+    #[serde(default)]
+    pub partial: bool,
+    /// Lifetime statistics, attached by a
+    /// [`ProfileTracker`](profile::ProfileTracker).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<VisitorProfile>,
+    /// ISO week of the first visit, see [`VisitorProfile::cohort`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cohort: Option<String>,
+}
+
+/// The `prefers-color-scheme` media feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
+
+/// Largest plausible screen width or height in pixels.
+pub const MAX_SCREEN_SIZE: i32 = 20_000;
+
+/// The screen size if both dimensions are within `1..=MAX_SCREEN_SIZE`.
+///
+/// Spoofed sizes would otherwise create a visitor segment each, invalid
+/// ones are stored and hashed as `0x0` instead.
+pub(crate) fn valid_screen((width, height): (i32, i32)) -> Option<(i32, i32)> {
+    let range = 1..=MAX_SCREEN_SIZE;
+    (range.contains(&width) && range.contains(&height)).then_some((width, height))
+}
+
+/// Lower bounds of the downlink buckets in megabits per second.
+const DOWNLINK_BUCKETS: &[u16] = &[0, 1, 2, 5, 10, 20, 50, 100];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Connection {
+    pub effective_type: Option<EffectiveType>,
+    /// Lower bound in megabits per second of the estimated bandwidth, one
+    /// of 0, 1, 2, 5, 10, 20, 50 or 100.
+    pub downlink_bucket: Option<u16>,
+    /// Whether the user asked for reduced data usage.
+    pub save_data: Option<bool>,
+}
+
+impl From<&PubConnection> for Connection {
+    fn from(val: &PubConnection) -> Self {
+        Connection {
+            effective_type: val.effective_type.as_deref().and_then(EffectiveType::parse),
+            downlink_bucket: val
+                .downlink
+                .filter(|downlink| downlink.is_finite() && *downlink >= 0.0)
+                .map(|downlink| {
+                    DOWNLINK_BUCKETS
+                        .iter()
+                        .copied()
+                        .rfind(|bucket| f64::from(*bucket) <= downlink)
+                        .unwrap_or(0)
+                }),
+            save_data: val.save_data,
+        }
+    }
+}
+
+/// The `effectiveType` of the Network Information API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EffectiveType {
+    #[serde(rename = "slow-2g")]
+    Slow2g,
+    #[serde(rename = "2g")]
+    Cellular2g,
+    #[serde(rename = "3g")]
+    Cellular3g,
+    #[serde(rename = "4g")]
+    Cellular4g,
+}
+
+impl EffectiveType {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "slow-2g" => Some(EffectiveType::Slow2g),
+            "2g" => Some(EffectiveType::Cellular2g),
+            "3g" => Some(EffectiveType::Cellular3g),
+            "4g" => Some(EffectiveType::Cellular4g),
+            _ => None,
+        }
+    }
 }
 
 impl Visitor {
     pub fn new(project_id: i64, visitor: &PubVisitor, user_agent: &str) -> Self {
-        let mut val = Visitor {
-            project: project_id,
-            region: TIMEZONES.get(&visitor.tz).cloned().map(ToString::to_string),
+        Visitor::builder(project_id, visitor)
+            .user_agent(user_agent)
+            .build()
+    }
+
+    /// The city the timezone is named after, e.g. `Zurich` for
+    /// `Europe/Zurich` or `Buenos Aires` for `America/Argentina/Buenos_Aires`.
+    ///
+    /// A coarse hint that needs no IP lookup, the visitor may be anywhere in
+    /// the timezone. `None` for unknown zones and zones not named after a
+    /// place, e.g. `Etc/GMT+2`.
+    pub fn tz_city(&self) -> Option<Cow<'_, str>> {
+        if region_for_tz(&self.timezone).is_none() || self.timezone.starts_with("Antarctica/") {
+            return None;
+        }
+        let (_, city) = self.timezone.rsplit_once('/')?;
+        if city.contains('_') {
+            Some(Cow::Owned(city.replace('_', " ")))
+        } else {
+            Some(Cow::Borrowed(city))
+        }
+    }
+
+    /// English display name of the language, e.g. `German (Switzerland)`
+    /// for `de-CH`, see [`language_name`].
+    pub fn language_name(&self) -> Option<String> {
+        language_name(&self.language)
+    }
+
+    pub fn fields(&self) -> VisitorFields<'_> {
+        VisitorFields {
+            project: self.project,
+            region: self.region.as_deref(),
+            timezone: &self.timezone,
+            language: &self.language,
+            browser: self.browser.as_deref(),
+            platform: self.platform.as_deref(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Returns a builder to customize the individual enrichment steps.
+    ///
+    /// Without further configuration the user agent is not parsed and the
+    /// region is looked up from the timezone.
+    pub fn builder(project_id: i64, visitor: &PubVisitor) -> VisitorBuilder<'_> {
+        VisitorBuilder {
+            project_id,
+            visitor,
+            user_agent: None,
+            parsed_user_agent: None,
+            region: None,
+            salt: None,
+            hasher: None,
+            persistent_id: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VisitorBuilder<'a> {
+    project_id: i64,
+    visitor: &'a PubVisitor,
+    user_agent: Option<&'a str>,
+    parsed_user_agent: Option<UserAgent>,
+    region: Option<Option<Cow<'static, str>>>,
+    salt: Option<u64>,
+    hasher: Option<Box<dyn IdHasher>>,
+    persistent_id: Option<i64>,
+}
+
+impl<'a> VisitorBuilder<'a> {
+    /// Parses browser and platform from the given user agent.
+    pub fn user_agent(mut self, user_agent: &'a str) -> Self {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    /// Uses an already parsed user agent, e.g. from the cache only.
+    pub fn parsed_user_agent(mut self, user_agent: UserAgent) -> Self {
+        self.parsed_user_agent = Some(user_agent);
+        self
+    }
+
+    /// Uses a precomputed region instead of the timezone lookup.
+    /// Pass `None` to omit the region.
+    pub fn region(mut self, region: Option<Cow<'static, str>>) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    /// Mixes a salt into the visitor id.
+    pub fn salt(mut self, salt: u64) -> Self {
+        self.salt = Some(salt);
+        self
+    }
+
+    /// Computes the id with a custom hasher backend, e.g. a keyed one. The
+    /// id is tagged with [`CUSTOM_TAG`](id::CUSTOM_TAG) instead of a version.
+    pub fn hasher(mut self, hasher: impl IdHasher + 'static) -> Self {
+        self.hasher = Some(Box::new(hasher));
+        self
+    }
+
+    /// Uses the id of a visitor cookie instead of computing one, see
+    /// `cookie::VisitorCookie`.
+    pub fn persistent_id(mut self, id: i64) -> Self {
+        self.persistent_id = Some(id);
+        self
+    }
+
+    pub fn build(self) -> Visitor {
+        let visitor = self.visitor;
+        Visitor {
             timezone: visitor.tz.clone(),
             language: visitor.lang.clone(),
-            width: visitor.screen.0,
-            height: visitor.screen.1,
+            ..self.build_detached()
+        }
+    }
+
+    /// Like [`build`](Self::build), but leaves the timezone and language
+    /// empty for callers storing them elsewhere, e.g. `arena::ArenaVisitor`.
+    /// The id covers them all the same.
+    pub(crate) fn build_detached(self) -> Visitor {
+        let visitor = self.visitor;
+        let (region, low_confidence) = match self.region {
+            Some(region) => (region, false),
+            None => {
+                let (region, low_confidence) = region_for(&visitor.tz);
+                (region.map(Cow::Borrowed), low_confidence)
+            }
+        };
+        let subdivision = subdivision(&visitor.tz, region.as_deref()).map(Cow::Borrowed);
+        let screen = valid_screen(visitor.screen).unwrap_or((0, 0));
+        let mut val = Visitor {
+            project: self.project_id,
+            continent: continent(region.as_deref()).map(Cow::Borrowed),
+            is_eu: is_eu(region.as_deref()),
+            region,
+            subdivision,
+            low_confidence,
+            width: screen.0,
+            height: screen.1,
+            invalid_screen: screen != visitor.screen,
+            viewport_width: visitor.viewport.map(|(width, _)| width),
+            viewport_height: visitor.viewport.map(|(_, height)| height),
+            pixel_ratio: visitor.dpr,
+            prefers_color_scheme: visitor.prefers_color_scheme,
+            prefers_reduced_motion: visitor.prefers_reduced_motion,
+            connection: visitor.connection.as_ref().map(Connection::from),
             ..Default::default()
         };
 
-        let ua = UA_PARSER.parse(user_agent);
-        let browser = ua.user_agent.family.to_string();
-        if !browser.is_empty() {
-            val.browser = Some(browser);
+        let ua = self
+            .parsed_user_agent
+            .or_else(|| self.user_agent.map(|user_agent| UA_CACHE.parse(user_agent)));
+        if let Some(ua) = ua {
+            val.browser = ua.browser;
+            val.platform = ua.platform;
+            val.bogus_user_agent = ua.bogus;
+        }
+
+        let fields = VisitorFields {
+            timezone: &visitor.tz,
+            language: &visitor.lang,
+            ..val.fields()
         };
-        let platform = ua.os.family.to_string();
-        if !platform.is_empty() {
-            val.platform = Some(platform);
+        val.id = match (self.persistent_id, self.hasher) {
+            (Some(id), _) => id,
+            (None, Some(mut hasher)) => {
+                fields
+                    .for_version(IdVersion::CURRENT)
+                    .hash_with(&mut *hasher, self.salt);
+                id::tag_custom(hasher.finish())
+            }
+            (None, None) => fields.id(IdVersion::CURRENT, self.salt),
         };
+        val
+    }
+}
 
-        let mut hasher = Hasher::new();
-        hasher.write(val.project as u64);
-        if let Some(region) = &val.region {
-            hasher.write_bytes(region.as_bytes());
-        }
-        hasher.write_bytes(val.timezone.as_bytes());
-        hasher.write_bytes(val.language.as_bytes());
-        if let Some(browser) = &val.browser {
-            hasher.write_bytes(browser.as_bytes());
-        }
-        if let Some(platform) = &val.platform {
-            hasher.write_bytes(platform.as_bytes());
-        }
-        hasher.write(val.width as u64);
-        hasher.write(val.height as u64);
+/// Most populous region per UTC offset in minutes, by standard time, sorted.
+const OFFSET_REGIONS: &[(i32, &str)] = &[
+    (-660, "AS"),
+    (-600, "US"),
+    (-570, "PF"),
+    (-540, "US"),
+    (-480, "US"),
+    (-420, "US"),
+    (-360, "MX"),
+    (-300, "US"),
+    (-240, "VE"),
+    (-210, "CA"),
+    (-180, "BR"),
+    (-120, "BR"),
+    (-60, "CV"),
+    (0, "GB"),
+    (60, "NG"),
+    (120, "EG"),
+    (180, "RU"),
+    (210, "IR"),
+    (240, "AE"),
+    (270, "AF"),
+    (300, "PK"),
+    (330, "IN"),
+    (345, "NP"),
+    (360, "BD"),
+    (390, "MM"),
+    (420, "ID"),
+    (480, "CN"),
+    (525, "AU"),
+    (540, "JP"),
+    (570, "AU"),
+    (600, "AU"),
+    (630, "AU"),
+    (660, "SB"),
+    (720, "NZ"),
+    (765, "NZ"),
+    (780, "TO"),
+    (840, "KI"),
+];
+
+/// The region of an IANA timezone or, as a fallback, a UTC offset. The
+/// flag is set for offsets, see [`region_for_offset`].
+pub(crate) fn region_for(tz: &str) -> (Option<&'static str>, bool) {
+    match region_for_tz(tz) {
+        Some(region) => (Some(region), false),
+        None => match region_for_offset(tz) {
+            Some(region) => (Some(region), true),
+            None => (None, false),
+        },
+    }
+}
+
+/// The region of an IANA timezone, e.g. `CH` for `Europe/Zurich`.
+///
+/// Overrides set with [`set_timezone_overrides`] take precedence over the
+/// compiled `timezones.json`.
+pub fn region_for_tz(tz: &str) -> Option<&'static str> {
+    if let Some(region) = TIMEZONE_OVERRIDES.read().unwrap().get(tz) {
+        return *region;
+    }
+    TIMEZONES.get(tz).copied()
+}
+
+/// Replaces the runtime corrections of the timezone map, e.g. for zones
+/// added or countries renamed after the build. A `None` region removes the
+/// zone. Returns an error without changing anything if a region is unknown.
+pub fn set_timezone_overrides(
+    overrides: impl IntoIterator<Item = (String, Option<String>)>,
+) -> Result<(), Error> {
+    let overrides = overrides
+        .into_iter()
+        .map(|(tz, region)| {
+            let region = match region {
+                // interned to hand out static regions like the compiled map
+                Some(region) => Some(
+                    *REGIONS
+                        .get_key(region.as_str())
+                        .ok_or(Error::UnknownRegion(region))?,
+                ),
+                None => None,
+            };
+            Ok((tz, region))
+        })
+        .collect::<Result<_, Error>>()?;
+    *TIMEZONE_OVERRIDES.write().unwrap() = overrides;
+    Ok(())
+}
+
+/// Reads [timezone overrides](set_timezone_overrides) from a JSON file in
+/// the format of `timezones.json`, with `null` to remove a zone. Returns
+/// the number of overrides.
+pub fn load_timezone_overrides(path: impl AsRef<Path>) -> Result<usize, Error> {
+    let file = std::fs::File::open(path)?;
+    let overrides: HashMap<String, Option<String>> =
+        serde_json::from_reader(std::io::BufReader::new(file))?;
+    let len = overrides.len();
+    set_timezone_overrides(overrides)?;
+    Ok(len)
+}
+
+/// English display name of a BCP 47 language tag like `de-CH` or
+/// `zh-Hant-TW`. Script and variant subtags are ignored, unknown regions
+/// are left out. `None` if the language is unknown.
+pub fn language_name(tag: &str) -> Option<String> {
+    let mut subtags = tag.trim().split(['-', '_']);
+    let language = subtags.next()?.to_ascii_lowercase();
+    let name = LANGUAGE_NAMES.get(&language)?;
+    let region = subtags
+        .find(|subtag| {
+            subtag.len() == 2 || (subtag.len() == 3 && subtag.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .and_then(|region| REGION_NAMES.get(&region.to_ascii_uppercase()));
+    Some(match region {
+        Some(region) => format!("{name} ({region})"),
+        None => name.to_string(),
+    })
+}
+
+/// Best-effort region for a UTC offset like `+02:00`, `-0530` or
+/// `UTC+2`: the most populous country using it as standard time.
+///
+/// Offsets say little about the location, e.g. Switzerland is at `+02:00`
+/// in summer, so use the result for coarse reports only.
+pub fn region_for_offset(tz: &str) -> Option<&'static str> {
+    let minutes = parse_offset(tz)?;
+    let idx = OFFSET_REGIONS
+        .binary_search_by_key(&minutes, |(offset, _)| *offset)
+        .ok()?;
+    Some(OFFSET_REGIONS[idx].1)
+}
+
+/// Minutes east of UTC.
+fn parse_offset(tz: &str) -> Option<i32> {
+    let tz = tz.trim();
+    let tz = tz
+        .strip_prefix("UTC")
+        .or_else(|| tz.strip_prefix("GMT"))
+        .unwrap_or(tz);
+    let (sign, rest) = match tz.as_bytes().first()? {
+        b'+' => (1, &tz[1..]),
+        b'-' => (-1, &tz[1..]),
+        _ => return None,
+    };
+    if rest.is_empty() || !rest.bytes().all(|b| b.is_ascii_digit() || b == b':') {
+        return None;
+    }
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if rest.len() > 2 => rest.split_at(rest.len() - 2),
+        None => (rest, "0"),
+    };
+    let (hours, minutes): (i32, i32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+    Some(sign * (hours * 60 + minutes))
+}
+
+/// The subdivision implied by a timezone, if it lies in `region`.
+///
+/// Regions supplied by other means, e.g. an IP lookup, may contradict the
+/// timezone, the subdivision is omitted then.
+pub(crate) fn subdivision(tz: &str, region: Option<&str>) -> Option<&'static str> {
+    let code = SUBDIVISIONS.get(tz).copied()?;
+    let (country, _) = code.split_once('-')?;
+    (Some(country) == region).then_some(code)
+}
+
+/// Member states of the European Union, sorted.
+const EU_MEMBERS: &[&str] = &[
+    "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "ES", "FI", "FR", "GR", "HR", "HU", "IE", "IT",
+    "LT", "LU", "LV", "MT", "NL", "PL", "PT", "RO", "SE", "SI", "SK",
+];
 
-        val.id = hasher.finalize() as i64;
+pub(crate) fn continent(region: Option<&str>) -> Option<&'static str> {
+    CONTINENTS.get(region?).copied()
+}
+
+pub(crate) fn is_eu(region: Option<&str>) -> bool {
+    region.is_some_and(|region| EU_MEMBERS.binary_search(&region).is_ok())
+}
+
+/// Borrowed inputs of the visitor id.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VisitorFields<'a> {
+    pub project: i64,
+    pub region: Option<&'a str>,
+    pub timezone: &'a str,
+    pub language: &'a str,
+    pub browser: Option<&'a str>,
+    pub platform: Option<&'a str>,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl VisitorFields<'_> {
+    pub fn id(&self, version: IdVersion, salt: Option<u64>) -> i64 {
+        let mut hasher = version.hasher();
+        self.for_version(version).hash_with(&mut hasher, salt);
+        version.tag(hasher.finalize())
+    }
+
+    /// The fields as hashed by `version`, see [`IdVersion::V3`].
+    pub fn for_version(&self, version: IdVersion) -> Self {
+        let mut val = *self;
+        if version >= IdVersion::V3 {
+            val.width = self.width.min(self.height);
+            val.height = self.width.max(self.height);
+        }
         val
     }
+
+    /// Feeds the fields into a custom hasher backend.
+    pub fn hash_with<H: IdHasher + ?Sized>(&self, hasher: &mut H, salt: Option<u64>) {
+        hasher.write(self.project as u64);
+        if let Some(salt) = salt {
+            hasher.write(salt);
+        }
+        hasher.write_optional_bytes(self.region.map(str::as_bytes));
+        hasher.write_bytes(self.timezone.as_bytes());
+        hasher.write_bytes(self.language.as_bytes());
+        hasher.write_optional_bytes(self.browser.map(str::as_bytes));
+        hasher.write_optional_bytes(self.platform.map(str::as_bytes));
+        hasher.write(self.width as u64);
+        hasher.write(self.height as u64);
+    }
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Page {
     pub id: i64,
     pub project: i64,
     pub domain: String,
+    /// At most [`MAX_PATH_LEN`] characters.
     pub path: String,
+    /// The url the page was viewed at, if the page is identified by its
+    /// canonical url. At most [`MAX_URL_LEN`] characters.
+    pub actual_url: Option<String>,
+}
+
+/// Longest domain of a page or referrer, the limit of DNS names. Pages
+/// with longer domains fail with [`Error::TooLong`].
+pub const MAX_DOMAIN_LEN: usize = 253;
+
+/// Longest page path in characters. Longer paths are cut and end with the
+/// [`TRUNCATION_MARKER`](utm::TRUNCATION_MARKER) before they are hashed, so
+/// abused urls neither bloat the storage nor create unbounded numbers of
+/// page ids.
+pub const MAX_PATH_LEN: usize = 1024;
+
+/// Longest url in characters kept as [`Page::actual_url`], cut like paths.
+pub const MAX_URL_LEN: usize = 2048;
+
+/// Decodes the percent-encoded unreserved characters and UTF-8 sequences of
+/// a path and composes it to Unicode NFC, so `/caf%C3%A9` and `/cafe%CC%81`
+/// both become `/café`.
+///
+/// Other characters, e.g. `%2F`, `%3F` or `%23`, stay encoded as decoding
+/// them would change how the path splits. Paths that don't decode to UTF-8
+/// are kept encoded. Pages store the path of their url as is, only ids of
+/// [`IdVersion::V4`] on hash it normalized.
+pub fn normalize_path(path: &str) -> Cow<'_, str> {
+    const NFC: ComposingNormalizerBorrowed<'static> = ComposingNormalizerBorrowed::new_nfc();
+    match decode_unreserved(path) {
+        Cow::Borrowed(path) => NFC.normalize(path),
+        Cow::Owned(path) => Cow::Owned(NFC.normalize(&path).into_owned()),
+    }
+}
+
+/// Decodes the unreserved characters of RFC 3986 and the non-ASCII bytes of
+/// `path`, the hex digits of the triplets kept are uppercased.
+fn decode_unreserved(path: &str) -> Cow<'_, str> {
+    if !path.contains('%') {
+        return Cow::Borrowed(path);
+    }
+    let hex = |digit: u8| (digit as char).to_digit(16).map(|digit| digit as u8);
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = match bytes[i..] {
+            [b'%', high, low, ..] => hex(high).zip(hex(low)).map(|(high, low)| (high << 4) | low),
+            _ => None,
+        };
+        match byte {
+            Some(byte)
+                if !byte.is_ascii() || byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) =>
+            {
+                decoded.push(byte);
+                i += 3;
+            }
+            Some(_) => {
+                decoded.push(b'%');
+                decoded.extend(bytes[i + 1..i + 3].iter().map(u8::to_ascii_uppercase));
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_or(Cow::Borrowed(path), Cow::Owned)
 }
 
 impl Page {
     /// Returns an error if the url has no valid domain.
+    ///
+    /// Views of AMP cache urls are attributed to the origin page. Paths
+    /// longer than [`MAX_PATH_LEN`] are truncated.
     pub fn new(project_id: i64, url: &Url) -> Result<Self, Error> {
-        let mut val = Page {
+        Page::with_canonical(project_id, url, None)
+    }
+
+    /// Like [`Page::new`], but identifies the page by its canonical url,
+    /// so e.g. paginated pages roll up into one.
+    ///
+    /// The canonical url is ignored unless it has the same origin.
+    pub fn with_canonical(
+        project_id: i64,
+        url: &Url,
+        canonical: Option<&Url>,
+    ) -> Result<Self, Error> {
+        let origin = amp::origin(url);
+        let (domain, path, actual_url) = Page::parts(url, canonical, origin.as_ref())?;
+        Ok(Page {
+            id: Page::compute_id(IdVersion::CURRENT, project_id, domain, &path),
             project: project_id,
-            ..Default::default()
+            domain: domain.to_string(),
+            path: path.into_owned(),
+            actual_url: actual_url.map(Cow::into_owned),
+        })
+    }
+
+    /// Domain, path and actual url of a page viewed at `url`, borrowed
+    /// where not truncated. `origin` is the [AMP origin](amp::origin) of
+    /// `url`.
+    pub(crate) fn parts<'u>(
+        url: &'u Url,
+        canonical: Option<&'u Url>,
+        origin: Option<&'u Url>,
+    ) -> Result<(&'u str, Cow<'u, str>, Option<Cow<'u, str>>), Error> {
+        let actual = origin.unwrap_or(url);
+        let (url, actual_url) = match canonical {
+            Some(canonical) if canonical.origin() == actual.origin() && canonical != actual => {
+                (canonical, Some(utm::truncate(actual.as_str(), MAX_URL_LEN)))
+            }
+            _ => (actual, None),
         };
-        val.domain = url
-            .domain()
-            .ok_or(Error::Missing("domain".to_string()))?
-            .to_string();
-        val.path = url.path().to_string();
-
-        let mut hasher = Hasher::new();
-        hasher.write(val.project as u64);
-        hasher.write_bytes(val.domain.as_bytes());
-        hasher.write_bytes(val.path.as_bytes());
-
-        val.id = hasher.finalize() as i64;
-        Ok(val)
+        let domain = url.domain().ok_or(Error::Missing("domain".to_string()))?;
+        if domain.len() > MAX_DOMAIN_LEN {
+            return Err(Error::TooLong("domain"));
+        }
+        Ok((domain, utm::truncate(url.path(), MAX_PATH_LEN), actual_url))
+    }
+
+    /// Paths are [normalized](normalize_path) from [`IdVersion::V4`] on.
+    pub fn compute_id(version: IdVersion, project_id: i64, domain: &str, path: &str) -> i64 {
+        let path = if version >= IdVersion::V4 {
+            normalize_path(path)
+        } else {
+            Cow::Borrowed(path)
+        };
+        let mut hasher = version.hasher();
+        Page::hash_with(&mut hasher, project_id, domain, &path);
+        version.tag(hasher.finalize())
+    }
+
+    /// Feeds the id inputs into a custom hasher backend.
+    pub fn hash_with<H: IdHasher + ?Sized>(
+        hasher: &mut H,
+        project_id: i64,
+        domain: &str,
+        path: &str,
+    ) {
+        hasher.write(project_id as u64);
+        hasher.write_bytes(domain.as_bytes());
+        hasher.write_bytes(path.as_bytes());
     }
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct UtmParam {
     pub id: i64,
     pub project: i64,
@@ -125,68 +799,62 @@ pub struct UtmParam {
 
 impl UtmParam {
     pub fn new(project_id: i64, url: &Url) -> Option<Self> {
+        UtmParam::parse(project_id, url, &UtmOptions::default())
+    }
+
+    /// Reads the campaign parameters of `url`, see [`utm`].
+    pub fn parse(project_id: i64, url: &Url, options: &UtmOptions) -> Option<Self> {
         let mut val = UtmParam {
             project: project_id,
             ..Default::default()
         };
 
         let mut found_any = false;
-
-        for (key, value) in url.query_pairs() {
-            match &*key {
-                "campaign" => {
-                    val.campaign = Some(value.to_string());
-                    found_any = true;
-                }
-                "content" => {
-                    val.content = Some(value.to_string());
-                    found_any = true;
-                }
-                "medium" => {
-                    val.medium = Some(value.to_string());
-                    found_any = true;
-                }
-                "source" => {
-                    val.source = Some(value.to_string());
-                    found_any = true;
-                }
-                "term" => {
-                    val.term = Some(value.to_string());
-                    found_any = true;
-                }
-                _ => {}
+        for (field, values) in utm::values(url, options) {
+            if let Some(value) = options.repeated.resolve(&values) {
+                *val.field_mut(field) = Some(utm::truncate(&value, options.max_len).into_owned());
+                found_any = true;
             }
         }
 
         if found_any {
-            let mut hasher = Hasher::new();
-            hasher.write(val.project as u64);
-
-            if let Some(campaign) = &val.campaign {
-                hasher.write_bytes(campaign.as_bytes());
-            }
-            if let Some(content) = &val.content {
-                hasher.write_bytes(content.as_bytes());
-            }
-            if let Some(medium) = &val.medium {
-                hasher.write_bytes(medium.as_bytes());
-            }
-            if let Some(source) = &val.source {
-                hasher.write_bytes(source.as_bytes());
-            }
-            if let Some(term) = &val.term {
-                hasher.write_bytes(term.as_bytes());
-            }
-
-            val.id = hasher.finalize() as i64;
+            val.id = val.compute_id(IdVersion::CURRENT);
             Some(val)
         } else {
             None
         }
     }
+
+    pub fn field_mut(&mut self, field: UtmField) -> &mut Option<String> {
+        match field {
+            UtmField::Campaign => &mut self.campaign,
+            UtmField::Content => &mut self.content,
+            UtmField::Medium => &mut self.medium,
+            UtmField::Source => &mut self.source,
+            UtmField::Term => &mut self.term,
+        }
+    }
+
+    /// Computes the id from the parameters with the given scheme version.
+    pub fn compute_id(&self, version: IdVersion) -> i64 {
+        let mut hasher = version.hasher();
+        self.hash_with(&mut hasher);
+        version.tag(hasher.finalize())
+    }
+
+    /// Feeds the parameters into a custom hasher backend.
+    pub fn hash_with<H: IdHasher + ?Sized>(&self, hasher: &mut H) {
+        hasher.write(self.project as u64);
+
+        hasher.write_optional_bytes(self.campaign.as_deref().map(str::as_bytes));
+        hasher.write_optional_bytes(self.content.as_deref().map(str::as_bytes));
+        hasher.write_optional_bytes(self.medium.as_deref().map(str::as_bytes));
+        hasher.write_optional_bytes(self.source.as_deref().map(str::as_bytes));
+        hasher.write_optional_bytes(self.term.as_deref().map(str::as_bytes));
+    }
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Referrer {
     pub id: i64,
     pub project: i64,
@@ -195,27 +863,43 @@ pub struct Referrer {
 
 impl Referrer {
     pub fn new(project_id: i64, referrer: Option<&Url>, host: &str) -> Option<Self> {
-        let referrer = referrer?.domain()?.to_lowercase();
-        if referrer == host.to_lowercase() {
-            return None;
-        }
-
-        let mut val = Referrer {
+        let domain = Referrer::domain(referrer, host)?.into_owned();
+        Some(Referrer {
+            id: Referrer::compute_id(IdVersion::CURRENT, project_id, &domain),
             project: project_id,
-            domain: referrer,
-            ..Default::default()
+            domain,
+        })
+    }
+
+    /// The lowercased domain of `referrer`, `None` if it is the `host`
+    /// itself.
+    pub(crate) fn domain<'u>(referrer: Option<&'u Url>, host: &str) -> Option<Cow<'u, str>> {
+        let domain = referrer?.domain()?;
+        let domain = if domain
+            .bytes()
+            .any(|b| !b.is_ascii() || b.is_ascii_uppercase())
+        {
+            Cow::Owned(domain.to_lowercase())
+        } else {
+            Cow::Borrowed(domain)
         };
+        (*domain != host.to_lowercase() && domain.len() <= MAX_DOMAIN_LEN).then_some(domain)
+    }
 
-        let mut hasher = Hasher::new();
-        hasher.write(val.project as u64);
-        hasher.write_bytes(val.domain.as_bytes());
+    pub fn compute_id(version: IdVersion, project_id: i64, domain: &str) -> i64 {
+        let mut hasher = version.hasher();
+        Referrer::hash_with(&mut hasher, project_id, domain);
+        version.tag(hasher.finalize())
+    }
 
-        val.id = hasher.finalize() as i64;
-        Some(val)
+    /// Feeds the id inputs into a custom hasher backend.
+    pub fn hash_with<H: IdHasher + ?Sized>(hasher: &mut H, project_id: i64, domain: &str) {
+        hasher.write(project_id as u64);
+        hasher.write_bytes(domain.as_bytes());
     }
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Visit {
     pub time: DateTime<Utc>,
     pub project: i64,
@@ -224,8 +908,24 @@ pub struct Visit {
     pub page: Page,
     pub utm_param: Option<UtmParam>,
     pub referrer: Option<Referrer>,
+    /// Time on page in seconds.
     pub duration: Option<i32>,
     pub distance: Option<f64>,
+    /// Identifier of the embedded widget, `page` is the embedding page.
+    pub embed: Option<String>,
+    /// Whether this is the first view of the page within the session,
+    /// `None` if the session is not tracked.
+    pub is_unique_in_session: Option<bool>,
+    /// Page viewed before this one in the same session.
+    pub previous_page_id: Option<i64>,
+    /// Marketing channel the visit came from, e.g. `Organic Search`, see
+    /// [`channel`].
+    #[serde(default)]
+    pub channel_group: Option<String>,
+    /// Processed for verification only, must not be stored, see
+    /// [`api::handle_dry_run`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
 }
 
 impl Visit {
@@ -238,7 +938,7 @@ impl Visit {
         referrer: Option<Referrer>,
     ) -> Self {
         Visit {
-            time: Utc::now(),
+            time: clock::now(),
             project: project_id,
             session,
             visitor,
@@ -248,17 +948,67 @@ impl Visit {
             ..Default::default()
         }
     }
+
+    /// Time-on-page bucket derived from `duration`, if known.
+    pub fn duration_bucket(&self) -> Option<DurationBucket> {
+        self.duration.map(DurationBucket::new)
+    }
 }
 
-#[derive(Debug, Default, Clone)]
+/// Coarse time-on-page ranges used for engagement histograms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DurationBucket {
+    /// Less than 10 seconds.
+    UpTo10s,
+    /// 10 to 30 seconds.
+    UpTo30s,
+    /// 30 to 60 seconds.
+    UpTo1m,
+    /// 1 to 3 minutes.
+    UpTo3m,
+    /// 3 minutes or more.
+    Over3m,
+}
+
+impl DurationBucket {
+    /// Buckets a duration given in seconds.
+    pub fn new(seconds: i32) -> Self {
+        match seconds {
+            ..=9 => DurationBucket::UpTo10s,
+            10..=29 => DurationBucket::UpTo30s,
+            30..=59 => DurationBucket::UpTo1m,
+            60..=179 => DurationBucket::UpTo3m,
+            _ => DurationBucket::Over3m,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DurationBucket::UpTo10s => "0-10s",
+            DurationBucket::UpTo30s => "10-30s",
+            DurationBucket::UpTo1m => "30-60s",
+            DurationBucket::UpTo3m => "1-3m",
+            DurationBucket::Over3m => "3m+",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Event {
     pub time: DateTime<Utc>,
     pub project: i64,
     pub session: i64,
     pub visitor: Visitor,
     pub page: Page,
+    /// Campaign the session was acquired by, see
+    /// [`SessionTracker::attribute_event`](session::SessionTracker::attribute_event).
+    pub utm_param: Option<UtmParam>,
     pub name: String,
     pub data: Value,
+    /// Processed for verification only, must not be stored, see
+    /// [`api::handle_dry_run`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
 }
 
 impl Event {
@@ -271,17 +1021,205 @@ impl Event {
         data: Value,
     ) -> Self {
         Event {
-            time: Utc::now(),
+            time: clock::now(),
             project: project_id,
             session,
             visitor,
             page,
+            utm_param: None,
             name,
             data,
+            dry_run: false,
+        }
+    }
+
+    /// Normalizes an event name so downstream grouping stays stable.
+    ///
+    /// Surrounding whitespace is trimmed, inner whitespace is replaced with
+    /// `_` and the name is lowercased. Names that are empty, longer than
+    /// [`MAX_EVENT_NAME_LEN`], contain characters other than ascii
+    /// alphanumerics and `_-.:` or start with a reserved prefix are rejected.
+    pub fn normalize_name(name: &str) -> Result<Cow<'_, str>, Error> {
+        let trimmed = name.trim();
+        let is_normalized = trimmed.len() == name.len()
+            && !name
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_ascii_uppercase());
+        let val = if is_normalized {
+            Cow::Borrowed(name)
+        } else {
+            Cow::Owned(
+                trimmed
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join("_")
+                    .to_ascii_lowercase(),
+            )
+        };
+
+        if val.is_empty() {
+            return Err(Error::InvalidEventName("empty"));
+        }
+        if val.len() > MAX_EVENT_NAME_LEN {
+            return Err(Error::InvalidEventName("too long"));
+        }
+        if !val
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c))
+        {
+            return Err(Error::InvalidEventName("invalid character"));
+        }
+        if RESERVED_EVENT_PREFIXES
+            .iter()
+            .any(|prefix| val.starts_with(prefix))
+        {
+            return Err(Error::InvalidEventName("reserved prefix"));
+        }
+        Ok(val)
+    }
+
+    /// Looks up a value in the event data by [JSON pointer], e.g. `/cart/total`.
+    ///
+    /// [JSON pointer]: https://datatracker.ietf.org/doc/html/rfc6901
+    pub fn get(&self, pointer: &str) -> Option<&Value> {
+        self.data.pointer(pointer)
+    }
+
+    /// Like [`Event::get`] but only returns numbers.
+    pub fn get_number(&self, pointer: &str) -> Option<f64> {
+        self.get(pointer)?.as_f64()
+    }
+
+    /// Like [`Event::get`] but only returns strings.
+    pub fn get_string(&self, pointer: &str) -> Option<&str> {
+        self.get(pointer)?.as_str()
+    }
+
+    pub fn flat_data(&self) -> FlatEventData {
+        FlatEventData::new(&self.data)
+    }
+}
+
+pub const MAX_EVENT_NAME_LEN: usize = 64;
+
+/// Prefixes reserved for events emitted by the collector itself.
+pub const RESERVED_EVENT_PREFIXES: &[&str] = &["_", "abineo:"];
+
+/// Links a logged-in user to the visitor of a session, see
+/// [`handle_identify`](api::handle_identify).
+///
+/// Only the visitor id is kept, so joining product data reveals no more
+/// than the visits already do.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub time: DateTime<Utc>,
+    pub project: i64,
+    pub session: i64,
+    pub visitor: i64,
+    /// Hash of the user id, lowercase hex.
+    pub user: String,
+}
+
+impl Identity {
+    /// Checks that `user` looks like a hex digest of at least
+    /// [`MIN_USER_HASH_LEN`] digits, so plain ids or emails are rejected.
+    pub fn normalize_user(user: &str) -> Result<String, Error> {
+        let user = user.trim();
+        if user.len() < MIN_USER_HASH_LEN || user.len() > MAX_USER_HASH_LEN {
+            return Err(Error::InvalidUserHash("length"));
+        }
+        if !user.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::InvalidUserHash("not hex"));
+        }
+        Ok(user.to_ascii_lowercase())
+    }
+}
+
+/// Minimum length of a hashed user id, 128 bits in hex.
+pub const MIN_USER_HASH_LEN: usize = 32;
+
+/// Maximum length of a hashed user id, 512 bits in hex.
+pub const MAX_USER_HASH_LEN: usize = 128;
+
+/// Output of the batch handlers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Record {
+    Visit(Visit),
+    Event(Event),
+    /// Emitted when a session expired, see
+    /// [`SessionTracker::expire`](session::SessionTracker::expire).
+    Session(SessionSummary),
+}
+
+impl Record {
+    pub fn time(&self) -> DateTime<Utc> {
+        match self {
+            Record::Visit(visit) => visit.time,
+            Record::Event(event) => event.time,
+            Record::Session(summary) => summary.time,
+        }
+    }
+
+    pub fn project(&self) -> i64 {
+        match self {
+            Record::Visit(visit) => visit.project,
+            Record::Event(event) => event.project,
+            Record::Session(summary) => summary.project,
+        }
+    }
+
+    pub fn visitor(&self) -> &Visitor {
+        match self {
+            Record::Visit(visit) => &visit.visitor,
+            Record::Event(event) => &event.visitor,
+            Record::Session(summary) => &summary.visitor,
+        }
+    }
+
+    pub fn visitor_mut(&mut self) -> &mut Visitor {
+        match self {
+            Record::Visit(visit) => &mut visit.visitor,
+            Record::Event(event) => &mut event.visitor,
+            Record::Session(summary) => &mut summary.visitor,
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        match self {
+            Record::Visit(visit) => visit.dry_run,
+            Record::Event(event) => event.dry_run,
+            Record::Session(_) => false,
+        }
+    }
+
+    pub(crate) fn mark_dry_run(&mut self) {
+        match self {
+            Record::Visit(visit) => visit.dry_run = true,
+            Record::Event(event) => event.dry_run = true,
+            Record::Session(_) => {}
         }
     }
 }
 
+impl From<Visit> for Record {
+    fn from(visit: Visit) -> Self {
+        Record::Visit(visit)
+    }
+}
+
+impl From<Event> for Record {
+    fn from(event: Event) -> Self {
+        Record::Event(event)
+    }
+}
+
+impl From<SessionSummary> for Record {
+    fn from(summary: SessionSummary) -> Self {
+        Record::Session(summary)
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("missing {0}")]
@@ -289,12 +1227,100 @@ pub enum Error {
 
     #[error(transparent)]
     ParseIntError(#[from] std::num::ParseIntError),
+
+    #[error("invalid event name: {0}")]
+    InvalidEventName(&'static str),
+
+    #[error("invalid revenue: {0}")]
+    InvalidRevenue(&'static str),
+
+    #[error("incompatible sketch dimensions")]
+    IncompatibleSketch,
+
+    #[error("invalid linker token: {0}")]
+    InvalidLinker(&'static str),
+
+    #[error("invalid cost in row {row}: {reason}")]
+    InvalidCost { row: usize, reason: &'static str },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error("pipeline overloaded")]
+    Overloaded,
+
+    #[error("pipeline closed")]
+    Closed,
+
+    #[error("webhook responded with status {0}")]
+    Webhook(u16),
+
+    #[error("unknown region {0}")]
+    UnknownRegion(String),
+
+    #[error("unsupported payload version {0}")]
+    UnsupportedVersion(u64),
+
+    #[error("raw beacon was truncated")]
+    Truncated,
+
+    /// Domains longer than [`MAX_DOMAIN_LEN`] are rejected rather than
+    /// truncated with a marker like paths, as a cut domain names another
+    /// site.
+    #[error("{0} too long")]
+    TooLong(&'static str),
+
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("invalid visitor cookie: {0}")]
+    InvalidCookie(&'static str),
+
+    #[error("invalid user hash: {0}")]
+    InvalidUserHash(&'static str),
+
+    #[error("{0} is not valid UTF-8")]
+    InvalidUtf8(&'static str),
+
+    #[error("project paused")]
+    ProjectPaused,
+
+    #[error("quota exceeded")]
+    QuotaExceeded,
+
+    #[error("circuit open")]
+    CircuitOpen,
+
+    #[error("invalid user agent regexes: {0}")]
+    UaRegexes(String),
+
+    #[error("incompatible aggregate resolutions")]
+    IncompatibleAggregate,
+
+    #[cfg(feature = "sqlite")]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[cfg(feature = "duckdb")]
+    #[error(transparent)]
+    DuckDb(#[from] duckdb::Error),
+
+    #[cfg(feature = "redis")]
+    #[error(transparent)]
+    Redis(#[from] redis::RedisError),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(not(feature = "minimal"))]
     #[test]
     fn smoke_test_timezones_map() {
         let ch = TIMEZONES
@@ -304,11 +1330,443 @@ mod tests {
         assert_eq!(ch, "CH");
     }
 
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn subdivisions_from_timezone() {
+        assert!(SUBDIVISIONS
+            .entries()
+            .all(|(tz, code)| code.starts_with(TIMEZONES[tz])));
+        assert_eq!(
+            subdivision("America/Indiana/Indianapolis", Some("US")),
+            Some("US-IN")
+        );
+        assert_eq!(subdivision("America/New_York", Some("US")), None);
+        assert_eq!(subdivision("Australia/Sydney", Some("NZ")), None);
+
+        let visitor = PubVisitor {
+            tz: "America/Toronto".to_string(),
+            lang: "en-CA".to_string(),
+            screen: (1920, 1080),
+            ..Default::default()
+        };
+        let built = Visitor::builder(1, &visitor).build();
+        assert_eq!(built.subdivision.as_deref(), Some("CA-ON"));
+        let overridden = Visitor::builder(1, &visitor)
+            .region(Some("US".into()))
+            .build();
+        assert_eq!(overridden.subdivision, None);
+    }
+
+    #[test]
+    fn timezone_overrides() {
+        let path = std::env::temp_dir().join(format!("timezones-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"Test/Added": "UA", "Test/Removed": null}"#).unwrap();
+        assert_eq!(load_timezone_overrides(&path).unwrap(), 2);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(region_for_tz("Test/Added"), Some("UA"));
+        assert_eq!(region_for_tz("Test/Removed"), None);
+        #[cfg(not(feature = "minimal"))]
+        assert_eq!(region_for_tz("Europe/Zurich"), Some("CH"));
+        assert!(matches!(
+            set_timezone_overrides([("Test/Added".to_string(), Some("XX".to_string()))]),
+            Err(Error::UnknownRegion(_))
+        ));
+        assert_eq!(region_for_tz("Test/Added"), Some("UA"));
+
+        set_timezone_overrides([]).unwrap();
+        assert_eq!(region_for_tz("Test/Added"), None);
+    }
+
+    #[test]
+    fn viewport_is_not_hashed() {
+        let mut visitor: PubVisitor = serde_json::from_str(
+            r#"{"tz": "Europe/Zurich", "screen": [1920, 1080], "viewport": [1280, 720], "dpr": 2}"#,
+        )
+        .unwrap();
+        let built = Visitor::builder(1, &visitor).build();
+        assert_eq!(built.viewport_width, Some(1280));
+        assert_eq!(built.viewport_height, Some(720));
+        assert_eq!(built.pixel_ratio, Some(2.0));
+
+        visitor.viewport = None;
+        visitor.dpr = None;
+        assert_eq!(Visitor::builder(1, &visitor).build().id, built.id);
+    }
+
+    #[test]
+    fn preferences_are_not_hashed() {
+        let mut visitor: PubVisitor = serde_json::from_str(
+            r#"{"tz": "Europe/Zurich", "prefers_color_scheme": "dark", "prefers_reduced_motion": true}"#,
+        )
+        .unwrap();
+        let built = Visitor::builder(1, &visitor).build();
+        assert_eq!(built.prefers_color_scheme, Some(ColorScheme::Dark));
+        assert_eq!(built.prefers_reduced_motion, Some(true));
+
+        visitor.prefers_color_scheme = None;
+        visitor.prefers_reduced_motion = None;
+        assert_eq!(Visitor::builder(1, &visitor).build().id, built.id);
+    }
+
+    #[test]
+    fn buckets_connection() {
+        let visitor: PubVisitor = serde_json::from_str(
+            r#"{"connection": {"effectiveType": "3g", "downlink": 1.45, "saveData": true}}"#,
+        )
+        .unwrap();
+        let built = Visitor::builder(1, &visitor).build();
+        assert_eq!(
+            built.connection,
+            Some(Connection {
+                effective_type: Some(EffectiveType::Cellular3g),
+                downlink_bucket: Some(1),
+                save_data: Some(true),
+            })
+        );
+        assert_eq!(
+            Visitor::builder(1, &PubVisitor::default()).build().id,
+            built.id
+        );
+
+        let connection = |effective_type: &str, downlink| {
+            Connection::from(&PubConnection {
+                effective_type: Some(effective_type.to_string()),
+                downlink: Some(downlink),
+                save_data: None,
+            })
+        };
+        assert_eq!(connection("5g", 0.2).effective_type, None);
+        assert_eq!(connection("4g", 0.2).downlink_bucket, Some(0));
+        assert_eq!(connection("4g", 10.0).downlink_bucket, Some(10));
+        assert_eq!(connection("4g", 1000.0).downlink_bucket, Some(100));
+        assert_eq!(connection("4g", -1.0).downlink_bucket, None);
+    }
+
+    #[test]
+    fn rejects_implausible_screens() {
+        let visitor = |screen| PubVisitor {
+            tz: "Europe/Zurich".to_string(),
+            screen,
+            ..Default::default()
+        };
+        let valid = Visitor::builder(1, &visitor((1920, 1080))).build();
+        assert!(!valid.invalid_screen);
+        assert_eq!((valid.width, valid.height), (1920, 1080));
+
+        let spoofed: Vec<Visitor> = [(0, 1080), (-1, -1), (1920, 20_001), (i32::MAX, 1)]
+            .into_iter()
+            .map(|screen| Visitor::builder(1, &visitor(screen)).build())
+            .collect();
+        assert!(spoofed.iter().all(|visitor| visitor.invalid_screen
+            && (visitor.width, visitor.height) == (0, 0)
+            && visitor.id == spoofed[0].id));
+        assert_ne!(spoofed[0].id, valid.id);
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn language_display_names() {
+        assert!(TIMEZONES
+            .values()
+            .all(|region| REGION_NAMES.contains_key(region)));
+        assert_eq!(
+            language_name("de-CH").as_deref(),
+            Some("German (Switzerland)")
+        );
+        assert_eq!(language_name("FR").as_deref(), Some("French"));
+        assert_eq!(
+            language_name("zh-Hant-TW").as_deref(),
+            Some("Chinese (Taiwan)")
+        );
+        assert_eq!(
+            language_name("es_419").as_deref(),
+            Some("Spanish (Latin America)")
+        );
+        assert_eq!(language_name("en-XX").as_deref(), Some("English"));
+        assert_eq!(language_name("xx-CH"), None);
+        assert_eq!(language_name(""), None);
+    }
+
+    #[test]
+    fn region_from_offset() {
+        assert!(OFFSET_REGIONS.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(parse_offset("+02:00"), Some(120));
+        assert_eq!(parse_offset("-0530"), Some(-330));
+        assert_eq!(parse_offset("UTC+2"), Some(120));
+        assert_eq!(parse_offset("GMT-03:30"), Some(-210));
+        assert_eq!(parse_offset("Europe/Zurich"), None);
+        assert_eq!(parse_offset("+15:00"), None);
+        assert_eq!(parse_offset("+"), None);
+        assert_eq!(region_for_offset("+05:30"), Some("IN"));
+        assert_eq!(region_for_offset("+05:10"), None);
+
+        let visitor: PubVisitor = serde_json::from_str(r#"{"tz": -330}"#).unwrap();
+        assert_eq!(visitor.tz, "+05:30");
+        let visitor: PubVisitor = serde_json::from_str(r#"{"tz": 300}"#).unwrap();
+        assert_eq!(visitor.tz, "-05:00");
+
+        let built = Visitor::builder(1, &visitor).build();
+        assert_eq!(built.region.as_deref(), Some("US"));
+        assert!(built.low_confidence);
+        let visitor = PubVisitor {
+            tz: "Europe/Zurich".to_string(),
+            ..Default::default()
+        };
+        assert!(!Visitor::builder(1, &visitor).build().low_confidence);
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn city_from_timezone() {
+        let city = |tz: &str| {
+            Visitor {
+                timezone: tz.to_string(),
+                ..Default::default()
+            }
+            .tz_city()
+            .map(Cow::into_owned)
+        };
+        assert_eq!(city("Europe/Zurich").as_deref(), Some("Zurich"));
+        assert_eq!(
+            city("America/Argentina/Buenos_Aires").as_deref(),
+            Some("Buenos Aires")
+        );
+        assert_eq!(city("Etc/GMT+2"), None);
+        assert_eq!(city("Antarctica/Troll"), None);
+        assert_eq!(city("Mars/Olympus"), None);
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn continent_and_eu_membership() {
+        assert!(EU_MEMBERS.windows(2).all(|w| w[0] < w[1]));
+        assert!(TIMEZONES
+            .values()
+            .all(|region| CONTINENTS.contains_key(region)));
+        assert!(EU_MEMBERS
+            .iter()
+            .all(|region| continent(Some(region)) == Some("EU")));
+
+        let visitor = |tz: &str| PubVisitor {
+            tz: tz.to_string(),
+            lang: "de".to_string(),
+            screen: (1920, 1080),
+            ..Default::default()
+        };
+        let zurich = Visitor::builder(1, &visitor("Europe/Zurich")).build();
+        assert_eq!(zurich.continent.as_deref(), Some("EU"));
+        assert!(!zurich.is_eu);
+        let vienna = Visitor::builder(1, &visitor("Europe/Vienna")).build();
+        assert!(vienna.is_eu);
+        let unknown = Visitor::builder(1, &visitor("Mars/Olympus")).build();
+        assert_eq!(unknown.continent, None);
+        assert!(!unknown.is_eu);
+    }
+
+    #[cfg(not(feature = "minimal"))]
     #[test]
     fn smoke_test_ua_parser() {
+        use uaparser::Parser;
+
         let user_agent = "Mozilla/5.0 (Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/112.0.0.0 Safari/537.36";
-        let user_agent = UA_PARSER.parse(user_agent);
+        let user_agent = ua::parser().unwrap().parse(user_agent);
         assert_eq!(user_agent.user_agent.family.to_string(), "Chrome");
         assert_eq!(user_agent.os.family.to_string(), "Linux");
     }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn visitor_builder_overrides_enrichment() {
+        let pub_visitor = PubVisitor {
+            tz: "Europe/Zurich".to_string(),
+            lang: "de-CH".to_string(),
+            screen: (1920, 1080),
+            ..Default::default()
+        };
+        let user_agent = "Mozilla/5.0 (Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/112.0.0.0 Safari/537.36";
+
+        let default = Visitor::new(1, &pub_visitor, user_agent);
+        let built = Visitor::builder(1, &pub_visitor)
+            .user_agent(user_agent)
+            .build();
+        assert_eq!(default.id, built.id);
+
+        let no_ua = Visitor::builder(1, &pub_visitor).build();
+        assert_eq!(no_ua.browser, None);
+        assert_eq!(no_ua.platform, None);
+        assert_ne!(no_ua.id, default.id);
+
+        let region = Visitor::builder(1, &pub_visitor)
+            .region(Some("LI".into()))
+            .build();
+        assert_eq!(region.region.as_deref(), Some("LI"));
+
+        let salted = Visitor::builder(1, &pub_visitor)
+            .user_agent(user_agent)
+            .salt(42)
+            .build();
+        assert_ne!(salted.id, default.id);
+
+        let custom = Visitor::builder(1, &pub_visitor)
+            .user_agent(user_agent)
+            .hasher(hash::Hasher::new())
+            .build();
+        assert_ne!(custom.id, default.id);
+        assert_eq!(IdVersion::from_tag(custom.id), None);
+        assert_eq!(
+            (custom.id as u64) >> (64 - id::VERSION_BITS),
+            id::CUSTOM_TAG
+        );
+
+        let persistent = Visitor::builder(1, &pub_visitor)
+            .user_agent(user_agent)
+            .salt(42)
+            .persistent_id(7)
+            .build();
+        assert_eq!(persistent.id, 7);
+        assert_eq!(persistent.browser, default.browser);
+    }
+
+    #[test]
+    fn normalize_event_name() {
+        assert!(matches!(
+            Event::normalize_name("signup"),
+            Ok(Cow::Borrowed("signup"))
+        ));
+        assert_eq!(
+            Event::normalize_name("  Add To\tCart ").unwrap(),
+            "add_to_cart"
+        );
+        assert_eq!(
+            Event::normalize_name("video:play-25").unwrap(),
+            "video:play-25"
+        );
+
+        let invalid = |name: &str| match Event::normalize_name(name) {
+            Err(Error::InvalidEventName(reason)) => reason,
+            val => panic!("expected error, got {val:?}"),
+        };
+        assert_eq!(invalid(" "), "empty");
+        assert_eq!(invalid(&"a".repeat(MAX_EVENT_NAME_LEN + 1)), "too long");
+        assert_eq!(invalid("🎉"), "invalid character");
+        assert_eq!(invalid("_internal"), "reserved prefix");
+        assert_eq!(invalid("abineo:pageview"), "reserved prefix");
+    }
+
+    #[test]
+    fn event_data_helpers() {
+        let event = Event {
+            data: serde_json::json!({"cart": {"total": 49.5, "currency": "CHF"}}),
+            ..Default::default()
+        };
+        assert_eq!(event.get_number("/cart/total"), Some(49.5));
+        assert_eq!(event.get_string("/cart/currency"), Some("CHF"));
+        assert_eq!(event.get_number("/cart/currency"), None);
+        assert_eq!(event.get_string("/cart/missing"), None);
+        assert_eq!(event.flat_data().len(), 2);
+    }
+
+    #[test]
+    fn amp_pages_use_origin() {
+        let amp =
+            Url::parse("https://example-com.cdn.ampproject.org/c/s/example.com/post").unwrap();
+        let page = Page::new(1, &amp).unwrap();
+        let origin = Page::new(1, &Url::parse("https://example.com/post").unwrap()).unwrap();
+        assert_eq!(page.domain, "example.com");
+        assert_eq!(page.path, "/post");
+        assert_eq!(page.id, origin.id);
+    }
+
+    #[test]
+    fn canonical_urls_of_same_origin() {
+        let url = Url::parse("https://example.com/blog?page=2").unwrap();
+        let canonical = Url::parse("https://example.com/blog/").unwrap();
+        let page = Page::with_canonical(1, &url, Some(&canonical)).unwrap();
+        assert_eq!(page.path, "/blog/");
+        assert_eq!(
+            page.actual_url.as_deref(),
+            Some("https://example.com/blog?page=2")
+        );
+
+        let foreign = Url::parse("https://other.com/blog/").unwrap();
+        let page = Page::with_canonical(1, &url, Some(&foreign)).unwrap();
+        assert_eq!(page.domain, "example.com");
+        assert_eq!(page.path, "/blog");
+        assert_eq!(page.actual_url, None);
+        assert_eq!(page.id, Page::new(1, &url).unwrap().id);
+    }
+
+    #[test]
+    fn normalizes_paths_from_v4() {
+        use crate::id::Rehash;
+
+        assert_eq!(normalize_path("/caf%C3%A9"), "/café");
+        assert_eq!(normalize_path("/cafe\u{301}"), "/café");
+        assert_eq!(normalize_path("/%7Euser/a%2Db"), "/~user/a-b");
+        // reserved characters and spaces keep their meaning
+        assert_eq!(normalize_path("/a%2Fb%3Fc%23d"), "/a%2Fb%3Fc%23d");
+        assert_eq!(normalize_path("/a%2fb%20c"), "/a%2Fb%20c");
+        assert_ne!(
+            Page::compute_id(IdVersion::V4, 1, "example.com", "/a%2Fb"),
+            Page::compute_id(IdVersion::V4, 1, "example.com", "/a/b")
+        );
+        assert!(matches!(normalize_path("/blog/"), Cow::Borrowed("/blog/")));
+        // not UTF-8 once decoded
+        assert_eq!(normalize_path("/%FF"), "/%FF");
+
+        let page = |url| Page::new(1, &Url::parse(url).unwrap()).unwrap();
+        let (encoded, composed, decomposed) = (
+            page("https://example.com/caf%C3%A9"),
+            page("https://example.com/café"),
+            page("https://example.com/cafe\u{301}"),
+        );
+        assert_eq!(encoded.path, "/caf%C3%A9");
+        assert_eq!(encoded.id, composed.id);
+        assert_eq!(encoded.id, decomposed.id);
+        assert_eq!(encoded.rehash(IdVersion::CURRENT), encoded.id);
+        assert_ne!(
+            encoded.rehash(IdVersion::V3),
+            decomposed.rehash(IdVersion::V3)
+        );
+    }
+
+    #[test]
+    fn limits_url_lengths() {
+        let long = "a".repeat(100_000);
+        let url = Url::parse(&format!("https://example.com/{long}?{long}")).unwrap();
+        let page = Page::new(1, &url).unwrap();
+        assert_eq!(page.path.chars().count(), MAX_PATH_LEN);
+        assert!(page.path.ends_with(utm::TRUNCATION_MARKER));
+        let canonical = Url::parse("https://example.com/").unwrap();
+        let page = Page::with_canonical(1, &url, Some(&canonical)).unwrap();
+        assert_eq!(page.actual_url.unwrap().chars().count(), MAX_URL_LEN);
+
+        let host = format!("{}.example.com", "a.".repeat(MAX_DOMAIN_LEN / 2));
+        let url = Url::parse(&format!("https://{host}/")).unwrap();
+        assert!(matches!(Page::new(1, &url), Err(Error::TooLong("domain"))));
+        assert!(Referrer::new(1, Some(&url), "example.com").is_none());
+
+        let padding = "x=1&".repeat(utm::MAX_QUERY_LEN / 4);
+        let url = Url::parse(&format!(
+            "https://example.com/?source=a&{padding}campaign=b"
+        ))
+        .unwrap();
+        let utm = UtmParam::new(1, &url).unwrap();
+        assert_eq!((utm.source.as_deref(), utm.campaign), (Some("a"), None));
+    }
+
+    #[test]
+    fn duration_bucket_boundaries() {
+        assert_eq!(DurationBucket::new(0), DurationBucket::UpTo10s);
+        assert_eq!(DurationBucket::new(9), DurationBucket::UpTo10s);
+        assert_eq!(DurationBucket::new(10), DurationBucket::UpTo30s);
+        assert_eq!(DurationBucket::new(30), DurationBucket::UpTo1m);
+        assert_eq!(DurationBucket::new(60), DurationBucket::UpTo3m);
+        assert_eq!(DurationBucket::new(180), DurationBucket::Over3m);
+        assert_eq!(DurationBucket::new(180).as_str(), "3m+");
+
+        let visit = Visit::default();
+        assert_eq!(visit.duration_bucket(), None);
+    }
 }
@@ -0,0 +1,183 @@
+//! Cross-domain session linking.
+//!
+//! Trackers store the session in first-party storage, so a visitor
+//! navigating from `shop.example` to `checkout.example` would start a new
+//! session. The tracker on the first domain decorates outgoing links with a
+//! signed [`LINKER_PARAM`] token, which the second domain continues the
+//! session with.
+
+use std::collections::HashSet;
+use std::hash::Hasher as _;
+
+use chrono::{DateTime, Duration, Utc};
+use siphasher::sip::SipHasher24;
+use url::Url;
+
+use crate::Error;
+
+/// Query parameter carrying the linker token.
+pub const LINKER_PARAM: &str = "_abineo";
+
+/// Visitor and session carried over from another domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Link {
+    pub visitor: i64,
+    pub session: i64,
+}
+
+/// Creates and verifies linker tokens of a project.
+///
+/// Tokens are `visitor.session.expires.signature` in hex, signed with
+/// SipHash-2-4 under a secret key.
+#[derive(Debug, Clone)]
+pub struct Linker {
+    project: i64,
+    key: [u8; 16],
+    domains: HashSet<String>,
+    ttl: Duration,
+}
+
+impl Linker {
+    /// Links sessions into the given domains. Tokens expire after two
+    /// minutes, enough to follow a link.
+    pub fn new<'a>(
+        project_id: i64,
+        key: [u8; 16],
+        domains: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        Linker {
+            project: project_id,
+            key,
+            domains: domains.into_iter().map(str::to_lowercase).collect(),
+            ttl: Duration::minutes(2),
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Whether sessions may be continued on `domain`.
+    pub fn allows(&self, domain: &str) -> bool {
+        self.domains.contains(&domain.to_lowercase())
+    }
+
+    pub fn token(&self, link: Link, now: DateTime<Utc>) -> String {
+        let expires = (now + self.ttl).timestamp();
+        let signature = self.sign(link, expires);
+        format!(
+            "{:x}.{:x}.{:x}.{signature:x}",
+            link.visitor as u64, link.session as u64, expires
+        )
+    }
+
+    /// Verifies a token, returning the link if it is authentic and valid.
+    pub fn verify(&self, token: &str, now: DateTime<Utc>) -> Result<Link, Error> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let [visitor, session, expires, signature] = parts[..] else {
+            return Err(Error::InvalidLinker("malformed"));
+        };
+        let hex =
+            |part| u64::from_str_radix(part, 16).map_err(|_| Error::InvalidLinker("malformed"));
+        let link = Link {
+            visitor: hex(visitor)? as i64,
+            session: hex(session)? as i64,
+        };
+        let expires = hex(expires)? as i64;
+
+        if self.sign(link, expires) != hex(signature)? {
+            return Err(Error::InvalidLinker("bad signature"));
+        }
+        if now.timestamp() > expires {
+            return Err(Error::InvalidLinker("expired"));
+        }
+        Ok(link)
+    }
+
+    /// The link of a landing page url, if it carries a valid token and its
+    /// domain is allowed. Invalid tokens are ignored like a missing one.
+    pub fn link(&self, url: &Url, now: DateTime<Utc>) -> Option<Link> {
+        if !self.allows(url.domain()?) {
+            return None;
+        }
+        let (_, token) = url.query_pairs().find(|(key, _)| key == LINKER_PARAM)?;
+        self.verify(&token, now).ok()
+    }
+
+    fn sign(&self, link: Link, expires: i64) -> u64 {
+        let mut hasher = SipHasher24::new_with_key(&self.key);
+        hasher.write_i64(self.project);
+        hasher.write_i64(link.visitor);
+        hasher.write_i64(link.session);
+        hasher.write_i64(expires);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+    const LINK: Link = Link {
+        visitor: -4050998703357402561,
+        session: 7,
+    };
+
+    fn linker() -> Linker {
+        Linker::new(1, KEY, ["shop.example", "Checkout.example"])
+    }
+
+    #[test]
+    fn roundtrip() {
+        let now = Utc::now();
+        let token = linker().token(LINK, now);
+        assert_eq!(linker().verify(&token, now).unwrap(), LINK);
+        assert_eq!(
+            linker().verify(&token, now + Duration::minutes(2)).unwrap(),
+            LINK
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let now = Utc::now();
+        let token = linker().token(LINK, now);
+        let reason = |linker: Linker, token: &str, now| match linker.verify(token, now) {
+            Err(Error::InvalidLinker(reason)) => reason,
+            val => panic!("expected error, got {val:?}"),
+        };
+
+        assert_eq!(
+            reason(linker(), &token, now + Duration::minutes(3)),
+            "expired"
+        );
+        assert_eq!(
+            reason(Linker::new(2, KEY, []), &token, now),
+            "bad signature"
+        );
+        assert_eq!(
+            reason(Linker::new(1, [0; 16], []), &token, now),
+            "bad signature"
+        );
+        let forged = token.replacen("7", "8", 1);
+        assert_eq!(reason(linker(), &forged, now), "bad signature");
+        assert_eq!(reason(linker(), "7.8", now), "malformed");
+    }
+
+    #[test]
+    fn links_only_allowed_domains() {
+        let now = Utc::now();
+        let token = linker().token(LINK, now);
+        let url =
+            |domain| Url::parse(&format!("https://{domain}/?{LINKER_PARAM}={token}")).unwrap();
+
+        assert_eq!(linker().link(&url("checkout.example"), now), Some(LINK));
+        assert_eq!(linker().link(&url("evil.example"), now), None);
+        assert_eq!(
+            linker().link(&Url::parse("https://shop.example/").unwrap(), now),
+            None
+        );
+    }
+}
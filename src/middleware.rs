@@ -0,0 +1,191 @@
+//! Custom processing of records before they reach a sink.
+//!
+//! A [`Middleware`] can redact, enrich or drop processed records. Several
+//! of them are combined into a [`Chain`], which the
+//! [`Pipeline`](crate::pipeline::Pipeline) runs on its enrich workers or
+//! which wraps a sink with [`Chain::sink`].
+
+use std::fmt;
+
+use crate::filter::{EventFilter, Verdict};
+use crate::flush::Flushable;
+use crate::sink::Sink;
+use crate::{Error, Record};
+
+pub trait Middleware: Send + Sync {
+    /// Returns the record to pass on, `None` to drop it.
+    fn process(&self, record: Record) -> Option<Record>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Record) -> Option<Record> + Send + Sync,
+{
+    fn process(&self, record: Record) -> Option<Record> {
+        self(record)
+    }
+}
+
+/// Drops events the filter doesn't accept, visits pass.
+impl Middleware for EventFilter {
+    fn process(&self, record: Record) -> Option<Record> {
+        match record {
+            Record::Event(mut event) => match self.apply(&mut event) {
+                Verdict::Dropped => None,
+                _ => Some(Record::Event(event)),
+            },
+            record => Some(record),
+        }
+    }
+}
+
+/// Middlewares run in the order they were added.
+#[derive(Default)]
+pub struct Chain {
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.middlewares.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middlewares.is_empty()
+    }
+
+    /// Runs the chain on every record of a batch.
+    pub fn process_all(&self, records: Vec<Record>) -> Vec<Record> {
+        records
+            .into_iter()
+            .filter_map(|record| self.process(record))
+            .collect()
+    }
+
+    /// Runs the chain on every batch before writing it to `sink`.
+    pub fn sink<S: Sink>(self, sink: S) -> ChainSink<S> {
+        ChainSink { chain: self, sink }
+    }
+}
+
+/// Stops at the first middleware that drops the record.
+impl Middleware for Chain {
+    fn process(&self, record: Record) -> Option<Record> {
+        self.middlewares
+            .iter()
+            .try_fold(record, |record, middleware| middleware.process(record))
+    }
+}
+
+impl fmt::Debug for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Chain")
+            .field("middlewares", &self.middlewares.len())
+            .finish()
+    }
+}
+
+/// A sink behind a [`Chain`], see [`Chain::sink`].
+#[derive(Debug)]
+pub struct ChainSink<S> {
+    chain: Chain,
+    sink: S,
+}
+
+impl<S: Sink> Sink for ChainSink<S> {
+    /// Batches dropped entirely are not written.
+    fn write(&mut self, records: Vec<Record>) -> Result<(), Error> {
+        let records = self.chain.process_all(records);
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.sink.write(records)
+    }
+}
+
+impl<S: Sink> Flushable for ChainSink<S> {
+    fn flush(&mut self) -> Result<(), Error> {
+        self.sink.flush()
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.sink.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Event, Visit};
+
+    fn event(name: &str) -> Record {
+        Event {
+            name: name.to_string(),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    fn redact_email(record: Record) -> Option<Record> {
+        match record {
+            Record::Event(mut event) => {
+                if let Some(data) = event.data.as_object_mut() {
+                    data.remove("email");
+                }
+                Some(Record::Event(event))
+            }
+            record => Some(record),
+        }
+    }
+
+    #[test]
+    fn runs_middlewares_in_order() {
+        let chain = Chain::new()
+            .with(EventFilter::allow(["signup"]).unwrap())
+            .with(redact_email)
+            .with(|record: Record| match record {
+                Record::Visit(_) => None,
+                record => Some(record),
+            });
+        assert_eq!(chain.len(), 3);
+
+        let signup = Event {
+            name: "signup".to_string(),
+            data: serde_json::json!({"email": "a@example.com", "plan": "pro"}),
+            ..Default::default()
+        };
+        let records =
+            chain.process_all(vec![Visit::default().into(), signup.into(), event("debug")]);
+
+        assert_eq!(records.len(), 1);
+        let Record::Event(event) = &records[0] else {
+            panic!("expected event");
+        };
+        assert_eq!(event.data, serde_json::json!({"plan": "pro"}));
+    }
+
+    #[test]
+    fn wraps_sinks() {
+        let mut written = Vec::new();
+        let mut sink = Chain::new()
+            .with(EventFilter::deny(["debug"]).unwrap())
+            .sink(|records: Vec<Record>| {
+                written.push(records.len());
+                Ok(())
+            });
+        sink.write(vec![event("signup"), event("debug")]).unwrap();
+        sink.write(vec![event("debug")]).unwrap();
+        sink.close().unwrap();
+        drop(sink);
+        assert_eq!(written, [1]);
+    }
+}
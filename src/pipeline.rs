@@ -0,0 +1,1115 @@
+//! Staged processing of beacons with bounded queues.
+//!
+//! A [`Pipeline`] parses, enriches and writes beacons on background
+//! threads. The stages are connected by bounded channels, so a slow sink
+//! slows down the stages before it instead of buffering without limit.
+//! When the input queue is full, new beacons are dropped or the caller is
+//! parked, depending on the [`Overload`] policy. The pipeline needs no
+//! async runtime: with [`Overload::Drop`], [`Pipeline::submit`] never
+//! blocks and can be called from async handlers directly.
+//!
+//! With [event priorities](crate::priority) of the projects, events are
+//! sampled on the enrich workers, low priority events are shed once the
+//! sink queue is half full and beacons with critical events may fill a
+//! reserve of the input queue instead of being dropped.
+//!
+//! Beacons with the [`DRY_RUN_HEADER`] and all beacons of projects in
+//! [dry-run mode](crate::config::ProjectSettings::dry_run) go through every
+//! stage but the sink, and are neither captured nor metered.
+//!
+//! With a [session store](PipelineConfig::sessions), the sink thread writes
+//! the summaries of expired sessions along with the records, and those of
+//! all open sessions on shutdown.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use serde::Deserialize;
+
+use crate::api::http::DRY_RUN_HEADER;
+use crate::api::{self, PubRecord};
+use crate::audit::{AuditLog, Reason};
+use crate::config::provider::ProjectConfigProvider;
+use crate::flush::Flushable;
+use crate::middleware::{Chain, Middleware};
+use crate::priority::Priority;
+use crate::quota::QuotaGuard;
+use crate::raw::Capture;
+use crate::session::SessionStore;
+use crate::shedding::LoadShedder;
+use crate::sink::Sink;
+use crate::usage::UsageMeter;
+use crate::{Error, Record};
+
+/// What [`Pipeline::submit`] does when the input queue is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overload {
+    /// Rejects the beacon with [`Error::Overloaded`]. Beacons with
+    /// critical events are only rejected once a reserve of another quarter
+    /// of the capacity is full too.
+    #[default]
+    Drop,
+    /// Blocks the caller until there is room.
+    Park,
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    /// Capacity of each queue between the stages.
+    pub capacity: usize,
+    pub parse_workers: usize,
+    pub enrich_workers: usize,
+    /// Maximum number of records per sink write.
+    pub batch_size: usize,
+    /// Maximum time a record waits for its batch to fill up.
+    pub batch_timeout: Duration,
+    pub overload: Overload,
+    /// Repairs beacons of outdated trackers, see [`api::lenient`].
+    pub lenient: bool,
+    /// Latency budget of the user agent parsing of a record, see
+    /// [`api::handle_batch_within`].
+    pub enrich_budget: Option<Duration>,
+    /// Runs on the enrich workers after a record was processed.
+    pub middleware: Arc<Chain>,
+    /// Runs after `middleware` unless load is shed, e.g. costly lookups.
+    pub optional_middleware: Arc<Chain>,
+    /// Sheds load when overloaded, see [`crate::shedding`].
+    pub shedding: Option<Arc<LoadShedder>>,
+    /// Journals the raw beacons before parsing, see [`crate::raw`].
+    pub capture: Option<Arc<Capture>>,
+    /// Rejects beacons of [paused](crate::config::ProjectSettings::paused)
+    /// projects on submit and provides the
+    /// [utm options](crate::utm::UtmOptions) of the projects.
+    pub projects: Option<Arc<dyn ProjectConfigProvider>>,
+    /// Rejects beacons of projects over their hard limit on submit, counts
+    /// the processed records and delivers the warnings, see [`crate::quota`].
+    pub quota: Option<Arc<QuotaGuard>>,
+    /// Counts the accepted beacons and processed records, see
+    /// [`crate::usage`].
+    pub usage: Option<Arc<UsageMeter>>,
+    /// Records beacons rejected because of the project settings.
+    pub audit: Option<Arc<AuditLog>>,
+    /// Summarizes the sessions of the delivered records.
+    pub sessions: Option<Arc<dyn SessionStore>>,
+    /// Time without records after which a session expires and its summary
+    /// is written.
+    pub session_timeout: Duration,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            capacity: 1024,
+            parse_workers: 1,
+            enrich_workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            batch_size: 500,
+            batch_timeout: Duration::from_secs(1),
+            overload: Overload::default(),
+            lenient: false,
+            enrich_budget: None,
+            middleware: Arc::default(),
+            optional_middleware: Arc::default(),
+            shedding: None,
+            capture: None,
+            projects: None,
+            quota: None,
+            usage: None,
+            audit: None,
+            sessions: None,
+            session_timeout: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+/// A raw beacon as received by the server.
+#[derive(Debug, Clone)]
+pub struct Beacon {
+    pub project_id: i64,
+    pub user_agent: String,
+    /// Request headers, only kept by a [`Capture`]. Beacons with the
+    /// [`DRY_RUN_HEADER`] are processed but not written to the sink.
+    pub headers: Vec<(String, String)>,
+    /// A single tagged record or an array of them, see [`api::decode`].
+    pub body: Vec<u8>,
+}
+
+impl Beacon {
+    /// Whether the beacon carries the [`DRY_RUN_HEADER`].
+    pub fn is_dry_run(&self) -> bool {
+        self.headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case(DRY_RUN_HEADER))
+    }
+}
+
+/// Counters of a [`Pipeline`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineStats {
+    /// Beacons accepted by [`Pipeline::submit`].
+    pub accepted: u64,
+    /// Beacons rejected because the pipeline was overloaded.
+    pub dropped: u64,
+    /// Beacons rejected because their project was paused.
+    pub paused: u64,
+    /// Beacons or records that failed to parse or process.
+    pub invalid: u64,
+    /// Beacons that were only valid after lenient repairs.
+    pub repaired: u64,
+    /// Beacons journaled by the capture.
+    pub captured: u64,
+    /// Events not sampled, see [`EventPriorities`](crate::priority::EventPriorities).
+    pub sampled: u64,
+    /// Low priority events dropped because the sink queue was half full.
+    pub shed: u64,
+    /// Records dropped by the middleware.
+    pub filtered: u64,
+    /// Records written to the sink.
+    pub delivered: u64,
+    /// Records the sink failed to write.
+    pub failed: u64,
+    /// Records processed as dry runs, which are not written to the sink.
+    pub dry_run: u64,
+    /// Records enriched partially because they ran out of the
+    /// [budget](PipelineConfig::enrich_budget).
+    pub partial: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    accepted: AtomicU64,
+    dropped: AtomicU64,
+    paused: AtomicU64,
+    invalid: AtomicU64,
+    repaired: AtomicU64,
+    captured: AtomicU64,
+    sampled: AtomicU64,
+    shed: AtomicU64,
+    filtered: AtomicU64,
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    dry_run: AtomicU64,
+    partial: AtomicU64,
+}
+
+impl Counters {
+    fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Project, user agent, record, when its beacon was submitted and whether
+/// it is a dry run.
+type Job = (i64, String, PubRecord, Instant, bool);
+
+#[derive(Debug)]
+pub struct Pipeline {
+    input: Option<SyncSender<(Beacon, Instant)>>,
+    overload: Overload,
+    projects: Option<Arc<dyn ProjectConfigProvider>>,
+    quota: Option<Arc<QuotaGuard>>,
+    usage: Option<Arc<UsageMeter>>,
+    audit: Option<Arc<AuditLog>>,
+    capacity: usize,
+    /// Beacons in the input queue.
+    waiting: Arc<AtomicUsize>,
+    queued: Arc<AtomicUsize>,
+    counters: Arc<Counters>,
+    parse: Vec<JoinHandle<()>>,
+    enrich: Vec<JoinHandle<()>>,
+    sink: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl Pipeline {
+    /// Starts the worker threads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a capacity, worker count or the batch size is zero.
+    pub fn new(config: PipelineConfig, mut sink: impl Sink + 'static) -> Self {
+        assert!(config.capacity > 0, "capacity must be positive");
+        assert!(config.parse_workers > 0 && config.enrich_workers > 0);
+        assert!(config.batch_size > 0, "batch size must be positive");
+
+        let counters = Arc::new(Counters::default());
+        let reserve = match config.overload {
+            Overload::Drop => config.capacity.div_ceil(4),
+            Overload::Park => 0,
+        };
+        let (input, beacons) = mpsc::sync_channel::<(Beacon, Instant)>(config.capacity + reserve);
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let (jobs_tx, jobs) = mpsc::sync_channel::<Job>(config.capacity);
+        let (records_tx, records) = mpsc::sync_channel::<Record>(config.capacity);
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let beacons = Arc::new(Mutex::new(beacons));
+        let parse = (0..config.parse_workers)
+            .map(|_| {
+                let (beacons, jobs_tx, counters) =
+                    (beacons.clone(), jobs_tx.clone(), counters.clone());
+                let (lenient, capture, waiting) =
+                    (config.lenient, config.capture.clone(), waiting.clone());
+                thread::spawn(move || {
+                    while let Some((beacon, submitted)) = next(&beacons) {
+                        waiting.fetch_sub(1, Ordering::Relaxed);
+                        if let (Some(capture), false) = (&capture, beacon.is_dry_run()) {
+                            if capture.record(&beacon).is_ok() {
+                                Counters::add(&counters.captured, 1);
+                            }
+                        }
+                        let decoded = if lenient {
+                            api::lenient::decode(&beacon.body).map(|(body, warnings)| {
+                                if !warnings.is_empty() {
+                                    Counters::add(&counters.repaired, 1);
+                                }
+                                body
+                            })
+                        } else {
+                            api::decode(&beacon.body)
+                        };
+                        let Ok(body) = decoded else {
+                            Counters::add(&counters.invalid, 1);
+                            continue;
+                        };
+                        for record in body {
+                            let job = (
+                                beacon.project_id,
+                                beacon.user_agent.clone(),
+                                record,
+                                submitted,
+                                beacon.is_dry_run(),
+                            );
+                            if jobs_tx.send(job).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        drop(jobs_tx);
+
+        let jobs = Arc::new(Mutex::new(jobs));
+        let enrich = (0..config.enrich_workers)
+            .map(|_| {
+                let (jobs, records_tx, counters, middleware, projects, queued) = (
+                    jobs.clone(),
+                    records_tx.clone(),
+                    counters.clone(),
+                    config.middleware.clone(),
+                    config.projects.clone(),
+                    queued.clone(),
+                );
+                let (sessions, quota) = (config.sessions.clone(), config.quota.clone());
+                let (optional, shedder) =
+                    (config.optional_middleware.clone(), config.shedding.clone());
+                let (capacity, shed_at) = (config.capacity, config.capacity.div_ceil(2));
+                let budget = config.enrich_budget;
+                thread::spawn(move || {
+                    while let Some((project_id, user_agent, record, submitted, dry_run)) =
+                        next(&jobs)
+                    {
+                        let shedding = shedder.as_deref().filter(|shedder| {
+                            let queued = queued.load(Ordering::Relaxed);
+                            shedder.update(queued, capacity, submitted.elapsed())
+                        });
+                        let settings = projects.as_ref().and_then(|p| p.project(project_id));
+                        let mut enrich = settings
+                            .as_deref()
+                            .map_or_else(api::Enrich::default, api::Enrich::for_project);
+                        enrich.shedding = shedding;
+                        enrich.budget = budget;
+                        enrich.dry_run =
+                            dry_run || settings.as_ref().is_some_and(|settings| settings.dry_run);
+                        let record =
+                            match api::process_record(project_id, record, &user_agent, enrich) {
+                                Ok(record) => record,
+                                Err(_) => {
+                                    Counters::add(&counters.invalid, 1);
+                                    continue;
+                                }
+                            };
+                        if record.visitor().partial {
+                            Counters::add(&counters.partial, 1);
+                        }
+                        let priority = match (&record, &settings) {
+                            (Record::Event(event), Some(settings)) => {
+                                if !settings.priorities.keeps(event) {
+                                    Counters::add(&counters.sampled, 1);
+                                    continue;
+                                }
+                                settings.priorities.priority(&event.name)
+                            }
+                            _ => Priority::Normal,
+                        };
+                        if let (Some(shedder), Record::Event(event)) = (shedding, &record) {
+                            if !shedder.keeps(event, priority) {
+                                continue;
+                            }
+                        }
+                        let Some(record) = middleware.process(record) else {
+                            Counters::add(&counters.filtered, 1);
+                            continue;
+                        };
+                        let record = match shedding {
+                            Some(shedder) if !optional.is_empty() => {
+                                shedder.count_enrichment();
+                                record
+                            }
+                            Some(_) => record,
+                            None => match optional.process(record) {
+                                Some(record) => record,
+                                None => {
+                                    Counters::add(&counters.filtered, 1);
+                                    continue;
+                                }
+                            },
+                        };
+                        if record.is_dry_run() {
+                            Counters::add(&counters.dry_run, 1);
+                            continue;
+                        }
+                        if priority == Priority::Low && queued.load(Ordering::Relaxed) >= shed_at {
+                            Counters::add(&counters.shed, 1);
+                            continue;
+                        }
+                        // a failed counter store keeps the record
+                        let warning = match quota.as_ref().map(|quota| quota.check(&record)) {
+                            Some(Err(Error::QuotaExceeded)) => {
+                                Counters::add(&counters.filtered, 1);
+                                continue;
+                            }
+                            Some(Ok(warning)) => warning,
+                            _ => None,
+                        };
+                        if let Some(sessions) = &sessions {
+                            // a failed update leaves the record out of the summary
+                            let _ = sessions.observe(&record);
+                        }
+                        let warning = warning.map(|warning| warning.to_event(record.time()));
+                        for record in std::iter::once(record).chain(warning.map(Record::Event)) {
+                            queued.fetch_add(1, Ordering::Relaxed);
+                            if records_tx.send(record).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        drop(records_tx);
+
+        let (sink_counters, usage, sink_queued) =
+            (counters.clone(), config.usage.clone(), queued.clone());
+        let sessions = config.sessions.clone();
+        let idle =
+            chrono::Duration::from_std(config.session_timeout).unwrap_or(chrono::Duration::MAX);
+        let sink = thread::spawn(move || {
+            let mut write = |batch: Vec<Record>| {
+                let n = batch.len();
+                if let Some(usage) = &usage {
+                    batch.iter().for_each(|record| usage.count_record(record));
+                }
+                match sink.write(batch) {
+                    Ok(()) => Counters::add(&sink_counters.delivered, n),
+                    Err(_) => Counters::add(&sink_counters.failed, n),
+                }
+            };
+            let mut batch = Vec::with_capacity(config.batch_size);
+            let mut deadline = Instant::now() + config.batch_timeout;
+            let mut expired_at = Instant::now();
+            loop {
+                if let Some(usage) = &usage {
+                    // a failed write keeps the counts for the next tick
+                    let _ = usage.tick(Utc::now());
+                }
+                if let Some(sessions) = &sessions {
+                    if expired_at.elapsed() >= config.batch_timeout {
+                        expired_at = Instant::now();
+                        // a failed expiry keeps the sessions for the next tick
+                        match sessions.expire(idle) {
+                            Ok(summaries) if !summaries.is_empty() => {
+                                write(summaries.into_iter().map(Record::Session).collect())
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match records.recv_timeout(timeout) {
+                    Ok(record) => {
+                        sink_queued.fetch_sub(1, Ordering::Relaxed);
+                        if batch.is_empty() {
+                            deadline = Instant::now() + config.batch_timeout;
+                        }
+                        batch.push(record);
+                        if batch.len() < config.batch_size {
+                            continue;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        deadline = Instant::now() + config.batch_timeout;
+                        if batch.is_empty() {
+                            continue;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        if let Some(sessions) = &sessions {
+                            if let Ok(summaries) = sessions.expire_all() {
+                                batch.extend(summaries.into_iter().map(Record::Session));
+                            }
+                        }
+                        if !batch.is_empty() {
+                            write(batch);
+                        }
+                        if let Some(usage) = &usage {
+                            let _ = usage.flush(Utc::now());
+                        }
+                        return sink.close();
+                    }
+                }
+                write(std::mem::replace(
+                    &mut batch,
+                    Vec::with_capacity(config.batch_size),
+                ));
+            }
+        });
+
+        Pipeline {
+            input: Some(input),
+            overload: config.overload,
+            projects: config.projects,
+            quota: config.quota,
+            usage: config.usage.clone(),
+            audit: config.audit,
+            capacity: config.capacity,
+            waiting,
+            queued,
+            counters,
+            parse,
+            enrich,
+            sink: Some(sink),
+        }
+    }
+
+    /// Queues a beacon for processing.
+    ///
+    /// With [`Overload::Park`] this blocks while the pipeline is saturated,
+    /// call it from a blocking context (e.g. `spawn_blocking`) in async
+    /// servers. With [`Overload::Drop`] it never blocks.
+    ///
+    /// Beacons of paused projects are rejected with
+    /// [`Error::ProjectPaused`], those of projects over their hard limit
+    /// with [`Error::QuotaExceeded`].
+    pub fn submit(&self, beacon: Beacon) -> Result<(), Error> {
+        let input = self.input.as_ref().ok_or(Error::Closed)?;
+        let (project_id, bytes) = (beacon.project_id, beacon.body.len());
+        let mut dry_run = beacon.is_dry_run();
+        let settings = self
+            .projects
+            .as_ref()
+            .and_then(|projects| projects.project(beacon.project_id));
+        if let Some(settings) = &settings {
+            if let Err(err) = settings.ensure_collecting() {
+                Counters::add(&self.counters.paused, 1);
+                if let Some(audit) = &self.audit {
+                    audit.record(project_id, Reason::ProjectPaused, None, Utc::now());
+                }
+                return Err(err);
+            }
+            dry_run |= settings.dry_run;
+        }
+        if let (Some(quota), false) = (&self.quota, dry_run) {
+            quota.ensure_within(project_id, Utc::now())?;
+        }
+        let critical = || {
+            settings.as_ref().is_some_and(|settings| {
+                settings.priorities.beacon_priority(&beacon.body) == Priority::Critical
+            })
+        };
+        let sent = match self.overload {
+            // only beacons with critical events may use the reserve
+            Overload::Drop
+                if self.waiting.load(Ordering::Relaxed) >= self.capacity && !critical() =>
+            {
+                Err(Error::Overloaded)
+            }
+            overload => {
+                self.waiting.fetch_add(1, Ordering::Relaxed);
+                let beacon = (beacon, Instant::now());
+                let sent = match overload {
+                    Overload::Drop => input.try_send(beacon).map_err(|err| match err {
+                        TrySendError::Full(_) => Error::Overloaded,
+                        TrySendError::Disconnected(_) => Error::Closed,
+                    }),
+                    Overload::Park => input.send(beacon).map_err(|_| Error::Closed),
+                };
+                if sent.is_err() {
+                    self.waiting.fetch_sub(1, Ordering::Relaxed);
+                }
+                sent
+            }
+        };
+        match &sent {
+            Ok(()) => {
+                Counters::add(&self.counters.accepted, 1);
+                if let (Some(usage), false) = (&self.usage, dry_run) {
+                    usage.count_beacon(project_id, bytes);
+                }
+            }
+            Err(Error::Overloaded) => Counters::add(&self.counters.dropped, 1),
+            Err(_) => {}
+        }
+        sent
+    }
+
+    /// Records waiting for the sink.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Capacity of each queue, see [`PipelineConfig::capacity`].
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        PipelineStats {
+            accepted: load(&self.counters.accepted),
+            dropped: load(&self.counters.dropped),
+            paused: load(&self.counters.paused),
+            invalid: load(&self.counters.invalid),
+            repaired: load(&self.counters.repaired),
+            captured: load(&self.counters.captured),
+            sampled: load(&self.counters.sampled),
+            shed: load(&self.counters.shed),
+            filtered: load(&self.counters.filtered),
+            delivered: load(&self.counters.delivered),
+            failed: load(&self.counters.failed),
+            dry_run: load(&self.counters.dry_run),
+            partial: load(&self.counters.partial),
+        }
+    }
+
+    /// Stops accepting beacons and waits until every queued beacon and the
+    /// summaries of the open sessions are written to the sink, then closes
+    /// the sink.
+    ///
+    /// Returns an error if closing the sink fails.
+    pub fn shutdown(mut self) -> Result<PipelineStats, Error> {
+        self.close()?;
+        Ok(self.stats())
+    }
+}
+
+/// Batches are written as they fill up, so only closing the pipeline
+/// drains it, see [`Pipeline::shutdown`].
+impl Flushable for Pipeline {
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.input = None;
+        for worker in self.parse.drain(..).chain(self.enrich.drain(..)) {
+            worker.join().expect("pipeline worker panicked");
+        }
+        match self.sink.take() {
+            Some(sink) => sink.join().expect("pipeline sink panicked"),
+            None => Ok(()),
+        }
+    }
+}
+
+fn next<T>(queue: &Mutex<Receiver<T>>) -> Option<T> {
+    queue.lock().unwrap().recv().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use crate::buffer::Journal;
+    use crate::session::SessionTracker;
+
+    use super::*;
+
+    const USER_AGENT: &str = "Mozilla/5.0 (Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/112.0.0.0 Safari/537.36";
+
+    fn beacon(body: &str) -> Beacon {
+        Beacon {
+            project_id: 1,
+            user_agent: USER_AGENT.to_string(),
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    const VISIT: &str = r#"{"type": "visit", "session": "7", "visitor": {}, "page": {"url": "https://example.com/"}}"#;
+
+    #[test]
+    fn processes_beacons_into_sink() {
+        let (tx, rx) = channel();
+        let sink = move |records: Vec<Record>| {
+            tx.send(records.len()).unwrap();
+            Ok(())
+        };
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                enrich_workers: 2,
+                batch_size: 2,
+                ..Default::default()
+            },
+            sink,
+        );
+        pipeline.submit(beacon(VISIT)).unwrap();
+        pipeline
+            .submit(beacon(&format!("[{VISIT}, {VISIT}]")))
+            .unwrap();
+        pipeline.submit(beacon("not json")).unwrap();
+
+        let stats = pipeline.shutdown().unwrap();
+        assert_eq!(stats.accepted, 3);
+        assert_eq!(stats.invalid, 1);
+        assert_eq!(stats.delivered, 3);
+        assert_eq!(rx.iter().sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn drops_beacons_when_saturated() {
+        let (unblock, blocked) = channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let sink = move |_: Vec<Record>| {
+            let _ = blocked.lock().unwrap().recv();
+            Ok(())
+        };
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                capacity: 1,
+                parse_workers: 1,
+                enrich_workers: 1,
+                batch_size: 1,
+                ..Default::default()
+            },
+            sink,
+        );
+
+        let mut dropped = 0;
+        for _ in 0..20 {
+            if let Err(Error::Overloaded) = pipeline.submit(beacon(VISIT)) {
+                dropped += 1;
+            }
+        }
+        assert!(dropped > 0);
+        assert_eq!(pipeline.stats().dropped, dropped);
+
+        drop(unblock);
+        let stats = pipeline.shutdown().unwrap();
+        assert_eq!(stats.accepted + stats.dropped, 20);
+        assert_eq!(stats.delivered, stats.accepted);
+    }
+
+    #[test]
+    fn reserves_room_for_critical_beacons() {
+        use crate::config::provider::StaticProjects;
+        use crate::config::ProjectSettings;
+        use crate::priority::EventPriorities;
+
+        let priorities = EventPriorities {
+            names: [("purchase".to_string(), Priority::Critical)].into(),
+            ..Default::default()
+        };
+        let projects = StaticProjects::new(
+            [(
+                1,
+                ProjectSettings {
+                    priorities,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        );
+        let (unblock, blocked) = channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let sink = move |_: Vec<Record>| {
+            let _ = blocked.lock().unwrap().recv();
+            Ok(())
+        };
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                capacity: 4,
+                parse_workers: 1,
+                enrich_workers: 1,
+                batch_size: 1,
+                projects: Some(Arc::new(projects)),
+                ..Default::default()
+            },
+            sink,
+        );
+
+        while pipeline.submit(beacon(VISIT)).is_ok() {}
+        let purchase = beacon(
+            r#"{"type": "event", "session": "7", "visitor": {}, "page": {"url": "https://example.com/"}, "name": "purchase", "data": null}"#,
+        );
+        pipeline.submit(purchase.clone()).unwrap();
+        // the reserve is bounded as well, submitting never blocks
+        while pipeline.submit(purchase.clone()).is_ok() {}
+
+        drop(unblock);
+        let stats = pipeline.shutdown().unwrap();
+        assert_eq!(stats.delivered, stats.accepted);
+    }
+
+    #[test]
+    fn repairs_beacons_in_lenient_mode() {
+        let sloppy = r#"{"type": "visit", "session": 7, "page": "https://example.com/"}"#;
+        let run = |lenient| {
+            let pipeline = Pipeline::new(
+                PipelineConfig {
+                    lenient,
+                    ..Default::default()
+                },
+                |_: Vec<Record>| Ok(()),
+            );
+            pipeline.submit(beacon(sloppy)).unwrap();
+            pipeline.submit(beacon(VISIT)).unwrap();
+            pipeline.shutdown().unwrap()
+        };
+
+        let strict = run(false);
+        assert_eq!((strict.invalid, strict.delivered), (1, 1));
+        let lenient = run(true);
+        assert_eq!((lenient.invalid, lenient.repaired), (0, 1));
+        assert_eq!(lenient.delivered, 2);
+    }
+
+    #[test]
+    fn rejects_paused_projects() {
+        use crate::config::provider::StaticProjects;
+        use crate::config::ProjectSettings;
+
+        let projects = StaticProjects::new(
+            [(
+                1,
+                ProjectSettings {
+                    paused: true,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        );
+        let audit = Arc::new(AuditLog::default());
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                projects: Some(Arc::new(projects)),
+                audit: Some(audit.clone()),
+                ..Default::default()
+            },
+            |_: Vec<Record>| Ok(()),
+        );
+        assert!(matches!(
+            pipeline.submit(beacon(VISIT)),
+            Err(Error::ProjectPaused)
+        ));
+        let mut other = beacon(VISIT);
+        other.project_id = 2;
+        pipeline.submit(other).unwrap();
+        let stats = pipeline.shutdown().unwrap();
+        assert_eq!((stats.paused, stats.accepted, stats.delivered), (1, 1, 1));
+        let rejected = audit.query(&Default::default());
+        assert_eq!(
+            (rejected[0].project, rejected[0].reason),
+            (1, Reason::ProjectPaused)
+        );
+    }
+
+    #[test]
+    fn enforces_quotas() {
+        use crate::config::provider::StaticProjects;
+        use crate::config::ProjectSettings;
+        use crate::quota::{self, CounterStore, MemoryCounters, Quota, WARNING_EVENT};
+
+        let settings = ProjectSettings {
+            quota: Some(Quota {
+                soft: 2,
+                hard: Some(2),
+            }),
+            ..Default::default()
+        };
+        let projects = Arc::new(StaticProjects::new(
+            [(1, settings.clone()), (2, settings)].into(),
+        ));
+        let store = Arc::new(MemoryCounters::default());
+        store.increment(2, &quota::month(Utc::now()), 2).unwrap();
+        let (tx, rx) = channel();
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                enrich_workers: 1,
+                quota: Some(Arc::new(QuotaGuard::new(store, projects))),
+                ..Default::default()
+            },
+            move |records: Vec<Record>| {
+                tx.send(records).unwrap();
+                Ok(())
+            },
+        );
+        let mut over = beacon(VISIT);
+        over.project_id = 2;
+        assert!(matches!(pipeline.submit(over), Err(Error::QuotaExceeded)));
+        for _ in 0..3 {
+            pipeline.submit(beacon(VISIT)).unwrap();
+        }
+
+        let stats = pipeline.shutdown().unwrap();
+        assert_eq!((stats.accepted, stats.filtered), (3, 1));
+        let records: Vec<_> = rx.iter().flatten().collect();
+        assert_eq!(records.len(), 3);
+        let Record::Event(warning) = &records[2] else {
+            panic!("expected a quota warning, got {:?}", records[2]);
+        };
+        assert_eq!(warning.name, WARNING_EVENT);
+        assert_eq!(warning.data["percent"], 100);
+    }
+
+    #[test]
+    fn skips_sink_for_dry_runs() {
+        use crate::config::provider::StaticProjects;
+        use crate::config::ProjectSettings;
+
+        let projects = StaticProjects::new(
+            [(
+                2,
+                ProjectSettings {
+                    dry_run: true,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        );
+        let (tx, rx) = channel();
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                projects: Some(Arc::new(projects)),
+                ..Default::default()
+            },
+            move |records: Vec<Record>| {
+                tx.send(records).unwrap();
+                Ok(())
+            },
+        );
+        let mut header = beacon(VISIT);
+        header.headers = vec![("X-Abineo-Dry-Run".to_string(), "1".to_string())];
+        pipeline.submit(header).unwrap();
+        let mut project = beacon(VISIT);
+        project.project_id = 2;
+        pipeline.submit(project).unwrap();
+        pipeline.submit(beacon(VISIT)).unwrap();
+
+        let stats = pipeline.shutdown().unwrap();
+        assert_eq!((stats.accepted, stats.dry_run, stats.delivered), (3, 2, 1));
+        let records: Vec<_> = rx.iter().flatten().collect();
+        assert_eq!(records.len(), 1);
+        assert!(!records[0].is_dry_run());
+    }
+
+    #[test]
+    fn sheds_load_when_overloaded() {
+        use crate::shedding::ShedPolicy;
+
+        let shedder = Arc::new(LoadShedder::new(ShedPolicy {
+            latency: Duration::ZERO,
+            sample_percent: 0,
+            ..Default::default()
+        }));
+        let (tx, rx) = channel();
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                optional_middleware: Arc::new(Chain::new().with(|_: Record| None)),
+                shedding: Some(shedder.clone()),
+                ..Default::default()
+            },
+            move |records: Vec<Record>| {
+                tx.send(records).unwrap();
+                Ok(())
+            },
+        );
+        let event = r#"{"type": "event", "session": "7", "visitor": {}, "page": {"url": "https://example.com/"}, "name": "scroll", "data": null}"#;
+        let mut beacon = beacon(&format!("[{VISIT}, {event}]"));
+        beacon.user_agent = "Mozilla/5.0 (shedding test)".to_string();
+        pipeline.submit(beacon).unwrap();
+        let stats = pipeline.shutdown().unwrap();
+
+        let records: Vec<Record> = rx.try_iter().flatten().collect();
+        let [Record::Visit(visit)] = &records[..] else {
+            panic!("expected the visit only: {records:?}");
+        };
+        assert_eq!(visit.visitor.browser, None);
+        assert_eq!(stats.filtered, 0);
+        let shed = shedder.stats();
+        assert_eq!((shed.activations, shed.user_agents), (1, 2));
+        assert_eq!((shed.enrichments, shed.events), (1, 1));
+    }
+
+    #[test]
+    fn samples_events_by_priority() {
+        use crate::config::provider::StaticProjects;
+        use crate::config::ProjectSettings;
+        use crate::priority::EventPriorities;
+
+        let priorities = EventPriorities {
+            names: [("scroll".to_string(), Priority::Low)].into(),
+            sample: [(Priority::Low, 0)].into(),
+            ..Default::default()
+        };
+        let projects = StaticProjects::new(
+            [(
+                1,
+                ProjectSettings {
+                    priorities,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        );
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                projects: Some(Arc::new(projects)),
+                ..Default::default()
+            },
+            |_: Vec<Record>| Ok(()),
+        );
+        let event = |name: &str| {
+            format!(
+                r#"{{"type": "event", "session": "7", "visitor": {{}}, "page": {{"url": "https://example.com/"}}, "name": "{name}", "data": null}}"#
+            )
+        };
+        pipeline
+            .submit(beacon(&format!(
+                "[{VISIT}, {}, {}]",
+                event("scroll"),
+                event("purchase")
+            )))
+            .unwrap();
+        let stats = pipeline.shutdown().unwrap();
+        assert_eq!((stats.sampled, stats.delivered), (1, 2));
+    }
+
+    #[test]
+    fn meters_usage() {
+        use crate::usage::Usage;
+
+        let (tx, rx) = channel();
+        let usage = UsageMeter::new(chrono::Duration::hours(1), move |usage: Vec<Usage>| {
+            tx.send(usage).unwrap();
+            Ok(())
+        });
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                usage: Some(Arc::new(usage)),
+                ..Default::default()
+            },
+            |_: Vec<Record>| Ok(()),
+        );
+        pipeline.submit(beacon(VISIT)).unwrap();
+        pipeline.shutdown().unwrap();
+
+        let usage = rx.recv().unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!((usage[0].project, usage[0].visits), (1, 1));
+        assert_eq!(usage[0].bytes, VISIT.len() as u64);
+    }
+
+    #[test]
+    fn captures_raw_beacons() {
+        let dir = std::env::temp_dir().join(format!("abineo-pipeline-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let capture = Arc::new(Capture::new(Journal::open(&dir).unwrap()));
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                capture: Some(capture.clone()),
+                ..Default::default()
+            },
+            |_: Vec<Record>| Ok(()),
+        );
+        pipeline.submit(beacon(VISIT)).unwrap();
+        pipeline.submit(beacon("not json")).unwrap();
+        let stats = pipeline.shutdown().unwrap();
+        assert_eq!((stats.captured, stats.invalid), (2, 1));
+
+        let mut bodies = Vec::new();
+        capture
+            .drain(|raw| {
+                bodies.extend(raw.into_iter().map(|raw| raw.body));
+                Ok::<_, std::convert::Infallible>(())
+            })
+            .unwrap();
+        assert_eq!(bodies, [VISIT, "not json"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn runs_middleware_before_sink() {
+        let (tx, rx) = channel();
+        let sink = move |records: Vec<Record>| {
+            tx.send(records.len()).unwrap();
+            Ok(())
+        };
+        let event = r#"{"type": "event", "session": "7", "visitor": {}, "page": {"url": "https://example.com/"}, "name": "debug", "data": {}}"#;
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                middleware: Arc::new(
+                    Chain::new().with(crate::filter::EventFilter::deny(["debug"]).unwrap()),
+                ),
+                ..Default::default()
+            },
+            sink,
+        );
+        pipeline
+            .submit(beacon(&format!("[{VISIT}, {event}]")))
+            .unwrap();
+
+        let stats = pipeline.shutdown().unwrap();
+        assert_eq!(stats.filtered, 1);
+        assert_eq!(stats.delivered, 1);
+        assert_eq!(rx.iter().sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn writes_session_summaries() {
+        let (tx, rx) = channel();
+        let sink = move |records: Vec<Record>| {
+            tx.send(records).unwrap();
+            Ok(())
+        };
+        let sessions = Arc::new(SessionTracker::new());
+        let pipeline = Pipeline::new(
+            PipelineConfig {
+                sessions: Some(sessions.clone()),
+                ..Default::default()
+            },
+            sink,
+        );
+        pipeline
+            .submit(beacon(&format!("[{VISIT}, {VISIT}]")))
+            .unwrap();
+
+        let stats = pipeline.shutdown().unwrap();
+        assert_eq!(stats.delivered, 3);
+        assert!(sessions.is_empty());
+        let summaries: Vec<_> = rx
+            .iter()
+            .flatten()
+            .filter_map(|record| match record {
+                Record::Session(summary) => Some(summary),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].pages_viewed, 2);
+    }
+}
@@ -0,0 +1,137 @@
+//! Priority classes of events.
+//!
+//! A purchase matters more than a scroll event. Every event falls into a
+//! [`Priority`] class by its name. Each class has its own sampling rate, and
+//! under load the [`Pipeline`](crate::pipeline::Pipeline) sheds the low
+//! priority events first, while critical events are only dropped once a
+//! reserve of its input queue is full too.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::api;
+use crate::hash::Hasher;
+use crate::Event;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Shed first when the pipeline is under load.
+    Low,
+    /// Visits and events without a class.
+    #[default]
+    Normal,
+    /// Dropped last on overload, e.g. conversions.
+    Critical,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventPriorities {
+    /// Classes of event names, normalized like incoming names.
+    pub names: HashMap<String, Priority>,
+    /// Class of events not in `names`.
+    pub default: Priority,
+    /// Percentage of sessions whose events of a class are kept, 100 for
+    /// classes without a rate.
+    pub sample: BTreeMap<Priority, u8>,
+}
+
+impl EventPriorities {
+    pub fn priority(&self, name: &str) -> Priority {
+        self.names.get(name).copied().unwrap_or(self.default)
+    }
+
+    pub fn rate(&self, priority: Priority) -> u8 {
+        self.sample.get(&priority).copied().unwrap_or(100).min(100)
+    }
+
+    /// Whether the event is sampled.
+    ///
+    /// Sampling is by session, so a sampled session keeps all its events of
+    /// the class.
+    pub fn keeps(&self, event: &Event) -> bool {
+        let rate = self.rate(self.priority(&event.name));
+        if rate == 100 {
+            return true;
+        }
+        let mut hasher = Hasher::hardened();
+        hasher.write(event.session as u64);
+        hasher.finalize() % 100 < u64::from(rate)
+    }
+
+    /// The highest class among the records of a beacon body.
+    ///
+    /// Bodies that fail to decode are [`Priority::Normal`].
+    pub fn beacon_priority(&self, body: &[u8]) -> Priority {
+        let Ok(records) = api::split(body) else {
+            return Priority::Normal;
+        };
+        records
+            .iter()
+            .map(|record| match (record.get("type"), record.get("name")) {
+                (Some(Value::String(kind)), Some(Value::String(name))) if kind == "event" => {
+                    Event::normalize_name(name).map_or(self.default, |name| self.priority(&name))
+                }
+                _ => Priority::Normal,
+            })
+            .max()
+            .unwrap_or(Priority::Normal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn priorities() -> EventPriorities {
+        EventPriorities {
+            names: HashMap::from([
+                ("purchase".to_string(), Priority::Critical),
+                ("scroll".to_string(), Priority::Low),
+            ]),
+            sample: BTreeMap::from([(Priority::Low, 10)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn samples_by_session() {
+        let priorities = priorities();
+        let event = |name: &str, session| Event {
+            name: name.to_string(),
+            session,
+            ..Default::default()
+        };
+        let kept = (0..1000)
+            .filter(|session| priorities.keeps(&event("scroll", *session)))
+            .count();
+        assert!((50..150).contains(&kept), "{kept}");
+        assert!((0..1000).all(|session| priorities.keeps(&event("purchase", session))));
+        assert!((0..1000).all(|session| priorities.keeps(&event("signup", session))));
+    }
+
+    #[test]
+    fn beacon_has_highest_priority() {
+        let priorities = priorities();
+        let beacon = |names: &[&str]| {
+            let records: Vec<Value> = names
+                .iter()
+                .map(|name| serde_json::json!({"type": "event", "name": name}))
+                .chain([serde_json::json!({"type": "visit"})])
+                .collect();
+            serde_json::to_vec(&records).unwrap()
+        };
+        assert_eq!(
+            priorities.beacon_priority(&beacon(&["scroll", "Purchase"])),
+            Priority::Critical
+        );
+        assert_eq!(
+            priorities.beacon_priority(&beacon(&["scroll"])),
+            Priority::Normal
+        );
+        assert_eq!(priorities.beacon_priority(b"{"), Priority::Normal);
+    }
+}
@@ -0,0 +1,123 @@
+//! Privacy related helpers.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+use crate::hash::Hasher;
+
+pub mod erasure;
+pub mod ip;
+pub mod scrub;
+
+/// A salt and the time range it was used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaltPeriod {
+    pub start: DateTime<Utc>,
+    /// Exclusive.
+    pub end: DateTime<Utc>,
+    pub salt: u64,
+}
+
+/// Policy of which salt is mixed into visitor ids at which time.
+pub trait SaltSchedule {
+    fn salt_at(&self, time: DateTime<Utc>) -> u64;
+
+    /// All salt periods overlapping `from..to`, in chronological order.
+    fn periods(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<SaltPeriod>;
+}
+
+/// Derives a new salt from a secret every `period`.
+///
+/// Visitor ids can't be linked across periods without knowing the secret.
+#[derive(Debug, Clone)]
+pub struct RotatingSalt {
+    secret: u64,
+    period: Duration,
+    epoch: DateTime<Utc>,
+}
+
+impl RotatingSalt {
+    /// Periods are aligned to the unix epoch, so daily salts rotate at
+    /// midnight UTC.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is not positive.
+    pub fn new(secret: u64, period: Duration) -> Self {
+        assert!(period > Duration::zero(), "salt period must be positive");
+        RotatingSalt {
+            secret,
+            period,
+            epoch: Utc.timestamp_opt(0, 0).unwrap(),
+        }
+    }
+
+    fn index(&self, time: DateTime<Utc>) -> i64 {
+        let elapsed = (time - self.epoch).num_milliseconds();
+        elapsed.div_euclid(self.period.num_milliseconds())
+    }
+
+    fn start(&self, index: i64) -> DateTime<Utc> {
+        self.epoch + Duration::milliseconds(index * self.period.num_milliseconds())
+    }
+
+    fn salt(&self, index: i64) -> u64 {
+        let mut hasher = Hasher::hardened();
+        hasher.write(self.secret);
+        hasher.write(index as u64);
+        hasher.finalize()
+    }
+}
+
+impl SaltSchedule for RotatingSalt {
+    fn salt_at(&self, time: DateTime<Utc>) -> u64 {
+        self.salt(self.index(time))
+    }
+
+    fn periods(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<SaltPeriod> {
+        if from >= to {
+            return Vec::new();
+        }
+        (self.index(from)..=self.index(to - Duration::milliseconds(1)))
+            .map(|index| SaltPeriod {
+                start: self.start(index),
+                end: self.start(index + 1),
+                salt: self.salt(index),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotating_salt_changes_per_period() {
+        let salts = RotatingSalt::new(42, Duration::days(1));
+        let morning = Utc.with_ymd_and_hms(2023, 9, 21, 8, 0, 0).unwrap();
+        let evening = Utc.with_ymd_and_hms(2023, 9, 21, 20, 0, 0).unwrap();
+        let next_day = Utc.with_ymd_and_hms(2023, 9, 22, 0, 0, 0).unwrap();
+        assert_eq!(salts.salt_at(morning), salts.salt_at(evening));
+        assert_ne!(salts.salt_at(evening), salts.salt_at(next_day));
+        assert_ne!(
+            salts.salt_at(morning),
+            RotatingSalt::new(43, Duration::days(1)).salt_at(morning)
+        );
+    }
+
+    #[test]
+    fn periods_cover_range() {
+        let salts = RotatingSalt::new(42, Duration::days(1));
+        let from = Utc.with_ymd_and_hms(2023, 9, 21, 8, 0, 0).unwrap();
+        let to = Utc.with_ymd_and_hms(2023, 9, 23, 0, 0, 0).unwrap();
+        let periods = salts.periods(from, to);
+        assert_eq!(periods.len(), 2);
+        assert_eq!(
+            periods[0].start,
+            Utc.with_ymd_and_hms(2023, 9, 21, 0, 0, 0).unwrap()
+        );
+        assert_eq!(periods[1].end, to);
+        assert_eq!(periods[1].salt, salts.salt_at(periods[1].start));
+        assert!(salts.periods(to, from).is_empty());
+    }
+}
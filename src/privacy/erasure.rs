@@ -0,0 +1,86 @@
+//! Data-subject erasure.
+//!
+//! Visitor ids are one-way hashes, so a visitor can only be found again by
+//! recomputing their ids from the same attributes. With rotating salts every
+//! period produces a different id, all of which need to be deleted.
+
+use chrono::{DateTime, Utc};
+
+use crate::id::IdVersion;
+use crate::privacy::SaltSchedule;
+use crate::VisitorFields;
+
+/// Records of `visitor_id` created within `start..end` must be deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeletionKey {
+    pub visitor_id: i64,
+    pub start: DateTime<Utc>,
+    /// Exclusive.
+    pub end: DateTime<Utc>,
+}
+
+/// Computes the visitor ids of every salt period within `from..to`.
+///
+/// The periods of the keys may extend beyond the requested range, deleting
+/// all records of a key is safe since the id only existed in its period.
+pub fn visitor_keys(
+    visitor: &VisitorFields<'_>,
+    salts: &impl SaltSchedule,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<DeletionKey> {
+    salts
+        .periods(from, to)
+        .into_iter()
+        .map(|period| DeletionKey {
+            visitor_id: visitor.id(IdVersion::CURRENT, Some(period.salt)),
+            start: period.start,
+            end: period.end,
+        })
+        .collect()
+}
+
+/// Deletion key for an unsalted visitor id.
+///
+/// Without the visitor's attributes no other ids can be derived from it.
+pub fn raw_id_key(visitor_id: i64) -> DeletionKey {
+    DeletionKey {
+        visitor_id,
+        start: DateTime::<Utc>::MIN_UTC,
+        end: DateTime::<Utc>::MAX_UTC,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+    use crate::api::PubVisitor;
+    use crate::privacy::RotatingSalt;
+    use crate::Visitor;
+
+    #[test]
+    fn keys_match_ids_created_with_rotating_salts() {
+        let salts = RotatingSalt::new(42, Duration::days(1));
+        let pub_visitor = PubVisitor {
+            tz: "Europe/Zurich".to_string(),
+            lang: "de-CH".to_string(),
+            screen: (1920, 1080),
+            ..Default::default()
+        };
+        let day1 = Utc.with_ymd_and_hms(2023, 9, 21, 12, 0, 0).unwrap();
+        let day2 = Utc.with_ymd_and_hms(2023, 9, 22, 12, 0, 0).unwrap();
+        let visit1 = Visitor::builder(1, &pub_visitor)
+            .salt(salts.salt_at(day1))
+            .build();
+        let visit2 = Visitor::builder(1, &pub_visitor)
+            .salt(salts.salt_at(day2))
+            .build();
+
+        let keys = visitor_keys(&visit1.fields(), &salts, day1, day2 + Duration::hours(1));
+        let ids: Vec<i64> = keys.iter().map(|key| key.visitor_id).collect();
+        assert_eq!(ids, vec![visit1.id, visit2.id]);
+        assert!(keys[0].start <= day1 && day1 < keys[0].end);
+    }
+}
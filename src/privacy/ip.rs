@@ -0,0 +1,91 @@
+//! Short-lived client IP hashes for abuse detection.
+//!
+//! Rate limiting and bot scoring need to tell clients apart, but the IP
+//! must never reach the records. An [`IpHasher`] turns it into an
+//! [`IpHash`] under a key that rotates every hour, so hashes can't be
+//! linked across hours nor reversed once the key is gone.
+//!
+//! Ip hashes are deliberately separate from visitor ids: they are not
+//! serializable and are not an input of any id.
+
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+
+use super::{RotatingSalt, SaltSchedule};
+use crate::hash::Hasher;
+
+/// Opaque hash of a client IP, only valid within its hour.
+///
+/// Use it as a key of in-memory rate limiters or bot scores, never store it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpHash(u64);
+
+impl IpHash {
+    /// The raw hash, e.g. to shard rate limiters.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IpHasher {
+    keys: RotatingSalt,
+}
+
+impl IpHasher {
+    /// Derives an hourly key from `secret`, which should be random per
+    /// process so hashes don't survive restarts either.
+    pub fn new(secret: u64) -> Self {
+        IpHasher {
+            keys: RotatingSalt::new(secret, Duration::hours(1)),
+        }
+    }
+
+    /// Hashes the client IP with the key of the current hour.
+    ///
+    /// IPv6 addresses are truncated to their /64 network, the interface
+    /// part changes frequently with privacy extensions.
+    pub fn hash(&self, ip: IpAddr, now: DateTime<Utc>) -> IpHash {
+        let mut hasher = Hasher::hardened();
+        hasher.write(self.keys.salt_at(now));
+        match ip {
+            IpAddr::V4(ip) => hasher.write_bytes(&ip.octets()),
+            IpAddr::V6(ip) => hasher.write_bytes(&ip.octets()[..8]),
+        }
+        IpHash(hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn rotates_hourly() {
+        let hasher = IpHasher::new(42);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let at = |minute| Utc.with_ymd_and_hms(2023, 10, 1, 12, minute, 0).unwrap();
+        assert_eq!(hasher.hash(ip, at(0)), hasher.hash(ip, at(59)));
+        assert_ne!(
+            hasher.hash(ip, at(0)),
+            hasher.hash(ip, at(0) + Duration::hours(1))
+        );
+        assert_ne!(
+            hasher.hash(ip, at(0)),
+            hasher.hash("203.0.113.8".parse().unwrap(), at(0))
+        );
+        assert_ne!(hasher.hash(ip, at(0)), IpHasher::new(43).hash(ip, at(0)));
+    }
+
+    #[test]
+    fn groups_ipv6_by_network() {
+        let hasher = IpHasher::new(42);
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+        let hash = |ip: &str| hasher.hash(ip.parse().unwrap(), now);
+        assert_eq!(hash("2001:db8:1:2::1"), hash("2001:db8:1:2:abcd::9"));
+        assert_ne!(hash("2001:db8:1:2::1"), hash("2001:db8:1:3::1"));
+    }
+}
@@ -0,0 +1,269 @@
+//! Redaction of personal data in urls and event data.
+//!
+//! Trackers regularly leak emails, phone numbers or access tokens through
+//! paths, query strings or event properties. A [`Scrubber`] replaces them
+//! with a `[name]` marker before the records are stored.
+
+use std::borrow::Cow;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::Value;
+use url::Url;
+
+use crate::id::IdVersion;
+use crate::{Event, Page, UtmParam, Visit};
+
+lazy_static! {
+    static ref DEFAULT_PATTERNS: Vec<(String, Regex)> = [
+        (
+            "email",
+            r"[A-Za-z0-9._%+-]+(?:@|%40)[A-Za-z0-9.-]+\.[A-Za-z]{2,}"
+        ),
+        ("phone", r"(?:\+|%2B)\d[\d\s().-]{6,}\d"),
+        (
+            "token",
+            r"eyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+|\b[0-9a-fA-F]{32,}\b"
+        ),
+    ]
+    .into_iter()
+    .map(|(name, pattern)| (name.to_string(), Regex::new(pattern).unwrap()))
+    .collect();
+}
+
+/// Query parameters whose values are always redacted.
+const SECRET_PARAMS: &[&str] = &[
+    "access_token",
+    "api_key",
+    "auth",
+    "key",
+    "password",
+    "secret",
+    "token",
+];
+
+/// Replaces personal data matching its patterns with `[name]` markers.
+#[derive(Debug, Clone)]
+pub struct Scrubber {
+    patterns: Vec<(String, Regex)>,
+}
+
+impl Default for Scrubber {
+    /// Detects emails, international phone numbers and common tokens.
+    fn default() -> Self {
+        Scrubber {
+            patterns: DEFAULT_PATTERNS.clone(),
+        }
+    }
+}
+
+impl Scrubber {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A scrubber without any patterns, to configure from scratch.
+    pub fn empty() -> Self {
+        Scrubber {
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Adds a project specific pattern, matches are replaced with `[name]`.
+    pub fn with_pattern(mut self, name: &str, pattern: &str) -> Result<Self, regex::Error> {
+        self.patterns.push((name.to_string(), Regex::new(pattern)?));
+        Ok(self)
+    }
+
+    pub fn scrub_str<'a>(&self, input: &'a str) -> Cow<'a, str> {
+        let mut val = Cow::Borrowed(input);
+        for (name, regex) in &self.patterns {
+            if let Cow::Owned(scrubbed) = regex.replace_all(&val, format!("[{name}]").as_str()) {
+                val = Cow::Owned(scrubbed);
+            }
+        }
+        val
+    }
+
+    /// Scrubs the path and query of a url. Values of well-known secret
+    /// parameters like `token` are redacted entirely.
+    pub fn scrub_url(&self, url: &Url) -> Url {
+        let mut val = url.clone();
+        val.set_path(&self.scrub_str(url.path()));
+        if url.query().is_some() {
+            let pairs: Vec<(String, String)> = url
+                .query_pairs()
+                .map(|(key, value)| {
+                    let value = if SECRET_PARAMS.contains(&key.to_lowercase().as_str()) {
+                        "[secret]".to_string()
+                    } else {
+                        self.scrub_str(&value).into_owned()
+                    };
+                    (key.into_owned(), value)
+                })
+                .collect();
+            val.query_pairs_mut().clear().extend_pairs(pairs);
+        }
+        val
+    }
+
+    /// Scrubs all strings within a json value, including object keys.
+    pub fn scrub_json(&self, value: &mut Value) {
+        match value {
+            Value::String(s) => {
+                if let Cow::Owned(scrubbed) = self.scrub_str(s) {
+                    *s = scrubbed;
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|value| self.scrub_json(value)),
+            Value::Object(map) => {
+                *map = std::mem::take(map)
+                    .into_iter()
+                    .map(|(key, mut value)| {
+                        self.scrub_json(&mut value);
+                        (self.scrub_str(&key).into_owned(), value)
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+
+    /// Scrubs the page path and the utm parameters, recomputing their ids.
+    ///
+    /// Prefer scrubbing the url with [`Scrubber::scrub_url`] before
+    /// processing, this is for records that are already processed.
+    pub fn scrub_visit(&self, visit: &mut Visit) {
+        self.scrub_page(&mut visit.page);
+        if let Some(utm) = &mut visit.utm_param {
+            self.scrub_utm(utm);
+        }
+    }
+
+    /// Scrubs the page path, the utm parameters and the event data.
+    pub fn scrub_event(&self, event: &mut Event) {
+        self.scrub_page(&mut event.page);
+        if let Some(utm) = &mut event.utm_param {
+            self.scrub_utm(utm);
+        }
+        self.scrub_json(&mut event.data);
+    }
+
+    fn scrub_utm(&self, utm: &mut UtmParam) {
+        for value in [
+            &mut utm.campaign,
+            &mut utm.content,
+            &mut utm.medium,
+            &mut utm.source,
+            &mut utm.term,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let Cow::Owned(scrubbed) = self.scrub_str(value) {
+                *value = scrubbed;
+            }
+        }
+        utm.id = utm.compute_id(IdVersion::CURRENT);
+    }
+
+    fn scrub_page(&self, page: &mut Page) {
+        if let Some(actual_url) = &mut page.actual_url {
+            if let Ok(url) = Url::parse(actual_url) {
+                *actual_url = self.scrub_url(&url).into();
+            }
+        }
+        if let Cow::Owned(path) = self.scrub_str(&page.path) {
+            page.path = path;
+            page.id = Page::compute_id(IdVersion::CURRENT, page.project, &page.domain, &page.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn scrubs_default_patterns() {
+        let scrubber = Scrubber::new();
+        assert_eq!(
+            scrubber.scrub_str("/users/jane.doe@example.com/settings"),
+            "/users/[email]/settings"
+        );
+        assert_eq!(scrubber.scrub_str("call +41 44 123 45 67"), "call [phone]");
+        assert_eq!(
+            scrubber.scrub_str("/reset/9f86d081884c7d659a2feaa0c55ad015a3bf4f1b"),
+            "/reset/[token]"
+        );
+        assert!(matches!(
+            scrubber.scrub_str("/blog/how-to-set-up-analytics"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn scrubs_url_path_and_query() {
+        let scrubber = Scrubber::new();
+        let url = Url::parse(
+            "https://example.com/u/jane%40example.com?source=newsletter&email=jane@example.com&token=abc",
+        )
+        .unwrap();
+        let url = scrubber.scrub_url(&url);
+        assert_eq!(url.path(), "/u/[email]");
+        let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("source".to_string(), "newsletter".to_string()),
+                ("email".to_string(), "[email]".to_string()),
+                ("token".to_string(), "[secret]".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scrubs_event_data() {
+        let scrubber = Scrubber::new();
+        let mut data = json!({
+            "form": "contact",
+            "fields": ["jane@example.com", 42],
+            "jane@example.com": true,
+        });
+        scrubber.scrub_json(&mut data);
+        assert_eq!(
+            data,
+            json!({
+                "form": "contact",
+                "fields": ["[email]", 42],
+                "[email]": true,
+            })
+        );
+    }
+
+    #[test]
+    fn project_patterns_and_page_ids() {
+        let scrubber = Scrubber::empty()
+            .with_pattern("customer", r"C-\d{6}")
+            .unwrap();
+        let url = Url::parse("https://example.com/customers/C-123456").unwrap();
+        let mut visit = Visit {
+            page: Page::new(1, &url).unwrap(),
+            ..Default::default()
+        };
+        scrubber.scrub_visit(&mut visit);
+        assert_eq!(visit.page.path, "/customers/[customer]");
+        assert_eq!(
+            visit.page.id,
+            Page::compute_id(
+                IdVersion::CURRENT,
+                1,
+                "example.com",
+                "/customers/[customer]"
+            )
+        );
+        assert!(Scrubber::empty().with_pattern("broken", "(").is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! Lifetime statistics of visitors.
+//!
+//! A [`VisitorProfile`] counts the sessions and events of a visitor since
+//! it was first seen. Profiles live in a [`VisitorStore`], shared by all
+//! collectors of a deployment, and are updated and attached to the outgoing
+//! records by a [`ProfileTracker`], so cohorts and retention can be derived
+//! at ingest time. Records are also stamped with the
+//! [cohort](VisitorProfile::cohort) of their visitor, retention curves are
+//! then a group-by on the cohort and the record week.
+//!
+//! Visitor ids change with the [salt](crate::privacy), a profile only spans
+//! the salt period of its id.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::middleware::Middleware;
+use crate::{clock, Error, Record};
+
+#[cfg(feature = "redis")]
+pub mod redis;
+
+/// Lifetime statistics of a visitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VisitorProfile {
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Number of sessions, page views of the same session count once.
+    pub visit_count: u64,
+    pub total_events: u64,
+}
+
+impl VisitorProfile {
+    fn new(time: DateTime<Utc>) -> Self {
+        VisitorProfile {
+            first_seen: time,
+            last_seen: time,
+            visit_count: 0,
+            total_events: 0,
+        }
+    }
+
+    /// The acquisition cohort, the ISO week of the first visit like
+    /// `2023-W40`.
+    pub fn cohort(&self) -> String {
+        self.first_seen.format("%G-W%V").to_string()
+    }
+
+    /// Whether the visitor had an earlier session.
+    pub fn is_returning(&self) -> bool {
+        self.visit_count > 1
+    }
+}
+
+/// Profiles per project and visitor id, e.g. in Redis.
+pub trait VisitorStore: std::fmt::Debug + Send + Sync {
+    /// Adds a record to the profile of its visitor and returns the updated
+    /// profile.
+    fn record(&self, record: &Record) -> Result<VisitorProfile, Error>;
+}
+
+/// A profile and the last session it counted.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    profile: VisitorProfile,
+    session: Option<i64>,
+}
+
+/// A line of a [snapshot](MemoryVisitors::save).
+#[derive(Serialize, Deserialize)]
+struct Line<E> {
+    project: i64,
+    visitor: i64,
+    entry: E,
+}
+
+type Shard = Mutex<HashMap<(i64, i64), Entry>>;
+
+/// Profiles of a single collector.
+///
+/// Profiles are keyed by `(project, visitor)` and spread over independently
+/// locked shards, so concurrent records rarely contend.
+#[derive(Debug)]
+pub struct MemoryVisitors {
+    shards: Box<[Shard]>,
+}
+
+impl Default for MemoryVisitors {
+    fn default() -> Self {
+        MemoryVisitors {
+            shards: (0..64).map(|_| Shard::default()).collect(),
+        }
+    }
+}
+
+impl MemoryVisitors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of independently locked shards. Defaults to 64.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        self.shards = (0..shards.max(1)).map(|_| Shard::default()).collect();
+        self
+    }
+
+    fn shard(&self, key: (i64, i64)) -> MutexGuard<'_, HashMap<(i64, i64), Entry>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let idx = hasher.finish() as usize % self.shards.len();
+        self.shards[idx].lock().unwrap()
+    }
+
+    /// The profile of a visitor, if it was seen.
+    pub fn get(&self, project_id: i64, visitor: i64) -> Option<VisitorProfile> {
+        let key = (project_id, visitor);
+        Some(self.shard(key).get(&key)?.profile)
+    }
+
+    /// Removes the profiles of visitors not seen for longer than `idle`.
+    pub fn expire(&self, idle: Duration) {
+        let threshold = clock::now() - idle;
+        for shard in self.shards.iter() {
+            let mut profiles = shard.lock().unwrap();
+            profiles.retain(|_, entry| entry.profile.last_seen >= threshold);
+        }
+    }
+
+    /// Number of profiles currently kept.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes a snapshot of all profiles to `path`, one JSON object per
+    /// line, like [`SessionTracker::save`](crate::session::SessionTracker::save).
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        for shard in self.shards.iter() {
+            for (&(project, visitor), entry) in shard.lock().unwrap().iter() {
+                let line = Line {
+                    project,
+                    visitor,
+                    entry,
+                };
+                serde_json::to_writer(&mut out, &line)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        out.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Restores the profiles of a snapshot, replacing kept profiles of the
+    /// same visitors. Returns the number of profiles restored.
+    pub fn load(&self, path: impl AsRef<Path>) -> Result<usize, Error> {
+        let mut count = 0;
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line: Line<Entry> = serde_json::from_str(&line?)?;
+            let key = (line.project, line.visitor);
+            self.shard(key).insert(key, line.entry);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl VisitorStore for MemoryVisitors {
+    fn record(&self, record: &Record) -> Result<VisitorProfile, Error> {
+        let time = record.time();
+        let key = (record.project(), record.visitor().id);
+        let mut profiles = self.shard(key);
+        let entry = profiles.entry(key).or_insert_with(|| Entry {
+            profile: VisitorProfile::new(time),
+            session: None,
+        });
+        let profile = &mut entry.profile;
+        profile.first_seen = profile.first_seen.min(time);
+        profile.last_seen = profile.last_seen.max(time);
+        let session = match record {
+            Record::Visit(visit) => visit.session,
+            Record::Event(event) => {
+                profile.total_events += 1;
+                event.session
+            }
+            Record::Session(summary) => summary.session,
+        };
+        if entry.session.replace(session) != Some(session) {
+            profile.visit_count += 1;
+        }
+        Ok(*profile)
+    }
+}
+
+/// Maintains the profiles of the visitors and attaches them to their
+/// records as [`Visitor::profile`](crate::Visitor::profile) and
+/// [`Visitor::cohort`](crate::Visitor::cohort).
+#[derive(Debug)]
+pub struct ProfileTracker {
+    store: Arc<dyn VisitorStore>,
+}
+
+impl ProfileTracker {
+    pub fn new(store: Arc<dyn VisitorStore>) -> Self {
+        ProfileTracker { store }
+    }
+}
+
+/// Dry runs and session summaries are neither counted nor profiled.
+/// Records are kept without a profile if the store fails, an outage must
+/// not lose data.
+impl Middleware for ProfileTracker {
+    fn process(&self, mut record: Record) -> Option<Record> {
+        if record.is_dry_run() || matches!(record, Record::Session(_)) {
+            return Some(record);
+        }
+        if let Ok(profile) = self.store.record(&record) {
+            let visitor = record.visitor_mut();
+            visitor.cohort = Some(profile.cohort());
+            visitor.profile = Some(profile);
+        }
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::{Event, Visit, Visitor};
+
+    use super::*;
+
+    fn at(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 10, day, 12, 0, 0).unwrap()
+    }
+
+    fn visitor() -> Visitor {
+        Visitor {
+            id: 42,
+            project: 1,
+            ..Default::default()
+        }
+    }
+
+    fn visit(day: u32, session: i64) -> Record {
+        Record::Visit(Visit {
+            time: at(day),
+            project: 1,
+            session,
+            visitor: visitor(),
+            ..Default::default()
+        })
+    }
+
+    fn event(day: u32, session: i64) -> Record {
+        Record::Event(Event {
+            time: at(day),
+            project: 1,
+            session,
+            visitor: visitor(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn counts_sessions_and_events() {
+        let store = MemoryVisitors::new();
+        for record in [
+            visit(2, 7),
+            visit(2, 7),
+            event(2, 7),
+            visit(9, 8),
+            event(9, 8),
+        ] {
+            store.record(&record).unwrap();
+        }
+        // late records don't move the last visit back
+        let profile = store.record(&visit(1, 6)).unwrap();
+        assert_eq!(
+            profile,
+            VisitorProfile {
+                first_seen: at(1),
+                last_seen: at(9),
+                visit_count: 3,
+                total_events: 2,
+            }
+        );
+        assert!(profile.is_returning());
+        assert_eq!(profile.cohort(), "2023-W39");
+        let new_year = VisitorProfile::new(Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap());
+        assert_eq!(new_year.cohort(), "2020-W53");
+        assert_eq!(store.get(2, 42), None);
+    }
+
+    #[test]
+    fn attaches_profiles() {
+        let store = Arc::new(MemoryVisitors::new());
+        let tracker = ProfileTracker::new(store.clone());
+        let record = tracker.process(visit(2, 7)).unwrap();
+        let profile = record.visitor().profile.unwrap();
+        assert_eq!((profile.visit_count, profile.is_returning()), (1, false));
+        assert_eq!(record.visitor().cohort.as_deref(), Some("2023-W40"));
+
+        let mut dry_run = visit(3, 8);
+        dry_run.mark_dry_run();
+        let record = tracker.process(dry_run).unwrap();
+        assert_eq!(record.visitor().profile, None);
+        assert_eq!(record.visitor().cohort, None);
+        assert_eq!(store.get(1, 42).unwrap().last_seen, at(2));
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("visitors-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("visitors.ndjson");
+
+        let store = MemoryVisitors::new().with_shards(4);
+        store.record(&visit(2, 7)).unwrap();
+        store.record(&event(3, 8)).unwrap();
+        let mut other = visit(2, 7);
+        other.visitor_mut().id = 43;
+        store.record(&other).unwrap();
+        store.save(&path).unwrap();
+
+        let restored = MemoryVisitors::new();
+        assert_eq!(restored.load(&path).unwrap(), 2);
+        assert_eq!(restored.get(1, 42), store.get(1, 42));
+        // the last counted session survives the restart
+        let profile = restored.record(&visit(3, 8)).unwrap();
+        assert_eq!((profile.visit_count, profile.total_events), (2, 1));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+//! Visitor profiles in Redis, shared by all collectors of a deployment.
+//!
+//! Every profile is a hash updated by a Lua script, so records of a visitor
+//! on different collectors are counted atomically. Profiles expire after a
+//! [TTL](RedisVisitors::with_ttl) without records.
+
+use ::redis::{ErrorKind, RedisError, Script};
+use chrono::{Duration, TimeZone, Utc};
+use lazy_static::lazy_static;
+
+use crate::profile::{VisitorProfile, VisitorStore};
+use crate::session::redis::Pool;
+use crate::{Error, Record};
+
+lazy_static! {
+    /// Adds a record at `ARGV[1]` of the session in `ARGV[2]` to the
+    /// profile in `KEYS[1]`, `ARGV[3]` is `1` for events. Returns the first
+    /// and last time seen, and the visit and event counts.
+    static ref RECORD: Script = Script::new("
+local time = tonumber(ARGV[1])
+local first = tonumber(redis.call('HGET', KEYS[1], 'first_seen'))
+if not first or time < first then
+    first = time
+    redis.call('HSET', KEYS[1], 'first_seen', ARGV[1])
+end
+local last = tonumber(redis.call('HGET', KEYS[1], 'last_seen'))
+if not last or time > last then
+    last = time
+    redis.call('HSET', KEYS[1], 'last_seen', ARGV[1])
+end
+local events = tonumber(redis.call('HGET', KEYS[1], 'total_events')) or 0
+if ARGV[3] == '1' then
+    events = redis.call('HINCRBY', KEYS[1], 'total_events', 1)
+end
+local visits = tonumber(redis.call('HGET', KEYS[1], 'visit_count')) or 0
+if redis.call('HGET', KEYS[1], 'session') ~= ARGV[2] then
+    redis.call('HSET', KEYS[1], 'session', ARGV[2])
+    visits = redis.call('HINCRBY', KEYS[1], 'visit_count', 1)
+end
+redis.call('PEXPIRE', KEYS[1], ARGV[4])
+return {first, last, visits, events}
+");
+}
+
+/// A [`VisitorStore`] in Redis.
+#[derive(Debug)]
+pub struct RedisVisitors {
+    pool: Pool,
+    prefix: String,
+    ttl: Duration,
+}
+
+impl RedisVisitors {
+    /// Connects to the server at `url`, like `redis://127.0.0.1/`.
+    pub fn open(url: &str) -> Result<Self, Error> {
+        Ok(RedisVisitors {
+            pool: Pool::open(url)?,
+            prefix: "abineo".to_string(),
+            ttl: Duration::days(90),
+        })
+    }
+
+    /// Prefix of the keys, so several deployments can share a server.
+    /// Defaults to `abineo`.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// How long a profile is kept without records. Defaults to 90 days.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+}
+
+impl VisitorStore for RedisVisitors {
+    fn record(&self, record: &Record) -> Result<VisitorProfile, Error> {
+        let session = match record {
+            Record::Visit(visit) => visit.session,
+            Record::Event(event) => event.session,
+            Record::Session(summary) => summary.session,
+        };
+        let key = format!(
+            "{}:visitor:{}:{}",
+            self.prefix,
+            record.project(),
+            record.visitor().id
+        );
+        let (first, last, visit_count, total_events): (i64, i64, u64, u64) =
+            self.pool.run(|conn| {
+                RECORD
+                    .key(key)
+                    .arg(record.time().timestamp_millis())
+                    .arg(session)
+                    .arg(i32::from(matches!(record, Record::Event(_))))
+                    .arg(self.ttl.num_milliseconds())
+                    .invoke(conn)
+            })?;
+        let time = |millis| {
+            Utc.timestamp_millis_opt(millis).single().ok_or_else(|| {
+                RedisError::from((ErrorKind::TypeError, "profile time out of range"))
+            })
+        };
+        Ok(VisitorProfile {
+            first_seen: time(first)?,
+            last_seen: time(last)?,
+            visit_count,
+            total_events,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use crate::{Event, Visit, Visitor};
+
+    use super::*;
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_URL"]
+    fn shares_profiles_between_collectors() {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        let prefix = format!("abineo-test-{}", std::process::id());
+        let open = || RedisVisitors::open(&url).unwrap().with_prefix(&prefix);
+        let (first, second) = (open(), open());
+        let at = |day| DateTime::default() + Duration::days(day);
+        let visitor = Visitor {
+            id: 42,
+            project: 1,
+            ..Default::default()
+        };
+        let visit = |day, session| {
+            Record::Visit(Visit {
+                time: at(day),
+                project: 1,
+                session,
+                visitor: visitor.clone(),
+                ..Default::default()
+            })
+        };
+
+        first.record(&visit(2, 7)).unwrap();
+        second.record(&visit(2, 7)).unwrap();
+        let event = Record::Event(Event {
+            time: at(3),
+            project: 1,
+            session: 8,
+            visitor: visitor.clone(),
+            ..Default::default()
+        });
+        first.record(&event).unwrap();
+        let profile = second.record(&visit(1, 8)).unwrap();
+        assert_eq!(
+            profile,
+            VisitorProfile {
+                first_seen: at(1),
+                last_seen: at(3),
+                visit_count: 2,
+                total_events: 1,
+            }
+        );
+    }
+}
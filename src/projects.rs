@@ -0,0 +1,262 @@
+//! Registry of the projects served by a collector.
+//!
+//! A [`Project`] bundles the id passed to the handlers with its
+//! [settings](ProjectSettings) and the hash of its API key. The
+//! [`Registry`] resolves beacons to projects by id or API key, a
+//! [`StorageRegistry`] keeps it in sync with the host's project database.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::config::provider::ProjectConfigProvider;
+use crate::config::ProjectSettings;
+use crate::hash::Hasher;
+use crate::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Project {
+    pub id: i64,
+    /// [Hash](Project::hash_api_key) of the key used by server-side
+    /// integrations, the key itself is never stored.
+    pub api_key_hash: Option<u64>,
+    pub settings: Arc<ProjectSettings>,
+}
+
+impl Project {
+    pub fn new(id: i64, settings: ProjectSettings) -> Self {
+        Project {
+            id,
+            api_key_hash: None,
+            settings: Arc::new(settings),
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key_hash = Some(Project::hash_api_key(api_key));
+        self
+    }
+
+    pub fn hash_api_key(api_key: &str) -> u64 {
+        let mut hasher = Hasher::hardened();
+        hasher.write_bytes(api_key.as_bytes());
+        hasher.finalize()
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.settings.name.as_deref()
+    }
+
+    /// Whether beacons from `host` belong to the project.
+    ///
+    /// Hosts are compared case-insensitively, a project without domains
+    /// accepts every host.
+    pub fn allows_host(&self, host: &str) -> bool {
+        let domains = &self.settings.domains;
+        domains.is_empty()
+            || domains
+                .iter()
+                .any(|domain| domain.eq_ignore_ascii_case(host))
+    }
+}
+
+/// Storage of the projects, e.g. a table in the host's database.
+pub trait ProjectStorage: Debug + Send {
+    fn load(&mut self) -> Result<Vec<Project>, Error>;
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    by_id: HashMap<i64, Arc<Project>>,
+    by_key: HashMap<u64, i64>,
+}
+
+/// In-memory lookup of projects by id or API key.
+#[derive(Debug, Default)]
+pub struct Registry {
+    index: RwLock<Index>,
+}
+
+impl Registry {
+    pub fn new(projects: impl IntoIterator<Item = Project>) -> Self {
+        let val = Registry::default();
+        val.replace(projects);
+        val
+    }
+
+    /// Projects without API keys, e.g. from a
+    /// [`CollectorConfig`](crate::config::CollectorConfig).
+    pub fn from_settings(projects: BTreeMap<i64, ProjectSettings>) -> Self {
+        Registry::new(
+            projects
+                .into_iter()
+                .map(|(id, settings)| Project::new(id, settings)),
+        )
+    }
+
+    pub fn get(&self, id: i64) -> Option<Arc<Project>> {
+        self.index.read().unwrap().by_id.get(&id).cloned()
+    }
+
+    /// Looks up the project of an API key.
+    pub fn authenticate(&self, api_key: &str) -> Option<Arc<Project>> {
+        let index = self.index.read().unwrap();
+        let id = index.by_key.get(&Project::hash_api_key(api_key))?;
+        index.by_id.get(id).cloned()
+    }
+
+    /// Adds or replaces a project.
+    pub fn insert(&self, project: Project) {
+        let mut index = self.index.write().unwrap();
+        if let Some(old) = index
+            .by_id
+            .get(&project.id)
+            .and_then(|old| old.api_key_hash)
+        {
+            index.by_key.remove(&old);
+        }
+        if let Some(key) = project.api_key_hash {
+            index.by_key.insert(key, project.id);
+        }
+        index.by_id.insert(project.id, Arc::new(project));
+    }
+
+    pub fn remove(&self, id: i64) -> Option<Arc<Project>> {
+        let mut index = self.index.write().unwrap();
+        let project = index.by_id.remove(&id)?;
+        if let Some(key) = project.api_key_hash {
+            index.by_key.remove(&key);
+        }
+        Some(project)
+    }
+
+    /// Replaces all projects at once, lookups never see a partial state.
+    pub fn replace(&self, projects: impl IntoIterator<Item = Project>) {
+        let mut index = Index::default();
+        for project in projects {
+            if let Some(key) = project.api_key_hash {
+                index.by_key.insert(key, project.id);
+            }
+            index.by_id.insert(project.id, Arc::new(project));
+        }
+        *self.index.write().unwrap() = index;
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.read().unwrap().by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ProjectConfigProvider for Registry {
+    fn project(&self, id: i64) -> Option<Arc<ProjectSettings>> {
+        self.get(id).map(|project| project.settings.clone())
+    }
+
+    fn refresh(&self) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+/// A [`Registry`] loaded from a [`ProjectStorage`], reloaded on
+/// [`refresh`](ProjectConfigProvider::refresh).
+#[derive(Debug)]
+pub struct StorageRegistry<S> {
+    storage: Mutex<S>,
+    registry: Registry,
+}
+
+impl<S: ProjectStorage> StorageRegistry<S> {
+    /// Loads the projects, failing if the storage is unavailable.
+    pub fn open(mut storage: S) -> Result<Self, Error> {
+        let registry = Registry::new(storage.load()?);
+        Ok(StorageRegistry {
+            storage: Mutex::new(storage),
+            registry,
+        })
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+}
+
+impl<S: ProjectStorage> ProjectConfigProvider for StorageRegistry<S> {
+    fn project(&self, id: i64) -> Option<Arc<ProjectSettings>> {
+        self.registry.project(id)
+    }
+
+    /// Reloads all projects, always reporting a change.
+    fn refresh(&self) -> Result<bool, Error> {
+        let projects = self.storage.lock().unwrap().load()?;
+        self.registry.replace(projects);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(id: i64, domains: &[&str]) -> Project {
+        Project::new(
+            id,
+            ProjectSettings {
+                domains: domains.iter().map(ToString::to_string).collect(),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn authenticates_by_api_key() {
+        let registry = Registry::new([project(1, &[]).with_api_key("secret"), project(2, &[])]);
+        assert_eq!(registry.authenticate("secret").unwrap().id, 1);
+        assert!(registry.authenticate("other").is_none());
+
+        registry.insert(project(1, &[]).with_api_key("rotated"));
+        assert!(registry.authenticate("secret").is_none());
+        assert_eq!(registry.authenticate("rotated").unwrap().id, 1);
+
+        registry.remove(1);
+        assert!(registry.authenticate("rotated").is_none());
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn checks_hosts() {
+        let project = project(1, &["example.com"]);
+        assert!(project.allows_host("Example.com"));
+        assert!(!project.allows_host("evil.com"));
+        assert!(Project::new(2, ProjectSettings::default()).allows_host("any.com"));
+    }
+
+    #[derive(Debug, Default)]
+    struct MemoryStorage {
+        projects: Arc<Mutex<Vec<Project>>>,
+    }
+
+    impl ProjectStorage for MemoryStorage {
+        fn load(&mut self) -> Result<Vec<Project>, Error> {
+            Ok(self.projects.lock().unwrap().clone())
+        }
+    }
+
+    #[test]
+    fn reloads_from_storage() {
+        let storage = MemoryStorage::default();
+        let projects = storage.projects.clone();
+        projects.lock().unwrap().push(project(1, &["example.com"]));
+
+        let registry = StorageRegistry::open(storage).unwrap();
+        assert_eq!(registry.project(1).unwrap().domains, ["example.com"]);
+
+        *projects.lock().unwrap() = vec![project(2, &[])];
+        assert!(registry.refresh().unwrap());
+        assert!(registry.project(1).is_none());
+        assert!(registry.registry().get(2).is_some());
+    }
+}
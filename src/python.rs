@@ -0,0 +1,133 @@
+//! Python bindings of the api functions.
+//!
+//! Data science pipelines recompute ids and re-enrich historical beacons
+//! with the same code as the collector, so their results match production.
+//! Beacons are passed as JSON and records are returned as JSON, errors are
+//! raised as `ValueError`. Build a wheel with
+//!
+//! ```sh
+//! maturin build --release --features pyo3,pyo3/extension-module
+//! ```
+//!
+//! and use it like
+//!
+//! ```python
+//! import abineo_analytics_collector as collector
+//! records = json.loads(collector.process_batch(1, body, user_agent))
+//! ```
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use url::Url;
+
+use crate::api::{
+    decode, process_event, process_record, process_visit, Enrich, PubEvent, PubVisit, PubVisitor,
+};
+use crate::{Error, Page, Visitor};
+
+impl From<Error> for PyErr {
+    fn from(err: Error) -> Self {
+        PyValueError::new_err(err.to_string())
+    }
+}
+
+/// Enriches a visit beacon like [`handle_visit`](crate::api::handle_visit).
+#[pyfunction]
+fn handle_visit(project_id: i64, body: &str, user_agent: &str) -> PyResult<String> {
+    let body: PubVisit = serde_json::from_str(body).map_err(Error::from)?;
+    let visit = process_visit(project_id, body, user_agent, Enrich::default())?;
+    Ok(serde_json::to_string(&visit).map_err(Error::from)?)
+}
+
+/// Enriches an event beacon like [`handle_event`](crate::api::handle_event).
+#[pyfunction]
+fn handle_event(project_id: i64, body: &str, user_agent: &str) -> PyResult<String> {
+    let body: PubEvent = serde_json::from_str(body).map_err(Error::from)?;
+    let event = process_event(project_id, body, user_agent, Enrich::default())?;
+    Ok(serde_json::to_string(&event).map_err(Error::from)?)
+}
+
+/// Decodes and enriches a batch of beacons like
+/// [`handle_batch`](crate::api::handle_batch), returning the records.
+#[pyfunction]
+fn process_batch(project_id: i64, body: &str, user_agent: &str) -> PyResult<String> {
+    let records = decode(body.as_bytes())?
+        .into_iter()
+        .map(|record| process_record(project_id, record, user_agent, Enrich::default()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(serde_json::to_string(&records).map_err(Error::from)?)
+}
+
+/// The id of a visitor, given as the `visitor` object of a beacon.
+#[pyfunction]
+fn visitor_id(project_id: i64, visitor: &str, user_agent: &str) -> PyResult<i64> {
+    let visitor: PubVisitor = serde_json::from_str(visitor).map_err(Error::from)?;
+    Ok(Visitor::new(project_id, &visitor, user_agent).id)
+}
+
+/// The id of the page at `url`.
+#[pyfunction]
+fn page_id(project_id: i64, url: &str) -> PyResult<i64> {
+    let url = Url::parse(url).map_err(|err| PyValueError::new_err(err.to_string()))?;
+    Ok(Page::new(project_id, &url)?.id)
+}
+
+#[pymodule]
+fn abineo_analytics_collector(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(handle_visit, m)?)?;
+    m.add_function(wrap_pyfunction!(handle_event, m)?)?;
+    m.add_function(wrap_pyfunction!(process_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(visitor_id, m)?)?;
+    m.add_function(wrap_pyfunction!(page_id, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36";
+
+    #[test]
+    fn returns_same_records_as_api() {
+        let visitor = r#"{"tz": "Europe/Zurich", "lang": "de-CH", "screen": [1920, 1080]}"#;
+        let page = r#"{"url": "https://example.com/blog", "ref": null}"#;
+        let body = format!(r#"{{"session": "7", "visitor": {visitor}, "page": {page}}}"#);
+        let visit: Value =
+            serde_json::from_str(&handle_visit(1, &body, USER_AGENT).unwrap()).unwrap();
+        let expected = process_visit(
+            1,
+            serde_json::from_str(&body).unwrap(),
+            USER_AGENT,
+            Enrich::default(),
+        )
+        .unwrap();
+        assert_eq!(visit["visitor"]["id"], json!(expected.visitor.id));
+        assert_eq!(visit["page"]["id"], json!(expected.page.id));
+        assert_eq!(
+            visitor_id(1, visitor, USER_AGENT).unwrap(),
+            expected.visitor.id
+        );
+        assert_eq!(
+            page_id(1, "https://example.com/blog").unwrap(),
+            expected.page.id
+        );
+
+        let body = format!(
+            r#"[{{"type": "event", "session": "7", "visitor": {visitor}, "page": {page}, "name": "signup", "data": {{}}}}]"#
+        );
+        let records: Value =
+            serde_json::from_str(&process_batch(1, &body, USER_AGENT).unwrap()).unwrap();
+        assert_eq!(records[0]["name"], "signup");
+        assert_eq!(records[0]["visitor"]["id"], json!(expected.visitor.id));
+    }
+
+    #[test]
+    fn raises_errors() {
+        assert!(handle_visit(1, "{}", USER_AGENT).is_err());
+        assert!(handle_event(1, "[", USER_AGENT).is_err());
+        assert!(page_id(1, "not a url").is_err());
+    }
+}
@@ -0,0 +1,360 @@
+//! Monthly quotas of hosted projects.
+//!
+//! Every processed record (visits and events) counts towards the monthly
+//! quota of its project. Counters live in a [`CounterStore`], shared by all
+//! collectors of a deployment. Crossing [`WARNING_PERCENT`] and 100% of the
+//! soft limit produces a [`QuotaWarning`] once per month, records above the
+//! hard limit are rejected with [`Error::QuotaExceeded`].
+//!
+//! A [`QuotaGuard`] enforces the [quotas](ProjectSettings::quota) of the
+//! project settings, see [`PipelineConfig::quota`]. Beacons of projects over
+//! the hard limit are rejected on submit, warnings are delivered to the sink
+//! as [`WARNING_EVENT`] events.
+//!
+//! [`PipelineConfig::quota`]: crate::pipeline::PipelineConfig::quota
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::audit::{AuditLog, Reason};
+use crate::config::provider::ProjectConfigProvider;
+use crate::config::ProjectSettings;
+use crate::{Error, Event, Record};
+
+/// Share of the soft limit at which the first warning is emitted.
+pub const WARNING_PERCENT: u64 = 80;
+
+/// Name of the events carrying a [`QuotaWarning`], reserved for the
+/// collector (see [`RESERVED_EVENT_PREFIXES`](crate::RESERVED_EVENT_PREFIXES)).
+pub const WARNING_EVENT: &str = "abineo:quota_warning";
+
+/// Limits of records per month.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// The included volume, warnings are based on it.
+    pub soft: u64,
+    /// Records beyond it are rejected, unlimited if absent.
+    #[serde(default)]
+    pub hard: Option<u64>,
+}
+
+/// Counters of records per project and month, e.g. in Redis.
+pub trait CounterStore: Debug + Send + Sync {
+    /// Adds `n` to the counter and returns the new total.
+    fn increment(&self, project: i64, month: &str, n: u64) -> Result<u64, Error>;
+}
+
+/// Counters of a single collector.
+#[derive(Debug, Default)]
+pub struct MemoryCounters(Mutex<HashMap<(i64, String), u64>>);
+
+impl CounterStore for MemoryCounters {
+    fn increment(&self, project: i64, month: &str, n: u64) -> Result<u64, Error> {
+        let mut counters = self.0.lock().unwrap();
+        let count = counters.entry((project, month.to_string())).or_default();
+        *count += n;
+        Ok(*count)
+    }
+}
+
+/// Emitted when a project crossed a share of its soft limit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaWarning {
+    pub project: i64,
+    /// `YYYY-MM`.
+    pub month: String,
+    /// [`WARNING_PERCENT`] or 100.
+    pub percent: u64,
+    pub count: u64,
+    pub limit: u64,
+}
+
+impl QuotaWarning {
+    /// The [`WARNING_EVENT`] of the project, the warning is its data.
+    pub fn to_event(&self, time: DateTime<Utc>) -> Event {
+        Event {
+            time,
+            project: self.project,
+            name: WARNING_EVENT.to_string(),
+            data: json!({
+                "month": self.month,
+                "percent": self.percent,
+                "count": self.count,
+                "limit": self.limit,
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+/// Month of `time` as used for counter keys.
+pub fn month(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m").to_string()
+}
+
+/// Counts `n` records of a project.
+///
+/// Rejected records are counted too, the hard limit cuts off the offered
+/// traffic. Returns the warning if the records crossed a threshold.
+pub fn count(
+    store: &dyn CounterStore,
+    project: i64,
+    quota: &Quota,
+    n: u64,
+    now: DateTime<Utc>,
+) -> Result<Option<QuotaWarning>, Error> {
+    let month = month(now);
+    let count = store.increment(project, &month, n)?;
+    if quota.hard.is_some_and(|hard| count > hard) {
+        return Err(Error::QuotaExceeded);
+    }
+
+    let before = count.saturating_sub(n);
+    let percent = [100, WARNING_PERCENT].into_iter().find(|percent| {
+        let threshold = quota.soft.saturating_mul(*percent).div_ceil(100);
+        before < threshold && threshold <= count
+    });
+    Ok(percent.map(|percent| QuotaWarning {
+        project,
+        month,
+        percent,
+        count,
+        limit: quota.soft,
+    }))
+}
+
+/// Enforces the quotas of the project settings on submitted beacons and
+/// processed records.
+pub struct QuotaGuard {
+    store: Arc<dyn CounterStore>,
+    projects: Arc<dyn ProjectConfigProvider>,
+    on_warning: Box<dyn Fn(QuotaWarning) + Send + Sync>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl QuotaGuard {
+    pub fn new(store: Arc<dyn CounterStore>, projects: Arc<dyn ProjectConfigProvider>) -> Self {
+        QuotaGuard {
+            store,
+            projects,
+            on_warning: Box::new(|_| {}),
+            audit: None,
+        }
+    }
+
+    /// Records the rejected records.
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Receives the warnings, e.g. to notify the project owner.
+    pub fn on_warning(mut self, on_warning: impl Fn(QuotaWarning) + Send + Sync + 'static) -> Self {
+        self.on_warning = Box::new(on_warning);
+        self
+    }
+
+    fn quota(&self, project: i64) -> Option<Quota> {
+        let settings = self.projects.project(project)?;
+        let ProjectSettings { quota, .. } = settings.as_ref();
+        *quota
+    }
+
+    /// Fails if the project already reached its hard limit this month,
+    /// without counting. Beacons are checked before they are queued.
+    ///
+    /// Passes if the counter store fails, an outage must not lose data.
+    pub fn ensure_within(&self, project: i64, now: DateTime<Utc>) -> Result<(), Error> {
+        let Some(hard) = self.quota(project).and_then(|quota| quota.hard) else {
+            return Ok(());
+        };
+        match self.store.increment(project, &month(now), 0) {
+            Ok(count) if count >= hard => {
+                if let Some(audit) = &self.audit {
+                    audit.record(project, Reason::QuotaExceeded, None, now);
+                }
+                Err(Error::QuotaExceeded)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a record, failing if its project is over the hard limit.
+    /// Returns the warning if the record crossed a threshold, after passing
+    /// it to [`on_warning`](Self::on_warning).
+    ///
+    /// Projects without settings or quota and dry runs are not counted.
+    pub fn check(&self, record: &Record) -> Result<Option<QuotaWarning>, Error> {
+        if record.is_dry_run() {
+            return Ok(None);
+        }
+        let Some(quota) = self.quota(record.project()) else {
+            return Ok(None);
+        };
+        let warning = count(
+            self.store.as_ref(),
+            record.project(),
+            &quota,
+            1,
+            record.time(),
+        )
+        .inspect_err(|err| {
+            if let (Error::QuotaExceeded, Some(audit)) = (err, &self.audit) {
+                audit.record(record.project(), Reason::QuotaExceeded, None, record.time());
+            }
+        })?;
+        if let Some(warning) = &warning {
+            (self.on_warning)(warning.clone());
+        }
+        Ok(warning)
+    }
+}
+
+impl fmt::Debug for QuotaGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuotaGuard")
+            .field("store", &self.store)
+            .field("projects", &self.projects)
+            .field("audit", &self.audit)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use chrono::TimeZone;
+
+    use crate::config::provider::StaticProjects;
+    use crate::{Event, Visit};
+
+    use super::*;
+
+    fn at(month: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, month, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn warns_once_per_threshold() {
+        let store = MemoryCounters::default();
+        let quota = Quota {
+            soft: 10,
+            hard: Some(12),
+        };
+        let mut warnings = Vec::new();
+        for _ in 0..12 {
+            if let Some(warning) = count(&store, 1, &quota, 1, at(10)).unwrap() {
+                warnings.push((warning.percent, warning.count));
+            }
+        }
+        assert_eq!(warnings, [(80, 8), (100, 10)]);
+        assert!(matches!(
+            count(&store, 1, &quota, 1, at(10)),
+            Err(Error::QuotaExceeded)
+        ));
+        // a new month starts over
+        assert_eq!(count(&store, 1, &quota, 1, at(11)).unwrap(), None);
+    }
+
+    #[test]
+    fn reports_highest_crossed_threshold() {
+        let store = MemoryCounters::default();
+        let quota = Quota {
+            soft: 10,
+            hard: None,
+        };
+        let warning = count(&store, 1, &quota, 50, at(10)).unwrap().unwrap();
+        assert_eq!((warning.percent, warning.month.as_str()), (100, "2023-10"));
+    }
+
+    #[test]
+    fn guard_rejects_records_over_hard_limit() {
+        let settings = ProjectSettings {
+            quota: Some(Quota {
+                soft: 1,
+                hard: Some(2),
+            }),
+            ..Default::default()
+        };
+        let projects = StaticProjects::new(BTreeMap::from([(1, settings)]));
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let audit = Arc::new(AuditLog::default());
+        let guard = QuotaGuard::new(Arc::new(MemoryCounters::default()), Arc::new(projects))
+            .with_audit(audit.clone())
+            .on_warning({
+                let warnings = warnings.clone();
+                move |warning| warnings.lock().unwrap().push(warning.percent)
+            });
+
+        let record = |project| -> Record {
+            Event {
+                project,
+                time: at(10),
+                ..Default::default()
+            }
+            .into()
+        };
+        let checked: Vec<_> = (0..3).map(|_| guard.check(&record(1))).collect();
+        assert_eq!(checked[0].as_ref().unwrap().as_ref().unwrap().percent, 100);
+        assert!(matches!(checked[1], Ok(None)));
+        assert!(matches!(checked[2], Err(Error::QuotaExceeded)));
+        assert!((0..3).all(|_| matches!(guard.check(&Visit::default().into()), Ok(None))));
+        assert_eq!(*warnings.lock().unwrap(), [100]);
+        let rejected = audit.query(&Default::default());
+        assert_eq!(
+            (rejected[0].reason, rejected[0].count),
+            (Reason::QuotaExceeded, 1)
+        );
+    }
+
+    #[test]
+    fn guard_rejects_beacons_at_hard_limit() {
+        let settings = ProjectSettings {
+            quota: Some(Quota {
+                soft: 1,
+                hard: Some(1),
+            }),
+            ..Default::default()
+        };
+        let projects = StaticProjects::new(BTreeMap::from([(1, settings)]));
+        let store = Arc::new(MemoryCounters::default());
+        let guard = QuotaGuard::new(store.clone(), Arc::new(projects));
+        assert!(guard.ensure_within(1, at(10)).is_ok());
+        store.increment(1, "2023-10", 1).unwrap();
+        assert!(matches!(
+            guard.ensure_within(1, at(10)),
+            Err(Error::QuotaExceeded)
+        ));
+        // checking doesn't count
+        assert_eq!(store.increment(1, "2023-10", 0).unwrap(), 1);
+        assert!(guard.ensure_within(1, at(11)).is_ok());
+        assert!(guard.ensure_within(2, at(10)).is_ok());
+    }
+
+    #[test]
+    fn warnings_are_reserved_events() {
+        let warning = QuotaWarning {
+            project: 1,
+            month: "2023-10".to_string(),
+            percent: 80,
+            count: 8,
+            limit: 10,
+        };
+        let event = warning.to_event(at(10));
+        assert_eq!((event.project, event.time), (1, at(10)));
+        assert!(crate::RESERVED_EVENT_PREFIXES
+            .iter()
+            .any(|prefix| event.name.starts_with(prefix)));
+        assert_eq!(
+            event.data,
+            json!({"month": "2023-10", "percent": 80, "count": 8, "limit": 10})
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Capture of raw beacons for reprocessing.
+//!
+//! Enrichment bugs (wrong regions, outdated user agent rules) end up in every
+//! record processed before they are fixed. With capture enabled, the
+//! original body and request headers of each beacon are journaled next to
+//! the processed records, so the affected period can be processed again.
+//!
+//! Captures are size-limited, a truncated capture is kept for inspection but
+//! can't be reprocessed.
+
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::buffer::{DrainError, Journal};
+use crate::clock;
+use crate::pipeline::Beacon;
+use crate::Error;
+
+/// Default for [`CaptureLimits::max_body`].
+pub const DEFAULT_MAX_BODY: usize = 64 * 1024;
+
+/// Default for [`CaptureLimits::max_headers`].
+pub const DEFAULT_MAX_HEADERS: usize = 4 * 1024;
+
+/// Headers never captured, compared case-insensitively.
+pub const SENSITIVE_HEADERS: &[&str] = &["authorization", "cookie", "proxy-authorization"];
+
+/// A beacon as received, see [`CaptureLimits::capture`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RawBeacon {
+    pub received: DateTime<Utc>,
+    pub project_id: i64,
+    pub user_agent: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    /// Whether the body or headers were cut off at the limits.
+    pub truncated: bool,
+}
+
+impl RawBeacon {
+    /// Turns the capture back into a beacon.
+    ///
+    /// Returns `None` for truncated captures, their body is incomplete.
+    pub fn beacon(&self) -> Option<Beacon> {
+        if self.truncated {
+            return None;
+        }
+        Some(Beacon {
+            project_id: self.project_id,
+            user_agent: self.user_agent.clone(),
+            headers: self.headers.clone(),
+            body: self.body.clone().into_bytes(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureLimits {
+    /// Maximum size of the body in bytes.
+    pub max_body: usize,
+    /// Maximum size of all header names and values in bytes.
+    pub max_headers: usize,
+}
+
+impl Default for CaptureLimits {
+    fn default() -> Self {
+        CaptureLimits {
+            max_body: DEFAULT_MAX_BODY,
+            max_headers: DEFAULT_MAX_HEADERS,
+        }
+    }
+}
+
+impl CaptureLimits {
+    /// Copies the beacon within the limits.
+    ///
+    /// [Sensitive](SENSITIVE_HEADERS) headers are left out, headers that
+    /// don't fit anymore are dropped.
+    pub fn capture(&self, beacon: &Beacon) -> RawBeacon {
+        let mut truncated = false;
+
+        let mut body = String::from_utf8_lossy(&beacon.body).into_owned();
+        if body.len() > self.max_body {
+            let mut end = self.max_body;
+            while !body.is_char_boundary(end) {
+                end -= 1;
+            }
+            body.truncate(end);
+            truncated = true;
+        }
+
+        let mut headers = Vec::new();
+        let mut size = 0;
+        for (name, value) in &beacon.headers {
+            if SENSITIVE_HEADERS
+                .iter()
+                .any(|sensitive| name.eq_ignore_ascii_case(sensitive))
+            {
+                continue;
+            }
+            size += name.len() + value.len();
+            if size > self.max_headers {
+                truncated = true;
+                break;
+            }
+            headers.push((name.clone(), value.clone()));
+        }
+
+        RawBeacon {
+            received: clock::now(),
+            project_id: beacon.project_id,
+            user_agent: beacon.user_agent.clone(),
+            headers,
+            body,
+            truncated,
+        }
+    }
+}
+
+/// Journals raw beacons, shared by the parse workers of a
+/// [`Pipeline`](crate::pipeline::Pipeline).
+#[derive(Debug)]
+pub struct Capture {
+    limits: CaptureLimits,
+    journal: Mutex<Journal<RawBeacon>>,
+}
+
+impl Capture {
+    pub fn new(journal: Journal<RawBeacon>) -> Self {
+        Capture {
+            limits: CaptureLimits::default(),
+            journal: Mutex::new(journal),
+        }
+    }
+
+    pub fn with_limits(mut self, limits: CaptureLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn record(&self, beacon: &Beacon) -> Result<(), Error> {
+        let raw = self.limits.capture(beacon);
+        self.journal
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .append(&raw)
+    }
+
+    /// Hands the captured beacons to `deliver`, see [`Journal::drain`].
+    pub fn drain<E>(
+        &self,
+        deliver: impl FnMut(Vec<RawBeacon>) -> Result<(), E>,
+    ) -> Result<(), DrainError<E>> {
+        self.journal
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .drain(deliver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::fs;
+
+    use super::*;
+
+    fn beacon(body: &str) -> Beacon {
+        Beacon {
+            project_id: 1,
+            user_agent: "Mozilla/5.0".to_string(),
+            headers: vec![
+                ("Accept-Language".to_string(), "de-CH".to_string()),
+                ("Cookie".to_string(), "session=secret".to_string()),
+                ("Referer".to_string(), "https://example.com/".to_string()),
+            ],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn leaves_out_sensitive_headers() {
+        let raw = CaptureLimits::default().capture(&beacon("{}"));
+        let names: Vec<_> = raw.headers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["Accept-Language", "Referer"]);
+        assert!(!raw.truncated);
+        assert_eq!(raw.beacon().unwrap().body, b"{}");
+    }
+
+    #[test]
+    fn truncates_at_limits() {
+        let limits = CaptureLimits {
+            max_body: 3,
+            max_headers: 20,
+        };
+        let raw = limits.capture(&beacon("aäb"));
+        assert_eq!(raw.body, "aä");
+        assert_eq!(raw.headers.len(), 1);
+        assert!(raw.truncated);
+        assert!(raw.beacon().is_none());
+    }
+
+    #[test]
+    fn journals_captures() {
+        let dir = std::env::temp_dir().join(format!("abineo-capture-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let capture = Capture::new(Journal::open(&dir).unwrap());
+        capture.record(&beacon(r#"{"a":1}"#)).unwrap();
+        capture.record(&beacon(r#"{"b":2}"#)).unwrap();
+
+        let mut bodies = Vec::new();
+        capture
+            .drain(|raw| {
+                bodies.extend(raw.into_iter().map(|raw| raw.body));
+                Ok::<_, Infallible>(())
+            })
+            .unwrap();
+        assert_eq!(bodies, [r#"{"a":1}"#, r#"{"b":2}"#]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+//! Reprocessing of [captured](crate::raw) beacons.
+//!
+//! After an enrichment fix (new user agent rules, new geo data), the raw
+//! beacons of the affected period are read back from the capture journal or
+//! from archived NDJSON objects and run through the current enrichment
+//! again. The corrected records carry a generation, so stores can replace
+//! the records of earlier generations.
+
+use std::io::BufRead;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::api;
+use crate::middleware::{Chain, Middleware};
+use crate::raw::RawBeacon;
+use crate::{Error, Record};
+
+/// A record produced by a [`Reprocessor`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reprocessed {
+    /// Generation of the reprocessing run, records of the original
+    /// processing are generation 0.
+    pub generation: u32,
+    #[serde(flatten)]
+    pub record: Record,
+}
+
+/// Counters of [`Reprocessor::run`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReprocessStats {
+    pub beacons: u64,
+    /// Beacons that were truncated when captured.
+    pub skipped: u64,
+    /// Beacons or records that failed to parse or process.
+    pub invalid: u64,
+    /// Records dropped by the middleware.
+    pub filtered: u64,
+    pub records: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reprocessor {
+    generation: u32,
+    lenient: bool,
+    middleware: Arc<Chain>,
+}
+
+impl Reprocessor {
+    /// Tags the records with `generation`, which should be higher than the
+    /// generation of every earlier run over the same beacons.
+    pub fn new(generation: u32) -> Self {
+        Reprocessor {
+            generation,
+            lenient: false,
+            middleware: Arc::default(),
+        }
+    }
+
+    /// Repairs beacons of outdated trackers, see [`api::lenient`].
+    pub fn with_lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Runs after a record was processed, like
+    /// [`PipelineConfig::middleware`](crate::pipeline::PipelineConfig::middleware).
+    pub fn with_middleware(mut self, middleware: Arc<Chain>) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
+    /// Processes a single beacon again.
+    ///
+    /// The records keep the time the beacon was received. Records that
+    /// fail to process are left out, records dropped by the middleware too.
+    pub fn reprocess(&self, raw: &RawBeacon) -> Result<Vec<Reprocessed>, Error> {
+        self.records(raw, &mut ReprocessStats::default())
+    }
+
+    /// Processes the beacons and hands the records to `emit`, in order.
+    pub fn run<I>(&self, beacons: I, mut emit: impl FnMut(Reprocessed)) -> ReprocessStats
+    where
+        I: IntoIterator<Item = RawBeacon>,
+    {
+        let mut stats = ReprocessStats::default();
+        for raw in beacons {
+            stats.beacons += 1;
+            match self.records(&raw, &mut stats) {
+                Ok(records) => {
+                    stats.records += records.len() as u64;
+                    records.into_iter().for_each(&mut emit);
+                }
+                Err(Error::Truncated) => stats.skipped += 1,
+                Err(_) => stats.invalid += 1,
+            }
+        }
+        stats
+    }
+
+    fn records(
+        &self,
+        raw: &RawBeacon,
+        stats: &mut ReprocessStats,
+    ) -> Result<Vec<Reprocessed>, Error> {
+        let beacon = raw.beacon().ok_or(Error::Truncated)?;
+        let body = if self.lenient {
+            api::lenient::decode(&beacon.body)?.0
+        } else {
+            api::decode(&beacon.body)?
+        };
+
+        let mut val = Vec::with_capacity(body.len());
+        for record in body {
+            let Ok(mut record) = api::process_record(
+                beacon.project_id,
+                record,
+                &beacon.user_agent,
+                api::Enrich::default(),
+            ) else {
+                stats.invalid += 1;
+                continue;
+            };
+            set_time(&mut record, raw.received);
+            match self.middleware.process(record) {
+                Some(record) => val.push(Reprocessed {
+                    generation: self.generation,
+                    record,
+                }),
+                None => stats.filtered += 1,
+            }
+        }
+        Ok(val)
+    }
+}
+
+fn set_time(record: &mut Record, time: DateTime<Utc>) {
+    match record {
+        Record::Visit(visit) => visit.time = time,
+        Record::Event(event) => event.time = time,
+        Record::Session(summary) => summary.time = time,
+    }
+}
+
+/// Reads raw beacons from NDJSON, e.g. an archived and decompressed capture
+/// journal segment.
+///
+/// Empty lines are skipped.
+pub fn read_ndjson(reader: impl BufRead) -> impl Iterator<Item = Result<RawBeacon, Error>> {
+    reader.lines().filter_map(|line| match line {
+        Ok(line) if line.trim().is_empty() => None,
+        Ok(line) => Some(serde_json::from_str(&line).map_err(Error::from)),
+        Err(err) => Some(Err(err.into())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    const USER_AGENT: &str = "Mozilla/5.0 (Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/112.0.0.0 Safari/537.36";
+
+    fn raw(body: &str, truncated: bool) -> RawBeacon {
+        RawBeacon {
+            received: Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap(),
+            project_id: 1,
+            user_agent: USER_AGENT.to_string(),
+            headers: Vec::new(),
+            body: body.to_string(),
+            truncated,
+        }
+    }
+
+    const EVENT: &str = r#"{"type": "event", "session": "7", "visitor": {}, "page": {"url": "https://example.com/"}, "name": "signup", "data": {}}"#;
+
+    #[test]
+    fn tags_generation_and_keeps_time() {
+        let records = Reprocessor::new(3).reprocess(&raw(EVENT, false)).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].generation, 3);
+        assert_eq!(records[0].record.time(), raw(EVENT, false).received);
+
+        let json = serde_json::to_value(&records[0]).unwrap();
+        assert_eq!(json["generation"], 3);
+        assert_eq!(json["type"], "event");
+        let back: Reprocessed = serde_json::from_value(json).unwrap();
+        assert_eq!(back.generation, 3);
+    }
+
+    #[test]
+    fn counts_skipped_and_invalid() {
+        let beacons = [raw(EVENT, false), raw(EVENT, true), raw("not json", false)];
+        let mut records = Vec::new();
+        let stats = Reprocessor::new(1).run(beacons, |record| records.push(record));
+        assert_eq!(
+            stats,
+            ReprocessStats {
+                beacons: 3,
+                skipped: 1,
+                invalid: 1,
+                filtered: 0,
+                records: 1,
+            }
+        );
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn reads_ndjson() {
+        let lines = [raw(EVENT, false), raw("{}", true)]
+            .iter()
+            .map(|raw| serde_json::to_string(raw).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let beacons: Vec<_> = read_ndjson(lines.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(beacons, [raw(EVENT, false), raw("{}", true)]);
+    }
+}
@@ -0,0 +1,254 @@
+//! Revenue tracked through event data.
+//!
+//! Events carry revenue as `{"revenue": {"amount": 49.9, "currency": "CHF"}}`
+//! in their data. Currencies are validated against ISO 4217 and amounts can
+//! be converted to a project's base currency with a [`RateProvider`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+
+use crate::{Error, Event};
+
+/// Active ISO 4217 currency codes, sorted.
+const CURRENCIES: &[&str] = &[
+    "AED", "AFN", "ALL", "AMD", "ANG", "AOA", "ARS", "AUD", "AWG", "AZN", "BAM", "BBD", "BDT",
+    "BGN", "BHD", "BIF", "BMD", "BND", "BOB", "BRL", "BSD", "BTN", "BWP", "BYN", "BZD", "CAD",
+    "CDF", "CHF", "CLP", "CNY", "COP", "CRC", "CUP", "CVE", "CZK", "DJF", "DKK", "DOP", "DZD",
+    "EGP", "ERN", "ETB", "EUR", "FJD", "FKP", "GBP", "GEL", "GHS", "GIP", "GMD", "GNF", "GTQ",
+    "GYD", "HKD", "HNL", "HTG", "HUF", "IDR", "ILS", "INR", "IQD", "IRR", "ISK", "JMD", "JOD",
+    "JPY", "KES", "KGS", "KHR", "KMF", "KPW", "KRW", "KWD", "KYD", "KZT", "LAK", "LBP", "LKR",
+    "LRD", "LSL", "LYD", "MAD", "MDL", "MGA", "MKD", "MMK", "MNT", "MOP", "MRU", "MUR", "MVR",
+    "MWK", "MXN", "MYR", "MZN", "NAD", "NGN", "NIO", "NOK", "NPR", "NZD", "OMR", "PAB", "PEN",
+    "PGK", "PHP", "PKR", "PLN", "PYG", "QAR", "RON", "RSD", "RUB", "RWF", "SAR", "SBD", "SCR",
+    "SDG", "SEK", "SGD", "SHP", "SLE", "SOS", "SRD", "SSP", "STN", "SVC", "SYP", "SZL", "THB",
+    "TJS", "TMT", "TND", "TOP", "TRY", "TTD", "TWD", "TZS", "UAH", "UGX", "USD", "UYU", "UZS",
+    "VES", "VND", "VUV", "WST", "XAF", "XCD", "XOF", "XPF", "YER", "ZAR", "ZMW", "ZWL",
+];
+
+/// An ISO 4217 currency code.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const CHF: Currency = Currency(*b"CHF");
+    pub const EUR: Currency = Currency(*b"EUR");
+    pub const USD: Currency = Currency(*b"USD");
+
+    pub fn as_str(&self) -> &str {
+        // only constructed from ascii codes
+        std::str::from_utf8(&self.0).unwrap()
+    }
+}
+
+impl FromStr for Currency {
+    type Err = Error;
+
+    /// Codes are case insensitive.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = s.trim().to_ascii_uppercase();
+        if CURRENCIES.binary_search(&code.as_str()).is_err() {
+            return Err(Error::InvalidRevenue("unknown currency"));
+        }
+        let mut val = [0; 3];
+        val.copy_from_slice(code.as_bytes());
+        Ok(Currency(val))
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Revenue {
+    pub amount: f64,
+    pub currency: Currency,
+}
+
+impl Revenue {
+    /// Converts the amount to `currency`, `None` if no rate is known.
+    pub fn convert(
+        &self,
+        currency: Currency,
+        rates: &(impl RateProvider + ?Sized),
+    ) -> Option<Self> {
+        if self.currency == currency {
+            return Some(*self);
+        }
+        Some(Revenue {
+            amount: self.amount * rates.rate(self.currency, currency)?,
+            currency,
+        })
+    }
+}
+
+/// Source of exchange rates for [`Revenue::convert`].
+pub trait RateProvider {
+    /// The amount of `to` one unit of `from` is worth.
+    fn rate(&self, from: Currency, to: Currency) -> Option<f64>;
+}
+
+/// Fixed exchange rates relative to a single base currency.
+#[derive(Debug, Clone)]
+pub struct FixedRates {
+    base: Currency,
+    /// currency → units per one unit of the base currency
+    rates: HashMap<Currency, f64>,
+}
+
+impl FixedRates {
+    pub fn new(base: Currency) -> Self {
+        FixedRates {
+            base,
+            rates: HashMap::from([(base, 1.0)]),
+        }
+    }
+
+    /// Sets how many units of `currency` one unit of the base currency is worth.
+    pub fn with_rate(mut self, currency: Currency, rate: f64) -> Self {
+        self.rates.insert(currency, rate);
+        self
+    }
+
+    pub fn base(&self) -> Currency {
+        self.base
+    }
+}
+
+impl RateProvider for FixedRates {
+    fn rate(&self, from: Currency, to: Currency) -> Option<f64> {
+        Some(self.rates.get(&to)? / self.rates.get(&from)?)
+    }
+}
+
+impl Event {
+    /// Parses the `revenue` field of the event data.
+    ///
+    /// Returns `Ok(None)` if there is no such field and an error if it is
+    /// malformed, has a negative or non-finite amount or an unknown currency.
+    pub fn revenue(&self) -> Result<Option<Revenue>, Error> {
+        let Some(revenue) = self.get("/revenue") else {
+            return Ok(None);
+        };
+        let amount = revenue
+            .get("amount")
+            .and_then(|amount| amount.as_f64())
+            .ok_or(Error::InvalidRevenue("missing amount"))?;
+        if !amount.is_finite() || amount < 0.0 {
+            return Err(Error::InvalidRevenue("invalid amount"));
+        }
+        let currency = revenue
+            .get("currency")
+            .and_then(|currency| currency.as_str())
+            .ok_or(Error::InvalidRevenue("missing currency"))?
+            .parse()?;
+        Ok(Some(Revenue { amount, currency }))
+    }
+
+    /// Parses the revenue and converts it to the project's base currency.
+    ///
+    /// Returns an error if the revenue can't be converted.
+    pub fn revenue_in(
+        &self,
+        base: Currency,
+        rates: &(impl RateProvider + ?Sized),
+    ) -> Result<Option<Revenue>, Error> {
+        match self.revenue()? {
+            Some(revenue) => revenue
+                .convert(base, rates)
+                .map(Some)
+                .ok_or(Error::InvalidRevenue("no exchange rate")),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn event(data: Value) -> Event {
+        Event {
+            data,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn currency_codes() {
+        assert!(CURRENCIES.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!("chf".parse::<Currency>().unwrap(), Currency::CHF);
+        assert_eq!(Currency::EUR.to_string(), "EUR");
+        assert!("XYZ".parse::<Currency>().is_err());
+        assert!("EURO".parse::<Currency>().is_err());
+    }
+
+    #[test]
+    fn parses_revenue() {
+        let revenue = event(json!({"revenue": {"amount": 49.9, "currency": "chf"}}))
+            .revenue()
+            .unwrap();
+        assert_eq!(
+            revenue,
+            Some(Revenue {
+                amount: 49.9,
+                currency: Currency::CHF
+            })
+        );
+        assert_eq!(event(json!({"plan": "pro"})).revenue().unwrap(), None);
+
+        let invalid = |data: Value| match event(data).revenue() {
+            Err(Error::InvalidRevenue(reason)) => reason,
+            val => panic!("expected error, got {val:?}"),
+        };
+        assert_eq!(invalid(json!({"revenue": 10})), "missing amount");
+        assert_eq!(
+            invalid(json!({"revenue": {"amount": -1, "currency": "CHF"}})),
+            "invalid amount"
+        );
+        assert_eq!(
+            invalid(json!({"revenue": {"amount": 1}})),
+            "missing currency"
+        );
+        assert_eq!(
+            invalid(json!({"revenue": {"amount": 1, "currency": "BTC"}})),
+            "unknown currency"
+        );
+    }
+
+    #[test]
+    fn converts_to_base_currency() {
+        let rates = FixedRates::new(Currency::CHF)
+            .with_rate(Currency::EUR, 1.25)
+            .with_rate(Currency::USD, 1.5);
+        let event = event(json!({"revenue": {"amount": 30, "currency": "USD"}}));
+
+        let revenue = event.revenue_in(Currency::EUR, &rates).unwrap().unwrap();
+        assert_eq!(revenue.currency, Currency::EUR);
+        assert!((revenue.amount - 25.0).abs() < 1e-9);
+
+        let revenue = event.revenue_in(Currency::CHF, &rates).unwrap().unwrap();
+        assert!((revenue.amount - 20.0).abs() < 1e-9);
+
+        assert!(event.revenue_in("JPY".parse().unwrap(), &rates).is_err());
+    }
+}
@@ -0,0 +1,718 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::flush::Drain;
+use crate::{Error, Event, Page, Record, Referrer, UtmParam, Visit, Visitor};
+
+#[cfg(feature = "redis")]
+pub mod redis;
+
+/// Session state shared by the beacons of a session.
+///
+/// [`SessionTracker`] keeps the sessions of a single collector in memory.
+/// Deployments with several collectors share them in a store like Redis
+/// (with the `redis` feature) instead, so the beacons of a session may
+/// reach any collector.
+pub trait SessionStore: Debug + Send + Sync {
+    /// See [`SessionTracker::record_duration`].
+    fn record_duration(
+        &self,
+        project_id: i64,
+        session: i64,
+        page_id: i64,
+        duration: i32,
+    ) -> Result<Option<i32>, Error>;
+
+    /// See [`SessionTracker::mark_unique`].
+    fn mark_unique(&self, visit: &mut Visit) -> Result<(), Error>;
+
+    /// See [`SessionTracker::link_previous`].
+    fn link_previous(&self, visit: &mut Visit) -> Result<(), Error>;
+
+    /// See [`SessionTracker::attribute_visit`].
+    fn attribute_visit(&self, visit: &mut Visit) -> Result<(), Error>;
+
+    /// See [`SessionTracker::attribute_event`].
+    fn attribute_event(&self, event: &mut Event) -> Result<(), Error>;
+
+    /// See [`SessionTracker::observe`].
+    fn observe(&self, record: &Record) -> Result<(), Error>;
+
+    /// See [`SessionTracker::expire`].
+    fn expire(&self, idle: Duration) -> Result<Vec<SessionSummary>, Error>;
+
+    /// Summaries of the sessions to write before the collector shuts down,
+    /// see [`SessionTracker::expire_all`].
+    fn expire_all(&self) -> Result<Vec<SessionSummary>, Error>;
+}
+
+/// Per-session state shared between beacons of the same session.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Session {
+    last_seen: DateTime<Utc>,
+    /// Highest cumulative duration reported per page id.
+    durations: HashMap<i64, i32>,
+    /// Ids of the pages viewed in the session.
+    viewed: HashSet<i64>,
+    /// Id of the most recently viewed page.
+    last_page: Option<i64>,
+    /// First utm parameters of the session and when they were seen.
+    utm_param: Option<(UtmParam, DateTime<Utc>)>,
+    /// Summary of the [observed](SessionTracker::observe) records.
+    #[serde(default)]
+    summary: Option<SessionSummary>,
+}
+
+/// Synthesized when a session [expires](SessionTracker::expire), so
+/// session-level reports don't need to scan the visits.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SessionSummary {
+    /// Time of the first record of the session.
+    pub time: DateTime<Utc>,
+    pub project: i64,
+    pub session: i64,
+    /// The visitor of the first record.
+    pub visitor: Visitor,
+    /// Time of the last record of the session.
+    pub end: DateTime<Utc>,
+    pub pages_viewed: u32,
+    /// Sum of the time on page of the viewed pages in seconds.
+    pub duration: i32,
+    pub entry_page: Page,
+    pub exit_page: Page,
+    /// Names of the events tracked in the session, e.g. `signup`, sorted.
+    pub goals: Vec<String>,
+    /// Traffic source of the first record.
+    pub utm_param: Option<UtmParam>,
+    pub referrer: Option<Referrer>,
+    pub channel_group: Option<String>,
+}
+
+impl SessionSummary {
+    fn new(
+        project: i64,
+        session: i64,
+        visitor: &Visitor,
+        page: &Page,
+        time: DateTime<Utc>,
+    ) -> Self {
+        SessionSummary {
+            time,
+            project,
+            session,
+            visitor: visitor.clone(),
+            end: time,
+            entry_page: page.clone(),
+            exit_page: page.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// The summary of a session starting with `visit`.
+    fn from_visit(visit: &Visit) -> Self {
+        let mut summary = SessionSummary::new(
+            visit.project,
+            visit.session,
+            &visit.visitor,
+            &visit.page,
+            visit.time,
+        );
+        summary.utm_param = visit.utm_param.clone();
+        summary.referrer = visit.referrer.clone();
+        summary.channel_group = visit.channel_group.clone();
+        summary
+    }
+
+    /// The summary of a session starting with `event`.
+    fn from_event(event: &Event) -> Self {
+        SessionSummary::new(
+            event.project,
+            event.session,
+            &event.visitor,
+            &event.page,
+            event.time,
+        )
+    }
+}
+
+/// A line of a [snapshot](SessionTracker::save).
+#[derive(Serialize, Deserialize)]
+struct Entry<S> {
+    project: i64,
+    session: i64,
+    state: S,
+}
+
+type Shard = Mutex<HashMap<(i64, i64), Session>>;
+
+/// Keeps track of sessions across beacons.
+///
+/// Sessions are keyed by `(project, session)` and kept in memory until
+/// they are [expired](SessionTracker::expire). They are spread over
+/// independently locked shards, so concurrent beacons rarely contend.
+#[derive(Debug)]
+pub struct SessionTracker {
+    shards: Box<[Shard]>,
+    attribution_window: Duration,
+}
+
+impl Default for SessionTracker {
+    fn default() -> Self {
+        SessionTracker {
+            shards: (0..64).map(|_| Shard::default()).collect(),
+            attribution_window: Duration::minutes(30),
+        }
+    }
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long after the first visit of a session its utm parameters are
+    /// attached to later visits and events. Defaults to 30 minutes.
+    pub fn with_attribution_window(mut self, window: Duration) -> Self {
+        self.attribution_window = window;
+        self
+    }
+
+    /// Number of independently locked shards. Defaults to 64.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        self.shards = (0..shards.max(1)).map(|_| Shard::default()).collect();
+        self
+    }
+
+    fn shard(&self, key: (i64, i64)) -> MutexGuard<'_, HashMap<(i64, i64), Session>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let idx = hasher.finish() as usize % self.shards.len();
+        self.shards[idx].lock().unwrap()
+    }
+
+    /// Runs `f` on the state of a session, creating it if needed.
+    fn with_session<R>(
+        &self,
+        project_id: i64,
+        session: i64,
+        f: impl FnOnce(&mut Session) -> R,
+    ) -> R {
+        let key = (project_id, session);
+        let mut shard = self.shard(key);
+        let state = shard.entry(key).or_default();
+        state.last_seen = clock::now();
+        f(state)
+    }
+
+    /// Records a cumulative duration for a page within a session.
+    ///
+    /// Returns the new maximum if `duration` exceeds every value previously
+    /// reported for this page, or `None` if the beacon carries no new
+    /// information and can be dropped.
+    pub fn record_duration(
+        &self,
+        project_id: i64,
+        session: i64,
+        page_id: i64,
+        duration: i32,
+    ) -> Option<i32> {
+        self.with_session(project_id, session, |state| {
+            let max = state.durations.entry(page_id).or_insert(i32::MIN);
+            if duration > *max {
+                *max = duration;
+                Some(duration)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Marks whether a visit is the first view of its page in the session.
+    ///
+    /// Call this once per page view, exit beacons of the same view would
+    /// count as a repeated view.
+    pub fn mark_unique(&self, visit: &mut Visit) {
+        let unique = self.with_session(visit.project, visit.session, |state| {
+            state.viewed.insert(visit.page.id)
+        });
+        visit.is_unique_in_session = Some(unique);
+    }
+
+    /// Links a visit to the page viewed before it in the session, unless
+    /// the tracker already supplied the previous page.
+    ///
+    /// Like [`SessionTracker::mark_unique`], call this once per page view.
+    pub fn link_previous(&self, visit: &mut Visit) {
+        let previous = self.with_session(visit.project, visit.session, |state| {
+            state.last_page.replace(visit.page.id)
+        });
+        if visit.previous_page_id.is_none() {
+            visit.previous_page_id = previous;
+        }
+    }
+
+    /// Attributes a visit to the campaign that acquired its session.
+    ///
+    /// The first utm parameters seen in a session are remembered. Later
+    /// visits without their own utm parameters get them attached if they
+    /// happen within the attribution window.
+    pub fn attribute_visit(&self, visit: &mut Visit) {
+        if let Some(utm_param) = self.attribution(
+            visit.project,
+            visit.session,
+            visit.time,
+            visit.utm_param.as_ref(),
+        ) {
+            visit.utm_param.get_or_insert(utm_param);
+        }
+    }
+
+    /// Like [`SessionTracker::attribute_visit`], for conversions tracked
+    /// with events.
+    pub fn attribute_event(&self, event: &mut Event) {
+        if let Some(utm_param) = self.attribution(
+            event.project,
+            event.session,
+            event.time,
+            event.utm_param.as_ref(),
+        ) {
+            event.utm_param.get_or_insert(utm_param);
+        }
+    }
+
+    fn attribution(
+        &self,
+        project_id: i64,
+        session: i64,
+        time: DateTime<Utc>,
+        utm_param: Option<&UtmParam>,
+    ) -> Option<UtmParam> {
+        self.with_session(project_id, session, |state| {
+            match (&state.utm_param, utm_param) {
+                (Some((first, seen)), _) if time - *seen <= self.attribution_window => {
+                    Some(first.clone())
+                }
+                (_, Some(utm_param)) => {
+                    state.utm_param = Some((utm_param.clone(), time));
+                    None
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// Adds a processed record to the summary of its session.
+    ///
+    /// Call this once per delivered record, the
+    /// [pipeline](crate::pipeline::PipelineConfig::sessions) and the tracked
+    /// handlers do. Dry runs and summaries are ignored.
+    pub fn observe(&self, record: &Record) {
+        match record {
+            Record::Visit(visit) if !visit.dry_run && visit.embed.is_none() => {
+                self.with_session(visit.project, visit.session, |state| {
+                    let summary = state
+                        .summary
+                        .get_or_insert_with(|| SessionSummary::from_visit(visit));
+                    summary.end = summary.end.max(visit.time);
+                    match visit.duration {
+                        Some(duration) => {
+                            let max = state.durations.entry(visit.page.id).or_insert(duration);
+                            *max = duration.max(*max);
+                        }
+                        None => {
+                            summary.pages_viewed += 1;
+                            summary.exit_page = visit.page.clone();
+                        }
+                    }
+                });
+            }
+            Record::Event(event) if !event.dry_run => {
+                self.with_session(event.project, event.session, |state| {
+                    let summary = state
+                        .summary
+                        .get_or_insert_with(|| SessionSummary::from_event(event));
+                    summary.end = summary.end.max(event.time);
+                    if let Err(i) = summary.goals.binary_search(&event.name) {
+                        summary.goals.insert(i, event.name.clone());
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Removes all sessions that have not been seen for longer than `idle`
+    /// and returns the summaries of those with
+    /// [observed](SessionTracker::observe) records, to be written to a
+    /// sink as [`Record::Session`].
+    pub fn expire(&self, idle: Duration) -> Vec<SessionSummary> {
+        let threshold = clock::now() - idle;
+        self.remove(|state| state.last_seen < threshold)
+    }
+
+    /// Removes all sessions and returns their summaries, e.g. on shutdown
+    /// so the summaries of open sessions are not lost.
+    pub fn expire_all(&self) -> Vec<SessionSummary> {
+        self.remove(|_| true)
+    }
+
+    fn remove(&self, expired: impl Fn(&Session) -> bool) -> Vec<SessionSummary> {
+        let mut summaries = Vec::new();
+        for shard in self.shards.iter() {
+            let mut sessions = shard.lock().unwrap();
+            sessions.retain(|_, state| {
+                if !expired(state) {
+                    return true;
+                }
+                if let Some(mut summary) = state.summary.take() {
+                    summary.duration = state.durations.values().fold(0, |sum: i32, duration| {
+                        sum.saturating_add((*duration).max(0))
+                    });
+                    summaries.push(summary);
+                }
+                false
+            });
+        }
+        summaries
+    }
+
+    /// Number of sessions currently tracked.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes a snapshot of all sessions to `path`, one JSON object per
+    /// line. The snapshot is written next to `path` and renamed into place,
+    /// so a crash never leaves a partial file.
+    ///
+    /// Shards are locked one after another, call this periodically to
+    /// survive restarts.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        for shard in self.shards.iter() {
+            for (&(project, session), state) in shard.lock().unwrap().iter() {
+                let entry = Entry {
+                    project,
+                    session,
+                    state,
+                };
+                serde_json::to_writer(&mut out, &entry)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        out.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Restores the sessions of a snapshot, replacing tracked sessions with
+    /// the same key. Returns the number of sessions restored.
+    pub fn load(&self, path: impl AsRef<Path>) -> Result<usize, Error> {
+        let mut count = 0;
+        for line in BufReader::new(File::open(path)?).lines() {
+            let entry: Entry<Session> = serde_json::from_str(&line?)?;
+            let key = (entry.project, entry.session);
+            self.shard(key).insert(key, entry.state);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl SessionStore for SessionTracker {
+    fn record_duration(
+        &self,
+        project_id: i64,
+        session: i64,
+        page_id: i64,
+        duration: i32,
+    ) -> Result<Option<i32>, Error> {
+        Ok(SessionTracker::record_duration(
+            self, project_id, session, page_id, duration,
+        ))
+    }
+
+    fn mark_unique(&self, visit: &mut Visit) -> Result<(), Error> {
+        SessionTracker::mark_unique(self, visit);
+        Ok(())
+    }
+
+    fn link_previous(&self, visit: &mut Visit) -> Result<(), Error> {
+        SessionTracker::link_previous(self, visit);
+        Ok(())
+    }
+
+    fn attribute_visit(&self, visit: &mut Visit) -> Result<(), Error> {
+        SessionTracker::attribute_visit(self, visit);
+        Ok(())
+    }
+
+    fn attribute_event(&self, event: &mut Event) -> Result<(), Error> {
+        SessionTracker::attribute_event(self, event);
+        Ok(())
+    }
+
+    fn observe(&self, record: &Record) -> Result<(), Error> {
+        SessionTracker::observe(self, record);
+        Ok(())
+    }
+
+    fn expire(&self, idle: Duration) -> Result<Vec<SessionSummary>, Error> {
+        Ok(SessionTracker::expire(self, idle))
+    }
+
+    fn expire_all(&self) -> Result<Vec<SessionSummary>, Error> {
+        Ok(SessionTracker::expire_all(self))
+    }
+}
+
+/// Expires all sessions, so a [`FlushInto`](crate::flush::FlushInto)
+/// writes the summaries of the open sessions to a sink on shutdown.
+impl Drain for SessionTracker {
+    type Item = Record;
+
+    fn drain(&self) -> Vec<Record> {
+        self.expire_all().into_iter().map(Record::Session).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_max_duration() {
+        let tracker = SessionTracker::new();
+        assert_eq!(tracker.record_duration(1, 7, 42, 5), Some(5));
+        assert_eq!(tracker.record_duration(1, 7, 42, 12), Some(12));
+        assert_eq!(tracker.record_duration(1, 7, 42, 12), None);
+        assert_eq!(tracker.record_duration(1, 7, 42, 3), None);
+    }
+
+    #[test]
+    fn durations_are_scoped_by_session_and_page() {
+        let tracker = SessionTracker::new();
+        assert_eq!(tracker.record_duration(1, 7, 42, 10), Some(10));
+        assert_eq!(tracker.record_duration(1, 7, 43, 5), Some(5));
+        assert_eq!(tracker.record_duration(1, 8, 42, 5), Some(5));
+        assert_eq!(tracker.record_duration(2, 7, 42, 5), Some(5));
+        assert_eq!(tracker.len(), 3);
+    }
+
+    fn utm_param(campaign: &str) -> UtmParam {
+        let mut val = UtmParam {
+            project: 1,
+            campaign: Some(campaign.to_string()),
+            ..Default::default()
+        };
+        val.id = val.compute_id(crate::id::IdVersion::CURRENT);
+        val
+    }
+
+    fn visit(minutes: i64, utm: Option<UtmParam>) -> Visit {
+        Visit {
+            time: DateTime::default() + Duration::minutes(minutes),
+            project: 1,
+            session: 7,
+            utm_param: utm,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn attributes_first_campaign_within_window() {
+        let tracker = SessionTracker::new().with_attribution_window(Duration::minutes(60));
+
+        let mut landing = visit(0, Some(utm_param("autumn")));
+        tracker.attribute_visit(&mut landing);
+        assert_eq!(landing.utm_param.unwrap().id, utm_param("autumn").id);
+
+        let mut next = visit(10, None);
+        tracker.attribute_visit(&mut next);
+        assert_eq!(next.utm_param.unwrap().id, utm_param("autumn").id);
+
+        // visits keep their own campaign
+        let mut other = visit(20, Some(utm_param("winter")));
+        tracker.attribute_visit(&mut other);
+        assert_eq!(other.utm_param.unwrap().id, utm_param("winter").id);
+
+        let mut event = Event {
+            time: DateTime::default() + Duration::minutes(60),
+            project: 1,
+            session: 7,
+            ..Default::default()
+        };
+        tracker.attribute_event(&mut event);
+        assert_eq!(event.utm_param.unwrap().id, utm_param("autumn").id);
+
+        let mut late = visit(61, None);
+        tracker.attribute_visit(&mut late);
+        assert!(late.utm_param.is_none());
+    }
+
+    #[test]
+    fn campaign_after_window_starts_new_attribution() {
+        let tracker = SessionTracker::new();
+        tracker.attribute_visit(&mut visit(0, Some(utm_param("autumn"))));
+        tracker.attribute_visit(&mut visit(45, Some(utm_param("winter"))));
+
+        let mut next = visit(50, None);
+        tracker.attribute_visit(&mut next);
+        assert_eq!(next.utm_param.unwrap().id, utm_param("winter").id);
+
+        let mut other_session = Visit {
+            session: 8,
+            ..visit(50, None)
+        };
+        tracker.attribute_visit(&mut other_session);
+        assert!(other_session.utm_param.is_none());
+    }
+
+    #[test]
+    fn marks_first_view_of_page() {
+        let tracker = SessionTracker::new();
+        let view = |session, page| {
+            let mut visit = Visit {
+                project: 1,
+                session,
+                page: crate::Page {
+                    id: page,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            tracker.mark_unique(&mut visit);
+            visit.is_unique_in_session.unwrap()
+        };
+        assert!(view(7, 42));
+        assert!(view(7, 43));
+        assert!(!view(7, 42));
+        assert!(view(8, 42));
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("sessions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sessions.ndjson");
+
+        let tracker = SessionTracker::new().with_shards(4);
+        tracker.record_duration(1, 7, 42, 10);
+        tracker.attribute_visit(&mut visit(0, Some(utm_param("autumn"))));
+        tracker.record_duration(2, 8, 42, 5);
+        tracker.save(&path).unwrap();
+
+        let restored = SessionTracker::new();
+        assert_eq!(restored.load(&path).unwrap(), 2);
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored.record_duration(1, 7, 42, 10), None);
+        assert_eq!(restored.record_duration(2, 8, 42, 6), Some(6));
+        let mut next = visit(10, None);
+        restored.attribute_visit(&mut next);
+        assert_eq!(next.utm_param.unwrap().id, utm_param("autumn").id);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn summarizes_expired_sessions() {
+        let tracker = SessionTracker::new();
+        let page = |id, path: &str| Page {
+            id,
+            path: path.to_string(),
+            ..Default::default()
+        };
+        let view = |minutes, page| Visit {
+            page,
+            ..visit(minutes, Some(utm_param("autumn")))
+        };
+        let exit = |minutes, page, duration| Visit {
+            duration: Some(duration),
+            ..view(minutes, page)
+        };
+        let event = |minutes, name: &str| Event {
+            time: DateTime::default() + Duration::minutes(minutes),
+            project: 1,
+            session: 7,
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let records: [Record; 6] = [
+            view(0, page(1, "/")).into(),
+            exit(1, page(1, "/"), 40).into(),
+            view(1, page(2, "/pricing")).into(),
+            event(2, "signup").into(),
+            exit(3, page(2, "/pricing"), 90).into(),
+            event(3, "signup").into(),
+        ];
+        for record in &records {
+            tracker.observe(record);
+        }
+        tracker.record_duration(1, 7, 1, 45);
+        // sessions without observed records have no summary
+        tracker.record_duration(1, 8, 1, 5);
+
+        assert!(tracker.expire(Duration::hours(1)).is_empty());
+        let summaries = tracker.expire(Duration::seconds(-1));
+        assert!(tracker.is_empty());
+        let [summary] = &summaries[..] else {
+            panic!("{summaries:?}");
+        };
+        assert_eq!((summary.project, summary.session), (1, 7));
+        assert_eq!(summary.end - summary.time, Duration::minutes(3));
+        assert_eq!((summary.pages_viewed, summary.duration), (2, 135));
+        assert_eq!(summary.entry_page.path, "/");
+        assert_eq!(summary.exit_page.path, "/pricing");
+        assert_eq!(summary.goals, ["signup"]);
+        assert_eq!(
+            summary.utm_param.as_ref().unwrap().id,
+            utm_param("autumn").id
+        );
+    }
+
+    #[test]
+    fn expire_removes_idle_sessions() {
+        let tracker = SessionTracker::new();
+        tracker.record_duration(1, 7, 42, 10);
+        tracker.expire(Duration::hours(1));
+        assert_eq!(tracker.len(), 1);
+        tracker.expire(Duration::seconds(-1));
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn expire_all_summarizes_open_sessions() {
+        let tracker = SessionTracker::new();
+        tracker.observe(&visit(0, None).into());
+        tracker.record_duration(1, 8, 42, 10);
+
+        assert!(tracker.expire(Duration::hours(1)).is_empty());
+        let records = tracker.drain();
+        assert!(tracker.is_empty());
+        let [Record::Session(summary)] = &records[..] else {
+            panic!("{records:?}");
+        };
+        assert_eq!((summary.session, summary.pages_viewed), (7, 1));
+    }
+}
@@ -0,0 +1,524 @@
+//! Session state in Redis, shared by all collectors of a deployment.
+//!
+//! Every session is a hash updated by Lua scripts, so concurrent beacons of
+//! a session on different collectors don't lose updates. The hash expires
+//! after a [TTL](RedisSessions::with_ttl) without beacons, and a sorted set
+//! indexes the sessions by the time they were last seen, so
+//! [`expire`](SessionStore::expire) can pop the idle ones and return their
+//! summaries.
+//!
+//! The key prefix is a hash tag, e.g. `{abineo}:session:1:7`, so the index
+//! and the sessions share a slot and the scripts run on Redis Cluster.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use ::redis::{Client, Connection, RedisResult, Script};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use lazy_static::lazy_static;
+
+use crate::session::{SessionStore, SessionSummary};
+use crate::{clock, Error, Event, Record, UtmParam, Visit};
+
+/// Refreshes the session after a script updated it and returns `res`.
+///
+/// Every session script takes the session and the index as keys, and the
+/// current time and the TTL in milliseconds as the first arguments.
+const TOUCH: &str = "
+redis.call('ZADD', KEYS[2], ARGV[1], KEYS[1])
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+return res
+";
+
+lazy_static! {
+    /// Keeps the highest duration of the page in `ARGV[3]`, returns it if
+    /// `ARGV[4]` is higher than every value before.
+    static ref RECORD_DURATION: Script = script("
+local res = false
+local max = tonumber(redis.call('HGET', KEYS[1], ARGV[3]))
+if not max or tonumber(ARGV[4]) > max then
+    redis.call('HSET', KEYS[1], ARGV[3], ARGV[4])
+    res = tonumber(ARGV[4])
+end
+");
+
+    /// Returns whether the page in `ARGV[3]` wasn't viewed before.
+    static ref MARK_UNIQUE: Script = script("
+local res = redis.call('HSETNX', KEYS[1], ARGV[3], 1)
+");
+
+    /// Replaces the last viewed page with `ARGV[3]` and returns the former.
+    static ref LINK_PREVIOUS: Script = script("
+local res = redis.call('HGET', KEYS[1], 'last')
+redis.call('HSET', KEYS[1], 'last', ARGV[3])
+");
+
+    /// Returns the first utm parameters of the session if they were seen
+    /// within `ARGV[4]` of `ARGV[3]`, otherwise remembers those in `ARGV[5]`.
+    static ref ATTRIBUTION: Script = script("
+local res = false
+local seen = tonumber(redis.call('HGET', KEYS[1], 'utm_seen'))
+if seen and tonumber(ARGV[3]) - seen <= tonumber(ARGV[4]) then
+    res = redis.call('HGET', KEYS[1], 'utm')
+elseif ARGV[5] ~= '' then
+    redis.call('HSET', KEYS[1], 'utm', ARGV[5], 'utm_seen', ARGV[3])
+end
+");
+
+    /// Adds a record at `ARGV[3]` to the summary in `ARGV[4]`: the duration
+    /// `ARGV[6]` of the page in `ARGV[5]`, the page view `ARGV[7]` or the
+    /// goal in `ARGV[8]`.
+    static ref OBSERVE: Script = script("
+local res = false
+redis.call('HSETNX', KEYS[1], 'summary', ARGV[4])
+local stop = tonumber(redis.call('HGET', KEYS[1], 'end'))
+if not stop or tonumber(ARGV[3]) > stop then
+    redis.call('HSET', KEYS[1], 'end', ARGV[3])
+end
+if ARGV[5] ~= '' then
+    local max = tonumber(redis.call('HGET', KEYS[1], ARGV[5]))
+    if not max or tonumber(ARGV[6]) > max then
+        redis.call('HSET', KEYS[1], ARGV[5], ARGV[6])
+    end
+end
+if ARGV[7] ~= '' then
+    redis.call('HINCRBY', KEYS[1], 'pages', 1)
+    redis.call('HSET', KEYS[1], 'exit', ARGV[7])
+end
+if ARGV[8] ~= '' then
+    redis.call('HSET', KEYS[1], ARGV[8], 1)
+end
+");
+
+    /// Removes up to `ARGV[2]` sessions of the index in `KEYS[1]` last seen
+    /// before `ARGV[1]` and returns their fields. The sessions share the
+    /// hash tag of the index.
+    static ref EXPIRE: Script = Script::new("
+local res = {}
+local keys = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', '(' .. ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, key in ipairs(keys) do
+    res[#res + 1] = redis.call('HGETALL', key)
+    redis.call('DEL', key)
+    redis.call('ZREM', KEYS[1], key)
+end
+return res
+");
+}
+
+fn script(body: &str) -> Script {
+    Script::new(&[body, TOUCH].concat())
+}
+
+/// Number of sessions an [`EXPIRE`] call removes at most, so Redis isn't
+/// blocked for long.
+const EXPIRE_BATCH: usize = 1000;
+
+/// Connections to a Redis server, reused between commands.
+pub(crate) struct Pool {
+    client: Client,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl Pool {
+    pub(crate) fn open(url: &str) -> Result<Self, Error> {
+        Ok(Pool {
+            client: Client::open(url)?,
+            idle: Mutex::default(),
+        })
+    }
+
+    /// Runs `f` on an idle connection, opening one if all are busy.
+    /// Connections are dropped after errors.
+    pub(crate) fn run<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> RedisResult<T>,
+    ) -> Result<T, Error> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => self.client.get_connection()?,
+        };
+        let res = f(&mut conn)?;
+        self.idle.lock().unwrap().push(conn);
+        Ok(res)
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("client", &self.client)
+            .finish()
+    }
+}
+
+/// A [`SessionStore`] in Redis.
+#[derive(Debug)]
+pub struct RedisSessions {
+    pool: Pool,
+    prefix: String,
+    ttl: Duration,
+    attribution_window: Duration,
+}
+
+impl RedisSessions {
+    /// Connects to the server at `url`, like `redis://127.0.0.1/`.
+    pub fn open(url: &str) -> Result<Self, Error> {
+        Ok(RedisSessions {
+            pool: Pool::open(url)?,
+            prefix: "abineo".to_string(),
+            ttl: Duration::days(1),
+            attribution_window: Duration::minutes(30),
+        })
+    }
+
+    /// Prefix of the keys, so several deployments can share a server.
+    /// Defaults to `abineo`.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// How long a session is kept without beacons, even if it isn't
+    /// [expired](SessionStore::expire). Defaults to a day.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// See [`SessionTracker::with_attribution_window`](super::SessionTracker::with_attribution_window).
+    pub fn with_attribution_window(mut self, window: Duration) -> Self {
+        self.attribution_window = window;
+        self
+    }
+
+    fn index(&self) -> String {
+        format!("{{{}}}:sessions", self.prefix)
+    }
+
+    fn key(&self, project_id: i64, session: i64) -> String {
+        format!("{{{}}}:session:{project_id}:{session}", self.prefix)
+    }
+
+    /// Runs a session script with the keys and arguments of [`TOUCH`]
+    /// followed by `args`.
+    fn update<T: ::redis::FromRedisValue>(
+        &self,
+        script: &Script,
+        project_id: i64,
+        session: i64,
+        args: &[&str],
+    ) -> Result<T, Error> {
+        let mut invocation = script.key(self.key(project_id, session));
+        invocation
+            .key(self.index())
+            .arg(clock::now().timestamp_millis())
+            .arg(self.ttl.num_milliseconds());
+        for arg in args {
+            invocation.arg(*arg);
+        }
+        self.pool.run(|conn| invocation.invoke(conn))
+    }
+
+    fn attribution(
+        &self,
+        project_id: i64,
+        session: i64,
+        time: DateTime<Utc>,
+        utm_param: Option<&UtmParam>,
+    ) -> Result<Option<UtmParam>, Error> {
+        let utm_param = match utm_param {
+            Some(utm_param) => serde_json::to_string(utm_param)?,
+            None => String::new(),
+        };
+        let first: Option<String> = self.update(
+            &ATTRIBUTION,
+            project_id,
+            session,
+            &[
+                &time.timestamp_millis().to_string(),
+                &self.attribution_window.num_milliseconds().to_string(),
+                &utm_param,
+            ],
+        )?;
+        Ok(first.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    /// Number of sessions currently tracked.
+    pub fn len(&self) -> Result<usize, Error> {
+        self.pool
+            .run(|conn| ::redis::cmd("ZCARD").arg(self.index()).query(conn))
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+}
+
+impl SessionStore for RedisSessions {
+    fn record_duration(
+        &self,
+        project_id: i64,
+        session: i64,
+        page_id: i64,
+        duration: i32,
+    ) -> Result<Option<i32>, Error> {
+        self.update(
+            &RECORD_DURATION,
+            project_id,
+            session,
+            &[&format!("d:{page_id}"), &duration.to_string()],
+        )
+    }
+
+    fn mark_unique(&self, visit: &mut Visit) -> Result<(), Error> {
+        let unique = self.update(
+            &MARK_UNIQUE,
+            visit.project,
+            visit.session,
+            &[&format!("v:{}", visit.page.id)],
+        )?;
+        visit.is_unique_in_session = Some(unique);
+        Ok(())
+    }
+
+    fn link_previous(&self, visit: &mut Visit) -> Result<(), Error> {
+        let previous = self.update(
+            &LINK_PREVIOUS,
+            visit.project,
+            visit.session,
+            &[&visit.page.id.to_string()],
+        )?;
+        if visit.previous_page_id.is_none() {
+            visit.previous_page_id = previous;
+        }
+        Ok(())
+    }
+
+    fn attribute_visit(&self, visit: &mut Visit) -> Result<(), Error> {
+        if let Some(utm_param) = self.attribution(
+            visit.project,
+            visit.session,
+            visit.time,
+            visit.utm_param.as_ref(),
+        )? {
+            visit.utm_param.get_or_insert(utm_param);
+        }
+        Ok(())
+    }
+
+    fn attribute_event(&self, event: &mut Event) -> Result<(), Error> {
+        if let Some(utm_param) = self.attribution(
+            event.project,
+            event.session,
+            event.time,
+            event.utm_param.as_ref(),
+        )? {
+            event.utm_param.get_or_insert(utm_param);
+        }
+        Ok(())
+    }
+
+    fn observe(&self, record: &Record) -> Result<(), Error> {
+        let (summary, duration, view, goal) = match record {
+            Record::Visit(visit) if !visit.dry_run && visit.embed.is_none() => {
+                let summary = SessionSummary::from_visit(visit);
+                match visit.duration {
+                    Some(duration) => (
+                        summary,
+                        Some((visit.page.id, duration)),
+                        String::new(),
+                        String::new(),
+                    ),
+                    None => (
+                        summary,
+                        None,
+                        serde_json::to_string(&visit.page)?,
+                        String::new(),
+                    ),
+                }
+            }
+            Record::Event(event) if !event.dry_run => (
+                SessionSummary::from_event(event),
+                None,
+                String::new(),
+                format!("g:{}", event.name),
+            ),
+            _ => return Ok(()),
+        };
+        let (page, duration) = match duration {
+            Some((page_id, duration)) => (format!("d:{page_id}"), duration.to_string()),
+            None => Default::default(),
+        };
+        self.update(
+            &OBSERVE,
+            summary.project,
+            summary.session,
+            &[
+                &summary.time.timestamp_millis().to_string(),
+                &serde_json::to_string(&summary)?,
+                &page,
+                &duration,
+                &view,
+                &goal,
+            ],
+        )
+    }
+
+    fn expire(&self, idle: Duration) -> Result<Vec<SessionSummary>, Error> {
+        let threshold = (clock::now() - idle).timestamp_millis();
+        let mut summaries = Vec::new();
+        loop {
+            let sessions: Vec<HashMap<String, String>> = self.pool.run(|conn| {
+                EXPIRE
+                    .key(self.index())
+                    .arg(threshold)
+                    .arg(EXPIRE_BATCH)
+                    .invoke(conn)
+            })?;
+            // the sessions are removed already, a broken hash must not
+            // take the summaries of the others with it
+            summaries.extend(sessions.iter().filter_map(summary));
+            if sessions.len() < EXPIRE_BATCH {
+                return Ok(summaries);
+            }
+        }
+    }
+
+    /// Sessions are shared with the other collectors, they keep them open
+    /// and [expire](SessionStore::expire) them once they are idle.
+    fn expire_all(&self) -> Result<Vec<SessionSummary>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+/// Assembles the summary of an expired session from the fields of its
+/// hash, `None` if no records were observed or the summary is unreadable.
+///
+/// Fields that don't parse are skipped, the summary keeps what was known
+/// when the session started.
+fn summary(fields: &HashMap<String, String>) -> Option<SessionSummary> {
+    let mut summary: SessionSummary = serde_json::from_str(fields.get("summary")?).ok()?;
+    for (field, val) in fields {
+        if field.starts_with("d:") {
+            if let Ok(duration) = val.parse::<i32>() {
+                summary.duration = summary.duration.saturating_add(duration.max(0));
+            }
+        } else if let Some(goal) = field.strip_prefix("g:") {
+            summary.goals.push(goal.to_string());
+        }
+    }
+    summary.goals.sort();
+    let end = fields.get("end").and_then(|end| end.parse().ok());
+    if let Some(end) = end.and_then(|end| Utc.timestamp_millis_opt(end).single()) {
+        summary.end = end;
+    }
+    if let Some(pages) = fields.get("pages").and_then(|pages| pages.parse().ok()) {
+        summary.pages_viewed = pages;
+    }
+    if let Some(exit) = fields
+        .get("exit")
+        .and_then(|exit| serde_json::from_str(exit).ok())
+    {
+        summary.exit_page = exit;
+    }
+    Some(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Page;
+
+    use super::*;
+
+    #[test]
+    fn assembles_summaries() {
+        let start = SessionSummary {
+            project: 1,
+            session: 7,
+            ..Default::default()
+        };
+        let exit = Page {
+            path: "/pricing".to_string(),
+            ..Default::default()
+        };
+        let mut fields: HashMap<String, String> = [
+            ("summary", serde_json::to_string(&start).unwrap()),
+            ("end", "180000".to_string()),
+            ("pages", "2".to_string()),
+            ("exit", serde_json::to_string(&exit).unwrap()),
+            ("d:1", "45".to_string()),
+            ("d:2", "90".to_string()),
+            ("d:3", "-1".to_string()),
+            ("v:1", "1".to_string()),
+            ("g:signup", "1".to_string()),
+            ("g:demo", "1".to_string()),
+        ]
+        .into_iter()
+        .map(|(field, val)| (field.to_string(), val))
+        .collect();
+
+        let summary = summary(&fields).unwrap();
+        assert_eq!((summary.project, summary.session), (1, 7));
+        assert_eq!(summary.end - summary.time, Duration::minutes(3));
+        assert_eq!((summary.pages_viewed, summary.duration), (2, 135));
+        assert_eq!(summary.exit_page.path, "/pricing");
+        assert_eq!(summary.goals, ["demo", "signup"]);
+
+        // broken fields are skipped
+        fields.insert("d:4".to_string(), "soon".to_string());
+        fields.insert("pages".to_string(), "many".to_string());
+        let summary = super::summary(&fields).unwrap();
+        assert_eq!((summary.pages_viewed, summary.duration), (0, 135));
+
+        // unreadable summaries are skipped, sessions without observed
+        // records have none
+        fields.insert("summary".to_string(), "{".to_string());
+        assert!(super::summary(&fields).is_none());
+        fields.remove("summary");
+        assert!(super::summary(&fields).is_none());
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_URL"]
+    fn shares_sessions_between_collectors() {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        let prefix = format!("abineo-test-{}", std::process::id());
+        let open = || RedisSessions::open(&url).unwrap().with_prefix(&prefix);
+        let (first, second) = (open(), open());
+
+        assert_eq!(first.record_duration(1, 7, 42, 10).unwrap(), Some(10));
+        assert_eq!(second.record_duration(1, 7, 42, 10).unwrap(), None);
+        assert_eq!(second.record_duration(1, 7, 42, 12).unwrap(), Some(12));
+
+        let mut visit = Visit {
+            project: 1,
+            session: 7,
+            ..Default::default()
+        };
+        first.mark_unique(&mut visit).unwrap();
+        assert_eq!(visit.is_unique_in_session, Some(true));
+        second.mark_unique(&mut visit).unwrap();
+        assert_eq!(visit.is_unique_in_session, Some(false));
+
+        let event = Event {
+            project: 1,
+            session: 7,
+            name: "signup".to_string(),
+            ..Default::default()
+        };
+        first.observe(&visit.clone().into()).unwrap();
+        second.observe(&event.into()).unwrap();
+        assert_eq!(first.len().unwrap(), 1);
+
+        assert!(second.expire(Duration::hours(1)).unwrap().is_empty());
+        let summaries = first.expire(Duration::seconds(-1)).unwrap();
+        assert_eq!(first.len().unwrap(), 0);
+        let [summary] = &summaries[..] else {
+            panic!("{summaries:?}");
+        };
+        assert_eq!((summary.pages_viewed, summary.duration), (1, 12));
+        assert_eq!(summary.goals, ["signup"]);
+    }
+}
@@ -0,0 +1,355 @@
+//! Shadow comparisons of processing configurations.
+//!
+//! Before migrating to a new id version, visitor salt or campaign
+//! normalization, a [`Shadow`] processes the same beacons with the current
+//! and the candidate [`Variant`] and counts where their records diverge.
+//! Nothing is written, so it can run next to production on sampled or
+//! [captured](crate::raw) beacons.
+//!
+//! Ids of previous pages are computed with [`IdVersion::CURRENT`] on both
+//! sides and compared like the other fields.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::api::{self, Enrich};
+use crate::id::{IdInputs, IdVersion, Rehash};
+use crate::middleware::{Chain, Middleware};
+use crate::pipeline::Beacon;
+use crate::utm::UtmOptions;
+use crate::{Error, Record};
+
+/// One side of a [`Shadow`].
+#[derive(Debug, Clone, Default)]
+pub struct Variant {
+    version: IdVersion,
+    salt: Option<u64>,
+    lenient: bool,
+    utm: UtmOptions,
+    middleware: Arc<Chain>,
+}
+
+impl Variant {
+    /// Processes beacons like [`api::handle_batch`].
+    pub fn new() -> Self {
+        Variant::default()
+    }
+
+    pub fn with_id_version(mut self, version: IdVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Mixes a salt into the visitor ids, see
+    /// [`VisitorBuilder::salt`](crate::VisitorBuilder::salt).
+    pub fn with_salt(mut self, salt: Option<u64>) -> Self {
+        self.salt = salt;
+        self
+    }
+
+    /// Repairs beacons of outdated trackers, see [`api::lenient`].
+    pub fn with_lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    pub fn with_utm(mut self, utm: UtmOptions) -> Self {
+        self.utm = utm;
+        self
+    }
+
+    /// Runs after a record was processed, like
+    /// [`PipelineConfig::middleware`](crate::pipeline::PipelineConfig::middleware).
+    pub fn with_middleware(mut self, middleware: Arc<Chain>) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
+    /// The records of a beacon, `None` for records that failed to process
+    /// or were dropped by the middleware.
+    fn records(&self, beacon: &Beacon) -> Result<Vec<Option<Record>>, Error> {
+        let body = if self.lenient {
+            api::lenient::decode(&beacon.body)?.0
+        } else {
+            api::decode(&beacon.body)?
+        };
+        let enrich = Enrich::new(&self.utm);
+        Ok(body
+            .into_iter()
+            .map(|record| {
+                let record =
+                    api::process_record(beacon.project_id, record, &beacon.user_agent, enrich)
+                        .ok()?;
+                let mut record = self.middleware.process(record)?;
+                self.rehash(&mut record);
+                Some(record)
+            })
+            .collect())
+    }
+
+    fn rehash(&self, record: &mut Record) {
+        let (visitor, page, utm_param, referrer) = match record {
+            Record::Visit(visit) => (
+                &mut visit.visitor,
+                &mut visit.page,
+                visit.utm_param.as_mut(),
+                visit.referrer.as_mut(),
+            ),
+            Record::Event(event) => (
+                &mut event.visitor,
+                &mut event.page,
+                event.utm_param.as_mut(),
+                None,
+            ),
+            Record::Session(summary) => (
+                &mut summary.visitor,
+                &mut summary.entry_page,
+                summary.utm_param.as_mut(),
+                summary.referrer.as_mut(),
+            ),
+        };
+        visitor.id = visitor.fields().id(self.version, self.salt);
+        page.id = page.rehash(self.version);
+        if let Some(utm_param) = utm_param {
+            utm_param.id = utm_param.rehash(self.version);
+        }
+        if let Some(referrer) = referrer {
+            referrer.id = referrer.rehash(self.version);
+        }
+    }
+}
+
+/// Divergence counters of a [`Shadow`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ShadowReport {
+    pub beacons: u64,
+    /// Records both variants produced, which were compared.
+    pub records: u64,
+    /// Records only the baseline produced, e.g. because the candidate
+    /// rejected their beacon or its middleware dropped them.
+    pub baseline_only: u64,
+    /// Records only the candidate produced.
+    pub candidate_only: u64,
+    /// Compared records with at least one diverging id or field.
+    pub diverged: u64,
+    /// Compared records per id kind whose id diverged, e.g. `visitor`.
+    pub ids: BTreeMap<&'static str, u64>,
+    /// Compared records per field whose value diverged, e.g.
+    /// `visitor.browser`. Ids and the processing time are not included.
+    pub fields: BTreeMap<String, u64>,
+}
+
+impl ShadowReport {
+    /// Share of the compared records that diverged, 0 if there were none.
+    pub fn divergence(&self) -> f64 {
+        if self.records == 0 {
+            return 0.0;
+        }
+        self.diverged as f64 / self.records as f64
+    }
+
+    fn compare(&mut self, baseline: &Record, candidate: &Record) {
+        self.records += 1;
+        let mut diverged = false;
+        for ((kind, a), (_, b)) in ids(baseline).into_iter().zip(ids(candidate)) {
+            if a != b {
+                *self.ids.entry(kind).or_default() += 1;
+                diverged = true;
+            }
+        }
+        let (Ok(a), Ok(b)) = (
+            serde_json::to_value(baseline),
+            serde_json::to_value(candidate),
+        ) else {
+            return;
+        };
+        let mut fields = Vec::new();
+        diff("", &a, &b, &mut fields);
+        for field in fields {
+            *self.fields.entry(field).or_default() += 1;
+            diverged = true;
+        }
+        self.diverged += u64::from(diverged);
+    }
+}
+
+/// The kinds and ids of a record, `None` for absent parts.
+fn ids(record: &Record) -> [(&'static str, Option<i64>); 4] {
+    fn id<T: IdInputs>(part: Option<&T>) -> (&'static str, Option<i64>) {
+        (T::KIND, part.map(Rehash::id))
+    }
+    let (visitor, page, utm_param, referrer) = match record {
+        Record::Visit(visit) => (
+            &visit.visitor,
+            &visit.page,
+            visit.utm_param.as_ref(),
+            visit.referrer.as_ref(),
+        ),
+        Record::Event(event) => (&event.visitor, &event.page, event.utm_param.as_ref(), None),
+        Record::Session(summary) => (
+            &summary.visitor,
+            &summary.entry_page,
+            summary.utm_param.as_ref(),
+            summary.referrer.as_ref(),
+        ),
+    };
+    [
+        id(Some(visitor)),
+        id(Some(page)),
+        id(utm_param),
+        id(referrer),
+    ]
+}
+
+/// Collects the paths of the diverging values, objects are compared by
+/// key, a part present on one side only (e.g. the campaign) by its path.
+fn diff(path: &str, a: &Value, b: &Value, fields: &mut Vec<String>) {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for key in a.keys().chain(b.keys().filter(|key| !a.contains_key(*key))) {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                if is_ignored(&path) {
+                    continue;
+                }
+                match (a.get(key), b.get(key)) {
+                    (Some(a), Some(b)) => diff(&path, a, b, fields),
+                    _ => fields.push(path),
+                }
+            }
+        }
+        _ if a != b => fields.push(path.to_string()),
+        _ => {}
+    }
+}
+
+fn is_ignored(path: &str) -> bool {
+    matches!(
+        path,
+        "time" | "visitor.id" | "page.id" | "utm_param.id" | "referrer.id"
+    )
+}
+
+/// Runs a baseline and a candidate [`Variant`] over the same beacons.
+#[derive(Debug, Clone)]
+pub struct Shadow {
+    baseline: Variant,
+    candidate: Variant,
+}
+
+impl Shadow {
+    pub fn new(baseline: Variant, candidate: Variant) -> Self {
+        Shadow {
+            baseline,
+            candidate,
+        }
+    }
+
+    /// Processes a beacon with both variants and adds the outcome to
+    /// `report`. Records are matched by their position in the beacon.
+    pub fn compare(&self, beacon: &Beacon, report: &mut ShadowReport) {
+        report.beacons += 1;
+        let baseline = self.baseline.records(beacon).unwrap_or_default();
+        let candidate = self.candidate.records(beacon).unwrap_or_default();
+        for i in 0..baseline.len().max(candidate.len()) {
+            match (
+                baseline.get(i).and_then(Option::as_ref),
+                candidate.get(i).and_then(Option::as_ref),
+            ) {
+                (Some(a), Some(b)) => report.compare(a, b),
+                (Some(_), None) => report.baseline_only += 1,
+                (None, Some(_)) => report.candidate_only += 1,
+                (None, None) => {}
+            }
+        }
+    }
+
+    /// Compares the variants over all beacons.
+    pub fn run<I>(&self, beacons: I) -> ShadowReport
+    where
+        I: IntoIterator<Item = Beacon>,
+    {
+        let mut report = ShadowReport::default();
+        for beacon in beacons {
+            self.compare(&beacon, &mut report);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utm::UtmField;
+
+    use super::*;
+
+    const USER_AGENT: &str = "Mozilla/5.0 (Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/112.0.0.0 Safari/537.36";
+
+    fn beacon(body: &str) -> Beacon {
+        Beacon {
+            project_id: 1,
+            user_agent: USER_AGENT.to_string(),
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    const VISIT: &str = r#"{"type": "visit", "session": "7", "visitor": {"tz": "Europe/Zurich"}, "page": {"url": "https://example.com/?mtm_campaign=spring", "ref": "https://duckduckgo.com/"}}"#;
+
+    #[test]
+    fn identical_variants_agree() {
+        let report = Shadow::new(Variant::new(), Variant::new()).run([beacon(VISIT)]);
+        assert_eq!((report.records, report.diverged), (1, 0));
+        assert!(report.ids.is_empty() && report.fields.is_empty());
+        assert_eq!(report.divergence(), 0.0);
+    }
+
+    #[test]
+    fn reports_id_divergence() {
+        let shadow = Shadow::new(
+            Variant::new(),
+            Variant::new().with_id_version(IdVersion::V2),
+        );
+        let report = shadow.run([beacon(VISIT), beacon(VISIT)]);
+        assert_eq!((report.records, report.diverged), (2, 2));
+        assert_eq!(report.ids["visitor"], 2);
+        assert_eq!(report.ids["referrer"], 2);
+        assert!(!report.ids.contains_key("utm_param"));
+        assert!(report.fields.is_empty(), "{:?}", report.fields);
+
+        let salted = Shadow::new(Variant::new(), Variant::new().with_salt(Some(42)));
+        let report = salted.run([beacon(VISIT)]);
+        assert_eq!(report.ids.keys().copied().collect::<Vec<_>>(), ["visitor"]);
+    }
+
+    #[test]
+    fn reports_field_divergence() {
+        let utm = UtmOptions {
+            aliases: [("mtm_campaign".to_string(), UtmField::Campaign)].into(),
+            ..Default::default()
+        };
+        let shadow = Shadow::new(Variant::new(), Variant::new().with_utm(utm));
+        let report = shadow.run([beacon(VISIT)]);
+        assert_eq!(report.diverged, 1);
+        assert_eq!(
+            report.ids.keys().copied().collect::<Vec<_>>(),
+            ["utm_param"]
+        );
+        assert_eq!(report.fields.keys().collect::<Vec<_>>(), ["utm_param"]);
+    }
+
+    #[test]
+    fn counts_records_of_one_side() {
+        let sloppy = r#"{"type": "visit", "session": 7, "page": "https://example.com/"}"#;
+        let shadow = Shadow::new(Variant::new(), Variant::new().with_lenient(true));
+        let report = shadow.run([beacon(sloppy), beacon(VISIT)]);
+        assert_eq!((report.beacons, report.records), (2, 1));
+        assert_eq!((report.baseline_only, report.candidate_only), (0, 1));
+    }
+}
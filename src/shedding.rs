@@ -0,0 +1,165 @@
+//! Load shedding of an overloaded pipeline.
+//!
+//! When the sink queue fills up or beacons wait too long before they are
+//! processed, a [`LoadShedder`] switches the [`Pipeline`] into shedding
+//! mode instead of letting requests time out:
+//!
+//! - user agents are only looked up in the [cache](crate::ua::UaCache), new
+//!   ones are not parsed and their visitors get no browser and platform,
+//! - the [optional middleware](crate::pipeline::PipelineConfig::optional_middleware)
+//!   is skipped,
+//! - events below [`Priority::Critical`] are sampled by session.
+//!
+//! Shedding stops once queue and latency are back below half their
+//! thresholds. [`ShedStats`] count how much was shed.
+//!
+//! [`Pipeline`]: crate::pipeline::Pipeline
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::hash::Hasher;
+use crate::priority::Priority;
+use crate::Event;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShedPolicy {
+    /// Share of the sink queue that starts shedding.
+    pub queue_percent: usize,
+    /// Time beacons waited for processing that starts shedding.
+    pub latency: Duration,
+    /// Percentage of sessions whose events are kept while shedding.
+    pub sample_percent: u8,
+}
+
+impl Default for ShedPolicy {
+    fn default() -> Self {
+        ShedPolicy {
+            queue_percent: 75,
+            latency: Duration::from_secs(1),
+            sample_percent: 50,
+        }
+    }
+}
+
+/// What a [`LoadShedder`] shed so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ShedStats {
+    /// Times shedding started.
+    pub activations: u64,
+    /// User agents that were not parsed.
+    pub user_agents: u64,
+    /// Records that skipped the optional middleware.
+    pub enrichments: u64,
+    /// Events not sampled.
+    pub events: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct LoadShedder {
+    policy: ShedPolicy,
+    active: AtomicBool,
+    activations: AtomicU64,
+    user_agents: AtomicU64,
+    enrichments: AtomicU64,
+    events: AtomicU64,
+}
+
+impl LoadShedder {
+    pub fn new(policy: ShedPolicy) -> Self {
+        LoadShedder {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Updates the mode from the sink queue and the time the current beacon
+    /// waited, returning whether to shed.
+    pub fn update(&self, queued: usize, capacity: usize, latency: Duration) -> bool {
+        let queue = queued * 100 / capacity.max(1);
+        let active = self.active.load(Ordering::Relaxed);
+        let overloaded = queue >= self.policy.queue_percent || latency >= self.policy.latency;
+        let recovered = queue * 2 < self.policy.queue_percent && latency * 2 < self.policy.latency;
+        if !active && overloaded {
+            if !self.active.swap(true, Ordering::Relaxed) {
+                self.activations.fetch_add(1, Ordering::Relaxed);
+            }
+            return true;
+        }
+        if active && recovered {
+            self.active.store(false, Ordering::Relaxed);
+            return false;
+        }
+        active
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Whether a shed pipeline keeps the event, counting it otherwise.
+    pub fn keeps(&self, event: &Event, priority: Priority) -> bool {
+        if priority == Priority::Critical {
+            return true;
+        }
+        let mut hasher = Hasher::hardened();
+        hasher.write(event.session as u64);
+        let kept = hasher.finalize() % 100 < u64::from(self.policy.sample_percent);
+        if !kept {
+            self.events.fetch_add(1, Ordering::Relaxed);
+        }
+        kept
+    }
+
+    pub(crate) fn count_user_agent(&self) {
+        self.user_agents.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_enrichment(&self) {
+        self.enrichments.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ShedStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        ShedStats {
+            activations: load(&self.activations),
+            user_agents: load(&self.user_agents),
+            enrichments: load(&self.enrichments),
+            events: load(&self.events),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheds_until_recovered() {
+        let shedder = LoadShedder::default();
+        let second = Duration::from_secs(1);
+        assert!(!shedder.update(10, 100, Duration::ZERO));
+        assert!(shedder.update(80, 100, Duration::ZERO));
+        // still above half the thresholds
+        assert!(shedder.update(40, 100, Duration::ZERO));
+        assert!(shedder.update(10, 100, second / 2));
+        assert!(!shedder.update(10, 100, Duration::ZERO));
+        assert!(shedder.update(0, 100, second));
+        assert_eq!(shedder.stats().activations, 2);
+    }
+
+    #[test]
+    fn samples_all_but_critical_events() {
+        let shedder = LoadShedder::default();
+        let event = |session| Event {
+            session,
+            ..Default::default()
+        };
+        let kept = (0..1000)
+            .filter(|session| shedder.keeps(&event(*session), Priority::Normal))
+            .count();
+        assert!((400..600).contains(&kept), "{kept}");
+        assert_eq!(shedder.stats().events, 1000 - kept as u64);
+        assert!((0..1000).all(|session| shedder.keeps(&event(session), Priority::Critical)));
+    }
+}
@@ -0,0 +1,48 @@
+//! Destinations of processed records.
+
+pub mod archive;
+#[cfg(feature = "duckdb")]
+pub mod duckdb;
+pub mod retry;
+#[cfg(any(feature = "sqlite", feature = "duckdb"))]
+pub mod sql;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod webhook;
+
+use crate::buffer::Journal;
+use crate::flush::Flushable;
+use crate::{Error, Record};
+
+/// Receives batches of processed records, e.g. to store them.
+///
+/// Sinks that buffer records deliver them on [`Flushable::flush`].
+pub trait Sink: Flushable + Send {
+    fn write(&mut self, records: Vec<Record>) -> Result<(), Error>;
+}
+
+impl<F> Sink for F
+where
+    F: FnMut(Vec<Record>) -> Result<(), Error> + Send,
+{
+    fn write(&mut self, records: Vec<Record>) -> Result<(), Error> {
+        self(records)
+    }
+}
+
+/// Closures write records right away, there is nothing to flush.
+impl<F> Flushable for F
+where
+    F: FnMut(Vec<Record>) -> Result<(), Error> + Send,
+{
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Journals the records, to be [drained](Journal::drain) into another sink.
+impl Sink for Journal {
+    fn write(&mut self, records: Vec<Record>) -> Result<(), Error> {
+        records.iter().try_for_each(|record| self.append(record))
+    }
+}
@@ -0,0 +1,323 @@
+//! Long-term retention of raw records in object storage.
+//!
+//! An [`ArchiveSink`] collects records into one gzipped NDJSON object per
+//! hour and uploads it to S3-compatible storage. Objects are keyed
+//! Hive-style, e.g.
+//! `records/dt=2023-10-01/hour=12/collector-1-18f2a3c4d5e6-000000.ndjson.gz`,
+//! so they can be queried in place by Athena or DuckDB.
+//!
+//! The storage client is supplied by the host through [`ObjectStore`], so
+//! this crate doesn't depend on a specific SDK or runtime.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::Write;
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use flate2::write::GzEncoder;
+
+use crate::flush::Flushable;
+use crate::sink::Sink;
+use crate::{Error, Record};
+
+/// Default for [`ArchiveSink::with_max_object_size`].
+pub const DEFAULT_MAX_OBJECT_SIZE: usize = 64 * 1024 * 1024;
+
+/// S3-compatible object storage.
+pub trait ObjectStore: Debug + Send {
+    fn put(&mut self, key: &str, body: Vec<u8>) -> Result<(), Error>;
+}
+
+/// Compression of uploaded objects, [`Gzip`] by default.
+pub trait Compress: Debug + Send {
+    /// Appended to object keys, e.g. `.gz`.
+    fn extension(&self) -> &str;
+
+    fn compress(&self, data: Vec<u8>) -> Result<Vec<u8>, Error>;
+}
+
+/// Uploads objects gzipped, as Athena and DuckDB read them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Gzip;
+
+impl Compress for Gzip {
+    fn extension(&self) -> &str {
+        ".gz"
+    }
+
+    fn compress(&self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data)?;
+        Ok(encoder.finish()?)
+    }
+}
+
+/// Uploads objects as plain NDJSON.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Uncompressed;
+
+impl Compress for Uncompressed {
+    fn extension(&self) -> &str {
+        ""
+    }
+
+    fn compress(&self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        Ok(data)
+    }
+}
+
+#[derive(Debug)]
+pub struct ArchiveSink<S> {
+    store: S,
+    compress: Box<dyn Compress>,
+    prefix: String,
+    instance: String,
+    /// Tells the objects of a restarted instance apart, see
+    /// [`ArchiveSink::with_run_id`].
+    run: String,
+    max_object_size: usize,
+    /// Start of the hour → NDJSON lines of records in that hour.
+    buffers: BTreeMap<DateTime<Utc>, Vec<u8>>,
+    seq: u64,
+    failed_uploads: u64,
+}
+
+impl<S: ObjectStore> ArchiveSink<S> {
+    /// Uploads gzipped objects below `prefix`.
+    ///
+    /// `instance` must be unique among collectors sharing the prefix, it is
+    /// part of the object keys.
+    pub fn new(store: S, prefix: &str, instance: &str) -> Self {
+        ArchiveSink {
+            store,
+            compress: Box::new(Gzip),
+            prefix: prefix.trim_end_matches('/').to_string(),
+            instance: instance.to_string(),
+            run: format!("{:x}", Utc::now().timestamp_micros()),
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+            buffers: BTreeMap::new(),
+            seq: 0,
+            failed_uploads: 0,
+        }
+    }
+
+    /// Replaces the start time of the sink in the object keys, e.g. with a
+    /// deployment id. Must differ between runs of an instance, otherwise a
+    /// restart overwrites the objects of the hour it restarted in.
+    pub fn with_run_id(mut self, run: &str) -> Self {
+        self.run = run.to_string();
+        self
+    }
+
+    pub fn with_compression(mut self, compress: impl Compress + 'static) -> Self {
+        self.compress = Box::new(compress);
+        self
+    }
+
+    /// Uncompressed size after which an hour is uploaded early, further
+    /// records of the hour go into another object.
+    pub fn with_max_object_size(mut self, bytes: usize) -> Self {
+        self.max_object_size = bytes;
+        self
+    }
+
+    /// Number of uploads that failed on [`Sink::write`], their records stay
+    /// buffered for the next write or flush.
+    pub fn failed_uploads(&self) -> u64 {
+        self.failed_uploads
+    }
+
+    /// Uploads the buffers of every hour for which `done` returns true.
+    ///
+    /// Buffers are only removed once their upload succeeded.
+    fn upload(&mut self, mut done: impl FnMut(DateTime<Utc>, &[u8]) -> bool) -> Result<(), Error> {
+        let hours: Vec<DateTime<Utc>> = self
+            .buffers
+            .iter()
+            .filter(|(hour, lines)| done(**hour, lines))
+            .map(|(hour, _)| *hour)
+            .collect();
+        for hour in hours {
+            let key = format!(
+                "{}/dt={}/hour={}/{}-{}-{:06}.ndjson{}",
+                self.prefix,
+                hour.format("%Y-%m-%d"),
+                hour.format("%H"),
+                self.instance,
+                self.run,
+                self.seq,
+                self.compress.extension()
+            );
+            let body = self.compress.compress(self.buffers[&hour].clone())?;
+            self.store.put(&key, body)?;
+            self.buffers.remove(&hour);
+            self.seq += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<S: ObjectStore> Sink for ArchiveSink<S> {
+    /// Buffers the records and uploads the hours that are complete.
+    ///
+    /// Succeeds once the records are buffered. A failed upload is only
+    /// counted in [`ArchiveSink::failed_uploads`], so retrying the write
+    /// doesn't archive the records twice; the upload is retried by the
+    /// next write and its error returned by [`Flushable::flush`].
+    fn write(&mut self, records: Vec<Record>) -> Result<(), Error> {
+        for record in &records {
+            let hour = truncate_hour(record.time());
+            let lines = self.buffers.entry(hour).or_default();
+            serde_json::to_writer(&mut *lines, record)?;
+            lines.push(b'\n');
+        }
+
+        let current = truncate_hour(Utc::now());
+        let max_object_size = self.max_object_size;
+        if self
+            .upload(|hour, lines| hour < current || lines.len() >= max_object_size)
+            .is_err()
+        {
+            self.failed_uploads += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Flushing uploads the buffered records, including the current hour.
+impl<S: ObjectStore> Flushable for ArchiveSink<S> {
+    fn flush(&mut self) -> Result<(), Error> {
+        self.upload(|_, _| true)
+    }
+}
+
+fn truncate_hour(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(Duration::hours(1)).unwrap_or(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::{Event, Visit};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct MemoryStore {
+        objects: Vec<(String, Vec<u8>)>,
+        fail: bool,
+    }
+
+    impl ObjectStore for &mut MemoryStore {
+        fn put(&mut self, key: &str, body: Vec<u8>) -> Result<(), Error> {
+            if self.fail {
+                return Err(Error::Closed);
+            }
+            self.objects.push((key.to_string(), body));
+            Ok(())
+        }
+    }
+
+    fn event(time: DateTime<Utc>) -> Record {
+        Event {
+            time,
+            name: "signup".to_string(),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    fn lines(body: &[u8]) -> Vec<Record> {
+        body.split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn uploads_completed_hours() {
+        let mut store = MemoryStore::default();
+        let past = Utc.with_ymd_and_hms(2023, 10, 1, 12, 30, 0).unwrap();
+        {
+            let mut sink = ArchiveSink::new(&mut store, "records/", "collector-1")
+                .with_compression(Uncompressed)
+                .with_run_id("run-1");
+            sink.write(vec![
+                event(past),
+                Visit {
+                    time: past + Duration::minutes(10),
+                    ..Default::default()
+                }
+                .into(),
+                event(Utc::now() + Duration::hours(1)),
+            ])
+            .unwrap();
+            assert_eq!(sink.buffers.len(), 1);
+            sink.close().unwrap();
+        }
+
+        assert_eq!(store.objects.len(), 2);
+        let (key, body) = &store.objects[0];
+        assert_eq!(
+            key,
+            "records/dt=2023-10-01/hour=12/collector-1-run-1-000000.ndjson"
+        );
+        assert_eq!(lines(body).len(), 2);
+        assert!(store.objects[1]
+            .0
+            .ends_with("collector-1-run-1-000001.ndjson"));
+    }
+
+    #[test]
+    fn gzips_objects_by_default() {
+        let mut store = MemoryStore::default();
+        let mut sink = ArchiveSink::new(&mut store, "records", "collector-1");
+        sink.write(vec![event(Utc::now())]).unwrap();
+        sink.flush().unwrap();
+
+        let (key, body) = &store.objects[0];
+        assert!(key.ends_with(".ndjson.gz"), "{key}");
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded)
+            .unwrap();
+        assert_eq!(lines(&decoded).len(), 1);
+    }
+
+    #[test]
+    fn restarts_within_the_hour_keep_objects() {
+        let mut store = MemoryStore::default();
+        let time = Utc::now();
+        for _ in 0..2 {
+            let mut sink = ArchiveSink::new(&mut store, "records", "collector-1");
+            sink.write(vec![event(time)]).unwrap();
+            sink.close().unwrap();
+            // a restart takes longer than the resolution of the run id
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        assert_eq!(store.objects.len(), 2);
+        assert_ne!(store.objects[0].0, store.objects[1].0);
+    }
+
+    #[test]
+    fn keeps_buffers_when_upload_fails() {
+        let mut store = MemoryStore {
+            fail: true,
+            ..Default::default()
+        };
+        let mut sink =
+            ArchiveSink::new(&mut store, "records", "collector-1").with_max_object_size(1);
+        // buffered, so a retry of the write would archive the record twice
+        sink.write(vec![event(Utc::now() + Duration::hours(1))])
+            .unwrap();
+        assert_eq!(sink.failed_uploads(), 1);
+        assert_eq!(sink.buffers.len(), 1);
+        assert!(sink.flush().is_err());
+
+        sink.store.fail = false;
+        sink.flush().unwrap();
+        assert!(sink.buffers.is_empty());
+        assert_eq!(store.objects.len(), 1);
+    }
+}
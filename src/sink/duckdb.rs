@@ -0,0 +1,303 @@
+//! Storage in a local DuckDB file for embedded analytics.
+//!
+//! DuckDB stores the `visits`, `events` and `sessions` tables column-wise,
+//! so small deployments can run ad hoc aggregations without a ClickHouse
+//! cluster.
+//! Columnar storage favours large inserts, the sink therefore buffers
+//! records and inserts them once a batch is full or on flush.
+
+use std::path::Path;
+
+use ::duckdb::types::{ToSqlOutput, Value, ValueRef};
+use ::duckdb::ToSql;
+
+use crate::flush::Flushable;
+use crate::sink::sql::{self, SqlConnection, SqlValue};
+use crate::sink::Sink;
+use crate::{Error, Record};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS visits (
+    time TIMESTAMPTZ NOT NULL,
+    project BIGINT NOT NULL,
+    session BIGINT NOT NULL,
+    visitor BIGINT NOT NULL,
+    region VARCHAR,
+    timezone VARCHAR NOT NULL,
+    language VARCHAR NOT NULL,
+    browser VARCHAR,
+    platform VARCHAR,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    page BIGINT NOT NULL,
+    domain VARCHAR NOT NULL,
+    path VARCHAR NOT NULL,
+    utm_param BIGINT,
+    campaign VARCHAR,
+    content VARCHAR,
+    medium VARCHAR,
+    source VARCHAR,
+    term VARCHAR,
+    referrer BIGINT,
+    referrer_domain VARCHAR,
+    duration INTEGER,
+    distance DOUBLE,
+    embed VARCHAR,
+    is_unique_in_session BOOLEAN,
+    previous_page BIGINT,
+    channel_group VARCHAR
+);
+
+CREATE TABLE IF NOT EXISTS events (
+    time TIMESTAMPTZ NOT NULL,
+    project BIGINT NOT NULL,
+    session BIGINT NOT NULL,
+    visitor BIGINT NOT NULL,
+    page BIGINT NOT NULL,
+    domain VARCHAR NOT NULL,
+    path VARCHAR NOT NULL,
+    utm_param BIGINT,
+    name VARCHAR NOT NULL,
+    data JSON NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    time TIMESTAMPTZ NOT NULL,
+    project BIGINT NOT NULL,
+    session BIGINT NOT NULL,
+    visitor BIGINT NOT NULL,
+    region VARCHAR,
+    end_time TIMESTAMPTZ NOT NULL,
+    pages_viewed INTEGER NOT NULL,
+    duration INTEGER NOT NULL,
+    domain VARCHAR NOT NULL,
+    entry_page BIGINT NOT NULL,
+    entry_path VARCHAR NOT NULL,
+    exit_page BIGINT NOT NULL,
+    exit_path VARCHAR NOT NULL,
+    goals JSON NOT NULL,
+    utm_param BIGINT,
+    campaign VARCHAR,
+    medium VARCHAR,
+    source VARCHAR,
+    referrer_domain VARCHAR,
+    channel_group VARCHAR
+);
+";
+
+const INSERT_VISIT: &str = "INSERT INTO visits (time, project, session, visitor, region, \
+    timezone, language, browser, platform, width, height, page, domain, path, utm_param, campaign, \
+    content, medium, source, term, referrer, referrer_domain, duration, distance, embed, \
+    is_unique_in_session, previous_page, channel_group) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, \
+    ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+
+const INSERT_EVENT: &str = "INSERT INTO events (time, project, session, visitor, page, domain, \
+    path, utm_param, name, data) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+
+const INSERT_SESSION: &str = "INSERT INTO sessions (time, project, session, visitor, region, \
+    end_time, pages_viewed, duration, domain, entry_page, entry_path, exit_page, exit_path, goals, \
+    utm_param, campaign, medium, source, referrer_domain, channel_group) VALUES (?, ?, ?, ?, ?, ?, \
+    ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+
+#[derive(Debug)]
+pub struct DuckDbSink<C = ::duckdb::Connection> {
+    conn: C,
+    batch_size: usize,
+    buffer: Vec<Record>,
+}
+
+impl DuckDbSink {
+    /// Opens the database at `path`, creating the file and tables if they
+    /// don't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        DuckDbSink::new(::duckdb::Connection::open(path)?)
+    }
+}
+
+impl<C: SqlConnection> DuckDbSink<C> {
+    /// Creates the tables if they don't exist. Batches hold 10'000 records.
+    pub fn new(mut conn: C) -> Result<Self, Error> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(DuckDbSink {
+            conn,
+            batch_size: 10_000,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Number of records waiting for the next insert.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+impl<C: SqlConnection> Sink for DuckDbSink<C> {
+    fn write(&mut self, records: Vec<Record>) -> Result<(), Error> {
+        self.buffer.extend(records);
+        if self.buffer.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Flushing inserts the buffered records in a single transaction, they are
+/// kept if it fails. Closing also checkpoints the database file.
+impl<C: SqlConnection> Flushable for DuckDbSink<C> {
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        sql::insert_all(
+            &mut self.conn,
+            INSERT_VISIT,
+            INSERT_EVENT,
+            INSERT_SESSION,
+            &self.buffer,
+        )?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.conn.execute_batch("CHECKPOINT")
+    }
+}
+
+impl SqlConnection for ::duckdb::Connection {
+    fn execute_batch(&mut self, sql: &str) -> Result<(), Error> {
+        Ok(::duckdb::Connection::execute_batch(self, sql)?)
+    }
+
+    fn execute(&mut self, sql: &str, params: &[SqlValue]) -> Result<(), Error> {
+        self.prepare_cached(sql)?
+            .execute(::duckdb::params_from_iter(params))?;
+        Ok(())
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> ::duckdb::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            SqlValue::Null => ToSqlOutput::Owned(Value::Null),
+            SqlValue::Integer(val) => ToSqlOutput::Owned(Value::BigInt(*val)),
+            SqlValue::Real(val) => ToSqlOutput::Owned(Value::Double(*val)),
+            SqlValue::Text(val) => ToSqlOutput::Borrowed(ValueRef::Text(val.as_bytes())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionSummary;
+    use crate::sink::sql::tests::Recorder;
+    use crate::sink::sql::{
+        column_names, columns, insert_columns, EVENT_COLUMNS, SESSION_COLUMNS, VISIT_COLUMNS,
+    };
+    use crate::{Event, Page, Visit};
+
+    #[test]
+    fn statements_match_schema() {
+        assert_eq!(columns(SCHEMA, "visits"), VISIT_COLUMNS);
+        assert_eq!(columns(SCHEMA, "events"), EVENT_COLUMNS);
+        assert_eq!(INSERT_VISIT.matches('?').count(), VISIT_COLUMNS);
+        assert_eq!(INSERT_EVENT.matches('?').count(), EVENT_COLUMNS);
+        assert_eq!(columns(SCHEMA, "sessions"), SESSION_COLUMNS);
+        assert_eq!(INSERT_SESSION.matches('?').count(), SESSION_COLUMNS);
+        assert_eq!(insert_columns(INSERT_VISIT), column_names(SCHEMA, "visits"));
+        assert_eq!(insert_columns(INSERT_EVENT), column_names(SCHEMA, "events"));
+        assert_eq!(
+            insert_columns(INSERT_SESSION),
+            column_names(SCHEMA, "sessions")
+        );
+    }
+
+    #[test]
+    fn inserts_full_batches() {
+        let mut recorder = Recorder::default();
+        let mut sink = DuckDbSink::new(&mut recorder).unwrap().with_batch_size(3);
+        sink.write(vec![Visit::default().into(), Event::default().into()])
+            .unwrap();
+        assert_eq!(sink.buffered(), 2);
+        sink.write(vec![Visit::default().into()]).unwrap();
+        assert_eq!(sink.buffered(), 0);
+        sink.write(vec![Event::default().into()]).unwrap();
+        sink.close().unwrap();
+
+        assert_eq!(
+            recorder.keywords(),
+            [
+                "CREATE",
+                "BEGIN",
+                "INSERT",
+                "INSERT",
+                "INSERT",
+                "COMMIT",
+                "BEGIN",
+                "INSERT",
+                "COMMIT",
+                "CHECKPOINT"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_buffer_on_failure() {
+        let mut recorder = Recorder {
+            fail_events: true,
+            ..Default::default()
+        };
+        let mut sink = DuckDbSink::new(&mut recorder).unwrap();
+        sink.write(vec![Event::default().into()]).unwrap();
+        assert!(sink.flush().is_err());
+        assert_eq!(sink.buffered(), 1);
+    }
+
+    #[test]
+    fn writes_to_database_file() {
+        let path = std::env::temp_dir().join(format!("abineo-{}.duckdb", std::process::id()));
+        let mut sink = DuckDbSink::open(&path).unwrap();
+        let visit = Visit {
+            page: Page {
+                path: "/pricing".into(),
+                ..Default::default()
+            },
+            channel_group: Some("Organic Search".into()),
+            ..Default::default()
+        };
+        sink.write(vec![
+            visit.into(),
+            Event::default().into(),
+            SessionSummary::default().into(),
+        ])
+        .unwrap();
+        sink.close().unwrap();
+        drop(sink);
+
+        let conn = ::duckdb::Connection::open(&path).unwrap();
+        let (page, channel_group): (String, String) = conn
+            .query_row("SELECT path, channel_group FROM visits", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(page, "/pricing");
+        assert_eq!(channel_group, "Organic Search");
+        for table in ["events", "sessions"] {
+            let count: i64 = conn
+                .query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(count, 1);
+        }
+        drop(conn);
+        std::fs::remove_file(path).ok();
+    }
+}
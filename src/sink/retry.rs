@@ -0,0 +1,357 @@
+//! Retries of failed sink writes.
+//!
+//! A [`RetrySink`] wraps any [`Sink`] and retries failed writes with
+//! exponential backoff and jitter, as configured by a [`RetryPolicy`]. A
+//! retry budget limits retries to a share of the writes, so a struggling
+//! downstream isn't flooded with them, and a circuit breaker fails writes
+//! right away with [`Error::CircuitOpen`] once the downstream seems down.
+
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::flush::Flushable;
+use crate::hash::Hasher;
+use crate::sink::Sink;
+use crate::{Error, Record};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts per write, including the first one.
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubled for every further one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Share of the backoff that is randomized, from 0 to 1.
+    pub jitter: f64,
+    /// Retries earned per write, e.g. 0.1 allows one retry per 10 writes.
+    pub budget_ratio: f64,
+    /// Retries allowed without earning them, also the most that are saved up.
+    pub budget_reserve: f64,
+    /// Consecutive failed writes that open the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a trial write.
+    pub open_for: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// 3 attempts starting 100ms apart, the circuit opens for 30 seconds
+    /// after 5 failed writes.
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.5,
+            budget_ratio: 0.2,
+            budget_reserve: 10.0,
+            failure_threshold: 5,
+            open_for: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The backoff before retry `retry`, counting from 0, without jitter.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// The backoff reduced by a random share of up to `jitter`, so writers
+    /// that failed together don't retry together.
+    fn jittered(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+        let mut hasher = Hasher::hardened();
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        hasher.write(nanos.as_nanos() as u64);
+        hasher.write(u64::from(retry));
+        let random = hasher.finalize() as f64 / u64::MAX as f64;
+        backoff.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random)
+    }
+}
+
+/// Whether a failed write may succeed later.
+///
+/// Rejected payloads (4xx responses but timeouts and rate limits) and
+/// encoding errors fail again.
+pub fn is_retriable(err: &Error) -> bool {
+    match err {
+        Error::Webhook(status) => !(400..=499).contains(status) || matches!(status, 408 | 429),
+        Error::Json(_) | Error::Closed | Error::CircuitOpen => false,
+        _ => true,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    /// Writes fail until the instant.
+    Open(Instant),
+    /// The next write is a trial.
+    HalfOpen,
+}
+
+pub struct RetrySink<S> {
+    inner: S,
+    policy: RetryPolicy,
+    budget: f64,
+    failures: u32,
+    circuit: CircuitState,
+    sleep: Box<dyn FnMut(Duration) + Send>,
+}
+
+impl<S: Sink> RetrySink<S> {
+    pub fn new(inner: S, policy: RetryPolicy) -> Self {
+        RetrySink {
+            inner,
+            budget: policy.budget_reserve,
+            policy,
+            failures: 0,
+            circuit: CircuitState::Closed,
+            sleep: Box::new(thread::sleep),
+        }
+    }
+
+    /// Replaces sleeping the backoff, e.g. in tests.
+    pub fn with_sleep(mut self, sleep: impl FnMut(Duration) + Send + 'static) -> Self {
+        self.sleep = Box::new(sleep);
+        self
+    }
+
+    pub fn circuit(&self) -> CircuitState {
+        self.circuit
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn try_write(&mut self, records: Vec<Record>) -> Result<(), Error> {
+        self.budget = (self.budget + self.policy.budget_ratio).min(self.policy.budget_reserve);
+        let mut retry = 0;
+        loop {
+            let attempts_left = retry + 1 < self.policy.max_attempts;
+            if !attempts_left || self.budget < 1.0 {
+                // nothing to retry with, no copy needed
+                return self.inner.write(records);
+            }
+            let err = match self.inner.write(records.clone()) {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if !is_retriable(&err) {
+                return Err(err);
+            }
+            self.budget -= 1.0;
+            (self.sleep)(self.policy.jittered(retry));
+            retry += 1;
+        }
+    }
+}
+
+impl<S: Sink> Sink for RetrySink<S> {
+    /// Writes the records, retrying failures.
+    ///
+    /// Fails with [`Error::CircuitOpen`] without writing while the circuit
+    /// is open.
+    fn write(&mut self, records: Vec<Record>) -> Result<(), Error> {
+        if let CircuitState::Open(until) = self.circuit {
+            if Instant::now() < until {
+                return Err(Error::CircuitOpen);
+            }
+            self.circuit = CircuitState::HalfOpen;
+        }
+
+        let written = self.try_write(records);
+        match &written {
+            Ok(()) => {
+                self.failures = 0;
+                self.circuit = CircuitState::Closed;
+            }
+            Err(_) => {
+                self.failures += 1;
+                let trial = self.circuit == CircuitState::HalfOpen;
+                if trial || self.failures >= self.policy.failure_threshold {
+                    self.circuit = CircuitState::Open(Instant::now() + self.policy.open_for);
+                }
+            }
+        }
+        written
+    }
+}
+
+impl<S: Sink> Flushable for RetrySink<S> {
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.inner.close()
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for RetrySink<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetrySink")
+            .field("inner", &self.inner)
+            .field("policy", &self.policy)
+            .field("budget", &self.budget)
+            .field("failures", &self.failures)
+            .field("circuit", &self.circuit)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::Event;
+
+    fn records() -> Vec<Record> {
+        vec![Event::default().into()]
+    }
+
+    /// Fails with the given errors, then succeeds.
+    fn flaky(errors: Vec<Error>) -> (impl Sink, Arc<Mutex<u32>>) {
+        let (errors, calls) = (Mutex::new(errors), Arc::new(Mutex::new(0)));
+        let sink = {
+            let calls = calls.clone();
+            move |_: Vec<Record>| {
+                *calls.lock().unwrap() += 1;
+                let mut errors = errors.lock().unwrap();
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(errors.remove(0))
+                }
+            }
+        };
+        (sink, calls)
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy {
+            max_backoff: Duration::from_millis(300),
+            ..policy()
+        };
+        let backoffs: Vec<_> = (0..4).map(|retry| policy.backoff(retry)).collect();
+        assert_eq!(backoffs, [100, 200, 300, 300].map(Duration::from_millis));
+
+        let jittered = RetryPolicy::default().jittered(1);
+        assert!(jittered > Duration::from_millis(99) && jittered <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let slept = Arc::new(Mutex::new(Vec::new()));
+        let (inner, calls) = flaky(vec![Error::Webhook(503), Error::Webhook(503)]);
+        let mut sink = RetrySink::new(inner, policy()).with_sleep({
+            let slept = slept.clone();
+            move |backoff| slept.lock().unwrap().push(backoff)
+        });
+        sink.write(records()).unwrap();
+        assert_eq!(*calls.lock().unwrap(), 3);
+        assert_eq!(
+            *slept.lock().unwrap(),
+            [100, 200].map(Duration::from_millis)
+        );
+
+        let (inner, calls) = flaky(vec![Error::Webhook(400)]);
+        let mut sink = RetrySink::new(inner, policy()).with_sleep(|_| {});
+        assert!(sink.write(records()).is_err());
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn copies_records_only_for_retries() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let inner = {
+            let written = written.clone();
+            move |records: Vec<Record>| {
+                written.lock().unwrap().push(records.as_ptr() as usize);
+                Err(Error::Webhook(503))
+            }
+        };
+        let mut sink = RetrySink::new(inner, policy()).with_sleep(|_| {});
+        let records = records();
+        let original = records.as_ptr() as usize;
+        assert!(sink.write(records).is_err());
+
+        let written = written.lock().unwrap();
+        assert_eq!(written.len(), 3);
+        assert!(written[..2].iter().all(|&ptr| ptr != original));
+        assert_eq!(written[2], original);
+    }
+
+    #[test]
+    fn budget_limits_retries() {
+        let errors = (0..100).map(|_| Error::Webhook(503)).collect();
+        let (inner, calls) = flaky(errors);
+        let policy = RetryPolicy {
+            budget_reserve: 2.0,
+            budget_ratio: 0.0,
+            failure_threshold: u32::MAX,
+            ..policy()
+        };
+        let mut sink = RetrySink::new(inner, policy).with_sleep(|_| {});
+        for _ in 0..3 {
+            assert!(sink.write(records()).is_err());
+        }
+        // 2 retries of the reserve, then single attempts
+        assert_eq!(*calls.lock().unwrap(), 3 + 1 + 1);
+    }
+
+    #[test]
+    fn circuit_opens_after_failures() {
+        let errors = (0..6).map(|_| Error::Webhook(503)).collect();
+        let (inner, calls) = flaky(errors);
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            failure_threshold: 2,
+            open_for: Duration::from_millis(20),
+            ..policy()
+        };
+        let mut sink = RetrySink::new(inner, policy);
+        assert!(sink.write(records()).is_err());
+        assert_eq!(sink.circuit(), CircuitState::Closed);
+        assert!(sink.write(records()).is_err());
+        assert!(matches!(sink.circuit(), CircuitState::Open(_)));
+        assert!(matches!(sink.write(records()), Err(Error::CircuitOpen)));
+        assert_eq!(*calls.lock().unwrap(), 2);
+
+        // a failed trial reopens the circuit
+        thread::sleep(Duration::from_millis(25));
+        assert!(matches!(sink.write(records()), Err(Error::Webhook(503))));
+        assert!(matches!(sink.circuit(), CircuitState::Open(_)));
+    }
+
+    #[test]
+    fn successful_trial_closes_circuit() {
+        let (inner, _) = flaky(vec![Error::Webhook(503)]);
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            failure_threshold: 1,
+            open_for: Duration::ZERO,
+            ..policy()
+        };
+        let mut sink = RetrySink::new(inner, policy);
+        assert!(sink.write(records()).is_err());
+        sink.write(records()).unwrap();
+        assert_eq!(sink.circuit(), CircuitState::Closed);
+    }
+}
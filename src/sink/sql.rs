@@ -0,0 +1,297 @@
+//! Row mapping shared by the embedded database sinks.
+//!
+//! Records are stored denormalized, one row per visit, event or session
+//! summary. The sinks write through [`SqlConnection`], which is implemented
+//! for the connections of the enabled database crates.
+
+use std::fmt::Debug;
+
+use crate::session::SessionSummary;
+use crate::{Error, Event, Record, Visit};
+
+/// A bound statement parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl From<i64> for SqlValue {
+    fn from(val: i64) -> Self {
+        SqlValue::Integer(val)
+    }
+}
+
+impl From<i32> for SqlValue {
+    fn from(val: i32) -> Self {
+        SqlValue::Integer(val.into())
+    }
+}
+
+impl From<bool> for SqlValue {
+    fn from(val: bool) -> Self {
+        SqlValue::Integer(val.into())
+    }
+}
+
+impl From<f64> for SqlValue {
+    fn from(val: f64) -> Self {
+        SqlValue::Real(val)
+    }
+}
+
+impl From<&str> for SqlValue {
+    fn from(val: &str) -> Self {
+        SqlValue::Text(val.to_string())
+    }
+}
+
+impl<T: Into<SqlValue>> From<Option<T>> for SqlValue {
+    fn from(val: Option<T>) -> Self {
+        val.map_or(SqlValue::Null, Into::into)
+    }
+}
+
+/// An open database.
+pub trait SqlConnection: Debug + Send {
+    /// Executes one or more statements without parameters.
+    fn execute_batch(&mut self, sql: &str) -> Result<(), Error>;
+
+    fn execute(&mut self, sql: &str, params: &[SqlValue]) -> Result<(), Error>;
+}
+
+/// Number of columns of the `visits` table.
+#[cfg(test)]
+pub(crate) const VISIT_COLUMNS: usize = 28;
+
+/// Number of columns of the `events` table.
+#[cfg(test)]
+pub(crate) const EVENT_COLUMNS: usize = 10;
+
+/// Number of columns of the `sessions` table.
+#[cfg(test)]
+pub(crate) const SESSION_COLUMNS: usize = 20;
+
+/// Inserts the records in a single transaction, rolling back on errors.
+pub(crate) fn insert_all(
+    conn: &mut impl SqlConnection,
+    insert_visit: &str,
+    insert_event: &str,
+    insert_session: &str,
+    records: &[Record],
+) -> Result<(), Error> {
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let res = records.iter().try_for_each(|record| match record {
+        Record::Visit(visit) => conn.execute(insert_visit, &visit_row(visit)),
+        Record::Event(event) => conn.execute(insert_event, &event_row(event)?),
+        Record::Session(summary) => conn.execute(insert_session, &session_row(summary)?),
+    });
+    match res {
+        Ok(()) => conn.execute_batch("COMMIT"),
+        Err(err) => {
+            // a failed rollback must not hide why the batch failed
+            let _ = conn.execute_batch("ROLLBACK");
+            Err(err)
+        }
+    }
+}
+
+pub(crate) fn visit_row(visit: &Visit) -> Vec<SqlValue> {
+    let utm = visit.utm_param.as_ref();
+    vec![
+        visit.time.to_rfc3339().as_str().into(),
+        visit.project.into(),
+        visit.session.into(),
+        visit.visitor.id.into(),
+        visit.visitor.region.as_deref().into(),
+        visit.visitor.timezone.as_str().into(),
+        visit.visitor.language.as_str().into(),
+        visit.visitor.browser.as_deref().into(),
+        visit.visitor.platform.as_deref().into(),
+        visit.visitor.width.into(),
+        visit.visitor.height.into(),
+        visit.page.id.into(),
+        visit.page.domain.as_str().into(),
+        visit.page.path.as_str().into(),
+        utm.map(|utm| utm.id).into(),
+        utm.and_then(|utm| utm.campaign.as_deref()).into(),
+        utm.and_then(|utm| utm.content.as_deref()).into(),
+        utm.and_then(|utm| utm.medium.as_deref()).into(),
+        utm.and_then(|utm| utm.source.as_deref()).into(),
+        utm.and_then(|utm| utm.term.as_deref()).into(),
+        visit.referrer.as_ref().map(|referrer| referrer.id).into(),
+        visit
+            .referrer
+            .as_ref()
+            .map(|referrer| referrer.domain.as_str())
+            .into(),
+        visit.duration.into(),
+        visit.distance.into(),
+        visit.embed.as_deref().into(),
+        visit.is_unique_in_session.into(),
+        visit.previous_page_id.into(),
+        visit.channel_group.as_deref().into(),
+    ]
+}
+
+pub(crate) fn event_row(event: &Event) -> Result<Vec<SqlValue>, Error> {
+    Ok(vec![
+        event.time.to_rfc3339().as_str().into(),
+        event.project.into(),
+        event.session.into(),
+        event.visitor.id.into(),
+        event.page.id.into(),
+        event.page.domain.as_str().into(),
+        event.page.path.as_str().into(),
+        event.utm_param.as_ref().map(|utm| utm.id).into(),
+        event.name.as_str().into(),
+        SqlValue::Text(serde_json::to_string(&event.data)?),
+    ])
+}
+
+pub(crate) fn session_row(summary: &SessionSummary) -> Result<Vec<SqlValue>, Error> {
+    let utm = summary.utm_param.as_ref();
+    Ok(vec![
+        summary.time.to_rfc3339().as_str().into(),
+        summary.project.into(),
+        summary.session.into(),
+        summary.visitor.id.into(),
+        summary.visitor.region.as_deref().into(),
+        summary.end.to_rfc3339().as_str().into(),
+        i64::from(summary.pages_viewed).into(),
+        summary.duration.into(),
+        summary.entry_page.domain.as_str().into(),
+        summary.entry_page.id.into(),
+        summary.entry_page.path.as_str().into(),
+        summary.exit_page.id.into(),
+        summary.exit_page.path.as_str().into(),
+        SqlValue::Text(serde_json::to_string(&summary.goals)?),
+        utm.map(|utm| utm.id).into(),
+        utm.and_then(|utm| utm.campaign.as_deref()).into(),
+        utm.and_then(|utm| utm.medium.as_deref()).into(),
+        utm.and_then(|utm| utm.source.as_deref()).into(),
+        summary
+            .referrer
+            .as_ref()
+            .map(|referrer| referrer.domain.as_str())
+            .into(),
+        summary.channel_group.as_deref().into(),
+    ])
+}
+
+/// Counts the columns of a `CREATE TABLE` statement with one column per line.
+#[cfg(test)]
+pub(crate) fn columns(schema: &str, table: &str) -> usize {
+    column_names(schema, table).len()
+}
+
+/// Names of the columns of a `CREATE TABLE` statement with one column per
+/// line.
+#[cfg(test)]
+pub(crate) fn column_names<'a>(schema: &'a str, table: &str) -> Vec<&'a str> {
+    let start = schema
+        .find(&format!("TABLE IF NOT EXISTS {table}"))
+        .unwrap();
+    let end = start + schema[start..].find(");").unwrap();
+    schema[start..end]
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().next().unwrap())
+        .collect()
+}
+
+/// Names of the columns an `INSERT` statement lists.
+#[cfg(test)]
+pub(crate) fn insert_columns(insert: &str) -> Vec<&str> {
+    let start = insert.find('(').unwrap() + 1;
+    let end = insert.find(')').unwrap();
+    insert[start..end].split(',').map(str::trim).collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    pub(crate) struct Recorder {
+        pub statements: Vec<String>,
+        pub fail_events: bool,
+        pub fail_rollback: bool,
+    }
+
+    impl Recorder {
+        /// The leading keyword of every executed statement.
+        pub fn keywords(&self) -> Vec<&str> {
+            self.statements
+                .iter()
+                .map(|sql| &sql[..sql.find([' ', ';']).unwrap_or(sql.len())])
+                .collect()
+        }
+    }
+
+    impl SqlConnection for &mut Recorder {
+        fn execute_batch(&mut self, sql: &str) -> Result<(), Error> {
+            self.statements.push(sql.trim().to_string());
+            if self.fail_rollback && sql == "ROLLBACK" {
+                return Err(Error::Overloaded);
+            }
+            Ok(())
+        }
+
+        fn execute(&mut self, sql: &str, _: &[SqlValue]) -> Result<(), Error> {
+            if self.fail_events && sql.contains("events") {
+                return Err(Error::Closed);
+            }
+            self.statements.push(sql.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rows_have_all_columns() {
+        assert_eq!(visit_row(&Visit::default()).len(), VISIT_COLUMNS);
+        assert_eq!(event_row(&Event::default()).unwrap().len(), EVENT_COLUMNS);
+        assert_eq!(
+            session_row(&SessionSummary::default()).unwrap().len(),
+            SESSION_COLUMNS
+        );
+    }
+
+    #[test]
+    fn rolls_back_failed_batches() {
+        let mut recorder = Recorder {
+            fail_events: true,
+            ..Default::default()
+        };
+        let records = [Visit::default().into(), Event::default().into()];
+        assert!(insert_all(
+            &mut &mut recorder,
+            "INSERT INTO visits",
+            "INSERT INTO events",
+            "INSERT INTO sessions",
+            &records
+        )
+        .is_err());
+        assert_eq!(recorder.keywords(), ["BEGIN", "INSERT", "ROLLBACK"]);
+    }
+
+    #[test]
+    fn keeps_error_of_failed_rollbacks() {
+        let mut recorder = Recorder {
+            fail_events: true,
+            fail_rollback: true,
+            ..Default::default()
+        };
+        let records = [Event::default().into()];
+        let res = insert_all(
+            &mut &mut recorder,
+            "INSERT INTO visits",
+            "INSERT INTO events",
+            "INSERT INTO sessions",
+            &records,
+        );
+        assert!(matches!(res, Err(Error::Closed)));
+    }
+}
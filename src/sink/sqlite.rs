@@ -0,0 +1,262 @@
+//! Storage in a local SQLite database for small self-hosted installs.
+//!
+//! Records are stored denormalized in a `visits`, an `events` and a
+//! `sessions` table.
+//! The database runs in WAL mode and every [`Sink::write`] is a single
+//! transaction, so readers aren't blocked by the collector.
+
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+use rusqlite::ToSql;
+
+use crate::flush::Flushable;
+use crate::sink::sql::{self, SqlConnection, SqlValue};
+use crate::sink::Sink;
+use crate::{Error, Record};
+
+/// How long a write waits for readers holding a lock on the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA synchronous = NORMAL;
+
+CREATE TABLE IF NOT EXISTS visits (
+    time TEXT NOT NULL,
+    project INTEGER NOT NULL,
+    session INTEGER NOT NULL,
+    visitor INTEGER NOT NULL,
+    region TEXT,
+    timezone TEXT NOT NULL,
+    language TEXT NOT NULL,
+    browser TEXT,
+    platform TEXT,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    page INTEGER NOT NULL,
+    domain TEXT NOT NULL,
+    path TEXT NOT NULL,
+    utm_param INTEGER,
+    campaign TEXT,
+    content TEXT,
+    medium TEXT,
+    source TEXT,
+    term TEXT,
+    referrer INTEGER,
+    referrer_domain TEXT,
+    duration INTEGER,
+    distance REAL,
+    embed TEXT,
+    is_unique_in_session INTEGER,
+    previous_page INTEGER,
+    channel_group TEXT
+);
+CREATE INDEX IF NOT EXISTS visits_project_time ON visits (project, time);
+
+CREATE TABLE IF NOT EXISTS events (
+    time TEXT NOT NULL,
+    project INTEGER NOT NULL,
+    session INTEGER NOT NULL,
+    visitor INTEGER NOT NULL,
+    page INTEGER NOT NULL,
+    domain TEXT NOT NULL,
+    path TEXT NOT NULL,
+    utm_param INTEGER,
+    name TEXT NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_project_time ON events (project, time);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    time TEXT NOT NULL,
+    project INTEGER NOT NULL,
+    session INTEGER NOT NULL,
+    visitor INTEGER NOT NULL,
+    region TEXT,
+    end_time TEXT NOT NULL,
+    pages_viewed INTEGER NOT NULL,
+    duration INTEGER NOT NULL,
+    domain TEXT NOT NULL,
+    entry_page INTEGER NOT NULL,
+    entry_path TEXT NOT NULL,
+    exit_page INTEGER NOT NULL,
+    exit_path TEXT NOT NULL,
+    goals TEXT NOT NULL,
+    utm_param INTEGER,
+    campaign TEXT,
+    medium TEXT,
+    source TEXT,
+    referrer_domain TEXT,
+    channel_group TEXT
+);
+CREATE INDEX IF NOT EXISTS sessions_project_time ON sessions (project, time);
+";
+
+const INSERT_VISIT: &str = "INSERT INTO visits (time, project, session, visitor, region, \
+    timezone, language, browser, platform, width, height, page, domain, path, utm_param, campaign, \
+    content, medium, source, term, referrer, referrer_domain, duration, distance, embed, \
+    is_unique_in_session, previous_page, channel_group) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, \
+    ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, \
+    ?28)";
+
+const INSERT_EVENT: &str = "INSERT INTO events (time, project, session, visitor, page, domain, \
+    path, utm_param, name, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)";
+
+const INSERT_SESSION: &str = "INSERT INTO sessions (time, project, session, visitor, region, \
+    end_time, pages_viewed, duration, domain, entry_page, entry_path, exit_page, exit_path, goals, \
+    utm_param, campaign, medium, source, referrer_domain, channel_group) VALUES (?1, ?2, ?3, ?4, \
+    ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20)";
+
+#[derive(Debug)]
+pub struct SqliteSink<C = rusqlite::Connection> {
+    conn: C,
+}
+
+impl SqliteSink {
+    /// Opens the database at `path`, creating the file and tables if they
+    /// don't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        SqliteSink::new(conn)
+    }
+}
+
+impl<C: SqlConnection> SqliteSink<C> {
+    /// Enables WAL mode and creates the tables if they don't exist.
+    pub fn new(mut conn: C) -> Result<Self, Error> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteSink { conn })
+    }
+}
+
+impl<C: SqlConnection> Sink for SqliteSink<C> {
+    /// Inserts the records in a single transaction.
+    fn write(&mut self, records: Vec<Record>) -> Result<(), Error> {
+        sql::insert_all(
+            &mut self.conn,
+            INSERT_VISIT,
+            INSERT_EVENT,
+            INSERT_SESSION,
+            &records,
+        )
+    }
+}
+
+/// Records are committed on write, closing checkpoints the WAL into the
+/// database file.
+impl<C: SqlConnection> Flushable for SqliteSink<C> {
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
+    }
+}
+
+impl SqlConnection for rusqlite::Connection {
+    fn execute_batch(&mut self, sql: &str) -> Result<(), Error> {
+        Ok(rusqlite::Connection::execute_batch(self, sql)?)
+    }
+
+    fn execute(&mut self, sql: &str, params: &[SqlValue]) -> Result<(), Error> {
+        self.prepare_cached(sql)?
+            .execute(rusqlite::params_from_iter(params))?;
+        Ok(())
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            SqlValue::Null => ToSqlOutput::Owned(Value::Null),
+            SqlValue::Integer(val) => ToSqlOutput::Owned(Value::Integer(*val)),
+            SqlValue::Real(val) => ToSqlOutput::Owned(Value::Real(*val)),
+            SqlValue::Text(val) => ToSqlOutput::Borrowed(ValueRef::Text(val.as_bytes())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionSummary;
+    use crate::sink::sql::tests::Recorder;
+    use crate::sink::sql::{
+        column_names, columns, insert_columns, EVENT_COLUMNS, SESSION_COLUMNS, VISIT_COLUMNS,
+    };
+    use crate::{Event, Page, Visit};
+
+    #[test]
+    fn statements_match_schema() {
+        assert_eq!(columns(SCHEMA, "visits"), VISIT_COLUMNS);
+        assert_eq!(columns(SCHEMA, "events"), EVENT_COLUMNS);
+        assert_eq!(INSERT_VISIT.matches('?').count(), VISIT_COLUMNS);
+        assert_eq!(INSERT_EVENT.matches('?').count(), EVENT_COLUMNS);
+        assert_eq!(columns(SCHEMA, "sessions"), SESSION_COLUMNS);
+        assert_eq!(INSERT_SESSION.matches('?').count(), SESSION_COLUMNS);
+        assert_eq!(insert_columns(INSERT_VISIT), column_names(SCHEMA, "visits"));
+        assert_eq!(insert_columns(INSERT_EVENT), column_names(SCHEMA, "events"));
+        assert_eq!(
+            insert_columns(INSERT_SESSION),
+            column_names(SCHEMA, "sessions")
+        );
+    }
+
+    #[test]
+    fn writes_batches_in_transactions() {
+        let mut recorder = Recorder::default();
+        let mut sink = SqliteSink::new(&mut recorder).unwrap();
+        sink.write(vec![Visit::default().into(), Event::default().into()])
+            .unwrap();
+        sink.close().unwrap();
+        assert_eq!(
+            recorder.keywords(),
+            ["PRAGMA", "BEGIN", "INSERT", "INSERT", "COMMIT", "PRAGMA"]
+        );
+    }
+
+    #[test]
+    fn writes_to_database_file() {
+        let path = std::env::temp_dir().join(format!("abineo-{}.sqlite", std::process::id()));
+        let mut sink = SqliteSink::open(&path).unwrap();
+        let visit = Visit {
+            page: Page {
+                path: "/pricing".into(),
+                ..Default::default()
+            },
+            channel_group: Some("Organic Search".into()),
+            ..Default::default()
+        };
+        sink.write(vec![visit.into(), Event::default().into()])
+            .unwrap();
+        sink.write(vec![SessionSummary::default().into()]).unwrap();
+        sink.close().unwrap();
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+        let (page, channel_group): (String, String) = conn
+            .query_row("SELECT path, channel_group FROM visits", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(page, "/pricing");
+        assert_eq!(channel_group, "Organic Search");
+        for table in ["events", "sessions"] {
+            let count: i64 = conn
+                .query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(count, 1);
+        }
+        drop(conn);
+        std::fs::remove_file(path).ok();
+    }
+}
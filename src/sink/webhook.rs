@@ -0,0 +1,331 @@
+//! Notifications about selected records, e.g. to post purchases to Slack.
+//!
+//! A [`WebhookSink`] POSTs every selected record as JSON to a url. Events
+//! are selected by name and visits by the path of their page, e.g. a
+//! `/thank-you` page reached after a signup.
+//!
+//! Requests are signed like `Abineo-Signature: t=1696161600,v1=<signature>`
+//! where the signature is the hex HMAC-SHA256 of `"{t}.{body}"` with a
+//! shared secret, so receivers can verify the payload and reject replays.
+//! The HTTP client is supplied by the host through [`WebhookClient`].
+
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use url::Url;
+
+use crate::filter::EventNames;
+use crate::flush::Flushable;
+use crate::sink::Sink;
+use crate::{Error, Record};
+
+/// Header carrying the timestamp and signature of a request.
+pub const SIGNATURE_HEADER: &str = "Abineo-Signature";
+
+/// A blocking HTTP client.
+pub trait WebhookClient: Debug + Send {
+    /// Sends a POST request, returning the response status.
+    fn post(&mut self, url: &Url, headers: &[(&str, String)], body: &[u8]) -> Result<u16, Error>;
+}
+
+/// Hex HMAC-SHA256 of `payload`.
+fn sign(secret: &[u8], payload: &[u8]) -> String {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(payload);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct WebhookSink<C> {
+    client: C,
+    url: Url,
+    secret: Option<Vec<u8>>,
+    events: EventNames,
+    paths: HashSet<String>,
+    retries: u32,
+    backoff: Duration,
+    /// Bodies of the records the last failed write delivered, skipped when
+    /// the write is retried.
+    delivered: Vec<Vec<u8>>,
+}
+
+impl<C: WebhookClient> WebhookSink<C> {
+    /// Posts nothing until events or paths are selected. Failed requests
+    /// are retried 3 times, starting 1 second apart.
+    pub fn new(client: C, url: Url) -> Self {
+        WebhookSink {
+            client,
+            url,
+            secret: None,
+            events: EventNames::Allow(HashSet::new()),
+            paths: HashSet::new(),
+            retries: 3,
+            backoff: Duration::from_secs(1),
+            delivered: Vec::new(),
+        }
+    }
+
+    /// Signs requests with the secret shared with the receiver.
+    pub fn with_secret(mut self, secret: &[u8]) -> Self {
+        self.secret = Some(secret.to_vec());
+        self
+    }
+
+    /// Selects the events to post by name.
+    pub fn with_events(mut self, events: EventNames) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Selects visits of pages with one of the given paths.
+    pub fn with_paths<'a>(mut self, paths: impl IntoIterator<Item = &'a str>) -> Self {
+        self.paths = paths.into_iter().map(str::to_string).collect();
+        self
+    }
+
+    /// Retries after the backoff, doubling it for every further attempt.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn selects(&self, record: &Record) -> bool {
+        match record {
+            Record::Visit(visit) => self.paths.contains(&visit.page.path),
+            Record::Event(event) => self.events.contains(&event.name),
+            Record::Session(_) => false,
+        }
+    }
+
+    fn post(&mut self, body: &[u8]) -> Result<(), Error> {
+        let mut headers = vec![("Content-Type", "application/json".to_string())];
+        if let Some(secret) = &self.secret {
+            let timestamp = Utc::now().timestamp();
+            let mut payload = format!("{timestamp}.").into_bytes();
+            payload.extend_from_slice(body);
+            let signature = sign(secret, &payload);
+            headers.push((SIGNATURE_HEADER, format!("t={timestamp},v1={signature}")));
+        }
+
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let err = match self.client.post(&self.url, &headers, body) {
+                Ok(200..=299) => return Ok(()),
+                // the receiver rejected the payload, it won't accept it later
+                Ok(status @ 400..=499) if status != 408 && status != 429 => {
+                    return Err(Error::Webhook(status))
+                }
+                Ok(status) => Error::Webhook(status),
+                Err(err) => err,
+            };
+            if attempt == self.retries {
+                return Err(err);
+            }
+            attempt += 1;
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+}
+
+impl<C: WebhookClient> Sink for WebhookSink<C> {
+    /// Posts the selected records one by one. Stops at the first record
+    /// that couldn't be delivered after all retries.
+    ///
+    /// The records delivered before are remembered, so retrying the write
+    /// with the same records only posts the ones that weren't delivered.
+    fn write(&mut self, records: Vec<Record>) -> Result<(), Error> {
+        let mut bodies = records
+            .iter()
+            .filter(|record| self.selects(record))
+            .map(serde_json::to_vec)
+            .collect::<Result<Vec<_>, _>>()?;
+        let delivered = std::mem::take(&mut self.delivered);
+        let skip = if bodies.starts_with(&delivered) {
+            delivered.len()
+        } else {
+            0
+        };
+        for (i, body) in bodies.iter().enumerate().skip(skip) {
+            if let Err(err) = self.post(body) {
+                bodies.truncate(i);
+                self.delivered = bodies;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Records are posted on write, there is nothing to flush.
+impl<C: WebhookClient> Flushable for WebhookSink<C> {
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Event, Page, Visit};
+
+    #[derive(Debug, Default)]
+    struct Client {
+        statuses: Vec<u16>,
+        requests: Vec<(Vec<String>, Vec<u8>)>,
+    }
+
+    impl WebhookClient for &mut Client {
+        fn post(&mut self, _: &Url, headers: &[(&str, String)], body: &[u8]) -> Result<u16, Error> {
+            let headers = headers
+                .iter()
+                .map(|(name, val)| format!("{name}: {val}"))
+                .collect();
+            self.requests.push((headers, body.to_vec()));
+            Ok(if self.statuses.is_empty() {
+                204
+            } else {
+                self.statuses.remove(0)
+            })
+        }
+    }
+
+    fn sink(client: &mut Client) -> WebhookSink<&mut Client> {
+        WebhookSink::new(client, Url::parse("https://hooks.example/abineo").unwrap())
+            .with_events(EventNames::Allow(HashSet::from(["purchase".to_string()])))
+            .with_paths(["/thank-you"])
+            .with_retries(2, Duration::ZERO)
+    }
+
+    fn event(name: &str) -> Record {
+        Event {
+            name: name.to_string(),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    fn visit(path: &str) -> Record {
+        Visit {
+            page: Page {
+                path: path.to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .into()
+    }
+
+    #[test]
+    fn posts_selected_records() {
+        let mut client = Client::default();
+        sink(&mut client)
+            .write(vec![
+                event("purchase"),
+                event("click"),
+                visit("/"),
+                visit("/thank-you"),
+            ])
+            .unwrap();
+
+        assert_eq!(client.requests.len(), 2);
+        let body: serde_json::Value = serde_json::from_slice(&client.requests[0].1).unwrap();
+        assert_eq!(body["type"], "event");
+        assert_eq!(body["name"], "purchase");
+        let body: serde_json::Value = serde_json::from_slice(&client.requests[1].1).unwrap();
+        assert_eq!(body["type"], "visit");
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        let mut client = Client::default();
+        sink(&mut client)
+            .with_secret(b"secret")
+            .write(vec![event("purchase")])
+            .unwrap();
+
+        let (headers, body) = &client.requests[0];
+        let signature = headers
+            .iter()
+            .find_map(|header| header.strip_prefix("Abineo-Signature: "))
+            .unwrap();
+        let (timestamp, signature) = signature.split_once(",v1=").unwrap();
+        let timestamp = timestamp.strip_prefix("t=").unwrap();
+        let mut payload = format!("{timestamp}.").into_bytes();
+        payload.extend_from_slice(body);
+        assert_eq!(signature, sign(b"secret", &payload));
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let mut client = Client {
+            statuses: vec![503, 429],
+            ..Default::default()
+        };
+        sink(&mut client).write(vec![event("purchase")]).unwrap();
+        assert_eq!(client.requests.len(), 3);
+
+        let mut client = Client {
+            statuses: vec![500, 500, 500],
+            ..Default::default()
+        };
+        let res = sink(&mut client).write(vec![event("purchase")]);
+        assert!(matches!(res, Err(Error::Webhook(500))));
+        assert_eq!(client.requests.len(), 3);
+
+        let mut client = Client {
+            statuses: vec![400],
+            ..Default::default()
+        };
+        let res = sink(&mut client).write(vec![event("purchase")]);
+        assert!(matches!(res, Err(Error::Webhook(400))));
+        assert_eq!(client.requests.len(), 1);
+    }
+
+    #[test]
+    fn retries_only_undelivered_records() {
+        let mut client = Client {
+            statuses: vec![204, 500, 500, 500],
+            ..Default::default()
+        };
+        let mut sink = sink(&mut client);
+        let records = vec![event("purchase"), visit("/thank-you")];
+        assert!(sink.write(records.clone()).is_err());
+        sink.write(records).unwrap();
+        sink.write(vec![event("purchase")]).unwrap();
+
+        let types: Vec<String> = client
+            .requests
+            .iter()
+            .map(|(_, body)| {
+                let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+                body["type"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(
+            types,
+            ["event", "visit", "visit", "visit", "visit", "event"]
+        );
+    }
+}
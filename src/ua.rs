@@ -0,0 +1,753 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, Once, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use uaparser::{Parser, UserAgentParser};
+
+use crate::Error;
+
+/// Number of distinct user agents kept by [`UA_CACHE`].
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// Longest user agent handed to the uap regexes, real ones stay well below.
+pub const MAX_USER_AGENT_LEN: usize = 1024;
+
+/// Longest run of a repeated character in a user agent, see [`is_bogus`].
+const MAX_REPEAT: usize = 32;
+
+/// Longest word of a user agent, see [`is_bogus`].
+const MAX_WORD_LEN: usize = 256;
+
+/// Parser threads of [`UaCache::parse_within`].
+pub const PARSE_WORKERS: usize = 4;
+
+/// Most user agents of [`UaCache::parse_within`] waiting for a parser
+/// thread.
+pub const PARSE_QUEUE_LEN: usize = 256;
+
+#[cfg(not(any(feature = "minimal", feature = "trim-ua-regexes")))]
+static UAP_REGEXES: &[u8] = include_bytes!("../uap-core/regexes.yaml");
+/// Without the device parsers and those of obsolete browsers, systems and
+/// devices, see `build.rs`. Their user agents get other or no families.
+#[cfg(all(not(feature = "minimal"), feature = "trim-ua-regexes"))]
+static UAP_REGEXES: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/regexes.yaml"));
+
+/// Compiling the regexes takes a few hundred milliseconds, which the first
+/// request pays unless the parser is initialized at startup.
+static UA_PARSER: OnceLock<Result<UserAgentParser, String>> = OnceLock::new();
+
+include!(concat!(env!("OUT_DIR"), "/ua-codegen.rs"));
+
+lazy_static! {
+    pub static ref UA_CACHE: UaCache = UaCache::new(DEFAULT_CAPACITY);
+}
+
+/// Compiles the uap regexes unless already done, failing if they are
+/// invalid. Call at startup to fail fast.
+#[cfg(not(feature = "minimal"))]
+pub fn try_init() -> Result<(), Error> {
+    UA_PARSER
+        .get_or_init(|| UserAgentParser::from_bytes(UAP_REGEXES).map_err(|err| err.to_string()))
+        .as_ref()
+        .map(|_| ())
+        .map_err(|err| Error::UaRegexes(err.clone()))
+}
+
+/// Minimal builds have no regexes, there is nothing to initialize.
+#[cfg(feature = "minimal")]
+pub fn try_init() -> Result<(), Error> {
+    Ok(())
+}
+
+/// [`try_init`], panicking if the regexes are invalid.
+pub fn init() {
+    if let Err(err) = try_init() {
+        panic!("{err}");
+    }
+}
+
+/// Runs [`try_init`] on a background thread, resolving once done, so an
+/// async server can warm up the parser without blocking its runtime. Hosts
+/// without threads, like WASM at the edge, call [`try_init`] instead.
+pub fn warm_up() -> WarmUp {
+    let warm_up = WarmUp::default();
+    let (done, waker) = (warm_up.done.clone(), warm_up.waker.clone());
+    thread::spawn(move || {
+        let _ = try_init();
+        done.store(true, Ordering::Release);
+        if let Some(waker) = waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    });
+    warm_up
+}
+
+/// Future of [`warm_up`].
+#[derive(Debug, Default)]
+pub struct WarmUp {
+    done: Arc<AtomicBool>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl Future for WarmUp {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut waker = self.waker.lock().unwrap();
+        if self.done.load(Ordering::Acquire) {
+            return Poll::Ready(try_init());
+        }
+        *waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// The uap parser, initialized on first use. `None` if the regexes are
+/// invalid or in minimal builds.
+pub fn parser() -> Option<&'static UserAgentParser> {
+    let _ = try_init();
+    UA_PARSER.get()?.as_ref().ok()
+}
+
+/// Browser and platform parsed from a user agent string.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UserAgent {
+    pub browser: Option<Cow<'static, str>>,
+    pub platform: Option<Cow<'static, str>>,
+    /// Whether the user agent was rejected by [`is_bogus`] and not parsed.
+    pub bogus: bool,
+}
+
+impl UserAgent {
+    /// Classifies common user agents with the [`prefilter`], runs the uap
+    /// parser for the rest. Prefer [`UaCache::parse`] on hot paths.
+    ///
+    /// Minimal builds leave browser and platform unknown.
+    pub fn parse(user_agent: &str) -> Self {
+        if cfg!(feature = "minimal") {
+            return UserAgent::default();
+        }
+        prefilter(user_agent).unwrap_or_else(|| UserAgent::parse_uap(user_agent))
+    }
+
+    /// Runs the uap parser.
+    ///
+    /// Only the browser and os regexes are evaluated, device detection is
+    /// skipped since it is not used. Without a [`parser`] browser and
+    /// platform are unknown. [Bogus](is_bogus) user agents never reach the
+    /// regexes.
+    pub fn parse_uap(user_agent: &str) -> Self {
+        if is_bogus(user_agent) {
+            return UserAgent {
+                bogus: true,
+                ..Default::default()
+            };
+        }
+        let Some(parser) = parser() else {
+            return UserAgent::default();
+        };
+        UserAgent {
+            browser: intern(parser.parse_user_agent(user_agent).family),
+            platform: intern(parser.parse_os(user_agent).family),
+            bogus: false,
+        }
+    }
+}
+
+/// Borrows a family name from [`UA_FAMILIES`], families the regexes don't
+/// name verbatim are copied. `None` if empty.
+fn intern(family: Cow<'_, str>) -> Option<Cow<'static, str>> {
+    if family.is_empty() {
+        return None;
+    }
+    Some(match UA_FAMILIES.get_key(&*family) {
+        Some(name) => Cow::Borrowed(name),
+        None => Cow::Owned(family.into_owned()),
+    })
+}
+
+/// Whether a user agent is garbage not worth parsing: longer than
+/// [`MAX_USER_AGENT_LEN`], with control characters or replaced invalid
+/// UTF-8, or with the long runs of repeated characters or words without
+/// spaces that make the uap regexes backtrack.
+///
+/// User agents are attacker controlled, so these are checked before any
+/// regex runs.
+pub fn is_bogus(user_agent: &str) -> bool {
+    if user_agent.len() > MAX_USER_AGENT_LEN {
+        return true;
+    }
+    let (mut prev, mut repeat, mut word) = ('\0', 0, 0);
+    for c in user_agent.chars() {
+        if c.is_control() || c == char::REPLACEMENT_CHARACTER {
+            return true;
+        }
+        repeat = if c == prev { repeat + 1 } else { 1 };
+        word = if c == ' ' { 0 } else { word + 1 };
+        if repeat > MAX_REPEAT || word > MAX_WORD_LEN {
+            return true;
+        }
+        prev = c;
+    }
+    false
+}
+
+/// Classifies the user agents of current Chrome, Edge, Firefox and Safari
+/// on Windows, macOS, Linux, Android and iOS without regexes or
+/// allocations.
+///
+/// Only user agents of exactly the shape these browsers send are
+/// classified, with the names the uap parser gives them. Everything else,
+/// including other browsers built on the same engines, returns `None`.
+pub fn prefilter(user_agent: &str) -> Option<UserAgent> {
+    let rest = user_agent.strip_prefix("Mozilla/5.0 (")?;
+    let (platform, product) = rest.split_once(") ")?;
+    let mut buf = [""; 4];
+    let mut len = 0;
+    for part in platform.split("; ") {
+        *buf.get_mut(len)? = part;
+        len += 1;
+    }
+    let parts = &buf[..len];
+
+    let (browser, platform) = match parts.last()?.strip_prefix("rv:") {
+        Some(rv) if is_version(rv) => {
+            let parts = &parts[..len - 1];
+            let (gecko, firefox) = product.split_once(' ')?;
+            is_version(firefox.strip_prefix("Firefox/")?).then_some(())?;
+            match parts {
+                [android, "Mobile"] => {
+                    is_version(android.strip_prefix("Android ")?).then_some(())?;
+                    is_version(gecko.strip_prefix("Gecko/")?).then_some(())?;
+                    ("Firefox Mobile", "Android")
+                }
+                parts => {
+                    (gecko == "Gecko/20100101").then_some(())?;
+                    ("Firefox", desktop(parts)?)
+                }
+            }
+        }
+        Some(_) => return None,
+        None => {
+            let rest = product.strip_prefix("AppleWebKit/")?;
+            let (webkit, rest) = rest.split_once(" (KHTML, like Gecko) ")?;
+            if webkit == "537.36" {
+                chromium(rest, parts)?
+            } else {
+                (webkit == "605.1.15").then_some(())?;
+                safari(rest, parts)?
+            }
+        }
+    };
+    Some(UserAgent {
+        browser: Some(Cow::Borrowed(browser)),
+        platform: Some(Cow::Borrowed(platform)),
+        bogus: false,
+    })
+}
+
+/// `Chrome/<version> [Mobile ]Safari/537.36[ Edg/<version>]`
+fn chromium(rest: &str, parts: &[&str]) -> Option<(&'static str, &'static str)> {
+    let (chrome, rest) = rest.strip_prefix("Chrome/")?.split_once(' ')?;
+    is_version(chrome).then_some(())?;
+    let (mobile, rest) = match rest.strip_prefix("Mobile ") {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let edge = match rest.strip_prefix("Safari/537.36")? {
+        "" => false,
+        edge => is_version(edge.strip_prefix(" Edg/")?).then_some(true)?,
+    };
+    match (parts, mobile, edge) {
+        // the reduced user agent of Chrome on Android
+        (["Linux", android, "K"], true, false) => {
+            is_version(android.strip_prefix("Android ")?).then_some(())?;
+            Some(("Chrome Mobile", "Android"))
+        }
+        (parts, false, false) => Some(("Chrome", desktop(parts)?)),
+        (parts, false, true) => Some(("Edge", desktop(parts)?)),
+        _ => None,
+    }
+}
+
+/// `Version/<version> [Mobile/<build> ]Safari/<version>`
+fn safari(rest: &str, parts: &[&str]) -> Option<(&'static str, &'static str)> {
+    let (version, rest) = rest.strip_prefix("Version/")?.split_once(' ')?;
+    is_version(version).then_some(())?;
+    match (parts, rest.strip_prefix("Mobile/")) {
+        (["Macintosh", _], None) => {
+            (rest == "Safari/605.1.15").then_some(())?;
+            Some(("Safari", desktop(parts)?))
+        }
+        ([device, os], Some(rest)) => {
+            let (build, safari) = rest.split_once(' ')?;
+            build
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric())
+                .then_some(())?;
+            (safari == "Safari/604.1").then_some(())?;
+            let os = match *device {
+                "iPhone" => os.strip_prefix("CPU iPhone OS ")?,
+                "iPad" => os.strip_prefix("CPU OS ")?,
+                _ => return None,
+            };
+            is_version(os.strip_suffix(" like Mac OS X")?).then_some(())?;
+            Some(("Mobile Safari", "iOS"))
+        }
+        _ => None,
+    }
+}
+
+/// The platform of `Windows NT 10.0; Win64; x64`, `Macintosh; Intel Mac OS
+/// X 10_15_7` and `X11; Linux x86_64`.
+fn desktop(parts: &[&str]) -> Option<&'static str> {
+    match parts {
+        [windows] | [windows, "Win64", "x64"] | [windows, "WOW64"] => {
+            is_version(windows.strip_prefix("Windows NT ")?).then_some("Windows")
+        }
+        ["Macintosh", mac] => {
+            is_version(mac.strip_prefix("Intel Mac OS X ")?).then_some("Mac OS X")
+        }
+        ["X11", "Linux x86_64"] => Some("Linux"),
+        _ => None,
+    }
+}
+
+/// Digits separated by dots or underscores.
+fn is_version(version: &str) -> bool {
+    !version.is_empty()
+        && version
+            .split(['.', '_'])
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    /// user agent → (parsed value, last access tick)
+    entries: HashMap<String, (UserAgent, u64)>,
+    /// last access tick → user agent, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// A user agent waiting for a parser thread and where to send the result.
+#[derive(Debug)]
+struct Job {
+    user_agent: String,
+    reply: mpsc::Sender<UserAgent>,
+}
+
+/// Jobs of the parser threads, oldest first.
+#[derive(Debug, Default)]
+struct ParseQueue {
+    jobs: Mutex<VecDeque<Job>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+/// Bounded least-recently-used cache of parsed user agents.
+///
+/// Real traffic has extreme user agent repetition, so this avoids running
+/// the uap regexes for most requests.
+#[derive(Debug)]
+pub struct UaCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    queue: ParseQueue,
+    workers: Once,
+}
+
+impl UaCache {
+    pub fn new(capacity: usize) -> Self {
+        UaCache {
+            capacity,
+            lru: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            queue: ParseQueue::default(),
+            workers: Once::new(),
+        }
+    }
+
+    /// Returns the cached result or parses and caches the user agent.
+    ///
+    /// [Bogus](is_bogus) user agents are not cached, they would only evict
+    /// real ones.
+    pub fn parse(&self, user_agent: &str) -> UserAgent {
+        if let Some(val) = self.get(user_agent) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return val;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // parse without holding the lock
+        let val = UserAgent::parse(user_agent);
+        if !val.bogus {
+            self.insert(user_agent, val.clone());
+        }
+        val
+    }
+
+    /// Like [`UaCache::parse`], but gives up waiting after `budget` and
+    /// returns `None`, so a pathological user agent cannot stall the caller.
+    ///
+    /// A running regex can't be interrupted, so misses the [`prefilter`]
+    /// doesn't classify are queued for [`PARSE_WORKERS`] parser threads,
+    /// which cache the result once it is done. While [`PARSE_QUEUE_LEN`]
+    /// misses are waiting, callers wait for a free slot within their budget.
+    pub fn parse_within(&'static self, user_agent: &str, budget: Duration) -> Option<UserAgent> {
+        if cfg!(feature = "minimal") {
+            return Some(UserAgent::default());
+        }
+        if is_bogus(user_agent) {
+            return Some(UserAgent::parse(user_agent));
+        }
+        if let Some(val) = prefilter(user_agent).or_else(|| self.cached(user_agent)) {
+            return Some(val);
+        }
+        let deadline = Instant::now() + budget;
+        self.workers.call_once(|| {
+            for _ in 0..PARSE_WORKERS {
+                // without workers queued parses time out, like a stalled one
+                let _ = thread::Builder::new()
+                    .name("ua-parse".to_string())
+                    .spawn(move || self.work());
+            }
+        });
+        let (reply, rx) = mpsc::channel();
+        let mut jobs = self.queue.jobs.lock().unwrap();
+        while jobs.len() >= PARSE_QUEUE_LEN {
+            let timeout = deadline.checked_duration_since(Instant::now())?;
+            jobs = self.queue.not_full.wait_timeout(jobs, timeout).unwrap().0;
+        }
+        jobs.push_back(Job {
+            user_agent: user_agent.to_string(),
+            reply,
+        });
+        drop(jobs);
+        self.queue.not_empty.notify_one();
+        rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .ok()
+    }
+
+    /// Runs on a parser thread, parses and caches the queued user agents.
+    /// Jobs whose caller gave up are still parsed, so later records find
+    /// the result in the cache.
+    fn work(&self) {
+        loop {
+            let mut jobs = self.queue.jobs.lock().unwrap();
+            let job = loop {
+                match jobs.pop_front() {
+                    Some(job) => break job,
+                    None => jobs = self.queue.not_empty.wait(jobs).unwrap(),
+                }
+            };
+            drop(jobs);
+            self.queue.not_full.notify_one();
+            // a queued duplicate was parsed in the meantime
+            let val = self.get(&job.user_agent).unwrap_or_else(|| {
+                let val = UserAgent::parse(&job.user_agent);
+                self.insert(&job.user_agent, val.clone());
+                val
+            });
+            let _ = job.reply.send(val);
+        }
+    }
+
+    /// Returns the cached result without parsing on a miss.
+    pub fn cached(&self, user_agent: &str) -> Option<UserAgent> {
+        let val = self.get(user_agent);
+        match val {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        val
+    }
+
+    fn get(&self, user_agent: &str) -> Option<UserAgent> {
+        let mut lru = self.lru.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+        let (val, last) = lru.entries.get_mut(user_agent)?;
+        let prev = std::mem::replace(last, tick);
+        let val = val.clone();
+        let key = lru.recency.remove(&prev).expect("recency in sync");
+        lru.recency.insert(tick, key);
+        Some(val)
+    }
+
+    fn insert(&self, user_agent: &str, val: UserAgent) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some((_, prev)) = lru.entries.insert(user_agent.to_string(), (val, tick)) {
+            // another thread parsed the same user agent concurrently
+            lru.recency.remove(&prev);
+        }
+        lru.recency.insert(tick, user_agent.to_string());
+
+        while lru.entries.len() > self.capacity {
+            let (_, oldest) = lru.recency.pop_first().expect("recency in sync");
+            lru.entries.remove(&oldest);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Fraction of lookups served from the cache, `0.0` if there were none.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME: &str = "Mozilla/5.0 (Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/112.0.0.0 Safari/537.36";
+    const FIREFOX: &str =
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:109.0) Gecko/20100101 Firefox/117.0";
+    const SAFARI: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.6 Safari/605.1.15";
+
+    struct Unpark(thread::Thread);
+
+    impl std::task::Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    #[test]
+    fn warm_up_initializes_parser() {
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut warm_up = warm_up();
+        let warmed = loop {
+            match Pin::new(&mut warm_up).poll(&mut cx) {
+                Poll::Ready(warmed) => break warmed,
+                Poll::Pending => thread::park(),
+            }
+        };
+        warmed.unwrap();
+        try_init().unwrap();
+        assert_eq!(parser().is_some(), cfg!(not(feature = "minimal")));
+    }
+
+    #[cfg(feature = "minimal")]
+    #[test]
+    fn minimal_build_passes_through() {
+        assert_eq!(UserAgent::parse(CHROME), UserAgent::default());
+        assert_eq!(crate::region_for_tz("Europe/Zurich"), None);
+    }
+
+    #[test]
+    fn cached_result_matches_parser() {
+        let cache = UaCache::new(8);
+        let first = cache.parse(CHROME);
+        let second = cache.parse(CHROME);
+        assert_eq!(first, UserAgent::parse(CHROME));
+        assert_eq!(first, second);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+        assert_eq!(cache.stats().hit_rate(), 0.5);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = UaCache::new(2);
+        cache.parse(CHROME);
+        cache.parse(FIREFOX);
+        cache.parse(CHROME);
+        cache.parse(SAFARI);
+        assert_eq!(cache.len(), 2);
+
+        cache.parse(CHROME);
+        assert_eq!(cache.stats().hits, 2);
+        cache.parse(FIREFOX);
+        assert_eq!(cache.stats().misses, 4);
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn prefilter_matches_parser() {
+        let classified = [
+            FIREFOX,
+            SAFARI,
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36",
+            "Mozilla/5.0 (Windows NT 6.1; WOW64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/109.0.0.0 Safari/537.36",
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36",
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36",
+            "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Mobile Safari/537.36",
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36 Edg/117.0.2045.43",
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36 Edg/117.0.2045.43",
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:109.0) Gecko/20100101 Firefox/118.0",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/118.0",
+            "Mozilla/5.0 (Android 13; Mobile; rv:109.0) Gecko/118.0 Firefox/118.0",
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.6 Mobile/15E148 Safari/604.1",
+            "Mozilla/5.0 (iPad; CPU OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.6 Mobile/15E148 Safari/604.1",
+        ];
+        for user_agent in classified {
+            let prefiltered = prefilter(user_agent);
+            assert!(prefiltered.is_some(), "{user_agent}");
+            assert_eq!(
+                prefiltered.unwrap(),
+                UserAgent::parse_uap(user_agent),
+                "{user_agent}"
+            );
+        }
+
+        let long_tail = [
+            CHROME,
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36 OPR/102.0.0.0",
+            "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/22.0 Chrome/111.0.5563.116 Mobile Safari/537.36",
+            "Mozilla/5.0 (Linux; Android 13; Pixel 7 Build/TQ3A; wv) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/117.0.0.0 Mobile Safari/537.36",
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) CriOS/117.0.5938.117 Mobile/15E148 Safari/604.1",
+            "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/118.0",
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) HeadlessChrome/117.0.0.0 Safari/537.36",
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36 Edg/",
+            "curl/8.1.2",
+            "",
+        ];
+        for user_agent in long_tail {
+            assert_eq!(prefilter(user_agent), None, "{user_agent}");
+        }
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn borrows_known_families() {
+        let long_tail = [
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36 OPR/102.0.0.0", "Opera", "Windows"),
+            ("Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/22.0 Chrome/111.0.5563.116 Mobile Safari/537.36", "Samsung Internet", "Android"),
+            ("Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/118.0", "Firefox", "Ubuntu"),
+            ("curl/8.1.2", "curl", "Other"),
+        ];
+        for (user_agent, browser, platform) in long_tail {
+            let parsed = UserAgent::parse_uap(user_agent);
+            assert_eq!(parsed.browser, Some(Cow::Borrowed(browser)), "{user_agent}");
+            assert_eq!(
+                parsed.platform,
+                Some(Cow::Borrowed(platform)),
+                "{user_agent}"
+            );
+            let borrowed = |family: Option<Cow<'_, str>>| matches!(family, Some(Cow::Borrowed(_)));
+            assert!(
+                borrowed(parsed.browser) && borrowed(parsed.platform),
+                "{user_agent}"
+            );
+        }
+        assert_eq!(intern("".into()), None);
+        assert!(matches!(intern("Nonexistent".into()), Some(Cow::Owned(_))));
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn parses_within_budget() {
+        let cache: &'static UaCache = Box::leak(Box::new(UaCache::new(8)));
+        let curl = "curl/8.1.2";
+        let parsed = cache.parse_within(curl, Duration::from_secs(60));
+        assert_eq!(parsed, Some(UserAgent::parse(curl)));
+        assert_eq!(cache.len(), 1);
+        // classified without a parser thread
+        assert!(cache.parse_within(FIREFOX, Duration::ZERO).is_some());
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn queues_parses_beyond_workers() {
+        let cache: &'static UaCache = Box::leak(Box::new(UaCache::new(64)));
+        let parsed: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..4 * PARSE_WORKERS)
+                .map(|i| {
+                    scope.spawn(move || {
+                        cache.parse_within(&format!("curl/8.{i}.0"), Duration::from_secs(60))
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        // none ran out of its own budget
+        assert!(parsed.iter().all(Option::is_some));
+        assert_eq!(cache.len(), 4 * PARSE_WORKERS);
+
+        // a caller out of budget gives up, the result is still cached
+        let user_agent = "Wget/1.21.4";
+        assert_eq!(cache.parse_within(user_agent, Duration::ZERO), None);
+        let start = Instant::now();
+        while cache.cached(user_agent).is_none() {
+            assert!(start.elapsed() < Duration::from_secs(60));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn rejects_bogus_user_agents() {
+        for real in [CHROME, FIREFOX, SAFARI, "curl/8.1.2"] {
+            assert!(!is_bogus(real), "{real}");
+        }
+        let long = format!("Mozilla/5.0 {}", "(a; b) ".repeat(200));
+        let bogus = [
+            long.as_str(),
+            "Mozilla/5.0 \0\x01\x02",
+            "Mozilla/5.0 (\u{fffd}\u{fffd})",
+            &format!("Mozilla/5.0 ({})", " ".repeat(40)),
+            &format!("Mozilla/5.0 {}", "ab".repeat(200)),
+        ];
+        let cache = UaCache::new(8);
+        for user_agent in bogus {
+            assert!(is_bogus(user_agent), "{user_agent}");
+            let parsed = cache.parse(user_agent);
+            assert!(parsed.bogus);
+            assert_eq!((parsed.browser, parsed.platform), (None, None));
+        }
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn zero_capacity_disables_caching() {
+        let cache = UaCache::new(0);
+        cache.parse(CHROME);
+        cache.parse(CHROME);
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats().hits, 0);
+    }
+}
@@ -0,0 +1,242 @@
+//! Usage accounting per project.
+//!
+//! A [`UsageMeter`] counts the beacon bytes and the delivered visits and
+//! events of every project and periodically writes them as [`Usage`]
+//! records to a dedicated [`UsageSink`], so billing and capacity planning
+//! don't need to query the analytics tables.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Record};
+
+/// Counts of a project within `start..end`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub project: i64,
+    pub start: DateTime<Utc>,
+    /// Exclusive.
+    pub end: DateTime<Utc>,
+    pub visits: u64,
+    pub events: u64,
+    /// Size of the beacon bodies received.
+    pub bytes: u64,
+}
+
+/// Receives the usage records of a period.
+pub trait UsageSink: Send {
+    fn write(&mut self, usage: Vec<Usage>) -> Result<(), Error>;
+}
+
+impl<F> UsageSink for F
+where
+    F: FnMut(Vec<Usage>) -> Result<(), Error> + Send,
+{
+    fn write(&mut self, usage: Vec<Usage>) -> Result<(), Error> {
+        self(usage)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    visits: u64,
+    events: u64,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct Period {
+    start: DateTime<Utc>,
+    counts: HashMap<i64, Counts>,
+}
+
+pub struct UsageMeter {
+    interval: Duration,
+    period: Mutex<Period>,
+    sink: Mutex<Box<dyn UsageSink>>,
+}
+
+impl UsageMeter {
+    /// Writes the usage to `sink` every `interval`, starting now.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is not positive.
+    pub fn new(interval: Duration, sink: impl UsageSink + 'static) -> Self {
+        assert!(interval > Duration::zero(), "interval must be positive");
+        UsageMeter {
+            interval,
+            period: Mutex::new(Period {
+                start: Utc::now(),
+                counts: HashMap::new(),
+            }),
+            sink: Mutex::new(Box::new(sink)),
+        }
+    }
+
+    /// Counts a received beacon body.
+    pub fn count_beacon(&self, project: i64, bytes: usize) {
+        let mut period = self.period.lock().unwrap();
+        period.counts.entry(project).or_default().bytes += bytes as u64;
+    }
+
+    /// Counts a delivered record.
+    pub fn count_record(&self, record: &Record) {
+        let mut period = self.period.lock().unwrap();
+        let counts = period.counts.entry(record.project()).or_default();
+        match record {
+            Record::Visit(_) => counts.visits += 1,
+            Record::Event(_) => counts.events += 1,
+            // derived from the counted records
+            Record::Session(_) => {}
+        }
+    }
+
+    /// Writes the usage if the interval has passed since the period started.
+    pub fn tick(&self, now: DateTime<Utc>) -> Result<(), Error> {
+        let due = now - self.period.lock().unwrap().start >= self.interval;
+        if due {
+            self.flush(now)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the usage of the current period, ending it at `now`.
+    ///
+    /// If the sink fails, the counts are kept for the next period.
+    pub fn flush(&self, now: DateTime<Utc>) -> Result<(), Error> {
+        let (start, counts) = {
+            let mut period = self.period.lock().unwrap();
+            let start = std::mem::replace(&mut period.start, now);
+            (start, std::mem::take(&mut period.counts))
+        };
+        if counts.is_empty() {
+            return Ok(());
+        }
+
+        let mut usage: Vec<Usage> = counts
+            .iter()
+            .map(|(project, counts)| Usage {
+                project: *project,
+                start,
+                end: now,
+                visits: counts.visits,
+                events: counts.events,
+                bytes: counts.bytes,
+            })
+            .collect();
+        usage.sort_unstable_by_key(|usage| usage.project);
+
+        let written = self.sink.lock().unwrap().write(usage);
+        if written.is_err() {
+            let mut period = self.period.lock().unwrap();
+            period.start = start;
+            for (project, old) in counts {
+                let counts = period.counts.entry(project).or_default();
+                counts.visits += old.visits;
+                counts.events += old.events;
+                counts.bytes += old.bytes;
+            }
+        }
+        written
+    }
+}
+
+impl std::fmt::Debug for UsageMeter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UsageMeter")
+            .field("interval", &self.interval)
+            .field("period", &self.period)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::TimeZone;
+
+    use crate::{Event, Visit};
+
+    use super::*;
+
+    fn record(project: i64, event: bool) -> Record {
+        if event {
+            Event {
+                project,
+                ..Default::default()
+            }
+            .into()
+        } else {
+            Visit {
+                project,
+                ..Default::default()
+            }
+            .into()
+        }
+    }
+
+    #[test]
+    fn writes_counts_per_period() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let meter = UsageMeter::new(Duration::minutes(5), {
+            let written = written.clone();
+            move |usage: Vec<Usage>| {
+                written.lock().unwrap().extend(usage);
+                Ok(())
+            }
+        });
+        meter.count_beacon(1, 100);
+        meter.count_record(&record(1, false));
+        meter.count_record(&record(1, true));
+        meter.count_record(&record(2, true));
+
+        let start = meter.period.lock().unwrap().start;
+        meter.tick(start + Duration::minutes(1)).unwrap();
+        assert!(written.lock().unwrap().is_empty());
+
+        let end = start + Duration::minutes(5);
+        meter.tick(end).unwrap();
+        let written = written.lock().unwrap();
+        assert_eq!(written.len(), 2);
+        assert_eq!(
+            written[0],
+            Usage {
+                project: 1,
+                start,
+                end,
+                visits: 1,
+                events: 1,
+                bytes: 100,
+            }
+        );
+        assert_eq!((written[1].project, written[1].events), (2, 1));
+    }
+
+    #[test]
+    fn keeps_counts_on_sink_error() {
+        let fail = Arc::new(Mutex::new(true));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let meter = UsageMeter::new(Duration::minutes(5), {
+            let (fail, written) = (fail.clone(), written.clone());
+            move |usage: Vec<Usage>| {
+                if *fail.lock().unwrap() {
+                    return Err(Error::Closed);
+                }
+                written.lock().unwrap().extend(usage);
+                Ok(())
+            }
+        });
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+        meter.count_beacon(1, 10);
+        assert!(meter.flush(now).is_err());
+        meter.count_beacon(1, 5);
+        *fail.lock().unwrap() = false;
+        meter.flush(now + Duration::minutes(5)).unwrap();
+        assert_eq!(written.lock().unwrap()[0].bytes, 15);
+    }
+}
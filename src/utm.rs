@@ -0,0 +1,421 @@
+//! Campaign parameters of landing page urls.
+//!
+//! The collector reads the parameters with [`UtmParam::new`]. Links built
+//! with [`build_url`] use the same encoding, so marketing tools and the
+//! collector agree on the campaign of a visit.
+//!
+//! Keys are matched case-insensitively and may carry a `utm_` prefix, so
+//! `UTM_Source` from an email tool is read like `source`.
+//!
+//! Projects migrating from other tools can map their parameters onto the
+//! campaign parameters with [`UtmOptions::aliases`].
+//!
+//! Links that repeat a parameter (`?source=a&source=b`) are read as set by
+//! [`UtmOptions::repeated`], the last value wins by default. [`ambiguous`]
+//! finds such links.
+//!
+//! Values longer than [`UtmOptions::max_len`] characters are cut and end
+//! with [`TRUNCATION_MARKER`] before they are hashed, so abused parameters
+//! neither bloat the storage nor create unbounded numbers of ids. Only the
+//! first [`MAX_QUERY_LEN`] bytes of a query are read.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use url::{form_urlencoded, Url};
+
+#[cfg(doc)]
+use crate::UtmParam;
+
+/// Query keys of the campaign parameters.
+pub const KEYS: [&str; 5] = ["campaign", "content", "medium", "source", "term"];
+
+/// Optional prefix of the query keys.
+pub const KEY_PREFIX: &str = "utm_";
+
+/// A campaign parameter of [`UtmParam`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UtmField {
+    Campaign,
+    Content,
+    Medium,
+    Source,
+    Term,
+}
+
+impl UtmField {
+    pub const ALL: [UtmField; 5] = [
+        UtmField::Campaign,
+        UtmField::Content,
+        UtmField::Medium,
+        UtmField::Source,
+        UtmField::Term,
+    ];
+
+    /// The query key of the parameter, one of [`KEYS`].
+    pub fn key(self) -> &'static str {
+        match self {
+            UtmField::Campaign => "campaign",
+            UtmField::Content => "content",
+            UtmField::Medium => "medium",
+            UtmField::Source => "source",
+            UtmField::Term => "term",
+        }
+    }
+
+    /// The parameter a query key names, ignoring case and the
+    /// [`KEY_PREFIX`].
+    pub fn from_key(key: &str) -> Option<Self> {
+        let key = match key.get(..KEY_PREFIX.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(KEY_PREFIX) => &key[KEY_PREFIX.len()..],
+            _ => key,
+        };
+        let field = match key.to_ascii_lowercase().as_str() {
+            "campaign" => UtmField::Campaign,
+            "content" => UtmField::Content,
+            "medium" => UtmField::Medium,
+            "source" => UtmField::Source,
+            "term" => UtmField::Term,
+            _ => return None,
+        };
+        Some(field)
+    }
+}
+
+/// Default of [`UtmOptions::max_len`].
+pub const DEFAULT_MAX_LEN: usize = 256;
+
+/// Last character of truncated values.
+pub const TRUNCATION_MARKER: char = '…';
+
+/// Bytes of a query read for campaign parameters, parameters beyond are
+/// ignored.
+pub const MAX_QUERY_LEN: usize = 16 * 1024;
+
+/// Separator of the values of [`Repeated::Join`].
+pub const JOIN_SEPARATOR: &str = ",";
+
+/// What is kept of a parameter a link gives more than once.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Repeated {
+    First,
+    #[default]
+    Last,
+    /// The distinct values in order, joined with [`JOIN_SEPARATOR`].
+    Join,
+}
+
+impl Repeated {
+    /// The value of a parameter from its values in query order.
+    pub fn resolve<'a>(self, values: &[Cow<'a, str>]) -> Option<Cow<'a, str>> {
+        match self {
+            Repeated::First => values.first().cloned(),
+            Repeated::Last => values.last().cloned(),
+            Repeated::Join => {
+                let mut distinct: Vec<&str> = Vec::new();
+                for value in values {
+                    if !distinct.contains(&&**value) {
+                        distinct.push(value);
+                    }
+                }
+                match distinct[..] {
+                    [] => None,
+                    [_] => values.first().cloned(),
+                    _ => Some(Cow::Owned(distinct.join(JOIN_SEPARATOR))),
+                }
+            }
+        }
+    }
+}
+
+/// How the campaign parameters of a project are read.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UtmOptions {
+    /// Maximum length of a value in characters, including the marker.
+    pub max_len: usize,
+    /// Further query keys of the parameters, e.g. `mtm_campaign` or
+    /// `pk_campaign` of Matomo, matched case-insensitively. The campaign
+    /// parameters themselves take precedence.
+    pub aliases: BTreeMap<String, UtmField>,
+    /// What is kept of repeated parameters, aliases of the same parameter
+    /// count as repetitions.
+    pub repeated: Repeated,
+}
+
+impl Default for UtmOptions {
+    fn default() -> Self {
+        UtmOptions {
+            max_len: DEFAULT_MAX_LEN,
+            aliases: BTreeMap::new(),
+            repeated: Repeated::Last,
+        }
+    }
+}
+
+impl UtmOptions {
+    /// The parameter an alias names.
+    pub fn alias(&self, key: &str) -> Option<UtmField> {
+        self.aliases
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(key))
+            .map(|(_, field)| *field)
+    }
+}
+
+/// Cuts `value` to `max_len` characters, the last one being the
+/// [`TRUNCATION_MARKER`].
+pub fn truncate(value: &str, max_len: usize) -> Cow<'_, str> {
+    match value.char_indices().nth(max_len) {
+        None => Cow::Borrowed(value),
+        Some(_) => {
+            let mut val: String = value.chars().take(max_len.saturating_sub(1)).collect();
+            val.push(TRUNCATION_MARKER);
+            Cow::Owned(val)
+        }
+    }
+}
+
+/// The query pairs of `url` within the first [`MAX_QUERY_LEN`] bytes, a
+/// pair cut by the limit is left out.
+pub(crate) fn query_pairs(url: &Url) -> form_urlencoded::Parse<'_> {
+    let query = url.query().unwrap_or_default().as_bytes();
+    let query = match query.get(..MAX_QUERY_LEN) {
+        Some(head) if query.len() > MAX_QUERY_LEN => {
+            &head[..head.iter().rposition(|&b| b == b'&').unwrap_or(0)]
+        }
+        _ => query,
+    };
+    form_urlencoded::parse(query)
+}
+
+/// The values of each parameter given by `url` in query order, those of
+/// its aliases if the parameter itself is missing.
+pub(crate) fn values<'a>(url: &'a Url, options: &UtmOptions) -> Vec<(UtmField, Vec<Cow<'a, str>>)> {
+    let (mut direct, mut aliased) = (Vec::new(), Vec::new());
+    for (key, value) in query_pairs(url) {
+        if let Some(field) = UtmField::from_key(&key) {
+            direct.push((field, value));
+        } else if let Some(field) = options.alias(&key) {
+            aliased.push((field, value));
+        }
+    }
+    UtmField::ALL
+        .into_iter()
+        .filter_map(|field| {
+            let of = |pairs: &[(UtmField, Cow<'a, str>)]| -> Vec<Cow<'a, str>> {
+                pairs
+                    .iter()
+                    .filter(|(of, _)| *of == field)
+                    .map(|(_, value)| value.clone())
+                    .collect()
+            };
+            let values = Some(of(&direct))
+                .filter(|values| !values.is_empty())
+                .unwrap_or_else(|| of(&aliased));
+            (!values.is_empty()).then_some((field, values))
+        })
+        .collect()
+}
+
+/// The parameters `url` gives different values, so their value depends on
+/// [`UtmOptions::repeated`]. Such links are likely broken by tools that
+/// append parameters and should be fixed.
+pub fn ambiguous(url: &Url, options: &UtmOptions) -> Vec<UtmField> {
+    values(url, options)
+        .into_iter()
+        .filter(|(_, values)| values.iter().any(|value| *value != values[0]))
+        .map(|(field, _)| field)
+        .collect()
+}
+
+/// Adds the campaign parameters to `base`.
+///
+/// Campaign parameters already present in `base` are replaced, other query
+/// parameters and the fragment are kept. The parameters of the resulting
+/// url read back with [`UtmParam::new`] are exactly the given ones, as long
+/// as they are within [`DEFAULT_MAX_LEN`].
+pub fn build_url(
+    base: &Url,
+    campaign: &str,
+    source: &str,
+    medium: &str,
+    content: Option<&str>,
+    term: Option<&str>,
+) -> Url {
+    let mut url = base.clone();
+    let kept: Vec<(String, String)> = base
+        .query_pairs()
+        .filter(|(key, _)| UtmField::from_key(key).is_none())
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.set_query(None);
+
+    let mut query = url.query_pairs_mut();
+    query.extend_pairs(kept);
+    query.append_pair("campaign", campaign);
+    query.append_pair("source", source);
+    query.append_pair("medium", medium);
+    if let Some(content) = content {
+        query.append_pair("content", content);
+    }
+    if let Some(term) = term {
+        query.append_pair("term", term);
+    }
+    drop(query);
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UtmParam;
+
+    #[test]
+    fn round_trips_through_utm_param() {
+        let base = Url::parse("https://example.com/landing?campaign=old&ref=nav#top").unwrap();
+        let url = build_url(
+            &base,
+            "spring sale & more",
+            "news+letter",
+            "e/mail?",
+            Some("ünïcode=😀"),
+            None,
+        );
+        assert_eq!(url.fragment(), Some("top"));
+
+        let utm = UtmParam::new(1, &url).unwrap();
+        assert_eq!(utm.campaign.as_deref(), Some("spring sale & more"));
+        assert_eq!(utm.source.as_deref(), Some("news+letter"));
+        assert_eq!(utm.medium.as_deref(), Some("e/mail?"));
+        assert_eq!(utm.content.as_deref(), Some("ünïcode=😀"));
+        assert_eq!(utm.term, None);
+        assert!(url
+            .query_pairs()
+            .any(|(key, value)| key == "ref" && value == "nav"));
+        assert_eq!(
+            url.query_pairs()
+                .filter(|(key, _)| key == "campaign")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn matches_keys_case_insensitively() {
+        for key in ["source", "SOURCE", "utm_source", "UTM_Source", "Utm_SOURCE"] {
+            assert_eq!(UtmField::from_key(key), Some(UtmField::Source), "{key}");
+        }
+        for key in [
+            "utm_",
+            "utm_sources",
+            "xutm_source",
+            "utm_utm_source",
+            "ütm_source",
+        ] {
+            assert_eq!(UtmField::from_key(key), None, "{key}");
+        }
+
+        let url = Url::parse("https://example.com/?UTM_Source=mail&Utm_Medium=email").unwrap();
+        let utm = UtmParam::new(1, &url).unwrap();
+        assert_eq!(utm.source.as_deref(), Some("mail"));
+        assert_eq!(utm.medium.as_deref(), Some("email"));
+        let lower = Url::parse("https://example.com/?source=mail&medium=email").unwrap();
+        assert_eq!(UtmParam::new(1, &lower).unwrap().id, utm.id);
+
+        let base = Url::parse("https://example.com/?UTM_Campaign=old").unwrap();
+        let url = build_url(&base, "new", "mail", "email", None, None);
+        assert_eq!(url.query(), Some("campaign=new&source=mail&medium=email"));
+    }
+
+    #[test]
+    fn maps_aliases() {
+        let options = UtmOptions {
+            aliases: BTreeMap::from([
+                ("mtm_campaign".to_string(), UtmField::Campaign),
+                ("pk_campaign".to_string(), UtmField::Campaign),
+                ("ref".to_string(), UtmField::Source),
+            ]),
+            ..Default::default()
+        };
+        let url = Url::parse("https://example.com/?PK_Campaign=spring&ref=partner").unwrap();
+        let utm = UtmParam::parse(1, &url, &options).unwrap();
+        assert_eq!(utm.campaign.as_deref(), Some("spring"));
+        assert_eq!(utm.source.as_deref(), Some("partner"));
+        let standard = Url::parse("https://example.com/?campaign=spring&source=partner").unwrap();
+        assert_eq!(UtmParam::new(1, &standard).unwrap().id, utm.id);
+
+        let both = Url::parse("https://example.com/?ref=partner&utm_source=mail").unwrap();
+        let utm = UtmParam::parse(1, &both, &options).unwrap();
+        assert_eq!(utm.source.as_deref(), Some("mail"));
+
+        assert!(UtmParam::new(1, &url).is_none());
+    }
+
+    #[test]
+    fn resolves_repeated_parameters() {
+        let url = Url::parse(
+            "https://example.com/?source=a&medium=email&source=b&mtm_campaign=x&pk_campaign=y",
+        )
+        .unwrap();
+        let parse = |repeated| {
+            let options = UtmOptions {
+                aliases: [
+                    ("mtm_campaign".to_string(), UtmField::Campaign),
+                    ("pk_campaign".to_string(), UtmField::Campaign),
+                ]
+                .into(),
+                repeated,
+                ..Default::default()
+            };
+            let utm = UtmParam::parse(1, &url, &options).unwrap();
+            assert_eq!(utm.medium.as_deref(), Some("email"));
+            (utm.source.unwrap(), utm.campaign.unwrap())
+        };
+        assert_eq!(parse(Repeated::First), ("a".into(), "x".into()));
+        assert_eq!(parse(Repeated::Last), ("b".into(), "y".into()));
+        assert_eq!(parse(Repeated::Join), ("a,b".into(), "x,y".into()));
+        assert_eq!(
+            UtmParam::new(1, &url).unwrap().id,
+            UtmParam::parse(1, &url, &UtmOptions::default()).unwrap().id
+        );
+
+        assert_eq!(ambiguous(&url, &UtmOptions::default()), [UtmField::Source]);
+        let repeated = Url::parse("https://example.com/?source=a&source=a").unwrap();
+        assert!(ambiguous(&repeated, &UtmOptions::default()).is_empty());
+        let joined = UtmParam::parse(
+            1,
+            &repeated,
+            &UtmOptions {
+                repeated: Repeated::Join,
+                ..Default::default()
+            },
+        );
+        assert_eq!(joined.unwrap().source.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn truncates_long_values() {
+        assert_eq!(truncate("spring", 6), "spring");
+        assert_eq!(truncate("spring", 4), "spr…");
+        assert_eq!(truncate("ääää", 3), "ää…");
+        assert_eq!(truncate("spring", 0), "…");
+
+        let long = "x".repeat(10_000);
+        let url = Url::parse(&format!("https://example.com/?campaign={long}&term=y")).unwrap();
+        let options = UtmOptions {
+            max_len: 8,
+            ..Default::default()
+        };
+        let utm = UtmParam::parse(1, &url, &options).unwrap();
+        assert_eq!(utm.campaign.as_deref(), Some("xxxxxxx…"));
+        assert_eq!(utm.term.as_deref(), Some("y"));
+
+        // every value beyond the limit maps to the same id
+        let other = Url::parse(&format!("https://example.com/?campaign={long}z&term=y")).unwrap();
+        assert_eq!(UtmParam::parse(1, &other, &options).unwrap().id, utm.id);
+    }
+}
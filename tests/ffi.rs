@@ -0,0 +1,79 @@
+//! Calls the C ABI through its exported symbols, like an embedding backend
+//! linked against the shared library. No cdylib is built for it, the symbols
+//! come from the rlib.
+#![cfg(feature = "ffi")]
+
+use std::ffi::{c_char, CStr, CString};
+
+// links the library, the functions are only reached through their symbols
+use abineo_analytics_collector as _;
+use serde_json::Value;
+
+extern "C" {
+    fn abineo_handle_visit(
+        project_id: i64,
+        body: *const c_char,
+        user_agent: *const c_char,
+        settings: *const c_char,
+    ) -> *mut c_char;
+    fn abineo_handle_event(
+        project_id: i64,
+        body: *const c_char,
+        user_agent: *const c_char,
+        settings: *const c_char,
+    ) -> *mut c_char;
+    fn abineo_free_string(s: *mut c_char);
+}
+
+const USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36";
+
+const VISITOR: &str = r#"{"tz": "Europe/Zurich", "lang": "de-CH", "screen": [1920, 1080]}"#;
+
+const PAGE: &str = r#"{"url": "https://example.com/blog", "ref": null}"#;
+
+fn call(
+    f: unsafe extern "C" fn(i64, *const c_char, *const c_char, *const c_char) -> *mut c_char,
+    body: &str,
+    settings: Option<&str>,
+) -> Value {
+    let body = CString::new(body).unwrap();
+    let user_agent = CString::new(USER_AGENT).unwrap();
+    let settings = settings.map(|settings| CString::new(settings).unwrap());
+    let settings = settings
+        .as_ref()
+        .map_or(std::ptr::null(), |settings| settings.as_ptr());
+    unsafe {
+        let json = f(1, body.as_ptr(), user_agent.as_ptr(), settings);
+        let value = serde_json::from_str(CStr::from_ptr(json).to_str().unwrap()).unwrap();
+        abineo_free_string(json);
+        value
+    }
+}
+
+#[test]
+fn exported_symbols_link() {
+    let body = format!(r#"{{"session": "7", "visitor": {VISITOR}, "page": {PAGE}}}"#);
+    let visit = call(abineo_handle_visit, &body, None);
+    let visitor_id = &visit["ok"]["visitor"]["id"];
+    assert!(visitor_id.is_i64(), "{visitor_id}");
+
+    let body = format!(
+        r#"{{"session": "7", "visitor": {VISITOR}, "page": {PAGE}, "name": "signup", "data": {{}}}}"#
+    );
+    let event = call(abineo_handle_event, &body, None);
+    assert_eq!(event["ok"]["name"], "signup");
+    assert_eq!(&event["ok"]["visitor"]["id"], visitor_id);
+
+    assert!(call(abineo_handle_visit, "{", None)["error"].is_string());
+    unsafe { abineo_free_string(std::ptr::null_mut()) };
+}
+
+#[test]
+fn rejects_paused_projects() {
+    let body = format!(r#"{{"session": "7", "visitor": {VISITOR}, "page": {PAGE}}}"#);
+    let paused = call(abineo_handle_visit, &body, Some(r#"{"paused": true}"#));
+    assert_eq!(paused["error"], "project paused");
+
+    let collecting = call(abineo_handle_visit, &body, Some(r#"{"paused": false}"#));
+    assert!(collecting["ok"].is_object(), "{collecting}");
+}